@@ -1,7 +1,18 @@
+/* Every table and column added since the original dirs and files exists from user_version 1,
+   every timestamp is stored as RFC3339 in UTC from user_version 2,
+   file IDs are handed out by file_id_sequence from user_version 3,
+   and files.is_latest is kept from user_version 4 */
+PRAGMA user_version = 4;
+
 CREATE TABLE dirs (
     id INTEGER PRIMARY KEY NOT NULL,
     parent_dir_id INTEGER,
     dir_name TEXT NOT NULL,
+    /* 1 if the dir held no files to back up in the latest run, 
+       and is recreated empty on restore */
+    kept_empty INTEGER NOT NULL DEFAULT 0,
+    /* The unix permissions of an empty dir. NULL otherwise */
+    permissions INTEGER,
 
     FOREIGN KEY (parent_dir_id) REFERENCES dirs (id)
 );
@@ -19,9 +30,146 @@ CREATE TABLE files (
     backup_ts DATETIME NOT NULL,
     /* The MD5 hash of the file */
     hsh TEXT,
+    /* The last time the backup of this file was re-hashed and
+       confirmed to match `hsh`. NULL if never verified */
+    verified_ts DATETIME,
+    /* The size of the file in bytes when it was hashed.
+       NULL for deletion markers */
+    file_size INTEGER,
+    /* The canonicalization policy the file's path was normalized with.
+       NULL for deletion markers */
+    path_policy TEXT,
+    /* Which life of the file this version belongs to. A file's generation
+       increases each time it's recreated after being deleted, and its 
+       deletion marker belongs to the generation it ended */
+    generation INTEGER NOT NULL DEFAULT 0,
+    /* The backup run which recorded this version. NULL for
+       versions recorded outside of a run, ie. when imported */
+    run_id INTEGER,
+    /* A short label given to this version, ie. "before reformatting".
+       NULL if it has none */
+    label TEXT,
+    /* 1 if this marker records the file leaving the backup set by being 
+       excluded by policy while it still existed, rather than being deleted.
+       Always 0 for versions with a hash */
+    excluded INTEGER NOT NULL DEFAULT 0,
+    /* 1 if this is the latest version of a file which still exists, so the 
+       current state of every file is found without grouping its versions.
+       Always 0 for deletion and exclusion markers, and for every version of 
+       a file whose latest version is one */
+    is_latest INTEGER NOT NULL DEFAULT 0,
+
+    FOREIGN KEY(dir_id) REFERENCES dirs (id) ON DELETE CASCADE,
+    FOREIGN KEY(run_id) REFERENCES backup_runs (id)
+);
 
-    FOREIGN KEY(dir_id) REFERENCES dirs (id) ON DELETE CASCADE
+/* The ID the next file version is given. A version's ID is taken before it's
+   recorded, since its backup is stored under it first, so it's handed out here
+   rather than by the files table, for processes sharing the catalog to never 
+   be given the same one. Holds a single row */
+CREATE TABLE file_id_sequence (
+    next_id INTEGER NOT NULL
+);
+INSERT INTO file_id_sequence (next_id) VALUES (1);
+
+CREATE TABLE backups (
+    id INTEGER PRIMARY KEY NOT NULL,
+    /* Foreign Key to the file version stored by this backup */
+    file_id INTEGER NOT NULL,
+    /* The name of the configured destination holding this copy */
+    destination TEXT NOT NULL DEFAULT 'default',
+    /* The kind of destination the backup is stored in, ie. "local" */
+    backend TEXT NOT NULL,
+    /* Where the backup is stored, relative to its destination */
+    backend_key TEXT NOT NULL,
+    /* The time the backup was stored */
+    backup_ts DATETIME NOT NULL,
+    /* The stored size of the backup in bytes, after compression. 
+       NULL where it isn't stored by itself, ie. when split into shared chunks */
+    compressed_size INTEGER,
+    /* The file version this backup is stored as a delta against. NULL if it's
+       stored in full. Not a Foreign Key, since the base's own entry is removed
+       before its backup is, once every delta against it has been stored in full */
+    delta_base_id INTEGER,
+
+    FOREIGN KEY (file_id) REFERENCES files (id) ON DELETE CASCADE
+);
+
+CREATE TABLE backup_runs (
+    id INTEGER PRIMARY KEY NOT NULL,
+    /* The time the run began */
+    started_at DATETIME NOT NULL,
+    /* The time the run finished. NULL if it's running, or never finished */
+    completed_at DATETIME,
+    /* The number of files found to examine during the run */
+    files_scanned INTEGER,
+    /* The total size of every file backed up during the run */
+    bytes_backed_up INTEGER,
+    /* The config the run was made with, as JSON with any secrets redacted */
+    config_snapshot TEXT,
+    /* 'running' until the run finishes, then 'completed', or 'partial' if it was
+       stopped at its configured deadline before examining every file, so deleted 
+       files weren't marked. A run which never finished stays 'running' */
+    status TEXT NOT NULL DEFAULT 'running',
+    /* The number of files backed up during the run */
+    files_backed_up INTEGER,
+    /* The number of files examined during the run which didn't need backing up */
+    files_skipped INTEGER,
+    /* Every error met during the run, as a JSON array of messages */
+    errors TEXT,
+    /* What started the run: 'manual', 'scheduled', 'watch', or 'ad_hoc' if it backed up a given
+       list of paths instead of every file matching the configured globs, so deleted files weren't marked */
+    run_trigger TEXT NOT NULL DEFAULT 'manual',
+    /* The reason given for the run, if any */
+    reason TEXT
+);
+
+CREATE TABLE chunks (
+    /* The hex MD5 hash of the chunk's contents, which it's stored under */
+    hsh TEXT PRIMARY KEY NOT NULL,
+    /* The size of the chunk in bytes */
+    chunk_size INTEGER NOT NULL
+);
+
+CREATE TABLE file_chunks (
+    /* Foreign Key to the chunked file version */
+    file_id INTEGER NOT NULL,
+    /* The position of the chunk within the file */
+    seq INTEGER NOT NULL,
+    /* Foreign Key to the chunk */
+    chunk_hsh TEXT NOT NULL,
+
+    PRIMARY KEY (file_id, seq),
+    FOREIGN KEY (file_id) REFERENCES files (id) ON DELETE CASCADE,
+    FOREIGN KEY (chunk_hsh) REFERENCES chunks (hsh)
 );
 
 CREATE INDEX idx_dirs_path_name ON dirs(dir_name);
-CREATE INDEX idx_entrs_file_name ON files(file_name);
\ No newline at end of file
+CREATE INDEX idx_entrs_file_name ON files(file_name);
+CREATE INDEX idx_files_latest ON files(dir_id, file_name, is_latest);
+CREATE INDEX idx_file_chunks_chunk_hsh ON file_chunks(chunk_hsh);
+CREATE INDEX idx_backups_file_id ON backups(file_id);
+CREATE INDEX idx_backups_delta_base_id ON backups(delta_base_id);
+
+CREATE TABLE verify_progress (
+    /* The shard of the destination, holding backups with ids id / 100000 */
+    shard INTEGER PRIMARY KEY NOT NULL,
+    /* The last file id in the shard whose backup has been verified, in id order */
+    last_file_id INTEGER NOT NULL
+);
+
+CREATE TABLE verify_failures (
+    /* The file version whose backup failed verification */
+    file_id INTEGER PRIMARY KEY NOT NULL,
+    /* Why the backup failed verification */
+    reason TEXT NOT NULL,
+    /* The time the failure was found */
+    found_at DATETIME NOT NULL
+);
+
+/* Versions recorded with an ID from outside the sequence, ie. imported 
+   backups, move it past their ID, so it's never handed out again */
+CREATE TRIGGER trg_files_advance_id_sequence AFTER INSERT ON files
+BEGIN
+    UPDATE file_id_sequence SET next_id = NEW.id + 1 WHERE next_id <= NEW.id;
+END;
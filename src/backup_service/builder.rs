@@ -0,0 +1,158 @@
+use std::{path::{Path, PathBuf}, sync::Arc};
+
+use crate::{config::Durability, file_svc::long_path::long_path};
+
+use super::{error::*, spool::Spool, syncer::*, FileBackupService};
+
+///
+/// The name of the file written and deleted again to check a directory can be written to
+/// 
+const PROBE_FILE: &str = ".drive_backup_probe";
+
+///
+/// Configures a `FileBackupService`, checking its destination can be written to once built
+/// 
+pub struct BackupServiceBuilder {
+    backup_file_path: PathBuf,
+    backup_xattrs: bool,
+    syncer: Option<Arc<dyn Syncer>>,
+    temp_dir: Option<PathBuf>,
+    temp_dir_max_bytes: Option<u64>,
+    skip_compression_extensions: Vec<String>,
+    read_only: bool
+}
+
+impl BackupServiceBuilder {
+    ///
+    /// Starts configuring a `FileBackupService` storing backups under `backup_file_path`
+    /// 
+    pub fn new(backup_file_path: impl Into<PathBuf>) -> Self {
+        Self { backup_file_path: backup_file_path.into(), backup_xattrs: false, syncer: None, temp_dir: None, temp_dir_max_bytes: None, skip_compression_extensions: Vec::new(), read_only: false }
+    }
+
+    ///
+    /// Stores each file's extended attributes alongside its backup, if `backup_xattrs` is set
+    /// 
+    pub fn backup_xattrs(self, backup_xattrs: bool) -> Self {
+        Self { backup_xattrs, ..self }
+    }
+
+    ///
+    /// Syncs every written backup to disk, along with the directory holding it, if `durability` is `Safe`
+    /// 
+    pub fn durability(self, durability: Durability) -> Self {
+        match durability {
+            Durability::Fast => Self { syncer: None, ..self },
+            Durability::Safe => self.syncer(Arc::new(FsSyncer))
+        }
+    }
+
+    ///
+    /// Syncs every written backup, along with the directory holding it, through `syncer`
+    /// 
+    pub fn syncer(self, syncer: Arc<dyn Syncer>) -> Self {
+        Self { syncer: Some(syncer), ..self }
+    }
+
+    ///
+    /// Writes compressed files in `temp_dir`, if given, before moving them into the destination
+    /// 
+    pub fn temp_dir(self, temp_dir: Option<PathBuf>) -> Self {
+        Self { temp_dir, ..self }
+    }
+
+    ///
+    /// Fails the backup of any file which would take more than `max_bytes` in the `temp_dir`, if given,
+    /// with `Error::SpoolFull`. Without a `temp_dir`, backups aren't capped
+    /// 
+    pub fn temp_dir_max_bytes(self, temp_dir_max_bytes: Option<u64>) -> Self {
+        Self { temp_dir_max_bytes, ..self }
+    }
+
+    ///
+    /// Stores files with any of the given `extensions`, such as "jpg" or ".zip", uncompressed.
+    /// Matched regardless of case
+    /// 
+    pub fn skip_compression_extensions(self, extensions: &[String]) -> Self {
+        let skip_compression_extensions = extensions.iter()
+            .map(|ext| ext.trim_start_matches('.').to_lowercase())
+            .collect();
+        Self { skip_compression_extensions, ..self }
+    }
+
+    ///
+    /// Skips checking the destination can be written to, for services which only read backups,
+    /// so destinations on read-only media can still be read
+    /// 
+    pub fn read_only(self) -> Self {
+        Self { read_only: true, ..self }
+    }
+
+    ///
+    /// Builds the `FileBackupService`, first creating the destination and temporary directory
+    /// if they don't exist, and checking a file can be written to each.
+    /// Fails with `Error::Unwritable` if either can't be written to.
+    /// 
+    pub fn build(self) -> Result<FileBackupService> {
+        if !self.read_only {
+            check_writable(&self.backup_file_path)?;
+            if let Some(temp_dir) = &self.temp_dir {
+                check_writable(temp_dir)?;
+            }
+        }
+
+        // Every path the service uses is under these, so prefixing them lets any be long
+        Ok(FileBackupService {
+            backup_file_path: long_path(&self.backup_file_path).into_owned(), backup_xattrs: self.backup_xattrs,
+            syncer: self.syncer, 
+            spool: self.temp_dir.map(|temp_dir| Spool::new(long_path(&temp_dir).into_owned(), self.temp_dir_max_bytes)),
+            skip_compression_extensions: self.skip_compression_extensions
+        })
+    }
+}
+
+///
+/// Creates the directory at `dir` if it doesn't exist, then writes and deletes a probe file in it
+/// 
+fn check_writable(dir: &Path) -> Result<()> {
+    let unwritable = |e| Error::Unwritable(dir.to_path_buf(), e);
+    std::fs::create_dir_all(dir).map_err(unwritable)?;
+    let probe = dir.join(PROBE_FILE);
+    std::fs::write(&probe, []).map_err(unwritable)?;
+    std::fs::remove_file(&probe).map_err(unwritable)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backup_service::error::Error;
+
+    use super::BackupServiceBuilder;
+
+    #[test]
+    fn test_build_creates_destination() {
+        let dir = tempfile::tempdir().unwrap();
+        let destination = dir.path().join("nested/backups");
+
+        BackupServiceBuilder::new(&destination).temp_dir(Some(dir.path().join("temp"))).build().unwrap();
+
+        assert!(destination.is_dir());
+        assert!(dir.path().join("temp").is_dir());
+        assert_eq!(std::fs::read_dir(&destination).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_build_fails_for_unwritable_destination() {
+        let dir = tempfile::tempdir().unwrap();
+        // A file stands where the destination should be created
+        let blocked = dir.path().join("blocked");
+        std::fs::write(&blocked, "not a directory").unwrap();
+        let destination = blocked.join("backups");
+
+        let e = BackupServiceBuilder::new(&destination).build().err().unwrap();
+
+        assert!(matches!(&e, Error::Unwritable(path, _) if *path == destination));
+        assert!(e.to_string().starts_with(&format!("the backup destination {} can't be written to: ", destination.display())));
+        // Services which only read backups are built regardless
+        BackupServiceBuilder::new(&destination).read_only().build().unwrap();
+    }
+}
@@ -0,0 +1,62 @@
+use std::{fs::File, io::{BufReader, Read}, path::PathBuf};
+
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+///
+/// A single content-addressed chunk of a file, by its hex MD5 hash and size
+/// 
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChunkRef {
+    pub hsh: String,
+    pub size: u64
+}
+
+///
+/// Reads from `reader` until `buf` is full or the end of the reader is reached,
+/// returning the number of bytes read
+/// 
+pub async fn read_full(reader: &mut (impl AsyncRead + Unpin), buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]).await? {
+            0 => break,
+            n => filled += n
+        }
+    }
+
+    Ok(filled)
+}
+
+///
+/// Reads the decompressed contents of a sequence of stored chunks,
+/// opening each chunk only once the previous one is exhausted
+/// 
+pub struct ChunkedReader {
+    chunk_paths: std::vec::IntoIter<PathBuf>,
+    current: Option<GzDecoder<BufReader<File>>>
+}
+
+impl ChunkedReader {
+    pub fn new(chunk_paths: Vec<PathBuf>) -> Self {
+        Self { chunk_paths: chunk_paths.into_iter(), current: None }
+    }
+}
+
+impl Read for ChunkedReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if let Some(current) = &mut self.current {
+                let n = current.read(buf)?;
+                if n > 0 || buf.is_empty() {
+                    return Ok(n);
+                }
+            }
+            match self.chunk_paths.next() {
+                Some(path) => self.current = Some(GzDecoder::new(BufReader::new(File::open(path)?))),
+                None => return Ok(0)
+            }
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use std::{collections::HashMap, io::{Read, Write}};
+
+///
+/// Writes a delta turning `base` into `new`, headed by the `base_id` of the backup it's taken against
+/// 
+pub fn write_delta(base_id: i64, base: &[u8], new: &[u8], writer: &mut impl Write) -> std::io::Result<()> {
+    writer.write_all(&base_id.to_le_bytes())?;
+    bsdiff::diff(base, new, writer)
+}
+
+///
+/// Reads the ID of the backup a delta written by `write_delta` is taken against
+/// 
+pub fn read_base_id(reader: &mut impl Read) -> std::io::Result<i64> {
+    let mut base_id = [0u8; 8];
+    reader.read_exact(&mut base_id)?;
+    Ok(i64::from_le_bytes(base_id))
+}
+
+///
+/// Reads a delta written by `write_delta`, returning the ID of the backup it's taken against and its patch
+/// 
+pub fn read_delta(mut reader: impl Read) -> std::io::Result<(i64, Vec<u8>)> {
+    let base_id = read_base_id(&mut reader)?;
+    let mut patch = Vec::new();
+    reader.read_to_end(&mut patch)?;
+
+    Ok((base_id, patch))
+}
+
+///
+/// Applies each of the `patches` to `base` in turn, oldest first
+/// 
+pub fn apply_patches<'a>(base: Vec<u8>, mut patches: impl Iterator<Item = &'a Vec<u8>>) -> std::io::Result<Vec<u8>> {
+    patches.try_fold(base, |contents, patch| {
+        let mut patched = Vec::new();
+        bsdiff::patch(&contents, &mut patch.as_slice(), &mut patched)?;
+        Ok(patched)
+    })
+}
+
+///
+/// Gets the ID of every delta backup which should be stored in full, so that no backup
+/// is stored behind more than `max_chain_length` deltas, given each delta backup's
+/// ID and the ID of the backup it's taken against
+/// 
+pub fn plan_rebase(deltas: &[(i64, i64)], max_chain_length: usize) -> Vec<i64> {
+    // A delta is always taken against an older backup, so walking them in order
+    // finds each base's chain length before the deltas taken against it
+    let mut deltas = deltas.to_vec();
+    deltas.sort();
+
+    let mut chain_lengths: HashMap<i64, usize> = HashMap::new();
+    let mut rebased = Vec::new();
+    for (id, base_id) in deltas {
+        let mut chain_length = chain_lengths.get(&base_id).copied().unwrap_or(0) + 1;
+        if chain_length > max_chain_length {
+            rebased.push(id);
+            chain_length = 0;
+        }
+        chain_lengths.insert(id, chain_length);
+    }
+
+    rebased
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_patches, plan_rebase, read_delta, write_delta};
+
+    #[test]
+    fn test_delta_round_trip() {
+        let base = b"first line\n".repeat(100);
+        let new = [base.clone(), b"appended line\n".to_vec()].concat();
+
+        let mut delta = Vec::new();
+        write_delta(7, &base, &new, &mut delta).unwrap();
+        let (base_id, patch) = read_delta(delta.as_slice()).unwrap();
+
+        assert_eq!(base_id, 7);
+        assert_eq!(apply_patches(base, [patch].iter()).unwrap(), new);
+    }
+
+    #[test]
+    fn test_plan_rebase_bounds_chain_length() {
+        // 2 -> 1, 3 -> 2, 4 -> 3, 5 -> 4, and a separate short chain 11 -> 10
+        let deltas = [(5, 4), (3, 2), (2, 1), (4, 3), (11, 10)];
+
+        assert_eq!(plan_rebase(&deltas, 2), vec![4]);
+        assert_eq!(plan_rebase(&deltas, 1), vec![3, 5]);
+        assert_eq!(plan_rebase(&deltas, 0), vec![2, 3, 4, 5, 11]);
+    }
+}
@@ -1,13 +1,103 @@
+use std::{fmt::Display, path::{Path, PathBuf}};
+
+use crate::estimate::format_bytes;
+
+use super::spool::SpoolFull;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug)]
 pub enum Error {
     IOError(std::io::Error),
+    /// The destination is in maintenance mode, with the given note left in its flag file
+    MaintenanceMode(String),
+    /// No backup service exists yet for the given kind of destination
+    UnsupportedDestination(&'static str),
+    /// The directory at the given path couldn't be created, or a file written to it
+    Unwritable(PathBuf, std::io::Error),
+    /// The file being backed up changed size while it was read, so the backup
+    /// would hold neither its old contents nor its new ones
+    SourceChangedDuringBackup { expected: u64, read: u64 },
+    /// The destination at the given path is gone, ie. its drive was unplugged, so no backup can be written to it
+    DestinationUnavailable(PathBuf),
+    /// The file's backup would grow past the given cap on the spool it's written to before
+    /// it's moved into the destination, so it wasn't backed up
+    SpoolFull { max_bytes: u64 },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IOError(e) => write!(f, "{}", e),
+            Error::MaintenanceMode(note) => write!(f, "the backup destination is in maintenance mode: {}", note.trim()),
+            Error::UnsupportedDestination(kind) => write!(f, "{} backup destinations aren't supported yet", kind),
+            Error::Unwritable(path, e) => write!(f, "the backup destination {} can't be written to: {}", path.display(), e),
+            Error::SourceChangedDuringBackup { expected, read } => write!(
+                f, "the file changed while it was backed up: {} bytes were read, but it was {} bytes when the backup began", read, expected
+            ),
+            Error::DestinationUnavailable(path) => write!(f, "the backup destination {} is no longer available", path.display()),
+            Error::SpoolFull { max_bytes } => write!(
+                f, "its backup would be larger than the {} temp_dir_max_bytes allows, so it was skipped", format_bytes(*max_bytes)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IOError(e) | Error::Unwritable(_, e) => Some(e),
+            Error::MaintenanceMode(_) | Error::UnsupportedDestination(_) | Error::SourceChangedDuringBackup { .. } 
+                | Error::DestinationUnavailable(_) | Error::SpoolFull { .. } => None,
+        }
+    }
+}
+
+impl AsRef<Error> for Error {
+    fn as_ref(&self) -> &Error {
+        self
+    }
 }
 
 impl From<tokio::io::Error> for Error {
     fn from(value: tokio::io::Error) -> Self {
-        Error::IOError(value)
+        match value.get_ref().and_then(|e| e.downcast_ref::<SpoolFull>()) {
+            Some(full) => Error::SpoolFull { max_bytes: full.max_bytes },
+            None => Error::IOError(value)
+        }
     }
-}
\ No newline at end of file
+}
+
+///
+/// An `Error` raised while backing up a particular file
+/// 
+#[derive(Debug)]
+pub struct BackupError {
+    pub file_id: i64,
+    pub source_path: PathBuf,
+    pub kind: Error
+}
+
+impl BackupError {
+    pub fn new(file_id: i64, source_path: &Path, kind: Error) -> Self {
+        Self { file_id, source_path: source_path.to_path_buf(), kind }
+    }
+}
+
+impl Display for BackupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Failed to backup file {} (id={}): {}", self.source_path.display(), self.file_id, self.kind)
+    }
+}
+
+impl std::error::Error for BackupError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.kind)
+    }
+}
+
+impl AsRef<Error> for BackupError {
+    fn as_ref(&self) -> &Error {
+        &self.kind
+    }
+}
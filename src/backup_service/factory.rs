@@ -0,0 +1,57 @@
+use crate::config::BackupDestination;
+
+use super::{error::*, BackupService, BackupServiceBuilder};
+
+///
+/// Starts configuring the backup service storing backups in the given `dest`.
+/// Only `Local` destinations have a backup service so far.
+/// 
+pub fn backup_service_builder(dest: &BackupDestination) -> Result<BackupServiceBuilder> {
+    match dest {
+        BackupDestination::Local { path } => Ok(BackupServiceBuilder::new(path)),
+        BackupDestination::S3 { .. } => Err(Error::UnsupportedDestination("S3")),
+        BackupDestination::Sftp { .. } => Err(Error::UnsupportedDestination("SFTP")),
+    }
+}
+
+///
+/// Creates the backup service storing backups in the given `dest`, with its default settings,
+/// behind a `Box<dyn BackupService>` so that whichever backend it is can be held alike
+/// 
+pub fn create_backup_service(dest: &BackupDestination) -> Result<Box<dyn BackupService>> {
+    Ok(Box::new(backup_service_builder(dest)?.build()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{backup_service::{error::Error, BackupService}, config::BackupDestination};
+
+    use super::{backup_service_builder, create_backup_service};
+
+    #[test]
+    fn test_backup_service_builder() {
+        let dir = tempfile::tempdir().unwrap();
+        let local = BackupDestination::Local { path: dir.path().join("backups").to_string_lossy().to_string() };
+        assert!(backup_service_builder(&local).unwrap().build().is_ok());
+
+        let sftp = BackupDestination::Sftp { host: "nas".to_string(), user: "backup".to_string(), remote_path: "/srv".to_string() };
+        assert!(matches!(backup_service_builder(&sftp), Err(Error::UnsupportedDestination("SFTP"))));
+    }
+
+    #[tokio::test]
+    async fn test_create_backup_service() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("file.txt");
+        std::fs::write(&file_path, "contents").unwrap();
+        let local = BackupDestination::Local { path: dir.path().join("backups").to_string_lossy().to_string() };
+
+        let mut backup_services: Vec<Box<dyn BackupService>> = vec![create_backup_service(&local).unwrap()];
+        for backup_service in backup_services.iter_mut() {
+            backup_service.backup_data(1, &file_path).await.unwrap();
+            assert_eq!(backup_service.list_backup_ids().await.unwrap(), [1]);
+        }
+
+        let s3 = BackupDestination::S3 { bucket: "backups".to_string(), prefix: "home/".to_string(), region: "eu-west-1".to_string() };
+        assert!(matches!(create_backup_service(&s3), Err(Error::UnsupportedDestination("S3"))));
+    }
+}
@@ -1,55 +1,1275 @@
+pub mod builder;
+pub mod chunks;
+pub mod delta;
 pub mod error;
+pub mod factory;
+pub mod routed;
+mod sparse;
+pub mod spool;
+pub mod syncer;
+mod xattrs;
 
-use std::{io::{BufWriter, Write}, path::{Path, PathBuf}};
+use std::{collections::BTreeSet, io::{BufWriter, Cursor, ErrorKind, Read, Write}, path::{Path, PathBuf}, sync::Arc};
 
-use flate2::{write::GzEncoder, Compression};
-use tokio::io::{AsyncReadExt, BufReader};
-use tokio_util::bytes::BytesMut;
+use async_trait::async_trait;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
 
-use self::error::*;
+pub use self::{builder::BackupServiceBuilder, error::BackupError};
+use crate::{config::DEFAULT_DESTINATION, file_svc::long_path::{clamp_file_name, long_path, naming_path}};
 
-pub trait BackupService {
-    fn backup_data(&mut self, id: i64, path: &Path) -> impl std::future::Future<Output = Result<()>> + Send;
-    fn delete_backup(&mut self, id: i64) -> impl std::future::Future<Output = Result<()>> + Send;
+use self::{chunks::*, error::*, spool::{Spool, SpoolFile}, syncer::*};
+
+///
+/// The name of the flag file which, while present at the root of the 
+/// backup destination, puts the destination into maintenance mode
+/// 
+const MAINTENANCE_FLAG: &str = "MAINTENANCE";
+///
+/// How many bytes from the start of a file are sampled for the signature of a compressed format
+/// 
+const SIGNATURE_SAMPLE_SIZE: usize = 512;
+///
+/// The signatures starting files in formats which are already compressed: JPEG, ZIP and gzip
+/// 
+const COMPRESSED_SIGNATURES: [&[u8]; 3] = [&[0xFF, 0xD8, 0xFF], &[0x50, 0x4B, 0x03, 0x04], &[0x1F, 0x8B]];
+
+///
+/// Where a backup is stored within its destination
+/// 
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredBackup {
+    /// The kind of destination the backup is stored in
+    pub backend: &'static str,
+    /// Where the backup is stored, relative to the destination
+    pub key: String,
+    /// The stored size of the backup, if it's stored by itself
+    pub compressed_size: Option<u64>,
+    /// The ID of the backup this one is stored as a delta against, if it is
+    pub delta_base_id: Option<i64>
+}
+
+///
+/// Gets the shard the backup with the given `id` is stored in. Each shard
+/// is a directory of the destination holding 100,000 consecutive ids.
+/// 
+pub fn shard_of(id: i64) -> i64 {
+    id / 100_000
+}
+
+#[async_trait]
+pub trait BackupService : Send + Sync {
+    ///
+    /// Backs up the file at `path` whole, as the backup with the given `id`
+    /// 
+    async fn backup_data(&mut self, id: i64, path: &Path) -> std::result::Result<(), BackupError>;
+    async fn delete_backup(&mut self, id: i64) -> Result<()>;
+    ///
+    /// Opens the backup with the given `id`, returning a reader over its original,
+    /// decompressed contents
+    /// 
+    async fn open_backup(&self, id: i64) -> Result<Box<dyn Read + Send>>;
+    ///
+    /// Restores the backup with the given `id` to the given `path`, overwriting any file there
+    /// 
+    async fn restore_data(&self, id: i64, path: &Path) -> Result<()>;
+    ///
+    /// Backs up the file at `path` as content-addressed chunks of `chunk_size` bytes,
+    /// storing only the chunks which aren't already stored. Returns the file's chunks in order.
+    /// 
+    async fn backup_chunked(&mut self, id: i64, path: &Path, chunk_size: usize) -> std::result::Result<Vec<ChunkRef>, BackupError>;
+    ///
+    /// Backs up the file at `path` as a binary delta against the backup with the given 
+    /// `base_id`, as the backup with the given `id`. The delta can only be restored while
+    /// its base is kept, so the base mustn't be deleted before the delta is materialized.
+    /// 
+    async fn backup_delta(&mut self, id: i64, base_id: i64, path: &Path) -> std::result::Result<(), BackupError>;
+    ///
+    /// Stores the delta backup with the given `id` in full, so that it no longer 
+    /// depends on its base. Does nothing if it's already stored in full.
+    /// 
+    async fn materialize(&mut self, id: i64) -> Result<()>;
+    ///
+    /// Deletes the stored chunk with the given `hsh`
+    /// 
+    async fn delete_chunk(&mut self, hsh: &str) -> Result<()>;
+    ///
+    /// Describes where the backup with the given `id` is stored
+    /// 
+    async fn describe_backup(&self, id: i64) -> Result<StoredBackup>;
+    ///
+    /// Describes every copy of the backup with the given `id`, by the name of the destination holding it.
+    /// A service writing to a single destination holds its one copy in the `default` destination
+    /// 
+    async fn describe_copies(&self, id: i64) -> Result<Vec<(String, StoredBackup)>> {
+        Ok(vec![(DEFAULT_DESTINATION.to_string(), self.describe_backup(id).await?)])
+    }
+    ///
+    /// Gets the note left when the destination was put into maintenance mode, or `None`
+    /// if it isn't in maintenance mode. Nothing in the destination is written or 
+    /// deleted while it is.
+    /// 
+    async fn maintenance_note(&self) -> Result<Option<String>>;
+    ///
+    /// Puts the destination into maintenance mode, leaving the given `note`. 
+    /// Fails with the existing note if it's already in maintenance mode.
+    /// 
+    async fn enter_maintenance(&mut self, note: &str) -> Result<()>;
+    ///
+    /// Takes the destination out of maintenance mode, if it's in it
+    /// 
+    async fn leave_maintenance(&mut self) -> Result<()>;
+    ///
+    /// Lists the id of every backup stored in the destination, however it's stored, in order
+    /// 
+    async fn list_backup_ids(&self) -> Result<Vec<i64>>;
 }
 
 pub struct FileBackupService { 
-    backup_file_path: PathBuf 
+    backup_file_path: PathBuf,
+    backup_xattrs: bool,
+    /// Syncs every written backup to disk, or `None` if writes are left to the operating system
+    syncer: Option<Arc<dyn Syncer>>,
+    /// Where compressed files are written before they're moved into the destination,
+    /// or `None` to stream them beside where they're moved to, uncapped
+    spool: Option<Spool>,
+    /// The extensions of files stored uncompressed, in lowercase without their leading dot
+    skip_compression_extensions: Vec<String>
 }
 
-impl FileBackupService {
-    pub fn new(backup_file_path: String) -> Self { 
-        Self { backup_file_path: PathBuf::from(backup_file_path) }
+///
+/// Writes a file stored in the destination, compressing it unless it's stored raw
+/// 
+enum StoredWriter {
+    Compressed(GzEncoder<BufWriter<SpoolFile>>),
+    Raw(BufWriter<SpoolFile>)
+}
+
+impl StoredWriter {
+    ///
+    /// Writes out everything still buffered, returning the file written to
+    /// 
+    fn finish(self) -> std::io::Result<std::fs::File> {
+        let writer = match self {
+            StoredWriter::Compressed(gz) => gz.finish()?,
+            StoredWriter::Raw(writer) => writer
+        };
+        writer.into_inner().map(SpoolFile::into_file).map_err(|e| e.into_error())
     }
 }
 
-impl BackupService for FileBackupService {
-    async fn backup_data(&mut self, id: i64, path: &Path) -> Result<()> {
-        let from_file = tokio::fs::OpenOptions::new().read(true).open(path).await?;
-        let mut from_file = BufReader::new(from_file);
+impl Write for StoredWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            StoredWriter::Compressed(gz) => gz.write(buf),
+            StoredWriter::Raw(writer) => writer.write(buf)
+        }
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            StoredWriter::Compressed(gz) => gz.flush(),
+            StoredWriter::Raw(writer) => writer.flush()
+        }
+    }
+}
+
+impl FileBackupService {
+    ///
+    /// Creates a new `FileBackupService` storing backups under `backup_file_path`, creating
+    /// it if it doesn't exist. Fails with `Error::Unwritable` if it can't be written to.
+    /// Use a `BackupServiceBuilder` to configure anything else.
+    /// 
+    pub fn new(backup_file_path: String) -> Result<Self> { 
+        BackupServiceBuilder::new(backup_file_path).build()
+    }
+
+    ///
+    /// Whether every written backup is synced to disk before it's reported as stored
+    /// 
+    pub fn syncs_writes(&self) -> bool {
+        self.syncer.is_some()
+    }
+
+    ///
+    /// Sums the size of every file stored in the backup destination, 
+    /// being every whole backup, compressed or raw, delta and chunk. Walks the whole destination, so
+    /// takes a while for large destinations.
+    /// 
+    pub async fn calculate_total_size(&self) -> Result<u64> {
+        let mut total = 0;
+        let mut dirs = vec![self.backup_file_path.clone()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = match tokio::fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                // Nothing has been backed up yet
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into())
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = tokio::fs::metadata(entry.path()).await?;
+                if metadata.is_dir() {
+                    dirs.push(entry.path());
+                } else if entry.path().extension().is_some_and(|ext| ext == "gz" || ext == "raw") {
+                    total += metadata.len();
+                }
+            }
+        }
+
+        Ok(total)
+    }
+
+    ///
+    /// Whether the destination keeps apart files whose names differ only in case. Backups are
+    /// stored by their ID, so this never matters to them, but does to files restored onto it.
+    /// 
+    pub fn is_case_sensitive(&self) -> std::io::Result<bool> {
+        crate::file_svc::probe_case_sensitivity(&self.backup_file_path)
+    }
+
+    ///
+    /// Whether the destination holds the backup with the given `id`, stored in any way
+    /// 
+    pub async fn contains(&self, id: i64) -> Result<bool> {
+        for path in [self.get_backup_path(id), self.get_raw_path(id), self.get_manifest_path(id), self.get_delta_path(id), self.get_empty_path(id)] {
+            if tokio::fs::try_exists(path).await? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
 
-        let mut to_file = PathBuf::from(self.backup_file_path.clone());
-        to_file.push(&format!("{}", id / 100_000));
-        tokio::fs::create_dir_all(&to_file).await?;
-        to_file.push(&format!("{}.gz", id));
+    ///
+    /// Whether the destination holds the chunk with the given `hsh`
+    /// 
+    pub async fn contains_chunk(&self, hsh: &str) -> Result<bool> {
+        Ok(tokio::fs::try_exists(self.get_chunk_path(hsh)).await?)
+    }
+
+    ///
+    /// Whether the file at `path` is already compressed, so would gain nothing from being compressed 
+    /// again: either its extension is one of `skip_compression_extensions`, or it starts with 
+    /// the signature of a compressed format. A file which can't be read is taken as compressible
+    /// 
+    pub fn detect_incompressible(&self, path: &Path) -> bool {
+        let extension = path.extension().map(|ext| ext.to_string_lossy().to_lowercase());
+        if extension.is_some_and(|ext| self.skip_compression_extensions.contains(&ext)) {
+            return true;
+        }
 
-        let to_file = BufWriter::new(std::fs::OpenOptions::new().write(true).create(true).open(to_file)?);
-        let mut gz = GzEncoder::new(to_file, Compression::best());
+        let mut sample = Vec::with_capacity(SIGNATURE_SAMPLE_SIZE);
+        let sampled = std::fs::File::open(long_path(path))
+            .and_then(|file| file.take(SIGNATURE_SAMPLE_SIZE as u64).read_to_end(&mut sample));
+        sampled.is_ok() && COMPRESSED_SIGNATURES.iter().any(|signature| sample.starts_with(signature))
+    }
 
-        let mut bytes = BytesMut::with_capacity(1024);
-        while from_file.read_buf(&mut bytes).await? > 0 {
-            gz.write_all(&bytes[..])?;
-            bytes.clear();
+    ///
+    /// Gets the path of the backup file with the given `id`
+    /// 
+    fn get_backup_path(&self, id: i64) -> PathBuf {
+        self.get_shard_path(id).join(format!("{}.gz", id))
+    }
+    ///
+    /// Gets the path of the backup file with the given `id`, when it's stored uncompressed
+    /// 
+    fn get_raw_path(&self, id: i64) -> PathBuf {
+        self.get_shard_path(id).join(format!("{}.raw", id))
+    }
+    ///
+    /// Gets the path of the extended attributes sidecar for the backup with the given `id`
+    /// 
+    fn get_xattr_path(&self, id: i64) -> PathBuf {
+        self.get_shard_path(id).join(format!("{}.xattr.json", id))
+    }
+    ///
+    /// Gets the path of the chunk list for the chunked backup with the given `id`
+    /// 
+    fn get_manifest_path(&self, id: i64) -> PathBuf {
+        self.get_shard_path(id).join(format!("{}.chunks", id))
+    }
+    ///
+    /// Gets the path of the delta stored for the backup with the given `id`, if it's stored as one
+    /// 
+    fn get_delta_path(&self, id: i64) -> PathBuf {
+        self.get_shard_path(id).join(format!("{}.delta.gz", id))
+    }
+    ///
+    /// Gets the path of the marker stored in place of the backup with the given `id`, 
+    /// when it's of an empty file
+    /// 
+    fn get_empty_path(&self, id: i64) -> PathBuf {
+        self.get_shard_path(id).join(format!("{}.empty", id))
+    }
+    ///
+    /// Gets the path of the marker noting that the file backed up 
+    /// with the given `id` was sparse
+    /// 
+    fn get_sparse_path(&self, id: i64) -> PathBuf {
+        self.get_shard_path(id).join(format!("{}.sparse", id))
+    }
+    ///
+    /// Gets the directory the backup with the given `id` is stored in
+    /// 
+    fn get_shard_path(&self, id: i64) -> PathBuf {
+        self.backup_file_path.join(format!("{}", shard_of(id)))
+    }
+    ///
+    /// Gets the path of the stored chunk with the given `hsh`
+    /// 
+    fn get_chunk_path(&self, hsh: &str) -> PathBuf {
+        self.backup_file_path.join("chunks").join(&hsh[..2]).join(format!("{}.gz", hsh))
+    }
+    ///
+    /// Syncs the file written at `path`, and the directory holding it, to disk if writes are synced
+    /// 
+    fn sync_written(&self, path: &Path) -> Result<()> {
+        if let Some(syncer) = &self.syncer {
+            // Some platforms only flush files opened for writing
+            syncer.sync_file(&std::fs::OpenOptions::new().write(true).open(path)?)?;
+            syncer.sync_dir(path.parent().unwrap())?;
+        }
+        Ok(())
+    }
+    ///
+    /// Gets the path the file stored at `path` is written to until it's complete
+    /// 
+    fn get_temp_path(&self, path: &Path) -> PathBuf {
+        let name = format!("{}.tmp", path.file_name().unwrap().to_string_lossy());
+        let name = clamp_file_name(&name).into_owned();
+        match &self.spool {
+            Some(spool) => spool.dir().join(name),
+            None => path.with_file_name(name)
+        }
+    }
+    ///
+    /// Creates the temporary file written to until the compressed file stored at `path` is complete
+    /// 
+    fn create_temp(&self, path: &Path) -> Result<(PathBuf, StoredWriter)> {
+        let (temp_path, temp_file) = self.create_temp_file(path)?;
+        Ok((temp_path, StoredWriter::Compressed(GzEncoder::new(temp_file, Compression::best()))))
+    }
+    ///
+    /// Creates the temporary file written to until the uncompressed file stored at `path` is complete
+    /// 
+    fn create_raw_temp(&self, path: &Path) -> Result<(PathBuf, StoredWriter)> {
+        let (temp_path, temp_file) = self.create_temp_file(path)?;
+        Ok((temp_path, StoredWriter::Raw(temp_file)))
+    }
+    ///
+    /// Creates the temporary file written to until the file stored at `path` is complete,
+    /// which fails with `Error::SpoolFull` if it outgrows the spool's cap
+    /// 
+    fn create_temp_file(&self, path: &Path) -> Result<(PathBuf, BufWriter<SpoolFile>)> {
+        let temp_path = self.get_temp_path(path);
+        std::fs::create_dir_all(temp_path.parent().unwrap())?;
+        let temp_file = SpoolFile::new(std::fs::File::create(&temp_path)?, self.spool.as_ref().and_then(Spool::max_bytes));
+        Ok((temp_path, BufWriter::new(temp_file)))
+    }
+    ///
+    /// Finishes the file `writer` wrote to `temp_path`, then moves it to `path`. 
+    /// It's renamed, so `path` never holds part of a file, unless it's on another filesystem,
+    /// in which case it's copied beside `path` first, then renamed.
+    /// 
+    fn persist(&self, writer: StoredWriter, temp_path: &Path, path: &Path) -> Result<()> {
+        let temp_file = match writer.finish() {
+            Ok(temp_file) => temp_file,
+            Err(e) => {
+                std::fs::remove_file(temp_path).ok();
+                return Err(e.into());
+            }
+        };
+        if let Some(syncer) = &self.syncer {
+            syncer.sync_file(&temp_file)?;
+        }
+        drop(temp_file);
+
+        if std::fs::rename(temp_path, path).is_err() {
+            let copy_path = path.with_file_name(self.get_temp_path(path).file_name().unwrap());
+            let copied = std::fs::File::create(&copy_path)
+                .and_then(|mut copy| std::io::copy(&mut std::fs::File::open(temp_path)?, &mut copy).map(|_| copy));
+            let copy = match copied {
+                Ok(copy) => copy,
+                Err(e) => {
+                    let _ = std::fs::remove_file(&copy_path);
+                    return Err(e.into());
+                }
+            };
+            if let Some(syncer) = &self.syncer {
+                syncer.sync_file(&copy)?;
+            }
+            std::fs::rename(&copy_path, path)?;
+            std::fs::remove_file(temp_path)?;
+        }
+        if let Some(syncer) = &self.syncer {
+            syncer.sync_dir(path.parent().unwrap())?;
         }
 
         Ok(())
     }
+    ///
+    /// Fails with `Error::MaintenanceMode` if the destination is in maintenance mode, or with
+    /// `Error::DestinationUnavailable` if it's gone. Checked before every operation which changes 
+    /// the destination, so that a destination whose drive was unplugged isn't recreated in its mount point.
+    /// 
+    async fn ensure_writable(&self) -> Result<()> {
+        if !tokio::fs::try_exists(&self.backup_file_path).await? {
+            return Err(Error::DestinationUnavailable(self.backup_file_path.clone()));
+        }
+        match self.maintenance_note().await? {
+            Some(note) => Err(Error::MaintenanceMode(note)),
+            None => Ok(())
+        }
+    }
+    ///
+    /// Replaces the error `e`, raised while writing a backup, with `Error::DestinationUnavailable`
+    /// if it was raised because the destination disappeared, rather than because of the one file
+    /// 
+    fn explain_missing_destination(&self, e: Error) -> Error {
+        match &e {
+            Error::IOError(io) | Error::Unwritable(_, io) 
+                if matches!(io.kind(), std::io::ErrorKind::NotFound | std::io::ErrorKind::NotConnected) 
+                && !self.backup_file_path.exists() => Error::DestinationUnavailable(self.backup_file_path.clone()),
+            _ => e
+        }
+    }
+    ///
+    /// Stores what the backup with the given `id` needs to restore the file at `path` 
+    /// faithfully, besides its contents: whether it's sparse, and its extended attributes if enabled
+    /// 
+    async fn backup_metadata(&self, id: i64, path: &Path) -> Result<()> {
+        let metadata = tokio::fs::metadata(long_path(path)).await.map_err(|e| naming_path(e, path))?;
+        if sparse::is_sparse(&metadata) {
+            tokio::fs::write(self.get_sparse_path(id), []).await?;
+            self.sync_written(&self.get_sparse_path(id))?;
+        }
+
+        if self.backup_xattrs {
+            let entries = xattrs::read_xattrs(&long_path(path)).map_err(|e| naming_path(e, path))?;
+            if !entries.is_empty() {
+                tokio::fs::write(self.get_xattr_path(id), serde_json::to_vec(&entries).unwrap()).await?;
+                self.sync_written(&self.get_xattr_path(id))?;
+            }
+        }
+
+        Ok(())
+    }
+    ///
+    /// Stores the file at `path` whole, as the backup with the given `id`
+    /// 
+    async fn write_whole(&self, id: i64, path: &Path) -> Result<()> {
+        self.ensure_writable().await?;
+        // Empty files aren't opened or compressed, only marked as empty
+        let len = tokio::fs::metadata(long_path(path)).await.map_err(|e| naming_path(e, path))?.len();
+        if len == 0 {
+            tokio::fs::create_dir_all(self.get_shard_path(id)).await?;
+            tokio::fs::write(self.get_empty_path(id), []).await?;
+            self.sync_written(&self.get_empty_path(id))?;
+            return self.backup_metadata(id, path).await;
+        }
+
+        let from_file = tokio::fs::OpenOptions::new().read(true).open(long_path(path)).await.map_err(|e| naming_path(e, path))?;
+        let mut from_file = BufReader::new(from_file);
+
+        tokio::fs::create_dir_all(self.get_shard_path(id)).await?;
+        // Compressing a file which already is wastes time, and often grows it
+        let (to_file, (temp_path, mut writer)) = match self.detect_incompressible(path) {
+            true => (self.get_raw_path(id), self.create_raw_temp(&self.get_raw_path(id))?),
+            false => (self.get_backup_path(id), self.create_temp(&self.get_backup_path(id))?)
+        };
+
+        // Streamed through the encoder a buffer at a time, so only a buffer's worth of the file is ever held
+        if let Err(e) = copy_counted(&mut from_file, &mut writer, len).await {
+            drop(writer);
+            std::fs::remove_file(&temp_path).ok();
+            return Err(e);
+        }
+        self.persist(writer, &temp_path, &to_file)?;
+
+        self.backup_metadata(id, path).await
+    }
+    ///
+    /// Stores the file at `path` as a delta against the backup with the given `base_id`,
+    /// as the backup with the given `id`
+    /// 
+    async fn write_delta(&self, id: i64, base_id: i64, path: &Path) -> Result<()> {
+        self.ensure_writable().await?;
+        let mut base = Vec::new();
+        self.open_backup(base_id).await?.read_to_end(&mut base)?;
+        let contents = tokio::fs::read(long_path(path)).await.map_err(|e| naming_path(e, path))?;
+
+        tokio::fs::create_dir_all(self.get_shard_path(id)).await?;
+        let (temp_path, mut gz) = self.create_temp(&self.get_delta_path(id))?;
+        delta::write_delta(base_id, &base, &contents, &mut gz)?;
+        self.persist(gz, &temp_path, &self.get_delta_path(id))?;
+
+        self.backup_metadata(id, path).await
+    }
+    ///
+    /// Reads the delta stored for the backup with the given `id`, returning the ID 
+    /// of its base and its patch, or `None` if it's stored in full
+    /// 
+    async fn read_delta(&self, id: i64) -> Result<Option<(i64, Vec<u8>)>> {
+        match std::fs::File::open(self.get_delta_path(id)) {
+            Ok(file) => Ok(Some(delta::read_delta(GzDecoder::new(std::io::BufReader::new(file)))?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into())
+        }
+    }
+    ///
+    /// Opens the backup with the given `id`, which must not be stored as a delta
+    /// 
+    async fn open_stored(&self, id: i64) -> Result<Box<dyn Read + Send>> {
+        if tokio::fs::try_exists(self.get_empty_path(id)).await? {
+            return Ok(Box::new(std::io::empty()));
+        }
+        let manifest_path = self.get_manifest_path(id);
+        if tokio::fs::try_exists(&manifest_path).await? {
+            let chunks: Vec<ChunkRef> = serde_json::from_slice(&tokio::fs::read(manifest_path).await?)
+                .map_err(std::io::Error::from)?;
+            let chunk_paths = chunks.iter().map(|c| self.get_chunk_path(&c.hsh)).collect();
+            return Ok(Box::new(ChunkedReader::new(chunk_paths)));
+        }
+
+        match std::fs::File::open(self.get_raw_path(id)) {
+            Ok(file) => return Ok(Box::new(std::io::BufReader::new(file))),
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => { }
+        }
+        let file = std::fs::File::open(self.get_backup_path(id))?;
+        Ok(Box::new(GzDecoder::new(std::io::BufReader::new(file))))
+    }
+    ///
+    /// Stores the file at `path` as chunks of `chunk_size` bytes, as the backup with the given `id`
+    /// 
+    async fn write_chunks(&self, id: i64, path: &Path, chunk_size: usize) -> Result<Vec<ChunkRef>> {
+        self.ensure_writable().await?;
+        let from_file = tokio::fs::OpenOptions::new().read(true).open(long_path(path)).await.map_err(|e| naming_path(e, path))?;
+        let mut from_file = BufReader::new(from_file);
+
+        let mut chunks = Vec::new();
+        let mut bytes = vec![0u8; chunk_size];
+        loop {
+            let len = read_full(&mut from_file, &mut bytes).await?;
+            if len == 0 {
+                break;
+            }
+
+            let chunk = ChunkRef { hsh: format!("{:x}", md5::compute(&bytes[..len])), size: len as u64 };
+            // Chunks are addressed by their contents, so a chunk already 
+            // stored by any backup never needs to be written again
+            let chunk_path = self.get_chunk_path(&chunk.hsh);
+            if !tokio::fs::try_exists(&chunk_path).await? {
+                tokio::fs::create_dir_all(chunk_path.parent().unwrap()).await?;
+                let (temp_path, mut gz) = self.create_temp(&chunk_path)?;
+                gz.write_all(&bytes[..len])?;
+                self.persist(gz, &temp_path, &chunk_path)?;
+            }
+            chunks.push(chunk);
+
+            if len < chunk_size {
+                break;
+            }
+        }
+
+        tokio::fs::create_dir_all(self.get_shard_path(id)).await?;
+        tokio::fs::write(self.get_manifest_path(id), serde_json::to_vec(&chunks).unwrap()).await?;
+        self.sync_written(&self.get_manifest_path(id))?;
+        self.backup_metadata(id, path).await?;
+
+        Ok(chunks)
+    }
+}
+
+#[async_trait]
+impl BackupService for FileBackupService {
+    async fn backup_data(&mut self, id: i64, path: &Path) -> std::result::Result<(), BackupError> {
+        self.write_whole(id, path).await.map_err(|kind| BackupError::new(id, path, self.explain_missing_destination(kind)))
+    }
     async fn delete_backup(&mut self, id: i64) -> Result<()> {
+        self.ensure_writable().await?;
         let mut file_path = PathBuf::from(self.backup_file_path.clone());
         file_path.push(&format!("{}", id / 100_000));
         tokio::fs::create_dir_all(&file_path).await?;
         file_path.push(&format!("{}.gz", id));
 
-        Ok(tokio::fs::remove_file(file_path).await?)
+        // A backup is stored either whole, compressed or raw, as a list of chunks, as a delta, or as an empty marker
+        let stored_paths = [file_path, self.get_raw_path(id), self.get_manifest_path(id), self.get_delta_path(id), self.get_empty_path(id), self.get_xattr_path(id), self.get_sparse_path(id)];
+        for path in stored_paths {
+            if tokio::fs::try_exists(&path).await? {
+                tokio::fs::remove_file(path).await?;
+            }
+        }
+
+        Ok(())
+    }
+    async fn open_backup(&self, id: i64) -> Result<Box<dyn Read + Send>> {
+        // Follow the chain of deltas back to the backup stored in full, 
+        // then apply each delta's patch to it in turn
+        let mut patches = Vec::new();
+        let mut base_id = id;
+        while let Some((next_base_id, patch)) = self.read_delta(base_id).await? {
+            patches.push(patch);
+            base_id = next_base_id;
+        }
+
+        let mut base = self.open_stored(base_id).await?;
+        if patches.is_empty() {
+            return Ok(base);
+        }
+        let mut contents = Vec::new();
+        base.read_to_end(&mut contents)?;
+
+        Ok(Box::new(Cursor::new(delta::apply_patches(contents, patches.iter().rev())?)))
+    }
+    async fn restore_data(&self, id: i64, path: &Path) -> Result<()> {
+        let target = long_path(path);
+        if tokio::fs::try_exists(self.get_empty_path(id)).await? {
+            std::fs::File::create(&target).map_err(|e| naming_path(e, path))?;
+        } else {
+            let mut from_file = self.open_backup(id).await?;
+            let mut to_file = std::fs::File::create(&target).map_err(|e| naming_path(e, path))?;
+            // Sparse files get their holes back, rather than having every zero written out
+            if tokio::fs::try_exists(self.get_sparse_path(id)).await? {
+                sparse::copy_sparse(&mut from_file, &mut to_file)?;
+            } else {
+                let mut to_file = BufWriter::new(to_file);
+                std::io::copy(&mut from_file, &mut to_file)?;
+                to_file.flush()?;
+            }
+        }
+
+        let xattr_path = self.get_xattr_path(id);
+        if tokio::fs::try_exists(&xattr_path).await? {
+            let entries: Vec<xattrs::XattrEntry> = serde_json::from_slice(&tokio::fs::read(xattr_path).await?)
+                .map_err(std::io::Error::from)?;
+            xattrs::write_xattrs(&target, &entries).map_err(|e| naming_path(e, path))?;
+        }
+
+        Ok(())
+    }
+    async fn backup_chunked(&mut self, id: i64, path: &Path, chunk_size: usize) -> std::result::Result<Vec<ChunkRef>, BackupError> {
+        self.write_chunks(id, path, chunk_size).await.map_err(|kind| BackupError::new(id, path, self.explain_missing_destination(kind)))
+    }
+    async fn backup_delta(&mut self, id: i64, base_id: i64, path: &Path) -> std::result::Result<(), BackupError> {
+        self.write_delta(id, base_id, path).await.map_err(|kind| BackupError::new(id, path, self.explain_missing_destination(kind)))
+    }
+    async fn materialize(&mut self, id: i64) -> Result<()> {
+        self.ensure_writable().await?;
+        let delta_path = self.get_delta_path(id);
+        if !tokio::fs::try_exists(&delta_path).await? {
+            return Ok(());
+        }
+
+        let mut contents = self.open_backup(id).await?;
+        let (temp_path, mut gz) = self.create_temp(&self.get_backup_path(id))?;
+        std::io::copy(&mut contents, &mut gz)?;
+        // The delta is only removed once the backup stored in full can't be lost in its place
+        self.persist(gz, &temp_path, &self.get_backup_path(id))?;
+
+        Ok(tokio::fs::remove_file(delta_path).await?)
+    }
+    async fn delete_chunk(&mut self, hsh: &str) -> Result<()> {
+        self.ensure_writable().await?;
+        Ok(tokio::fs::remove_file(self.get_chunk_path(hsh)).await?)
+    }
+    async fn describe_backup(&self, id: i64) -> Result<StoredBackup> {
+        // Chunked backups share their chunks, so only have a size as a whole
+        let (manifest_path, empty_path, delta_path) = (self.get_manifest_path(id), self.get_empty_path(id), self.get_delta_path(id));
+        let mut delta_base_id = None;
+        let (path, compressed_size) = if tokio::fs::try_exists(&manifest_path).await? {
+            (manifest_path, None)
+        } else if tokio::fs::try_exists(&empty_path).await? {
+            (empty_path, Some(0))
+        } else if tokio::fs::try_exists(&delta_path).await? {
+            let file = std::fs::File::open(&delta_path)?;
+            delta_base_id = Some(delta::read_base_id(&mut GzDecoder::new(std::io::BufReader::new(file)))?);
+            let size = tokio::fs::metadata(&delta_path).await?.len();
+            (delta_path, Some(size))
+        } else {
+            let raw_path = self.get_raw_path(id);
+            let path = if tokio::fs::try_exists(&raw_path).await? { raw_path } else { self.get_backup_path(id) };
+            let size = tokio::fs::metadata(&path).await?.len();
+            (path, Some(size))
+        };
+        let key = path.strip_prefix(&self.backup_file_path).unwrap_or(path.as_path()).to_string_lossy().to_string();
+
+        Ok(StoredBackup { backend: "local", key, compressed_size, delta_base_id })
+    }
+    async fn maintenance_note(&self) -> Result<Option<String>> {
+        match tokio::fs::read_to_string(self.backup_file_path.join(MAINTENANCE_FLAG)).await {
+            Ok(note) => Ok(Some(note)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into())
+        }
+    }
+    async fn enter_maintenance(&mut self, note: &str) -> Result<()> {
+        tokio::fs::create_dir_all(&self.backup_file_path).await?;
+        // Never replace the note of whoever put the destination into maintenance mode first
+        let flag = tokio::fs::OpenOptions::new().write(true).create_new(true)
+            .open(self.backup_file_path.join(MAINTENANCE_FLAG)).await;
+        match flag {
+            Ok(mut flag) => {
+                tokio::io::AsyncWriteExt::write_all(&mut flag, note.as_bytes()).await?;
+                Ok(())
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => 
+                Err(Error::MaintenanceMode(self.maintenance_note().await?.unwrap_or_default())),
+            Err(e) => Err(e.into())
+        }
+    }
+    async fn leave_maintenance(&mut self) -> Result<()> {
+        match tokio::fs::remove_file(self.backup_file_path.join(MAINTENANCE_FLAG)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(())
+        }
+    }
+    async fn list_backup_ids(&self) -> Result<Vec<i64>> {
+        let mut ids = BTreeSet::new();
+        let mut shards = match tokio::fs::read_dir(&self.backup_file_path).await {
+            Ok(shards) => shards,
+            // Nothing has been backed up yet
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into())
+        };
+        while let Some(shard) = shards.next_entry().await? {
+            // Only shards are named by a number, which keeps out the chunks and the maintenance flag
+            if !shard.file_type().await?.is_dir() || shard.file_name().to_string_lossy().parse::<i64>().is_err() {
+                continue;
+            }
+            let mut entries = tokio::fs::read_dir(shard.path()).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                // Every file stored for a backup is named by its id, followed by the kind of file.
+                // Temporary files left by an interrupted write aren't stored backups
+                let name = entry.file_name().to_string_lossy().to_string();
+                if name.ends_with(".tmp") {
+                    continue;
+                }
+                if let Some(Ok(id)) = name.split_once('.').map(|(id, _)| id.parse::<i64>()) {
+                    ids.insert(id);
+                }
+            }
+        }
+
+        Ok(ids.into_iter().collect())
+    }
+}
+
+///
+/// The size of the buffer a file is copied through when it's backed up whole
+/// 
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+///
+/// Copies everything `from` has into the synchronous writer `to`, such as a `GzEncoder`,
+/// a buffer at a time. Interrupted reads are retried. Fails with `SourceChangedDuringBackup`
+/// if the number of bytes read differs from `expected_len`, the size of the source when 
+/// the copy began, as when a file is truncated or appended to while it's read
+/// 
+async fn copy_counted(from: &mut (impl AsyncRead + Unpin), to: &mut impl Write, expected_len: u64) -> Result<u64> {
+    let mut buf = vec![0u8; COPY_BUFFER_SIZE];
+    let mut total = 0u64;
+    loop {
+        // A read into an empty buffer returns 0 without reaching the end, so it would end the copy early
+        debug_assert!(!buf.is_empty());
+        let len = match from.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into())
+        };
+        to.write_all(&buf[..len])?;
+        total += len as u64;
+    }
+
+    if total != expected_len {
+        return Err(Error::SourceChangedDuringBackup { expected: expected_len, read: total });
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Durability;
+
+    const CHUNK_SIZE: usize = 64 * 1024;
+
+    ///
+    /// Generates `len` bytes of incompressible data
+    /// 
+    fn noise(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 56) as u8
+        }).collect()
+    }
+
+    fn stored_chunk_bytes(backup_path: &Path) -> u64 {
+        walkdir(&backup_path.join("chunks"))
+    }
+
+    fn walkdir(path: &Path) -> u64 {
+        std::fs::read_dir(path).unwrap().map(|entry| {
+            let entry = entry.unwrap();
+            match entry.file_type().unwrap().is_dir() {
+                true => walkdir(&entry.path()),
+                false => entry.metadata().unwrap().len()
+            }
+        }).sum()
+    }
+
+    #[tokio::test]
+    async fn test_appending_to_chunked_file_only_stores_new_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup_path = dir.path().join("backups");
+        let file_path = dir.path().join("data.log");
+        let mut backup_service = FileBackupService::new(backup_path.to_string_lossy().to_string()).unwrap();
+
+        let mut contents = noise(1, CHUNK_SIZE * 16 + 100);
+        std::fs::write(&file_path, &contents).unwrap();
+        backup_service.backup_chunked(1, &file_path, CHUNK_SIZE).await.unwrap();
+        let first_size = stored_chunk_bytes(&backup_path);
+
+        contents.extend(noise(2, 4096));
+        std::fs::write(&file_path, &contents).unwrap();
+        let chunks = backup_service.backup_chunked(2, &file_path, CHUNK_SIZE).await.unwrap();
+        let growth = stored_chunk_bytes(&backup_path) - first_size;
+
+        // Only the trailing chunk changed, so only it is stored again
+        assert_eq!(chunks.len(), 17);
+        assert!(growth < 2 * CHUNK_SIZE as u64, "stored {} bytes for a 4096 byte append", growth);
+
+        let mut restored = Vec::new();
+        backup_service.open_backup(2).await.unwrap().read_to_end(&mut restored).unwrap();
+        assert_eq!(restored, contents);
+    }
+
+    #[tokio::test]
+    async fn test_empty_file_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("empty.txt");
+        let restored_path = dir.path().join("restored.txt");
+        std::fs::write(&file_path, "").unwrap();
+        std::fs::write(&restored_path, "stale contents").unwrap();
+        let mut backup_service = FileBackupService::new(dir.path().join("backups").to_string_lossy().to_string()).unwrap();
+
+        backup_service.backup_data(3, &file_path).await.unwrap();
+
+        // Only the marker is stored, without any compressed data
+        assert!(!backup_service.get_backup_path(3).exists());
+        assert_eq!(backup_service.describe_backup(3).await.unwrap().compressed_size, Some(0));
+
+        backup_service.restore_data(3, &restored_path).await.unwrap();
+        assert_eq!(std::fs::read(&restored_path).unwrap(), Vec::<u8>::new());
+
+        backup_service.delete_backup(3).await.unwrap();
+        assert!(!backup_service.get_empty_path(3).exists());
+    }
+
+    #[tokio::test]
+    async fn test_large_file_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("large.bin");
+        let restored_path = dir.path().join("restored.bin");
+        // Many times larger than any buffer used copying it
+        let contents = noise(6, 8 * 1024 * 1024 + 17);
+        std::fs::write(&file_path, &contents).unwrap();
+        let mut backup_service = FileBackupService::new(dir.path().join("backups").to_string_lossy().to_string()).unwrap();
+
+        backup_service.backup_data(1, &file_path).await.unwrap();
+        backup_service.restore_data(1, &restored_path).await.unwrap();
+
+        assert_eq!(read_backup(&backup_service, 1).await, contents);
+        assert_eq!(std::fs::read(&restored_path).unwrap(), contents);
+    }
+
+    ///
+    /// What a `ScriptedReader` does on each read
+    /// 
+    enum ReadStep {
+        /// Returns the bytes, as many as fit in the buffer at a time
+        Data(Vec<u8>),
+        Interrupted
+    }
+
+    ///
+    /// A reader following a script of reads, ending once the script does
+    /// 
+    struct ScriptedReader(std::collections::VecDeque<ReadStep>);
+
+    impl AsyncRead for ScriptedReader {
+        fn poll_read(
+            self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>, buf: &mut tokio::io::ReadBuf<'_>
+        ) -> std::task::Poll<std::io::Result<()>> {
+            let steps = &mut self.get_mut().0;
+            match steps.pop_front() {
+                Some(ReadStep::Data(mut data)) => {
+                    let len = data.len().min(buf.remaining());
+                    buf.put_slice(&data[..len]);
+                    if len < data.len() {
+                        steps.push_front(ReadStep::Data(data.split_off(len)));
+                    }
+                    std::task::Poll::Ready(Ok(()))
+                },
+                Some(ReadStep::Interrupted) => std::task::Poll::Ready(Err(ErrorKind::Interrupted.into())),
+                None => std::task::Poll::Ready(Ok(()))
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_copy_counted_retries_interrupted_reads() {
+        let large = noise(7, COPY_BUFFER_SIZE + 10);
+        let mut reader = ScriptedReader([
+            ReadStep::Data(b"short".to_vec()),
+            ReadStep::Interrupted,
+            ReadStep::Data(large.clone()),
+            ReadStep::Interrupted,
+            ReadStep::Data(b"end".to_vec())
+        ].into());
+        let mut written = Vec::new();
+        let expected = [b"short".to_vec(), large, b"end".to_vec()].concat();
+
+        let copied = copy_counted(&mut reader, &mut written, expected.len() as u64).await.unwrap();
+
+        assert_eq!(copied, expected.len() as u64);
+        assert_eq!(written, expected);
+    }
+
+    #[tokio::test]
+    async fn test_copy_counted_detects_early_eof() {
+        let mut reader = ScriptedReader([ReadStep::Data(b"truncated".to_vec())].into());
+
+        let e = copy_counted(&mut reader, &mut Vec::new(), 100).await.unwrap_err();
+
+        assert!(matches!(e, Error::SourceChangedDuringBackup { expected: 100, read: 9 }));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_sparse_file_round_trip() {
+        use std::{io::{Seek, SeekFrom}, os::unix::fs::MetadataExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("sparse.bin");
+        let restored_path = dir.path().join("restored.bin");
+        // A byte at either end of a 64MB hole
+        let mut file = std::fs::File::create(&file_path).unwrap();
+        file.write_all(b"a").unwrap();
+        file.seek(SeekFrom::Start(64 * 1024 * 1024)).unwrap();
+        file.write_all(b"b").unwrap();
+        drop(file);
+        let size = std::fs::metadata(&file_path).unwrap().len();
+        let mut backup_service = FileBackupService::new(dir.path().join("backups").to_string_lossy().to_string()).unwrap();
+
+        backup_service.backup_data(1, &file_path).await.unwrap();
+        backup_service.restore_data(1, &restored_path).await.unwrap();
+
+        let restored = std::fs::metadata(&restored_path).unwrap();
+        assert_eq!(restored.len(), size);
+        assert!(restored.blocks() * 512 < size / 16);
+        assert_eq!(std::fs::read(&restored_path).unwrap(), std::fs::read(&file_path).unwrap());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_long_path_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        // Well past the 260 characters Windows allows without the extended-length prefix
+        let nested = (0..8).map(|i| format!("{}{}", i, "d".repeat(40))).collect::<PathBuf>();
+        let file_path = dir.path().join("source").join(&nested).join(format!("{}.txt", "f".repeat(200)));
+        let restored_path = dir.path().join("restored").join(&nested).join(format!("{}.txt", "r".repeat(200)));
+        assert!(file_path.as_os_str().len() > 300);
+        std::fs::create_dir_all(file_path.parent().unwrap()).unwrap();
+        std::fs::create_dir_all(restored_path.parent().unwrap()).unwrap();
+        std::fs::write(&file_path, noise(3, 4096)).unwrap();
+        let mut backup_service = FileBackupService::new(dir.path().join("backups").to_string_lossy().to_string()).unwrap();
+
+        backup_service.backup_data(1, &file_path).await.unwrap();
+        backup_service.restore_data(1, &restored_path).await.unwrap();
+
+        assert_eq!(std::fs::read(&restored_path).unwrap(), std::fs::read(&file_path).unwrap());
+        // Errors name the path which couldn't be used
+        let missing = file_path.with_file_name("missing.txt");
+        let e = backup_service.backup_data(2, &missing).await.unwrap_err();
+        assert!(e.to_string().contains(&missing.display().to_string()), "{}", e);
+    }
+
+    ///
+    /// Backs up each of `versions` of the same file in turn, as backups 1, 2, 3 and so on,
+    /// the first in full and every other as a delta against the one before it
+    /// 
+    async fn backup_delta_chain(backup_service: &mut FileBackupService, file_path: &Path, versions: &[Vec<u8>]) {
+        for (i, contents) in versions.iter().enumerate() {
+            let id = i as i64 + 1;
+            std::fs::write(file_path, contents).unwrap();
+            match id {
+                1 => backup_service.backup_data(id, file_path).await.unwrap(),
+                id => backup_service.backup_delta(id, id - 1, file_path).await.unwrap(),
+            }
+        }
     }
-}
\ No newline at end of file
+
+    async fn read_backup(backup_service: &FileBackupService, id: i64) -> Vec<u8> {
+        let mut contents = Vec::new();
+        backup_service.open_backup(id).await.unwrap().read_to_end(&mut contents).unwrap();
+        contents
+    }
+
+    fn appended_versions() -> Vec<Vec<u8>> {
+        let mut contents = noise(3, 256 * 1024);
+        (0..4).map(|i| {
+            contents.extend(noise(10 + i, 1024));
+            contents.clone()
+        }).collect()
+    }
+
+    #[tokio::test]
+    async fn test_three_deep_delta_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("mail.mbox");
+        let mut backup_service = FileBackupService::new(dir.path().join("backups").to_string_lossy().to_string()).unwrap();
+        let versions = appended_versions();
+
+        backup_delta_chain(&mut backup_service, &file_path, &versions).await;
+
+        for (i, contents) in versions.iter().enumerate() {
+            assert_eq!(&read_backup(&backup_service, i as i64 + 1).await, contents);
+        }
+        let stored = backup_service.describe_backup(4).await.unwrap();
+        assert_eq!(stored.delta_base_id, Some(3));
+        // Only the appended data is stored for each delta
+        assert!(stored.compressed_size.unwrap() < 16 * 1024, "stored {:?} bytes for a delta", stored.compressed_size);
+    }
+
+    #[tokio::test]
+    async fn test_materialized_delta_outlives_its_base() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("mail.mbox");
+        let mut backup_service = FileBackupService::new(dir.path().join("backups").to_string_lossy().to_string()).unwrap();
+        let versions = appended_versions();
+        backup_delta_chain(&mut backup_service, &file_path, &versions).await;
+
+        // Pruning the oldest backup first stores the delta depending on it in full
+        backup_service.materialize(2).await.unwrap();
+        backup_service.delete_backup(1).await.unwrap();
+
+        assert_eq!(backup_service.describe_backup(2).await.unwrap().delta_base_id, None);
+        assert_eq!(read_backup(&backup_service, 2).await, versions[1]);
+        assert_eq!(read_backup(&backup_service, 4).await, versions[3]);
+    }
+
+    #[tokio::test]
+    async fn test_incompressible_files_are_stored_raw() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut backup_service = BackupServiceBuilder::new(dir.path().join("backups"))
+            .skip_compression_extensions(&[".MP4".to_string()]).build().unwrap();
+        let video = dir.path().join("holiday.mp4");
+        let photo = dir.path().join("photo");
+        let notes = dir.path().join("notes.txt");
+        std::fs::write(&video, noise(1, 4096)).unwrap();
+        // Recognised as a JPEG by its signature, despite having no extension
+        std::fs::write(&photo, [&[0xFF, 0xD8, 0xFF, 0xE0][..], &noise(2, 4096)].concat()).unwrap();
+        std::fs::write(&notes, "notes ".repeat(1000)).unwrap();
+        assert!(backup_service.detect_incompressible(&video));
+        assert!(backup_service.detect_incompressible(&photo));
+        assert!(!backup_service.detect_incompressible(&notes));
+
+        for (id, path) in [(1, &video), (2, &photo), (3, &notes)] {
+            backup_service.backup_data(id, path).await.unwrap();
+        }
+
+        assert_eq!(std::fs::read(backup_service.get_raw_path(1)).unwrap(), std::fs::read(&video).unwrap());
+        assert!(!backup_service.get_backup_path(1).exists());
+        assert!(backup_service.get_raw_path(2).exists());
+        assert!(backup_service.get_backup_path(3).exists());
+        assert_eq!(backup_service.describe_backup(1).await.unwrap().key, format!("0{}1.raw", std::path::MAIN_SEPARATOR));
+        for (id, path) in [(1, &video), (2, &photo), (3, &notes)] {
+            let restored = dir.path().join(format!("restored{}", id));
+            backup_service.restore_data(id, &restored).await.unwrap();
+            assert_eq!(std::fs::read(restored).unwrap(), std::fs::read(path).unwrap());
+        }
+
+        backup_service.delete_backup(1).await.unwrap();
+        assert!(!backup_service.contains(1).await.unwrap());
+        assert_eq!(backup_service.list_backup_ids().await.unwrap(), vec![2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_calculate_total_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("data.txt");
+        let mut backup_service = FileBackupService::new(dir.path().join("backups").to_string_lossy().to_string()).unwrap();
+        assert_eq!(backup_service.calculate_total_size().await.unwrap(), 0);
+
+        std::fs::write(&file_path, "contents").unwrap();
+        backup_service.backup_data(1, &file_path).await.unwrap();
+        backup_service.backup_data(100_001, &file_path).await.unwrap();
+        std::fs::write(&file_path, "").unwrap();
+        backup_service.backup_data(2, &file_path).await.unwrap();
+
+        // Both compressed copies count, in their separate shards, but the empty marker doesn't
+        let stored_size = std::fs::metadata(backup_service.get_backup_path(1)).unwrap().len();
+        assert_eq!(backup_service.calculate_total_size().await.unwrap(), stored_size * 2);
+    }
+
+    ///
+    /// Counts every sync, without syncing anything
+    /// 
+    #[derive(Default)]
+    struct CountingSyncer {
+        files: std::sync::atomic::AtomicUsize,
+        dirs: std::sync::Mutex<Vec<PathBuf>>
+    }
+
+    impl Syncer for CountingSyncer {
+        fn sync_file(&self, _file: &std::fs::File) -> std::io::Result<()> {
+            self.files.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(())
+        }
+        fn sync_dir(&self, dir: &Path) -> std::io::Result<()> {
+            self.dirs.lock().unwrap().push(dir.to_path_buf());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_safe_durability_syncs_every_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("data.txt");
+        let syncer = Arc::new(CountingSyncer::default());
+        let fast = BackupServiceBuilder::new(dir.path().join("fast")).durability(Durability::Fast).build().unwrap();
+        assert!(!fast.syncs_writes());
+        let mut backup_service = BackupServiceBuilder::new(dir.path().join("backups")).syncer(syncer.clone()).build().unwrap();
+        assert!(backup_service.syncs_writes());
+
+        std::fs::write(&file_path, "contents").unwrap();
+        backup_service.backup_data(1, &file_path).await.unwrap();
+        std::fs::write(&file_path, "").unwrap();
+        backup_service.backup_data(2, &file_path).await.unwrap();
+        std::fs::write(&file_path, noise(4, CHUNK_SIZE + 10)).unwrap();
+        backup_service.backup_chunked(3, &file_path, CHUNK_SIZE).await.unwrap();
+
+        // The whole backup, the empty marker, both chunks and the chunk list
+        assert_eq!(syncer.files.load(std::sync::atomic::Ordering::SeqCst), 5);
+        let shard = backup_service.get_shard_path(1);
+        let dirs = syncer.dirs.lock().unwrap();
+        assert_eq!(dirs.len(), 5);
+        assert_eq!(dirs.iter().filter(|synced| **synced == shard).count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_backups_are_written_through_temp_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("data.txt");
+        let temp_dir = dir.path().join("temp");
+        std::fs::write(&file_path, noise(5, CHUNK_SIZE + 10)).unwrap();
+        let mut backup_service = BackupServiceBuilder::new(dir.path().join("backups")).temp_dir(Some(temp_dir.clone())).build().unwrap();
+
+        backup_service.backup_data(1, &file_path).await.unwrap();
+        backup_service.backup_chunked(2, &file_path, CHUNK_SIZE).await.unwrap();
+        backup_service.backup_delta(3, 1, &file_path).await.unwrap();
+        backup_service.materialize(3).await.unwrap();
+
+        // Every temporary file was moved into the destination
+        assert_eq!(std::fs::read_dir(&temp_dir).unwrap().count(), 0);
+        assert_eq!(read_backup(&backup_service, 1).await, std::fs::read(&file_path).unwrap());
+        assert_eq!(read_backup(&backup_service, 2).await, std::fs::read(&file_path).unwrap());
+        assert_eq!(read_backup(&backup_service, 3).await, std::fs::read(&file_path).unwrap());
+
+        // A write interrupted part way through leaves only its temporary file, which isn't a backup
+        std::fs::write(backup_service.get_shard_path(4).join("4.gz.tmp"), "trunc").unwrap();
+        assert_eq!(backup_service.list_backup_ids().await.unwrap(), vec![1, 2, 3]);
+        assert!(!backup_service.contains(4).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_backups_larger_than_the_spool_cap_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let (small_path, large_path) = (dir.path().join("small.bin"), dir.path().join("large.bin"));
+        let temp_dir = dir.path().join("temp");
+        std::fs::write(&small_path, noise(6, 1024)).unwrap();
+        std::fs::write(&large_path, noise(7, 16 * CHUNK_SIZE)).unwrap();
+        let mut backup_service = BackupServiceBuilder::new(dir.path().join("backups"))
+            .temp_dir(Some(temp_dir.clone())).temp_dir_max_bytes(Some(4 * CHUNK_SIZE as u64)).build().unwrap();
+
+        backup_service.backup_data(1, &small_path).await.unwrap();
+        let e = backup_service.backup_data(2, &large_path).await.unwrap_err();
+        assert!(matches!(e.kind, Error::SpoolFull { max_bytes } if max_bytes == 4 * CHUNK_SIZE as u64), "{:?}", e.kind);
+        // The refused backup left nothing behind in the spool or the destination
+        assert_eq!(std::fs::read_dir(&temp_dir).unwrap().count(), 0);
+        assert_eq!(backup_service.list_backup_ids().await.unwrap(), vec![1]);
+
+        // Written straight into the destination, nothing is spooled, so the cap doesn't apply
+        let mut streaming = BackupServiceBuilder::new(dir.path().join("streamed"))
+            .temp_dir_max_bytes(Some(4 * CHUNK_SIZE as u64)).build().unwrap();
+        streaming.backup_data(2, &large_path).await.unwrap();
+        assert_eq!(read_backup(&streaming, 2).await, std::fs::read(&large_path).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_backup_error_names_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("missing.txt");
+        let mut backup_service = FileBackupService::new(dir.path().join("backups").to_string_lossy().to_string()).unwrap();
+
+        let e = backup_service.backup_data(7, &file_path).await.unwrap_err();
+
+        assert!(matches!(e.kind, Error::IOError(_)));
+        assert!(e.to_string().starts_with(&format!("Failed to backup file {} (id=7): ", file_path.display())));
+    }
+
+    #[tokio::test]
+    async fn test_destination_disappearing_mid_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let destination = dir.path().join("backups");
+        let mut backup_service = FileBackupService::new(destination.to_string_lossy().to_string()).unwrap();
+        // Stands in for the destination's drive being unplugged once the first file is backed up
+        let unplug = |id| if id == 1 { std::fs::remove_dir_all(&destination).unwrap() };
+
+        let mut results = Vec::new();
+        for id in 1..=3 {
+            let file_path = dir.path().join(format!("{}.txt", id));
+            std::fs::write(&file_path, "contents").unwrap();
+            results.push(backup_service.backup_data(id, &file_path).await.map_err(|e| e.kind));
+            unplug(id);
+        }
+
+        assert!(results[0].is_ok());
+        for result in &results[1..] {
+            assert!(matches!(result, Err(Error::DestinationUnavailable(path)) if *path == destination), "{:?}", result);
+        }
+        // The destination isn't recreated where its drive was mounted
+        assert!(!destination.exists());
+        let chunked = backup_service.backup_chunked(4, &dir.path().join("1.txt"), 4).await;
+        assert!(matches!(chunked, Err(BackupError { kind: Error::DestinationUnavailable(_), .. })));
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_mode_refuses_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("data.txt");
+        std::fs::write(&file_path, "contents").unwrap();
+        let mut backup_service = FileBackupService::new(dir.path().join("backups").to_string_lossy().to_string()).unwrap();
+
+        backup_service.backup_data(1, &file_path).await.unwrap();
+        backup_service.enter_maintenance("moving to a new drive").await.unwrap();
+
+        // A second flag can't replace the first one's note
+        match backup_service.enter_maintenance("other note").await {
+            Err(Error::MaintenanceMode(note)) => assert_eq!(note, "moving to a new drive"),
+            other => panic!("expected maintenance mode, got {:?}", other)
+        }
+        match backup_service.backup_data(2, &file_path).await {
+            Err(BackupError { kind: Error::MaintenanceMode(note), .. }) => assert_eq!(note, "moving to a new drive"),
+            other => panic!("expected maintenance mode, got {:?}", other)
+        }
+        assert!(matches!(backup_service.delete_backup(1).await, Err(Error::MaintenanceMode(_))));
+        // Reads are still allowed
+        backup_service.open_backup(1).await.unwrap();
+
+        backup_service.leave_maintenance().await.unwrap();
+        backup_service.delete_backup(1).await.unwrap();
+        backup_service.leave_maintenance().await.unwrap();
+    }
+}
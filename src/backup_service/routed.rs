@@ -0,0 +1,255 @@
+use std::{collections::BTreeSet, io::Read, path::Path};
+
+use async_trait::async_trait;
+
+use super::{chunks::ChunkRef, error::*, BackupService, FileBackupService, StoredBackup};
+
+///
+/// Selects the destinations a file is written to by matching its path
+/// 
+pub struct Route {
+    pub patterns: Vec<glob::Pattern>,
+    pub destinations: Vec<String>
+}
+
+impl Route {
+    fn matches(&self, path: &Path) -> bool {
+        self.patterns.iter().any(|ptn| ptn.matches_path(path))
+    }
+}
+
+///
+/// Backs files up to several named destinations, writing each file to the destinations
+/// of the first route matching its path, or to every destination if none match
+/// 
+pub struct RoutedBackupService {
+    destinations: Vec<(String, FileBackupService)>,
+    routes: Vec<Route>
+}
+
+impl RoutedBackupService {
+    ///
+    /// Creates a new `RoutedBackupService` over the given named `destinations`.
+    /// Destinations named by `routes` which aren't among them are ignored.
+    /// 
+    pub fn new(destinations: Vec<(String, FileBackupService)>, routes: Vec<Route>) -> Self {
+        Self { destinations, routes }
+    }
+
+    ///
+    /// Gets the name of every destination
+    /// 
+    pub fn destination_names(&self) -> Vec<&str> {
+        self.destinations.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    ///
+    /// Probes whether each destination keeps apart files whose names differ only in case
+    /// 
+    pub fn probe_case_sensitivity(&self) -> Vec<(&str, std::io::Result<bool>)> {
+        self.destinations.iter().map(|(name, destination)| (name.as_str(), destination.is_case_sensitive())).collect()
+    }
+
+    ///
+    /// Whether every destination syncs each written backup to disk before it's reported as stored
+    /// 
+    pub fn syncs_writes(&self) -> bool {
+        self.destinations.iter().all(|(_, destination)| destination.syncs_writes())
+    }
+
+    ///
+    /// Gets the names of the destinations the file at `path` is written to
+    /// 
+    pub fn route(&self, path: &Path) -> Vec<&str> {
+        match self.routes.iter().find(|route| route.matches(path)) {
+            Some(route) => self.destination_names().into_iter()
+                .filter(|name| route.destinations.iter().any(|d| d == name))
+                .collect(),
+            None => self.destination_names()
+        }
+    }
+
+    ///
+    /// Opens the backup with the given `id` from the first of the `recorded` destinations
+    /// which is still configured, falling back to any destination holding it
+    /// 
+    pub async fn open_recorded(&self, id: i64, recorded: &[String]) -> Result<Box<dyn Read + Send>> {
+        self.locate(id, recorded).await?.open_backup(id).await
+    }
+
+    ///
+    /// Restores the backup with the given `id` to `path` from the first of the `recorded`
+    /// destinations which is still configured, falling back to any destination holding it
+    /// 
+    pub async fn restore_recorded(&self, id: i64, recorded: &[String], path: &Path) -> Result<()> {
+        self.locate(id, recorded).await?.restore_data(id, path).await
+    }
+
+    ///
+    /// Sums the size of every compressed file stored in every destination
+    /// 
+    pub async fn calculate_total_size(&self) -> Result<u64> {
+        let mut total = 0;
+        for (_, destination) in &self.destinations {
+            total += destination.calculate_total_size().await?;
+        }
+        Ok(total)
+    }
+
+    async fn locate(&self, id: i64, recorded: &[String]) -> Result<&FileBackupService> {
+        let recorded = self.destinations.iter().filter(|(name, _)| recorded.contains(name));
+        for (_, destination) in recorded.chain(self.destinations.iter()) {
+            if destination.contains(id).await? {
+                return Ok(destination);
+            }
+        }
+        Err(Error::IOError(std::io::Error::new(
+            std::io::ErrorKind::NotFound, format!("no destination holds the backup with id {}", id)
+        )))
+    }
+
+    fn routed_mut(&mut self, path: &Path) -> impl Iterator<Item = &mut FileBackupService> {
+        let names: Vec<String> = self.route(path).into_iter().map(String::from).collect();
+        self.destinations.iter_mut()
+            .filter(move |(name, _)| names.contains(name))
+            .map(|(_, destination)| destination)
+    }
+}
+
+#[async_trait]
+impl BackupService for RoutedBackupService {
+    async fn backup_data(&mut self, id: i64, path: &Path) -> std::result::Result<(), BackupError> {
+        for destination in self.routed_mut(path) {
+            destination.backup_data(id, path).await?;
+        }
+        Ok(())
+    }
+    async fn delete_backup(&mut self, id: i64) -> Result<()> {
+        for (_, destination) in &mut self.destinations {
+            if destination.contains(id).await? {
+                destination.delete_backup(id).await?;
+            }
+        }
+        Ok(())
+    }
+    async fn open_backup(&self, id: i64) -> Result<Box<dyn Read + Send>> {
+        self.open_recorded(id, &[]).await
+    }
+    async fn restore_data(&self, id: i64, path: &Path) -> Result<()> {
+        self.restore_recorded(id, &[], path).await
+    }
+    async fn backup_chunked(&mut self, id: i64, path: &Path, chunk_size: usize) -> std::result::Result<Vec<ChunkRef>, BackupError> {
+        // Every destination splits the file into the same chunks
+        let mut chunks = Vec::new();
+        for destination in self.routed_mut(path) {
+            chunks = destination.backup_chunked(id, path, chunk_size).await?;
+        }
+        Ok(chunks)
+    }
+    async fn backup_delta(&mut self, id: i64, base_id: i64, path: &Path) -> std::result::Result<(), BackupError> {
+        for destination in self.routed_mut(path) {
+            destination.backup_delta(id, base_id, path).await?;
+        }
+        Ok(())
+    }
+    async fn materialize(&mut self, id: i64) -> Result<()> {
+        for (_, destination) in &mut self.destinations {
+            if destination.contains(id).await? {
+                destination.materialize(id).await?;
+            }
+        }
+        Ok(())
+    }
+    async fn delete_chunk(&mut self, hsh: &str) -> Result<()> {
+        for (_, destination) in &mut self.destinations {
+            if destination.contains_chunk(hsh).await? {
+                destination.delete_chunk(hsh).await?;
+            }
+        }
+        Ok(())
+    }
+    async fn describe_backup(&self, id: i64) -> Result<StoredBackup> {
+        self.locate(id, &[]).await?.describe_backup(id).await
+    }
+    async fn describe_copies(&self, id: i64) -> Result<Vec<(String, StoredBackup)>> {
+        let mut copies = Vec::new();
+        for (name, destination) in &self.destinations {
+            if destination.contains(id).await? {
+                copies.push((name.clone(), destination.describe_backup(id).await?));
+            }
+        }
+        Ok(copies)
+    }
+    async fn maintenance_note(&self) -> Result<Option<String>> {
+        for (_, destination) in &self.destinations {
+            if let Some(note) = destination.maintenance_note().await? {
+                return Ok(Some(note));
+            }
+        }
+        Ok(None)
+    }
+    async fn enter_maintenance(&mut self, note: &str) -> Result<()> {
+        for (_, destination) in &mut self.destinations {
+            destination.enter_maintenance(note).await?;
+        }
+        Ok(())
+    }
+    async fn leave_maintenance(&mut self) -> Result<()> {
+        for (_, destination) in &mut self.destinations {
+            destination.leave_maintenance().await?;
+        }
+        Ok(())
+    }
+    async fn list_backup_ids(&self) -> Result<Vec<i64>> {
+        let mut ids = BTreeSet::new();
+        for (_, destination) in &self.destinations {
+            ids.extend(destination.list_backup_ids().await?);
+        }
+        Ok(ids.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::backup_service::{BackupService, FileBackupService};
+
+    use super::{Route, RoutedBackupService};
+
+    fn destination(root: &Path, name: &str) -> (String, FileBackupService) {
+        (name.to_string(), FileBackupService::new(root.join(name).to_string_lossy().to_string()).unwrap())
+    }
+    async fn copies(backup_service: &RoutedBackupService, id: i64) -> Vec<String> {
+        backup_service.describe_copies(id).await.unwrap().into_iter().map(|(name, _)| name).collect()
+    }
+
+    #[tokio::test]
+    async fn test_routes_file_types_to_destinations() {
+        let dir = tempfile::tempdir().unwrap();
+        let (photo, notes) = (dir.path().join("photo.raw"), dir.path().join("notes.txt"));
+        std::fs::write(&photo, "raw sensor data").unwrap();
+        std::fs::write(&notes, "shopping list").unwrap();
+        let routes = vec![Route { patterns: vec![glob::Pattern::new("**/*.raw").unwrap()], destinations: vec!["archive".to_string()] }];
+        let mut backup_service = RoutedBackupService::new(
+            vec![destination(dir.path(), "ssd"), destination(dir.path(), "archive")], routes
+        );
+
+        assert_eq!(backup_service.route(&photo), vec!["archive"]);
+        assert_eq!(backup_service.route(&notes), vec!["ssd", "archive"]);
+        backup_service.backup_data(1, &photo).await.unwrap();
+        backup_service.backup_data(2, &notes).await.unwrap();
+
+        assert_eq!(copies(&backup_service, 1).await, vec!["archive"]);
+        assert_eq!(copies(&backup_service, 2).await, vec!["ssd", "archive"]);
+
+        // Restoring uses the destination recorded for the backup, even if it's listed later
+        let restored = dir.path().join("restored.raw");
+        backup_service.restore_recorded(1, &["ssd".to_string(), "archive".to_string()], &restored).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&restored).unwrap(), "raw sensor data");
+        // A destination recorded but no longer configured falls back to the others
+        backup_service.restore_recorded(2, &["removed".to_string()], &restored).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&restored).unwrap(), "shopping list");
+    }
+
+}
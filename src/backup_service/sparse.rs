@@ -0,0 +1,67 @@
+use std::{fs::{File, Metadata}, io::{Read, Seek, SeekFrom, Write}};
+
+///
+/// The granularity holes are written at. Runs of zeros shorter than this,
+/// or not aligned to it, are written out as data
+/// 
+const BLOCK_SIZE: usize = 4096;
+
+///
+/// Whether the file with the given `metadata` has fewer blocks allocated
+/// than its length needs, meaning some of it is holes. Only detectable on unix;
+/// elsewhere no file is treated as sparse
+/// 
+#[cfg(unix)]
+pub fn is_sparse(metadata: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    metadata.blocks() * 512 < metadata.len()
+}
+
+#[cfg(not(unix))]
+pub fn is_sparse(_metadata: &Metadata) -> bool {
+    false
+}
+
+///
+/// Copies everything from `from` into the file `to`, seeking past blocks
+/// of zeros rather than writing them so that they're left as holes.
+/// Returns the number of bytes copied
+/// 
+pub fn copy_sparse(from: &mut impl Read, to: &mut File) -> std::io::Result<u64> {
+    let mut buf = vec![0u8; BLOCK_SIZE];
+    let mut total = 0u64;
+    loop {
+        let len = read_block(from, &mut buf)?;
+        if len == 0 {
+            break;
+        }
+
+        if buf[..len].iter().all(|b| *b == 0) {
+            to.seek(SeekFrom::Current(len as i64))?;
+        } else {
+            to.write_all(&buf[..len])?;
+        }
+        total += len as u64;
+    }
+
+    // A trailing hole is only a seek, so the length has to be set for it to exist
+    to.set_len(total)?;
+    Ok(total)
+}
+
+///
+/// Fills as much of `buf` as `from` has left, so that blocks line up
+/// with the file's offsets regardless of how short each read comes back
+/// 
+fn read_block(from: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match from.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(filled)
+}
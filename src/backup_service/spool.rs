@@ -0,0 +1,98 @@
+use std::{fmt::Display, io::Write, path::{Path, PathBuf}};
+
+use crate::estimate::format_bytes;
+
+///
+/// A directory backups are written to until they're complete, before they're moved into their
+/// destination, which holds at most `max_bytes` of any one backup, if given. Backends which
+/// stream straight into their destination have no spool, so aren't capped
+/// 
+#[derive(Clone, Debug)]
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: Option<u64>
+}
+
+impl Spool {
+    pub fn new(dir: PathBuf, max_bytes: Option<u64>) -> Self {
+        Self { dir, max_bytes }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn max_bytes(&self) -> Option<u64> {
+        self.max_bytes
+    }
+}
+
+///
+/// The error writing to a `SpoolFile` fails with once it would hold more than its cap,
+/// carried inside an `std::io::Error`
+/// 
+#[derive(Debug)]
+pub struct SpoolFull {
+    pub max_bytes: u64
+}
+
+impl Display for SpoolFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the spooled backup would be larger than the {} cap", format_bytes(self.max_bytes))
+    }
+}
+
+impl std::error::Error for SpoolFull { }
+
+///
+/// A file being written in a spool, which fails with `SpoolFull` rather than
+/// growing past `max_bytes`, so a single large file can't fill the spool's filesystem
+/// 
+pub struct SpoolFile {
+    file: std::fs::File,
+    written: u64,
+    max_bytes: Option<u64>
+}
+
+impl SpoolFile {
+    pub fn new(file: std::fs::File, max_bytes: Option<u64>) -> Self {
+        Self { file, written: 0, max_bytes }
+    }
+
+    pub fn into_file(self) -> std::fs::File {
+        self.file
+    }
+}
+
+impl Write for SpoolFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Some(max_bytes) = self.max_bytes.filter(|max_bytes| self.written + buf.len() as u64 > *max_bytes) {
+            return Err(std::io::Error::other(SpoolFull { max_bytes }));
+        }
+        let len = self.file.write(buf)?;
+        self.written += len as u64;
+        Ok(len)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::{SpoolFile, SpoolFull};
+
+    #[test]
+    fn test_writes_past_the_cap_fail() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file = SpoolFile::new(std::fs::File::create(dir.path().join("a.tmp")).unwrap(), Some(10));
+
+        file.write_all(b"0123456789").unwrap();
+        let e = file.write_all(b"a").unwrap_err();
+        assert_eq!(e.get_ref().unwrap().downcast_ref::<SpoolFull>().unwrap().max_bytes, 10);
+        // Nothing past the cap was written
+        assert_eq!(file.into_file().metadata().unwrap().len(), 10);
+    }
+}
@@ -0,0 +1,39 @@
+use std::{fs::File, io, path::Path};
+
+///
+/// Flushes written backups to disk, so they survive a crash or power loss
+/// 
+pub trait Syncer: Send + Sync {
+    ///
+    /// Flushes the contents and metadata of the written `file` to disk
+    /// 
+    fn sync_file(&self, file: &File) -> io::Result<()>;
+    ///
+    /// Flushes the entries of the directory at `dir`, so that files created in it
+    /// are found after a crash
+    /// 
+    fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+}
+
+///
+/// Syncs through the filesystem
+/// 
+pub struct FsSyncer;
+
+impl Syncer for FsSyncer {
+    fn sync_file(&self, file: &File) -> io::Result<()> {
+        file.sync_all()
+    }
+    #[cfg(unix)]
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        File::open(dir)?.sync_all()
+    }
+    ///
+    /// Directories can't be opened as files on other platforms,
+    /// which flush their entries along with the file
+    /// 
+    #[cfg(not(unix))]
+    fn sync_dir(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,74 @@
+use std::path::Path;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+
+///
+/// A single extended attribute, with its name and value base64-encoded
+/// so that neither needs to be valid UTF-8
+/// 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct XattrEntry {
+    pub name: String,
+    pub value: String
+}
+
+///
+/// Reads every extended attribute of the file at the given `path`.
+/// Filesystems which don't support extended attributes have none.
+/// 
+#[cfg(unix)]
+pub fn read_xattrs(path: &Path) -> std::io::Result<Vec<XattrEntry>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(Vec::new()),
+        Err(e) => return Err(e)
+    };
+
+    let mut entries = Vec::new();
+    for name in names {
+        // The attribute may have been removed since it was listed
+        if let Some(value) = xattr::get(path, &name)? {
+            entries.push(XattrEntry { 
+                name: STANDARD.encode(name.as_bytes()), 
+                value: STANDARD.encode(value) 
+            });
+        }
+    }
+
+    Ok(entries)
+}
+
+///
+/// Sets each of the given extended attribute `entries` on the file at the given `path`
+/// 
+#[cfg(unix)]
+pub fn write_xattrs(path: &Path, entries: &[XattrEntry]) -> std::io::Result<()> {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    for entry in entries {
+        let name = STANDARD.decode(&entry.name).map_err(invalid_data)?;
+        let value = STANDARD.decode(&entry.value).map_err(invalid_data)?;
+        xattr::set(path, OsStr::from_bytes(&name), &value)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn read_xattrs(_path: &Path) -> std::io::Result<Vec<XattrEntry>> {
+    Ok(Vec::new())
+}
+
+#[cfg(not(unix))]
+pub fn write_xattrs(_path: &Path, _entries: &[XattrEntry]) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn invalid_data(err: base64::DecodeError) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+}
+
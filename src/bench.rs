@@ -0,0 +1,236 @@
+use std::{fmt::Write, fs, future::Future, io, path::{Path, PathBuf}, time::{Duration, Instant}};
+
+use crate::estimate::{format_bytes, format_count};
+
+///
+/// The words compressible files are written with
+/// 
+const WORDS: [&str; 16] = [
+    "backup", "catalog", "version", "restore", "shard", "chunk", "delta", "hash",
+    "directory", "file", "the", "of", "and", "a", "to", "is"
+];
+
+///
+/// A SplitMix64 generator. Its output depends only on its seed,
+/// so the same tree is generated on every machine.
+/// 
+struct SeededRng(u64);
+
+impl SeededRng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+    ///
+    /// Generates a number from 0 up to, but not including, 1
+    /// 
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+    fn fill(&mut self, bytes: &mut [u8]) {
+        for chunk in bytes.chunks_mut(8) {
+            chunk.copy_from_slice(&self.next_u64().to_le_bytes()[..chunk.len()]);
+        }
+    }
+}
+
+///
+/// The settings of a generated tree of files, for comparing how quickly they're
+/// hashed and backed up. The same settings always generate the same tree.
+/// 
+#[derive(Clone, Debug)]
+pub struct SyntheticTree {
+    pub file_count: usize,
+    /// The smallest size of a file, in bytes
+    pub min_file_size: u64,
+    /// The largest size of a file, in bytes
+    pub max_file_size: u64,
+    /// The fraction of files, from 0 to 1, filled with text which compresses well.
+    /// The rest are filled with random bytes, which don't compress at all
+    pub compressible_fraction: f64,
+    /// The number of files in each directory of the tree
+    pub files_per_dir: usize,
+    pub seed: u64
+}
+
+impl Default for SyntheticTree {
+    fn default() -> Self {
+        Self {
+            file_count: 1000, min_file_size: 1024, max_file_size: 1024 * 1024,
+            compressible_fraction: 0.5, files_per_dir: 100, seed: 42
+        }
+    }
+}
+
+impl SyntheticTree {
+    ///
+    /// Writes the tree's files under `root`, returning the path and size of each
+    /// 
+    pub fn generate(&self, root: &Path) -> io::Result<Vec<(PathBuf, u64)>> {
+        let mut rng = SeededRng(self.seed);
+        let mut files = Vec::with_capacity(self.file_count);
+        for i in 0..self.file_count {
+            let dir = root.join(format!("dir_{}", i / self.files_per_dir.max(1)));
+            fs::create_dir_all(&dir)?;
+
+            let size = self.pick_size(&mut rng);
+            let contents = match rng.next_f64() < self.compressible_fraction {
+                true => compressible_contents(&mut rng, size as usize),
+                false => {
+                    let mut contents = vec![0u8; size as usize];
+                    rng.fill(&mut contents);
+                    contents
+                }
+            };
+            let path = dir.join(format!("file_{}.bin", i));
+            fs::write(&path, contents)?;
+            files.push((path, size));
+        }
+
+        Ok(files)
+    }
+
+    ///
+    /// Picks the size of a file, spread evenly across orders of magnitude
+    /// between the smallest and largest size, as trees tend to hold many
+    /// small files and few large ones
+    /// 
+    fn pick_size(&self, rng: &mut SeededRng) -> u64 {
+        let min = self.min_file_size.max(1);
+        let max = self.max_file_size.max(min);
+        let size = min as f64 * (max as f64 / min as f64).powf(rng.next_f64());
+        (size as u64).clamp(min, max)
+    }
+}
+
+///
+/// Generates `size` bytes of text built from a small set of words
+/// 
+fn compressible_contents(rng: &mut SeededRng, size: usize) -> Vec<u8> {
+    let mut contents = Vec::with_capacity(size + 16);
+    while contents.len() < size {
+        contents.extend_from_slice(WORDS[(rng.next_u64() % WORDS.len() as u64) as usize].as_bytes());
+        contents.push(b' ');
+    }
+    contents.truncate(size);
+    contents
+}
+
+///
+/// How long one configuration took to process a tree
+/// 
+#[derive(Clone, Debug)]
+pub struct BenchResult {
+    /// Describes the configuration
+    pub name: String,
+    pub files: u64,
+    pub bytes: u64,
+    pub wall: Duration,
+    /// The CPU time used by every thread of the process, if it can be measured on this platform
+    pub cpu: Option<Duration>
+}
+
+impl BenchResult {
+    ///
+    /// Gets the number of bytes processed per second of wall-clock time
+    /// 
+    pub fn throughput(&self) -> f64 {
+        self.bytes as f64 / self.wall.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+///
+/// Runs `stage` to completion, returning its output along with
+/// the wall-clock and CPU time it took
+/// 
+pub async fn measure<F: Future>(stage: F) -> (F::Output, Duration, Option<Duration>) {
+    let (start, cpu_start) = (Instant::now(), cpu_time());
+    let output = stage.await;
+    let cpu = cpu_time().zip(cpu_start).map(|(end, start)| end.saturating_sub(start));
+
+    (output, start.elapsed(), cpu)
+}
+
+///
+/// Gets the user and system CPU time used by the process so far
+/// 
+#[cfg(unix)]
+pub fn cpu_time() -> Option<Duration> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let usage = unsafe { usage.assume_init() };
+    let to_duration = |time: libc::timeval| Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000);
+
+    Some(to_duration(usage.ru_utime) + to_duration(usage.ru_stime))
+}
+#[cfg(not(unix))]
+pub fn cpu_time() -> Option<Duration> {
+    None
+}
+
+///
+/// Renders the results as a table, one configuration per row
+/// 
+pub fn render_table(results: &[BenchResult]) -> String {
+    let mut rendered = format!("{:<28} {:>8} {:>10} {:>8} {:>12} {:>8}\n", "configuration", "files", "size", "wall", "throughput", "cpu");
+    for result in results {
+        let cpu = result.cpu.map_or("-".to_string(), |cpu| format!("{:.2}s", cpu.as_secs_f64()));
+        writeln!(
+            rendered, "{:<28} {:>8} {:>10} {:>8} {:>12} {:>8}",
+            result.name, format_count(result.files), format_bytes(result.bytes), format!("{:.2}s", result.wall.as_secs_f64()),
+            format!("{}/s", format_bytes(result.throughput() as u64)), cpu
+        ).unwrap();
+    }
+
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{render_table, BenchResult, SyntheticTree};
+
+    #[test]
+    fn test_synthetic_tree_is_deterministic() {
+        let (first, second) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let tree = SyntheticTree { file_count: 25, min_file_size: 10, max_file_size: 5000, files_per_dir: 10, ..Default::default() };
+
+        let first_files = tree.generate(first.path()).unwrap();
+        let second_files = tree.generate(second.path()).unwrap();
+
+        assert_eq!(first_files.len(), 25);
+        assert!(first_files[20].0.starts_with(first.path().join("dir_2")));
+        for ((first_path, first_size), (second_path, second_size)) in first_files.iter().zip(&second_files) {
+            assert!((10..=5000).contains(first_size));
+            assert_eq!(first_size, second_size);
+            assert_eq!(std::fs::metadata(first_path).unwrap().len(), *first_size);
+            assert_eq!(std::fs::read(first_path).unwrap(), std::fs::read(second_path).unwrap());
+        }
+
+        // A different seed generates a different tree
+        let other = tempfile::tempdir().unwrap();
+        let other_files = SyntheticTree { seed: 7, ..tree }.generate(other.path()).unwrap();
+        assert_ne!(
+            first_files.iter().map(|(_, size)| size).collect::<Vec<_>>(),
+            other_files.iter().map(|(_, size)| size).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_render_table() {
+        let results = vec![
+            BenchResult { name: "hash x4".to_string(), files: 1000, bytes: 2048 * 1024, wall: Duration::from_secs(2), cpu: None }
+        ];
+        let rendered = render_table(&results);
+
+        assert_eq!(rendered.lines().count(), 2);
+        let row: Vec<&str> = rendered.lines().nth(1).unwrap().split_whitespace().collect();
+        assert_eq!(row, vec!["hash", "x4", "1,000", "2.0", "MB", "2.00s", "1.0", "MB/s", "-"]);
+    }
+}
@@ -0,0 +1,135 @@
+use std::fmt::Display;
+
+use console::{style, Color, Term};
+
+use crate::history_service::FileStatus;
+
+///
+/// What happened to a file during a backup run
+/// 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileOutcome {
+    /// The file was new or changed, and was backed up
+    BackedUp,
+    /// The file was unchanged since its latest backup
+    Skipped,
+    /// The file could not be backed up
+    Failed,
+    /// An old version of the file was removed
+    Pruned,
+}
+
+impl FileOutcome {
+    fn color(self) -> Color {
+        match self {
+            FileOutcome::BackedUp => Color::Green,
+            FileOutcome::Skipped => Color::Yellow,
+            FileOutcome::Failed => Color::Red,
+            FileOutcome::Pruned => Color::Blue,
+        }
+    }
+    fn symbol(self, ascii: bool) -> &'static str {
+        match (self, ascii) {
+            (FileOutcome::BackedUp, false) => "✓",
+            (FileOutcome::Skipped, false) => "↷",
+            (FileOutcome::Failed, false) => "✗",
+            (FileOutcome::Pruned, false) => "🗑",
+            (FileOutcome::BackedUp, true) => "+",
+            (FileOutcome::Skipped, true) => "=",
+            (FileOutcome::Failed, true) => "x",
+            (FileOutcome::Pruned, true) => "-",
+        }
+    }
+}
+
+impl From<&FileStatus<'_>> for FileOutcome {
+    fn from(status: &FileStatus<'_>) -> Self {
+        match status {
+            FileStatus::NeedsBackup { .. } => FileOutcome::BackedUp,
+            FileStatus::DoesNotNeedBackup => FileOutcome::Skipped,
+        }
+    }
+}
+
+///
+/// Prints a line for each file handled during a backup run, prefixed with
+/// a symbol and colored by what happened to it
+/// 
+#[derive(Clone, Debug)]
+pub struct ColoredStatusFormatter {
+    term: Term,
+    color: bool,
+    ascii: bool,
+}
+
+impl ColoredStatusFormatter {
+    ///
+    /// Creates a formatter printing to stdout. Colors are only used if `no_color` isn't set
+    /// and stdout is a terminal, and `ascii` replaces the Unicode symbols with ASCII ones.
+    /// 
+    pub fn new(no_color: bool, ascii: bool) -> Self {
+        let term = Term::stdout();
+        let color = !no_color && term.is_term();
+        Self { term, color, ascii }
+    }
+
+    ///
+    /// Whether the formatter prints with colors, for other output to follow
+    /// 
+    pub fn uses_color(&self) -> bool {
+        self.color
+    }
+
+    ///
+    /// Formats the line printed for the given `file`, with the given `outcome`
+    /// 
+    pub fn format(&self, outcome: FileOutcome, file: impl Display) -> String {
+        let line = format!("{} {}", outcome.symbol(self.ascii), file);
+        style(line).fg(outcome.color()).force_styling(self.color).to_string()
+    }
+
+    ///
+    /// Prints the line for the given `file`, with the given `outcome`
+    /// 
+    pub fn print(&self, outcome: FileOutcome, file: impl Display) {
+        self.term.write_line(&self.format(outcome, file)).unwrap();
+    }
+
+    ///
+    /// Prints a `line` about the run as a whole, such as its estimate, without a symbol or color
+    /// 
+    pub fn print_plain(&self, line: impl Display) {
+        self.term.write_line(&line.to_string()).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use console::Term;
+
+    use super::{ColoredStatusFormatter, FileOutcome};
+
+    fn formatter(color: bool, ascii: bool) -> ColoredStatusFormatter {
+        ColoredStatusFormatter { term: Term::stdout(), color, ascii }
+    }
+
+    #[test]
+    fn test_format_without_color() {
+        let path = Path::new("/home/user/notes.txt");
+
+        assert_eq!(formatter(false, false).format(FileOutcome::BackedUp, path.display()), "✓ /home/user/notes.txt");
+        assert_eq!(formatter(false, false).format(FileOutcome::Pruned, path.display()), "🗑 /home/user/notes.txt");
+        assert_eq!(formatter(false, true).format(FileOutcome::Skipped, path.display()), "= /home/user/notes.txt");
+        assert_eq!(formatter(false, true).format(FileOutcome::Failed, path.display()), "x /home/user/notes.txt");
+    }
+
+    #[test]
+    fn test_format_with_color() {
+        let line = formatter(true, true).format(FileOutcome::Failed, "notes.txt");
+
+        // Red foreground, then a reset
+        assert_eq!(line, "\u{1b}[31mx notes.txt\u{1b}[0m");
+    }
+}
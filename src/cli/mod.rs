@@ -0,0 +1,439 @@
+pub mod formatter;
+pub mod picker;
+
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use crate::{config::HashAlgorithm, history_service::models::{RunOrigin, RunTrigger, VersionSelector}, path_map::PathMap};
+
+///
+/// Command line arguments for the `drive_backup` executable
+/// 
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Prints without colors. Colors are also left out when stdout isn't a terminal
+    #[arg(long, global = true)]
+    pub no_color: bool,
+    /// Prints only ASCII symbols, for terminals without Unicode support
+    #[arg(long, global = true)]
+    pub ascii: bool,
+    /// Shows timestamps in UTC as ISO-8601, rather than in the local time zone
+    #[arg(long, global = true)]
+    pub utc: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Backs up all files matching the configured globs (the default)
+    Backup(BackupArgs),
+    /// Reports whether any files appear to need backing up, judging by their sizes and
+    /// modification times without hashing them. Exits with 0 if nothing appears to,
+    /// 1 if anything does, or 2 on error
+    Status {
+        /// Also measures how much the backup destination takes up on disk, which
+        /// walks the whole destination, and compares it against the catalog
+        #[arg(long)]
+        disk_usage: bool,
+    },
+    /// Registers a compressed backup made outside of the tool, already stored in 
+    /// the backup destination, in the backup history
+    Import {
+        /// The path of the file the backup was made from
+        #[arg(long)]
+        register: std::path::PathBuf,
+        /// The ID the backup is stored under
+        #[arg(long)]
+        id: i64,
+        /// The hash of the file's contents, as recorded for backups made by the tool
+        #[arg(long)]
+        hash: String,
+    },
+    /// Lists the most recently completed backup runs, newest first, with what started each
+    ListRuns {
+        /// The number of runs listed
+        #[arg(long, default_value_t = 20)]
+        limit: u32,
+    },
+    /// Shows the backup run with the given ID
+    ShowRun {
+        id: i64,
+        /// Also shows the config the run was made with, and how it differs from the current config
+        #[arg(long)]
+        config: bool,
+    },
+    /// Re-verifies the backups of files which have not been verified recently,
+    /// spreading verification of the whole backup over many runs
+    VerifyStale {
+        /// The maximum number of files to verify in this run
+        #[arg(long, default_value_t = 100)]
+        count: u32,
+        /// Files verified within this many days are not re-verified
+        #[arg(long, default_value_t = 30)]
+        older_than_days: i64,
+    },
+    /// Re-verifies the backup of every file version, a shard at a time. 
+    /// Progress is recorded as it goes, so an interrupted run can be resumed
+    Verify {
+        /// Continues from where the last run stopped, instead of starting over
+        #[arg(long)]
+        resume: bool,
+        /// The number of backups read at once
+        #[arg(long, default_value_t = 4)]
+        concurrency: usize,
+    },
+    /// Checks that the catalog and the backups stored in the destinations agree,
+    /// re-hashing every backup. Changes nothing unless `--fix` is given
+    Check {
+        /// Forgets file versions whose backup is missing, and deletes backups the catalog doesn't know of
+        #[arg(long)]
+        fix: bool,
+    },
+    /// Replaces the hash of every backed-up file version with one made with another algorithm,
+    /// re-hashing its backup, so the algorithm can be changed without backing every file up again.
+    /// Set `hash_algorithm` in the config to the same algorithm afterwards
+    Rehash {
+        #[arg(long, value_enum)]
+        algorithm: HashAlgorithm,
+    },
+    /// Lists every file which was added, modified or deleted between two points in the backup history,
+    /// given either as times or as the ends of two runs. Given a path, shows how the contents of
+    /// that file changed between two of its versions instead
+    Diff {
+        /// The file to compare two versions of
+        path: Option<std::path::PathBuf>,
+        /// The earlier point, as a local date like 2024-01-01 meaning its start, or an RFC 3339 timestamp.
+        /// With a path, it may also be the ID of a version, as listed by `list`, or a version's label
+        #[arg(long, value_parser = parse_version_selector, required_unless_present = "run_a", conflicts_with = "run_a")]
+        from: Option<VersionSelector>,
+        /// The later point, given the same way as `--from`
+        #[arg(long, value_parser = parse_version_selector, required_unless_present = "run_a", conflicts_with = "run_a")]
+        to: Option<VersionSelector>,
+        /// Prints only how many lines of the file were added and removed
+        #[arg(long, requires = "path")]
+        stat: bool,
+        /// The ID of the run whose end is compared from, as shown by `list-runs`
+        #[arg(long, requires = "run_b", conflicts_with = "path")]
+        run_a: Option<i64>,
+        /// The ID of the run whose end is compared to
+        #[arg(long, requires = "run_a")]
+        run_b: Option<i64>,
+    },
+    /// Prints the hash of the latest version of every file under the given path, 
+    /// in hex as `<hash>  <path>` lines which `md5sum -c` can check
+    PrintHashes {
+        path: std::path::PathBuf,
+    },
+    /// Compares a checksum file written by `md5sum`, or by `print-hashes`, against 
+    /// the latest version of each file in the catalog, listing every mismatch
+    ImportHashes {
+        checksums: std::path::PathBuf,
+    },
+    /// Lists every file in the backup history whose name matches the given 
+    /// query, where `*` matches any run of characters and `?` any one character
+    Search {
+        query: String,
+    },
+    /// Lists every version of every file matching the given query, using the 
+    /// same patterns as `search`, separated into the file's generations
+    List {
+        query: String,
+    },
+    /// Gives a version of a file a short label, ie. "before reformatting", 
+    /// which `list` shows and `restore --label` selects it by
+    Label {
+        path: std::path::PathBuf,
+        label: String,
+        /// The ID of the version to label, as listed by `list`. If unset, the latest version is labelled
+        #[arg(long)]
+        version: Option<i64>,
+    },
+    /// Restores the latest version of every file matching the given query, 
+    /// using the same patterns as `search`
+    Restore {
+        query: String,
+        /// Restores each file as it was when the latest completed run whose reason contains 
+        /// this text finished, instead of its latest version
+        #[arg(long, value_name = "REASON")]
+        run: Option<String>,
+        /// Restores the version of each file with this label instead of its latest version.
+        /// Files with no version labelled so aren't restored
+        #[arg(long, conflicts_with = "run")]
+        label: Option<String>,
+        /// Files are only restored inside this directory. Needed unless `--interactive`
+        /// is given, which otherwise restores the file to where it was backed up from
+        #[arg(long, required_unless_present = "interactive")]
+        root: Option<std::path::PathBuf>,
+        /// Restores files under the `old-prefix` path to under `new-prefix` instead,
+        /// as `old-prefix=new-prefix`. The map with the longest matching prefix is used
+        #[arg(long = "map")]
+        maps: Vec<PathMap>,
+        /// Lists the versions of the one file matching the query, prompting for which to 
+        /// restore and confirming where it's written before writing it. Needs a terminal
+        #[arg(long, conflicts_with_all = ["run", "label"])]
+        interactive: bool,
+    },
+    /// Lists every file version which shares its hash with a version of 
+    /// a different size, which can only be a hash collision
+    CollisionAudit,
+    /// Checks the catalog against the config, listing every backup recorded 
+    /// in a destination which is no longer configured
+    Doctor,
+    /// Backs up like `backup`, but reads each file from its copy in an existing mirror of the files,
+    /// such as one made by rsync, wherever the copy has the same size and modification time.
+    /// Every other file is read from itself. An interrupted seed is resumed by running it again
+    Seed {
+        /// The root of the mirror, holding each file at its path relative to the root of its glob
+        #[arg(long, value_name = "MIRROR_ROOT")]
+        from: std::path::PathBuf,
+        #[command(flatten)]
+        args: BackupArgs,
+    },
+    /// Shows which policy applies to the given path, every policy glob matching it from
+    /// the most specific down, and which policy each of the applied settings came from
+    ExplainPolicy {
+        path: std::path::PathBuf,
+    },
+    /// Stores each delta backup more than the configured `max_chain_length` deltas 
+    /// behind a full copy in full, bounding how many deltas a restore applies
+    Rebase,
+    /// Puts the backup destination into, or takes it out of, maintenance mode.
+    /// While in maintenance mode, backups refuse to change the destination.
+    /// Without a state, vacuums and analyzes the catalog instead, reporting its size before and after
+    Maintenance {
+        state: Option<MaintenanceState>,
+        /// A note explaining the maintenance, shown by any backup which is refused
+        #[arg(long, default_value = "")]
+        note: String,
+    },
+    /// Shrinks the catalog: expired versions, unused chunks and directories no longer holding
+    /// anything are removed, then the catalog is rewritten without its free space and swapped in.
+    /// Refuses to run while a backup is running
+    Compact,
+    /// Mounts a read-only view of the backed-up files at the given mountpoint
+    /// until Ctrl-C is pressed. Every file's older versions are listed in a
+    /// sibling `<file name>@versions` directory
+    #[cfg(all(feature = "mount", unix))]
+    Mount {
+        mountpoint: std::path::PathBuf,
+    },
+    /// Generates a tree of files, then hashes it and backs it up to a throwaway catalog
+    /// and destination under each configuration given, printing how quickly each ran
+    Bench(BenchArgs),
+}
+
+#[derive(Debug, Default, Args)]
+pub struct BackupArgs {
+    /// The number of directory levels shown in the summary of the run
+    #[arg(long)]
+    pub depth: Option<usize>,
+    /// Lists every changed file in the summary of the run, rather than
+    /// only those in directories with few changes, and prints each unchanged file as it's checked
+    #[arg(long)]
+    pub verbose: bool,
+    /// Summarizes what a backup would change, judging files by their size and modification
+    /// time without hashing them, then stops. Nothing is backed up and the catalog isn't changed
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Backs up only the paths listed in this file, one per line, instead of the files matching
+    /// the configured globs. `-` reads the list from stdin. Deleted files aren't marked
+    #[arg(long, value_name = "FILE")]
+    pub paths_from: Option<std::path::PathBuf>,
+    /// The paths listed by --paths-from are separated by NUL bytes, as written by `find -print0`
+    #[arg(short = '0', long, requires = "paths_from")]
+    pub null: bool,
+    /// Records the run as started by a scheduler, such as cron or a systemd timer
+    #[arg(long)]
+    pub scheduled: bool,
+    /// Why the run was started, recorded alongside it and shown by `list-runs`.
+    /// Every version the run backs up is labelled with it
+    #[arg(long)]
+    pub reason: Option<String>,
+}
+
+impl BackupArgs {
+    ///
+    /// Gets what started the run these arguments were given to. A run backing up a given
+    /// list of paths is `AdHoc` however it was started, as deleted files aren't marked.
+    /// 
+    pub fn origin(&self) -> RunOrigin {
+        let trigger = match (self.paths_from.is_some(), self.scheduled) {
+            (true, _) => RunTrigger::AdHoc,
+            (false, true) => RunTrigger::Scheduled,
+            (false, false) => RunTrigger::Manual
+        };
+
+        RunOrigin { trigger, reason: self.reason.clone() }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct BenchArgs {
+    /// The number of files generated
+    #[arg(long, default_value_t = 1000)]
+    pub files: usize,
+    /// The smallest size of a generated file, in bytes
+    #[arg(long, default_value_t = 1024)]
+    pub min_size: u64,
+    /// The largest size of a generated file, in bytes
+    #[arg(long, default_value_t = 1024 * 1024)]
+    pub max_size: u64,
+    /// The fraction of files, from 0 to 1, filled with text which compresses well rather than random bytes
+    #[arg(long, default_value_t = 0.5)]
+    pub compressible: f64,
+    /// Seeds the generator. The same seed generates the same files on every machine
+    #[arg(long, default_value_t = 42)]
+    pub seed: u64,
+    /// The numbers of files hashed at once to compare, separated by commas
+    #[arg(long, value_delimiter = ',', default_values_t = [1, 4, 16])]
+    pub concurrency: Vec<usize>,
+    /// The chunk sizes, in bytes, to compare backing files up in, separated by commas.
+    /// Files are always also backed up whole
+    #[arg(long, value_delimiter = ',')]
+    pub chunk_size: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum MaintenanceState {
+    On,
+    Off,
+}
+
+///
+/// Parses an instant given as a local date, meaning the start of that day, or as an RFC 3339 timestamp
+/// 
+fn parse_instant(instant: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(date) = NaiveDate::parse_from_str(instant, "%Y-%m-%d") {
+        return date.and_hms_opt(0, 0, 0).unwrap().and_local_timezone(Local).earliest()
+            .map(|start| start.with_timezone(&Utc))
+            .ok_or_else(|| format!("the start of {} doesn't exist in the local time zone", instant));
+    }
+    DateTime::parse_from_rfc3339(instant)
+        .map(|instant| instant.with_timezone(&Utc))
+        .map_err(|_| format!("\"{}\" should be a date like 2024-01-01, or an RFC 3339 timestamp", instant))
+}
+
+///
+/// Parses a version selector given as a version ID, an instant, as `parse_instant` takes them, or else a label
+/// 
+fn parse_version_selector(selector: &str) -> Result<VersionSelector, String> {
+    if let Ok(version_id) = selector.parse() {
+        return Ok(VersionSelector::Id(version_id));
+    }
+    Ok(parse_instant(selector).map_or_else(|_| VersionSelector::Label(selector.to_string()), VersionSelector::Instant))
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use crate::history_service::models::{RunOrigin, RunTrigger, VersionSelector};
+
+    use super::{Cli, Command};
+
+    fn origin_of(args: &[&str]) -> RunOrigin {
+        match Cli::parse_from(args).command {
+            Some(Command::Backup(args)) => args.origin(),
+            command => panic!("{:?} should be a backup", command)
+        }
+    }
+
+    #[test]
+    fn test_utc_is_global() {
+        assert!(!Cli::parse_from(["drive_backup", "list", "*"]).utc);
+        assert!(Cli::parse_from(["drive_backup", "list", "*", "--utc"]).utc);
+        assert!(Cli::parse_from(["drive_backup", "--utc", "list-runs"]).utc);
+    }
+
+    #[test]
+    fn test_label_and_restore_by_label() {
+        match Cli::parse_from(["drive_backup", "label", "budget.xlsx", "--version", "7", "before reformatting"]).command {
+            Some(Command::Label { path, label, version }) => 
+                assert_eq!((path.to_str().unwrap(), label.as_str(), version), ("budget.xlsx", "before reformatting", Some(7))),
+            command => panic!("{:?} should be a label", command)
+        }
+        assert!(matches!(
+            Cli::parse_from(["drive_backup", "restore", "*", "--root", "/tmp", "--label", "before reformatting"]).command,
+            Some(Command::Restore { label: Some(label), run: None, .. }) if label == "before reformatting"
+        ));
+        // A version is selected either by its label or by a run, not both
+        assert!(Cli::try_parse_from(["drive_backup", "restore", "*", "--root", "/tmp", "--label", "a", "--run", "b"]).is_err());
+    }
+
+    #[test]
+    fn test_interactive_restore() {
+        assert!(matches!(
+            Cli::parse_from(["drive_backup", "restore", "/home/me/notes.txt", "--interactive"]).command,
+            Some(Command::Restore { interactive: true, root: None, .. })
+        ));
+        // Without a prompt to confirm where it's written, a restore needs a root
+        assert!(Cli::try_parse_from(["drive_backup", "restore", "/home/me/notes.txt"]).is_err());
+        // The version is picked at the prompt instead
+        assert!(Cli::try_parse_from(["drive_backup", "restore", "notes.txt", "--interactive", "--label", "a"]).is_err());
+    }
+
+    #[test]
+    fn test_backup_origin() {
+        assert_eq!(origin_of(&["drive_backup", "backup"]), RunOrigin::default());
+        assert_eq!(
+            origin_of(&["drive_backup", "backup", "--scheduled", "--reason", "nightly"]),
+            RunOrigin { trigger: RunTrigger::Scheduled, reason: Some("nightly".to_string()) }
+        );
+        // Backing up a list of paths is ad-hoc, even when scheduled
+        assert_eq!(origin_of(&["drive_backup", "backup", "--paths-from", "-", "--scheduled"]).trigger, RunTrigger::AdHoc);
+    }
+
+    #[test]
+    fn test_diff_between_times_or_runs() {
+        assert!(matches!(
+            Cli::parse_from(["drive_backup", "diff", "--run-a", "5", "--run-b", "6"]).command,
+            Some(Command::Diff { path: None, from: None, to: None, run_a: Some(5), run_b: Some(6), .. })
+        ));
+        assert!(matches!(
+            Cli::parse_from(["drive_backup", "diff", "--from", "2024-01-01", "--to", "2024-02-01"]).command,
+            Some(Command::Diff { from: Some(VersionSelector::Instant(_)), to: Some(VersionSelector::Instant(_)), run_a: None, run_b: None, .. })
+        ));
+        assert!(Cli::try_parse_from(["drive_backup", "diff", "--run-a", "5"]).is_err());
+        assert!(Cli::try_parse_from(["drive_backup", "diff", "--from", "2024-01-01", "--run-a", "5", "--run-b", "6"]).is_err());
+        assert!(Cli::try_parse_from(["drive_backup", "diff", "--from", "2024-01-01"]).is_err());
+    }
+
+    #[test]
+    fn test_diff_between_versions_of_a_file() {
+        match Cli::parse_from(["drive_backup", "diff", "notes.txt", "--from", "3", "--to", "before reformatting", "--stat"]).command {
+            Some(Command::Diff { path: Some(path), from: Some(VersionSelector::Id(3)), to: Some(VersionSelector::Label(label)), stat: true, .. }) =>
+                assert_eq!((path.to_str().unwrap(), label.as_str()), ("notes.txt", "before reformatting")),
+            command => panic!("{:?} should be a diff of a file's versions", command)
+        }
+        assert!(matches!(
+            Cli::parse_from(["drive_backup", "diff", "notes.txt", "--from", "2024-01-01T12:00:00Z", "--to", "5"]).command,
+            Some(Command::Diff { from: Some(VersionSelector::Instant(_)), to: Some(VersionSelector::Id(5)), stat: false, .. })
+        ));
+        // Only a file's diff has line counts, and runs are compared as a whole
+        assert!(Cli::try_parse_from(["drive_backup", "diff", "--from", "2024-01-01", "--to", "2024-02-01", "--stat"]).is_err());
+        assert!(Cli::try_parse_from(["drive_backup", "diff", "notes.txt", "--run-a", "5", "--run-b", "6"]).is_err());
+    }
+
+    #[test]
+    fn test_explain_policy() {
+        match Cli::parse_from(["drive_backup", "explain-policy", "/home/me/projects/os.iso"]).command {
+            Some(Command::ExplainPolicy { path }) => assert_eq!(path.to_str().unwrap(), "/home/me/projects/os.iso"),
+            command => panic!("{:?} should be an explain-policy", command)
+        }
+        assert!(Cli::try_parse_from(["drive_backup", "explain-policy"]).is_err());
+    }
+
+    #[test]
+    fn test_seed() {
+        match Cli::parse_from(["drive_backup", "seed", "--from", "/mnt/mirror", "--verbose"]).command {
+            Some(Command::Seed { from, args }) => {
+                assert_eq!(from.to_str().unwrap(), "/mnt/mirror");
+                assert!(args.verbose);
+            },
+            command => panic!("{:?} should be a seed", command)
+        }
+        assert!(Cli::try_parse_from(["drive_backup", "seed"]).is_err());
+    }
+}
@@ -0,0 +1,160 @@
+use std::{io::{BufRead, Write}, path::Path};
+
+use crate::{config::HashEncoding, estimate::{format_bytes, format_timestamp, TimestampStyle}, hash_svc::checksums::encode_hash, history_service::models::FileModel};
+
+///
+/// The number of characters of a version's hash shown to tell versions apart
+/// 
+const HASH_PREFIX_LEN: usize = 8;
+
+///
+/// Lists the versions of a file which can be restored to `target`, newest first, then prompts
+/// on `output` for one to be picked by number from `input`, and for the restore to be confirmed.
+/// If the file was deleted after its newest version, that's noted above the list.
+/// Returns the version picked, or `None` if there's none to restore, or the prompt was
+/// quit, declined, or `input` ended
+/// 
+pub fn pick_version<'a>(
+    versions: &'a [FileModel],
+    target: &Path,
+    style: TimestampStyle,
+    encoding: HashEncoding,
+    input: &mut impl BufRead,
+    output: &mut impl Write
+) -> std::io::Result<Option<&'a FileModel>> {
+    let mut restorable: Vec<&FileModel> = versions.iter().filter(|version| version.hsh.is_some()).collect();
+    restorable.sort_by_key(|version| std::cmp::Reverse(version.backup_ts));
+    let Some(newest) = restorable.first() else {
+        writeln!(output, "No version of the file was backed up, so there's nothing to restore")?;
+        return Ok(None);
+    };
+    let deleted = versions.iter()
+        .filter(|version| version.is_tombstone() && version.backup_ts > newest.backup_ts)
+        .max_by_key(|version| version.backup_ts);
+    if let Some(deleted) = deleted {
+        writeln!(output, "The file was deleted {}, so its versions from before then are listed", format_timestamp(&deleted.backup_ts, style))?;
+    }
+
+    for (i, version) in restorable.iter().enumerate() {
+        let hsh = encode_hash(version.hsh.as_deref().unwrap(), encoding);
+        let label = version.label.as_ref().map(|label| format!("  \"{}\"", label)).unwrap_or_default();
+        writeln!(
+            output, "{:>3}) {}  {:>10}  {}{}", i + 1, format_timestamp(&version.backup_ts, style),
+            format_bytes(version.file_size.unwrap_or(0) as u64), &hsh[..hsh.len().min(HASH_PREFIX_LEN)], label
+        )?;
+    }
+
+    let version = loop {
+        write!(output, "Restore which version? [1-{}, or q to quit] ", restorable.len())?;
+        output.flush()?;
+        let Some(line) = read_line(input)? else {
+            return Ok(None);
+        };
+        match line.trim() {
+            "q" => return Ok(None),
+            choice => match choice.parse::<usize>().ok().and_then(|i| restorable.get(i.wrapping_sub(1))) {
+                Some(version) => break *version,
+                None => writeln!(output, "Enter a number from 1 to {}", restorable.len())?
+            }
+        }
+    };
+
+    writeln!(output, "The version from {} will be written to {}", format_timestamp(&version.backup_ts, style), target.display())?;
+    if target.exists() {
+        writeln!(output, "The file already there will be overwritten")?;
+    }
+    write!(output, "Restore it? [y/N] ")?;
+    output.flush()?;
+    let confirmed = read_line(input)?.is_some_and(|line| matches!(line.trim().to_lowercase().as_str(), "y" | "yes"));
+
+    Ok(confirmed.then_some(version))
+}
+
+///
+/// Reads a line from `input`, or `None` once it has ended
+/// 
+fn read_line(input: &mut impl BufRead) -> std::io::Result<Option<String>> {
+    let mut line = String::new();
+    match input.read_line(&mut line)? {
+        0 => Ok(None),
+        _ => Ok(Some(line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, path::Path};
+
+    use chrono::{TimeZone, Utc};
+
+    use crate::{config::HashEncoding, estimate::TimestampStyle, history_service::models::FileModel};
+
+    use super::pick_version;
+
+    fn version(id: i64, day: u32, hsh: Option<&str>, label: Option<&str>) -> FileModel {
+        FileModel {
+            version: 1, id, file_name: "notes.txt".to_string(), backup_ts: Utc.with_ymd_and_hms(2024, 1, day, 0, 0, 0).unwrap(),
+            hsh: hsh.map(str::to_string), verified_ts: None, file_size: hsh.map(|_| 2048), generation: 0,
+            label: label.map(str::to_string), excluded: false
+        }
+    }
+
+    ///
+    /// Picks from `versions` with the given lines of `input`, returning the ID picked and everything shown
+    /// 
+    fn pick(versions: &[FileModel], input: &str) -> (Option<i64>, String) {
+        let mut output = Vec::new();
+        let picked = pick_version(
+            versions, Path::new("/home/me/notes.txt"), TimestampStyle::Utc, HashEncoding::Base64, &mut Cursor::new(input), &mut output
+        ).unwrap();
+        (picked.map(|version| version.id), String::from_utf8(output).unwrap())
+    }
+
+    fn versions() -> Vec<FileModel> {
+        vec![version(1, 1, Some("AAAAAAAAAAAA"), None), version(2, 3, Some("BBBBBBBBBBBB"), Some("draft")), version(3, 2, Some("CCCCCCCCCCCC"), None)]
+    }
+
+    #[test]
+    fn test_picks_a_listed_version_once_confirmed() {
+        let (picked, output) = pick(&versions(), "2\ny\n");
+        assert_eq!(picked, Some(3));
+        // Newest first, with each version's size, hash prefix and label
+        assert!(output.contains("  1) 2024-01-03T00:00:00Z      2.0 KB  BBBBBBBB  \"draft\"\n"), "{}", output);
+        assert!(output.contains("  2) 2024-01-02T00:00:00Z      2.0 KB  CCCCCCCC\n"), "{}", output);
+        assert!(output.contains("will be written to /home/me/notes.txt"));
+    }
+
+    #[test]
+    fn test_reprompts_until_a_listed_number_is_given() {
+        let (picked, output) = pick(&versions(), "0\nfour\n4\n1\nyes\n");
+        assert_eq!(picked, Some(2));
+        assert_eq!(output.matches("Enter a number from 1 to 3").count(), 3);
+    }
+
+    #[test]
+    fn test_nothing_is_picked_when_quit_declined_or_input_ends() {
+        assert_eq!(pick(&versions(), "q\n").0, None);
+        assert_eq!(pick(&versions(), "1\nn\n").0, None);
+        assert_eq!(pick(&versions(), "1\n\n").0, None);
+        assert_eq!(pick(&versions(), "1\n").0, None);
+        assert_eq!(pick(&versions(), "").0, None);
+    }
+
+    #[test]
+    fn test_a_deleted_file_offers_its_versions_from_before_deletion() {
+        let mut versions = versions();
+        versions.push(version(4, 5, None, None));
+
+        let (picked, output) = pick(&versions, "1\ny\n");
+        assert_eq!(picked, Some(2));
+        assert!(output.contains("The file was deleted 2024-01-05T00:00:00Z, so its versions from before then are listed"), "{}", output);
+    }
+
+    #[test]
+    fn test_a_file_never_backed_up_has_nothing_to_pick() {
+        let (picked, output) = pick(&[version(1, 1, None, None)], "1\ny\n");
+        assert_eq!(picked, None);
+        assert!(output.contains("nothing to restore"));
+        assert!(!output.contains("Restore which version?"));
+    }
+}
@@ -79,6 +79,90 @@ impl<T> Cache<T> {
         }
         None
     }
+    ///
+    /// Whether an entry or sub-Cache exists at the given path. A trailing `/` is ignored,
+    /// so "a/b/" finds the sub-Cache holding "a/b/c"
+    /// 
+    pub fn path_exists(&self, prefix: &str) -> bool {
+        let prefix = prefix.strip_suffix('/').unwrap_or(prefix);
+        let (parent, name) = match prefix.rsplit_once('/') {
+            Some((parent, name)) => (self.sub_cache(parent), name),
+            None => (Some(self), prefix)
+        };
+
+        parent.is_some_and(|parent| parent.entries.contains_key(name) || parent.sub_caches.contains_key(name))
+    }
+    ///
+    /// Counts the entries at or beneath the given path, at any depth
+    /// 
+    pub fn subtree_entry_count(&self, prefix: &str) -> usize {
+        let prefix = prefix.strip_suffix('/').unwrap_or(prefix);
+        let entry = self.get(prefix).is_some() as usize;
+        entry + self.sub_cache(prefix).map_or(0, Cache::entry_count)
+    }
+    ///
+    /// Counts every entry in the Cache, at any depth
+    /// 
+    pub fn len(&self) -> usize {
+        self.entry_count()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    ///
+    /// Collects the full path and value of every entry in the Cache, at any depth
+    /// 
+    pub fn flatten(&self) -> Vec<(String, &T)> {
+        let mut flattened = Vec::with_capacity(self.len());
+        self.flatten_into(None, &mut flattened);
+        flattened
+    }
+    ///
+    /// Collects the full path and value of every entry at or beneath the given path.
+    /// A trailing `/` is ignored, as with `path_exists`.
+    /// 
+    pub fn flatten_with_prefix(&self, prefix: &str) -> Vec<(String, &T)> {
+        let prefix = prefix.strip_suffix('/').unwrap_or(prefix);
+        let mut flattened = Vec::new();
+        if let Some(entr) = self.get(prefix) {
+            flattened.push((prefix.to_string(), entr));
+        }
+        if let Some(cache) = self.sub_cache(prefix) {
+            cache.flatten_into(Some(prefix), &mut flattened);
+        }
+
+        flattened
+    }
+    ///
+    /// Iterates over the names and values of the entries directly in this Cache
+    /// 
+    pub fn entries(&self) -> impl Iterator<Item = (&String, &T)> {
+        self.entries.iter()
+    }
+    ///
+    /// Iterates over the names and contents of the sub-Caches directly in this Cache
+    /// 
+    pub fn sub_caches(&self) -> impl Iterator<Item = (&String, &Cache<T>)> {
+        self.sub_caches.iter()
+    }
+
+    fn sub_cache(&self, path: &str) -> Option<&Cache<T>> {
+        path.split('/').try_fold(self, |cache, name| cache.sub_caches.get(name))
+    }
+    fn entry_count(&self) -> usize {
+        self.entries.len() + self.sub_caches.values().map(Cache::entry_count).sum::<usize>()
+    }
+    fn flatten_into<'a>(&'a self, path: Option<&str>, flattened: &mut Vec<(String, &'a T)>) {
+        // The root Cache has no path, which differs from a sub-Cache named "" (as in "/home")
+        let join = |name: &str| match path {
+            Some(path) => format!("{}/{}", path, name),
+            None => name.to_string()
+        };
+        flattened.extend(self.entries.iter().map(|(name, entr)| (join(name), entr)));
+        for (name, cache) in &self.sub_caches {
+            cache.flatten_into(Some(&join(name)), flattened);
+        }
+    }
 }
 
 pub trait GroupBy<K : Eq + Hash, I> : IntoIterator<Item = I> {
@@ -152,4 +236,50 @@ mod tests {
         assert_eq!(cache.remove("the/path/to/secrets/secret1"), Some("I'm a secret".to_string()));
         assert_eq!(cache.get("the/path/to/secrets/secret1"), None);
     }
+
+    #[test]
+    fn test_cache_prefixes() {
+        let mut cache = Cache::new();
+        cache.insert("/home/user/documents/taxes/2023.pdf", 1);
+        cache.insert("/home/user/documents/taxes/2024.pdf", 2);
+        cache.insert("/home/user/documents/notes.txt", 3);
+        cache.insert("/home/user/music/song.mp3", 4);
+
+        assert!(cache.path_exists("/home/user/documents"));
+        assert!(cache.path_exists("/home/user/documents/"));
+        assert!(cache.path_exists("/home/user/documents/notes.txt"));
+        assert!(!cache.path_exists("/home/user/docs"));
+        assert!(!cache.path_exists("/home/user/documents/notes.txt/inner"));
+
+        assert_eq!(cache.subtree_entry_count("/home/user/documents/"), 3);
+        assert_eq!(cache.subtree_entry_count("/home/user"), 4);
+        assert_eq!(cache.subtree_entry_count("/home/user/documents/notes.txt"), 1);
+        assert_eq!(cache.subtree_entry_count("/home/user/videos"), 0);
+    }
+
+    #[test]
+    fn test_cache_flatten() {
+        let mut cache = Cache::new();
+        cache.insert("/home/user/documents/taxes/2023.pdf", 1);
+        cache.insert("/home/user/documents/notes.txt", 2);
+        cache.insert("/home/user/documents/notes.txt/inner", 3);
+        cache.insert("/home/user/music/song.mp3", 4);
+        cache.insert("relative.txt", 5);
+
+        let flattened = cache.flatten();
+        assert_eq!(flattened.len(), cache.len());
+        for (path, entr) in &flattened {
+            assert!(std::ptr::eq(cache.get(path).unwrap(), *entr));
+        }
+
+        let mut documents = cache.flatten_with_prefix("/home/user/documents/");
+        documents.sort();
+        assert_eq!(documents, vec![
+            ("/home/user/documents/notes.txt".to_string(), &2),
+            ("/home/user/documents/notes.txt/inner".to_string(), &3),
+            ("/home/user/documents/taxes/2023.pdf".to_string(), &1),
+        ]);
+        assert_eq!(cache.flatten_with_prefix("/home/user/documents/notes.txt").len(), 2);
+        assert!(cache.flatten_with_prefix("/home/user/videos").is_empty());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,21 @@
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    IOError(std::io::Error),
+    ParseError(serde_json::Error),
+    /// The config was written for a newer version of the application
+    UnsupportedVersion(u64),
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Error::IOError(value)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(value: serde_json::Error) -> Self {
+        Error::ParseError(value)
+    }
+}
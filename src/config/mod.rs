@@ -0,0 +1,678 @@
+pub mod error;
+pub mod policy;
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use error::*;
+
+use crate::path_map::PathMap;
+
+///
+/// The version of the config schema this application reads. 
+/// Configs written for older versions are migrated to it when loaded.
+/// 
+pub const CURRENT_SCHEMA_VERSION: u64 = 2;
+///
+/// Fields whose names contain any of these are secrets, which are redacted from config snapshots
+/// 
+const SECRET_FIELD_MARKERS: [&str; 7] = ["password", "passphrase", "secret", "token", "credential", "private_key", "access_key"];
+///
+/// The value secrets are replaced with in config snapshots
+/// 
+pub const REDACTED: &str = "<redacted>";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The version of the config schema this config was written for
+    pub schema_version: u64,
+    #[serde(default)]
+    pub backup_globs: Vec<String>,
+    /// A file listing more globs to back up, one per line, with `#` starting a comment.
+    /// Its globs are merged with `backup_globs`
+    pub glob_list_file: Option<PathBuf>,
+    /// Files matching any of these globs are never backed up
+    #[serde(default)]
+    pub exclude_globs: Vec<String>,
+    /// Files smaller than this many bytes are not backed up
+    pub min_file_size: Option<u64>,
+    /// Files larger than this many bytes are not backed up
+    pub max_file_size: Option<u64>,
+    /// Whether files matched through a symlink are backed up
+    #[serde(default = "default_follow_symlinks")]
+    pub follow_symlinks: bool,
+    /// Whether named pipes, device files and sockets are skipped with a warning. If false,
+    /// they're read like regular files, so a pipe fed by another process is backed up with
+    /// whatever it's written. Reading one blocks until something writes to it.
+    #[serde(default = "default_skip_special_files")]
+    pub skip_special_files: bool,
+    /// Deprecated in favour of `backup_destination`. Used as a `local` destination 
+    /// at this path when `backup_destination` is unset
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub backup_path: String,
+    /// Where backups are stored
+    pub backup_destination: Option<BackupDestination>,
+    /// Where backups are written until they're complete, then moved into their destination.
+    /// If unset, they're written beside where they're stored. Useful when destinations are
+    /// on slow filesystems, though moves between filesystems are copies, which aren't atomic
+    pub temp_dir: Option<PathBuf>,
+    /// The most bytes a single backup may take in the `temp_dir`. A file whose backup would take
+    /// more is skipped and reported, rather than filling the temp_dir's filesystem part way through.
+    /// Ignored without a `temp_dir`, as backups are then written straight into their destination
+    pub temp_dir_max_bytes: Option<u64>,
+    /// The extensions of files already compressed, such as "jpg", "mp4" or "zip", which are stored
+    /// as they are rather than compressed again. Files starting with a JPEG, ZIP or gzip signature
+    /// are stored uncompressed whatever their extension
+    #[serde(default)]
+    pub skip_compression_extensions: Vec<String>,
+    /// How many versions of each file are kept, counting only those since it was last recreated. 
+    /// Once there are more, the oldest is pruned as a new one is backed up, so 1 keeps only the latest.
+    /// 0, or "unlimited", keeps every version, and negative counts are rejected
+    #[serde(deserialize_with = "deserialize_max_copies")]
+    pub max_copies: i32,
+    /// The number of runs in a row a file's backup may fail in before it's no longer
+    /// retried first by the next run, and is left to be backed up like any other file
+    #[serde(default = "default_max_file_retry_attempts")]
+    pub max_file_retry_attempts: u32,
+    /// Versions of a file from before it was last deleted are removed this many days
+    /// after its deletion. If unset, they're kept indefinitely
+    pub tombstone_retention_days: Option<i64>,
+    #[serde(default)]
+    pub canonicalize: CanonicalizePolicy,
+    /// Whether each file's extended attributes are backed up alongside it
+    pub xattr_backup: Option<bool>,
+    /// How large files are split into chunks, so that only their changed parts are stored
+    pub chunking: Option<ChunkingConfig>,
+    /// Which files are stored as binary deltas against their previous version
+    pub delta: Option<DeltaConfig>,
+    /// Maps applied to every restored path, before any given on the command line
+    #[serde(default)]
+    pub path_maps: Vec<PathMap>,
+    /// Whether files already in the catalog which weren't modified since the last run to examine
+    /// every file began are taken as unchanged without being hashed. Saves reading large trees
+    /// which rarely change, but misses a file changed without its modification time moving forward
+    #[serde(default)]
+    pub skip_unmodified_since_last_run: bool,
+    /// Whether directories matched by `backup_globs` with no files to back up are
+    /// recorded, so restores recreate them
+    #[serde(default)]
+    pub include_empty_dirs: bool,
+    /// How long a backup may run, ie. "4h" or "1h30m". Once it passes, no more files are
+    /// examined, and the files left over are examined first by the next run
+    pub max_run_duration: Option<String>,
+    /// Upkeep of the catalog database between runs
+    #[serde(default)]
+    pub maintenance: MaintenanceConfig,
+    /// Local destinations backed up to alongside `backup_destination`, which is always the destination named `default`
+    #[serde(default)]
+    pub destinations: Vec<DestinationConfig>,
+    /// Rules selecting which destinations each file is written to. The first rule matching 
+    /// a file is used, and files matching no rule are written to every destination
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    /// Settings applied to the files matching a policy's globs. The policy whose matching glob 
+    /// has the longest literal prefix before its first wildcard is used, with ties going to the one
+    /// listed first. Files matching no policy are given the defaults. `explain-policy` shows which applies
+    #[serde(default)]
+    pub policies: Vec<PolicyConfig>,
+    /// How commands print hashes. The catalog stores them as base64 either way
+    #[serde(default)]
+    pub hash_encoding: HashEncoding,
+    /// The algorithm files are hashed with. A hash already in the catalog made with another
+    /// algorithm is converted when its file is next backed up, if the file hasn't changed
+    #[serde(default)]
+    pub hash_algorithm: HashAlgorithm,
+    /// Whether every hash in the catalog made with another algorithm than `hash_algorithm` is
+    /// converted before each backup run, as `rehash` does, rather than as each file is backed up
+    #[serde(default)]
+    pub eager_hash_migration: bool,
+    /// Whether backups and the catalog are flushed to disk as they're written, so that
+    /// a crash or power loss can't leave the catalog recording a backup which was lost
+    #[serde(default)]
+    pub durability: Durability,
+    /// Whether the catalog is copied into `catalog_cache_dir` when it's opened, and uploaded back
+    /// once the command finishes, rather than used in place. For catalogs on network filesystems,
+    /// where sqlite's locking is unreliable. The upload is refused if the catalog changed meanwhile
+    #[serde(default)]
+    pub catalog_cache: bool,
+    /// Where the catalog is copied when `catalog_cache` is set. If unset, the system's temporary directory
+    pub catalog_cache_dir: Option<PathBuf>
+}
+
+///
+/// The `max_copies` keeping every version of each file
+/// 
+pub const UNLIMITED_COPIES: i32 = 0;
+
+fn default_follow_symlinks() -> bool { true }
+fn default_skip_special_files() -> bool { true }
+fn default_max_file_retry_attempts() -> u32 { 3 }
+
+impl Config {
+    ///
+    /// Gets where backups are stored, falling back to the deprecated `backup_path`
+    /// 
+    pub fn default_destination(&self) -> BackupDestination {
+        match &self.backup_destination {
+            Some(destination) => destination.clone(),
+            None => BackupDestination::Local { path: self.backup_path.clone() }
+        }
+    }
+
+    ///
+    /// Gets the name and location of every destination, starting with the `default` one
+    /// 
+    pub fn destinations(&self) -> Vec<(String, BackupDestination)> {
+        std::iter::once((DEFAULT_DESTINATION.to_string(), self.default_destination()))
+            .chain(self.destinations.iter().map(|d| (d.name.clone(), BackupDestination::Local { path: d.path.clone() })))
+            .collect()
+    }
+
+    ///
+    /// Gets how the file at `path` is judged unchanged, by the policy applying to it
+    /// 
+    pub fn change_detection(&self, path: &Path) -> ChangeDetection {
+        policy::resolve_policy(&self.policies, path).change_detection.value
+    }
+
+    ///
+    /// Serializes the config to JSON to be recorded with a run, with any secrets redacted
+    /// 
+    pub fn snapshot(&self) -> String {
+        let mut value = serde_json::to_value(self).unwrap();
+        redact_secrets(&mut value);
+        value.to_string()
+    }
+}
+
+///
+/// Replaces the value of every field in `value` whose name marks it as a secret, at any depth
+/// 
+pub fn redact_secrets(value: &mut Value) {
+    match value {
+        Value::Object(fields) => for (name, field) in fields.iter_mut() {
+            let name = name.to_lowercase();
+            match SECRET_FIELD_MARKERS.iter().any(|marker| name.contains(marker)) {
+                true => *field = Value::from(REDACTED),
+                false => redact_secrets(field)
+            }
+        },
+        Value::Array(items) => items.iter_mut().for_each(redact_secrets),
+        _ => { }
+    }
+}
+
+///
+/// A top-level config field whose value differs between two config snapshots,
+/// with `None` where the field is missing from a snapshot
+/// 
+#[derive(Debug, PartialEq)]
+pub struct ConfigChange {
+    pub field: String,
+    pub old: Option<Value>,
+    pub new: Option<Value>
+}
+
+///
+/// Gets every top-level field which differs between the `old` and `new` snapshots, sorted by name
+/// 
+pub fn diff_snapshots(old: &Value, new: &Value) -> Vec<ConfigChange> {
+    let empty = serde_json::Map::new();
+    let (old, new) = (old.as_object().unwrap_or(&empty), new.as_object().unwrap_or(&empty));
+
+    let fields: std::collections::BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    fields.into_iter()
+        .filter(|field| old.get(*field) != new.get(*field))
+        .map(|field| ConfigChange { field: field.clone(), old: old.get(field).cloned(), new: new.get(field).cloned() })
+        .collect()
+}
+
+///
+/// Settings for storing large files as content-addressed chunks. Chunks are
+/// fixed-size, so appending to a file only stores its new trailing chunks
+/// 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkingConfig {
+    pub enabled: bool,
+    /// Files smaller than this many bytes are always stored whole
+    pub min_file_size: u64,
+    /// The size of each chunk in bytes
+    pub avg_chunk_size: usize
+}
+
+///
+/// Settings for storing files which change little between versions, such as logs
+/// which are only appended to, as binary deltas against their previous version.
+/// Chunking takes precedence over deltas for files large enough to be chunked
+/// 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeltaConfig {
+    /// Files matching any of these globs are stored as deltas
+    pub globs: Vec<String>,
+    /// Restoring a version applies every delta between it and the last version stored in full, 
+    /// so a version is stored in full instead of a delta once it would be this many deltas behind
+    #[serde(default = "default_max_chain_length")]
+    pub max_chain_length: usize
+}
+
+fn default_max_chain_length() -> usize { 8 }
+
+impl DeltaConfig {
+    ///
+    /// Whether the file at `path` should be stored as a delta
+    /// 
+    pub fn matches(&self, path: &std::path::Path) -> bool {
+        self.globs.iter().any(|ptn| glob::Pattern::new(ptn).is_ok_and(|ptn| ptn.matches_path(path)))
+    }
+}
+
+///
+/// The kind of storage backups are written to, and where in it
+/// 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BackupDestination {
+    /// A directory on a locally mounted filesystem
+    Local { path: String },
+    /// A prefix within an S3 bucket
+    S3 { bucket: String, prefix: String, region: String },
+    /// A directory on a host reached over SFTP
+    Sftp { host: String, user: String, remote_path: String }
+}
+
+///
+/// The name of the config's `backup_destination`
+/// 
+pub const DEFAULT_DESTINATION: &str = "default";
+
+///
+/// A named destination files are backed up to
+/// 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DestinationConfig {
+    pub name: String,
+    pub path: String
+}
+
+///
+/// Writes files whose source path matches any of `globs` only to the named `destinations`
+/// 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteConfig {
+    pub globs: Vec<String>,
+    pub destinations: Vec<String>
+}
+
+///
+/// Settings applied to the files whose source path matches any of `globs`
+/// 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PolicyConfig {
+    pub globs: Vec<String>,
+    /// If unset, the default, whether or not a less specific policy matching the file sets it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub change_detection: Option<ChangeDetection>
+}
+
+impl PolicyConfig {
+    ///
+    /// Whether the policy applies to the file at `path`
+    /// 
+    pub fn matches(&self, path: &Path) -> bool {
+        self.globs.iter().any(|ptn| glob::Pattern::new(ptn).is_ok_and(|ptn| ptn.matches_path(path)))
+    }
+}
+
+///
+/// How a file is judged unchanged since its latest backup
+/// 
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeDetection {
+    /// The file is hashed, and is unchanged if its hash and size match
+    #[default]
+    Hash,
+    /// The file is unchanged without being hashed if its size matches, and it wasn't 
+    /// modified since its latest backup was last seen. Only safe for files whose 
+    /// modification time always changes along with their contents
+    Metadata
+}
+
+///
+/// Settings for keeping the catalog database compact. Pruning and deletion marking leave
+/// free pages behind which SQLite only releases when the database is vacuumed
+/// 
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct MaintenanceConfig {
+    /// The catalog is vacuumed after every this many runs. If unset, it's only
+    /// vacuumed by the `maintenance` command
+    pub auto_vacuum_after_runs: Option<u32>
+}
+
+///
+/// How paths matched by the backup globs are normalized before being
+/// recorded in the catalog
+/// 
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CanonicalizePolicy {
+    /// Resolves every component of the path, including a final symlink
+    #[default]
+    Full,
+    /// Resolves `.`, `..` and intermediate symlinks, but keeps the final 
+    /// component as it was matched
+    ParentsOnly,
+    /// Only makes the path absolute, resolving `.` and `..` lexically
+    None
+}
+
+impl CanonicalizePolicy {
+    ///
+    /// The name of the policy, as it's recorded in the catalog
+    /// 
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CanonicalizePolicy::Full => "full",
+            CanonicalizePolicy::ParentsOnly => "parents_only",
+            CanonicalizePolicy::None => "none",
+        }
+    }
+}
+
+///
+/// How hashes are written out for people and other tools
+/// 
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HashEncoding {
+    #[default]
+    Base64,
+    /// Lowercase hex, as written by coreutils' `md5sum`
+    Hex
+}
+
+///
+/// The algorithm files are hashed with to tell whether they changed
+/// 
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum HashAlgorithm {
+    #[default]
+    Md5,
+    Sha256
+}
+
+impl HashAlgorithm {
+    ///
+    /// The name of the algorithm, as it's written in the config
+    /// 
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Md5 => "md5",
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+}
+
+///
+/// How carefully backups and the catalog are written to disk
+/// 
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Durability {
+    /// Writes are left to the operating system to flush whenever it chooses
+    #[default]
+    Fast,
+    /// Every backup is synced to disk, along with the directory holding it, before it's
+    /// recorded, and the catalog syncs every commit. Much slower on spinning disks
+    Safe
+}
+
+///
+/// Reads `max_copies` as a count of at least 0, or as "unlimited", which is `UNLIMITED_COPIES`
+/// 
+fn deserialize_max_copies<'de, D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<i32, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum MaxCopies {
+        Count(i32),
+        Keyword(String)
+    }
+
+    match MaxCopies::deserialize(deserializer)? {
+        MaxCopies::Count(count) if count >= 0 => Ok(count),
+        MaxCopies::Keyword(keyword) if keyword == "unlimited" => Ok(UNLIMITED_COPIES),
+        MaxCopies::Count(count) => Err(serde::de::Error::custom(format!("max_copies can't be negative, but is {}", count))),
+        MaxCopies::Keyword(keyword) => Err(serde::de::Error::custom(format!("max_copies must be a count or \"unlimited\", not \"{}\"", keyword)))
+    }
+}
+
+///
+/// Loads the config at `path`, first migrating it to the current schema version
+/// if it was written for an older one. A migrated config is written back to `path`,
+/// after copying the original to `<path>.v<old version>.bak`.
+/// 
+pub fn load_with_migration(path: &Path) -> Result<Config> {
+    let raw: Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    // Configs from before versioning was introduced have no version
+    let version = raw.get("schema_version").and_then(Value::as_u64).unwrap_or(1);
+
+    let config = match version {
+        CURRENT_SCHEMA_VERSION => return Ok(serde_json::from_value(raw)?),
+        1 => migrate_config_v1_to_v2(&raw)?,
+        version => return Err(Error::UnsupportedVersion(version))
+    };
+
+    let mut backup_path = path.as_os_str().to_owned();
+    backup_path.push(format!(".v{}.bak", version));
+    std::fs::copy(path, backup_path)?;
+    std::fs::write(path, serde_json::to_string_pretty(&config)?)?;
+    tracing::info!("Migrated {} from config version {} to {}", path.display(), version, CURRENT_SCHEMA_VERSION);
+
+    Ok(config)
+}
+
+///
+/// Migrates an unversioned config to version 2, which rejects unrecognized fields.
+/// Any unrecognized fields in the `old` config are dropped.
+/// 
+pub fn migrate_config_v1_to_v2(old: &Value) -> Result<Config> {
+    let mut config = old.as_object().cloned().unwrap_or_default();
+    config.insert("schema_version".to_string(), Value::from(2));
+
+    loop {
+        match serde_json::from_value(Value::Object(config.clone())) {
+            Ok(config) => return Ok(config),
+            Err(e) => match unknown_field(&e).filter(|field| config.contains_key(field)) {
+                Some(field) => {
+                    tracing::warn!("Dropping the unrecognized config field \"{}\"", field);
+                    config.remove(&field);
+                },
+                None => return Err(e.into())
+            }
+        }
+    }
+}
+
+///
+/// Gets the name of the field a deserialization error `e` was 
+/// raised for, if it was raised because the field is unrecognized
+/// 
+fn unknown_field(e: &serde_json::Error) -> Option<String> {
+    let message = e.to_string();
+    let field = message.strip_prefix("unknown field `")?;
+    Some(field[..field.find('`')?].to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn v1_config() -> Value {
+        json!({
+            "backup_globs": ["./sql/**/*"],
+            "backup_path": "./temp/",
+            "max_copies": 2
+        })
+    }
+
+    #[test]
+    fn test_migrate_config_v1_to_v2() {
+        let config = migrate_config_v1_to_v2(&v1_config()).unwrap();
+
+        assert_eq!(config.schema_version, 2);
+        assert_eq!(config.backup_globs, vec!["./sql/**/*".to_string()]);
+        assert_eq!(config.backup_path, "./temp/");
+        assert_eq!(config.max_copies, 2);
+        assert_eq!(config.canonicalize, CanonicalizePolicy::Full);
+        assert_eq!(config.maintenance.auto_vacuum_after_runs, None);
+    }
+
+    #[test]
+    fn test_migrate_config_v1_to_v2_drops_unrecognized_fields() {
+        let mut old = v1_config();
+        old["retired_setting"] = json!(true);
+
+        let config = migrate_config_v1_to_v2(&old).unwrap();
+
+        assert_eq!(config.max_copies, 2);
+    }
+
+    #[test]
+    fn test_max_copies() {
+        let max_copies = |value| {
+            let mut config = v1_config();
+            config["max_copies"] = value;
+            migrate_config_v1_to_v2(&config).map(|config| config.max_copies)
+        };
+
+        assert_eq!(max_copies(json!(1)).unwrap(), 1);
+        assert_eq!(max_copies(json!(0)).unwrap(), UNLIMITED_COPIES);
+        assert_eq!(max_copies(json!("unlimited")).unwrap(), UNLIMITED_COPIES);
+        assert!(matches!(max_copies(json!(-1)), Err(Error::ParseError(_))));
+        assert!(matches!(max_copies(json!("all")), Err(Error::ParseError(_))));
+    }
+
+    #[test]
+    fn test_migrate_config_v1_to_v2_requires_original_fields() {
+        let mut old = v1_config();
+        old.as_object_mut().unwrap().remove("max_copies");
+
+        assert!(matches!(migrate_config_v1_to_v2(&old), Err(Error::ParseError(_))));
+    }
+
+    #[test]
+    fn test_backup_path_is_alias_for_local_destination() {
+        let config = migrate_config_v1_to_v2(&v1_config()).unwrap();
+
+        assert_eq!(config.default_destination(), BackupDestination::Local { path: "./temp/".to_string() });
+    }
+
+    #[test]
+    fn test_backup_destination_is_tagged() {
+        let mut config = v1_config();
+        config.as_object_mut().unwrap().remove("backup_path");
+        config["backup_destination"] = json!({ "type": "s3", "bucket": "backups", "prefix": "home/", "region": "eu-west-1" });
+
+        let config = migrate_config_v1_to_v2(&config).unwrap();
+
+        assert_eq!(config.default_destination(), BackupDestination::S3 { 
+            bucket: "backups".to_string(), prefix: "home/".to_string(), region: "eu-west-1".to_string() 
+        });
+        assert_eq!(config.destinations()[0].0, DEFAULT_DESTINATION);
+    }
+
+    #[test]
+    fn test_load_with_migration_rewrites_old_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        std::fs::write(&path, v1_config().to_string()).unwrap();
+
+        let config = load_with_migration(&path).unwrap();
+        assert_eq!(config.schema_version, CURRENT_SCHEMA_VERSION);
+
+        let backup: Value = serde_json::from_str(&std::fs::read_to_string(dir.path().join("config.json.v1.bak")).unwrap()).unwrap();
+        assert_eq!(backup, v1_config());
+        let rewritten: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(rewritten["schema_version"], json!(CURRENT_SCHEMA_VERSION));
+
+        // Loading the migrated config again leaves it as it is
+        load_with_migration(&path).unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&std::fs::read_to_string(&path).unwrap()).unwrap(), rewritten);
+    }
+
+    #[test]
+    fn test_snapshot_round_trips() {
+        let config = migrate_config_v1_to_v2(&v1_config()).unwrap();
+
+        let restored: Config = serde_json::from_str(&config.snapshot()).unwrap();
+
+        assert_eq!(restored.snapshot(), config.snapshot());
+        assert_eq!(restored.backup_globs, config.backup_globs);
+    }
+
+    #[test]
+    fn test_redact_secrets() {
+        let mut value = json!({
+            "backup_path": "./temp/",
+            "remote": { "host": "example.com", "Password": "hunter2", "access_key_id": "AKIA" },
+            "keys": [{ "passphrase": "open sesame" }]
+        });
+
+        redact_secrets(&mut value);
+
+        assert_eq!(value, json!({
+            "backup_path": "./temp/",
+            "remote": { "host": "example.com", "Password": REDACTED, "access_key_id": REDACTED },
+            "keys": [{ "passphrase": REDACTED }]
+        }));
+    }
+
+    #[test]
+    fn test_diff_snapshots() {
+        let old = json!({ "backup_globs": ["a/*"], "max_copies": 2, "removed": true });
+        let new = json!({ "backup_globs": ["a/*", "b/*"], "max_copies": 2, "added": 1 });
+
+        assert_eq!(diff_snapshots(&old, &new), vec![
+            ConfigChange { field: "added".to_string(), old: None, new: Some(json!(1)) },
+            ConfigChange { field: "backup_globs".to_string(), old: Some(json!(["a/*"])), new: Some(json!(["a/*", "b/*"])) },
+            ConfigChange { field: "removed".to_string(), old: Some(json!(true)), new: None },
+        ]);
+    }
+
+    #[test]
+    fn test_change_detection_uses_most_specific_policy() {
+        let mut config: Config = serde_json::from_value(json!({
+            "schema_version": CURRENT_SCHEMA_VERSION,
+            "backup_globs": ["/home/**/*"],
+            "max_copies": 2,
+            "policies": [
+                { "globs": ["/home/photos/raw/**/*"] },
+                { "globs": ["/home/photos/**/*"], "change_detection": "metadata" }
+            ]
+        })).unwrap();
+
+        assert_eq!(config.change_detection(Path::new("/home/photos/2024/a.jpg")), ChangeDetection::Metadata);
+        assert_eq!(config.change_detection(Path::new("/home/photos/raw/a.cr2")), ChangeDetection::Hash);
+        assert_eq!(config.change_detection(Path::new("/home/src/main.rs")), ChangeDetection::Hash);
+
+        config.policies.clear();
+        assert_eq!(config.change_detection(Path::new("/home/photos/2024/a.jpg")), ChangeDetection::Hash);
+    }
+
+    #[test]
+    fn test_load_with_migration_rejects_newer_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        let mut newer = v1_config();
+        newer["schema_version"] = json!(CURRENT_SCHEMA_VERSION + 1);
+        std::fs::write(&path, newer.to_string()).unwrap();
+
+        assert!(matches!(load_with_migration(&path), Err(Error::UnsupportedVersion(_))));
+    }
+}
@@ -0,0 +1,173 @@
+use std::path::Path;
+
+use super::{ChangeDetection, PolicyConfig};
+
+///
+/// A policy glob which matched a path
+/// 
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PolicyMatch {
+    /// The position of the policy in the config's `policies`
+    pub policy_index: usize,
+    /// The glob of the policy which matched
+    pub glob: String,
+    /// How many characters the glob starts with before its first wildcard
+    pub literal_prefix_len: usize
+}
+
+///
+/// The value of a policy field for a path, and the policy it came from
+/// 
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResolvedField<T> {
+    pub value: T,
+    /// The policy which set the value, or `None` if it's the default, since no policy
+    /// matched, or the policy which did left the field unset
+    pub source: Option<PolicyMatch>
+}
+
+///
+/// The settings applied to a path by the config's policies
+/// 
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResolvedPolicy {
+    /// The policy which applies to the path, or `None` if no policy matched it
+    pub applied: Option<PolicyMatch>,
+    /// Every policy which matched the path, by its most specific matching glob, from the one applied down
+    pub matches: Vec<PolicyMatch>,
+    pub change_detection: ResolvedField<ChangeDetection>
+}
+
+///
+/// Gets how many characters `glob` starts with before its first wildcard,
+/// which ranks how specifically it picks out the paths it matches
+/// 
+pub fn literal_prefix_len(glob: &str) -> usize {
+    glob.find(['*', '?', '[']).unwrap_or(glob.len())
+}
+
+///
+/// Resolves which of the `policies` applies to the file at `path`. The policy with the most
+/// specific glob matching it applies, being the one with the longest literal prefix, with ties
+/// going to the policy listed first. Every field the applied policy leaves unset is the default
+/// 
+pub fn resolve_policy(policies: &[PolicyConfig], path: &Path) -> ResolvedPolicy {
+    let mut matches: Vec<PolicyMatch> = policies.iter().enumerate()
+        .filter_map(|(policy_index, policy)| policy.globs.iter()
+            .filter(|glob| glob::Pattern::new(glob).is_ok_and(|ptn| ptn.matches_path(path)))
+            .map(|glob| PolicyMatch { policy_index, glob: glob.clone(), literal_prefix_len: literal_prefix_len(glob) })
+            // The first of a policy's equally specific globs names the match
+            .reduce(|best, next| if next.literal_prefix_len > best.literal_prefix_len { next } else { best }))
+        .collect();
+    // The sort is stable, so policies matching as specifically keep their config order
+    matches.sort_by_key(|m| std::cmp::Reverse(m.literal_prefix_len));
+
+    let applied = matches.first().cloned();
+    let change_detection = match applied.as_ref().and_then(|applied| policies[applied.policy_index].change_detection) {
+        Some(value) => ResolvedField { value, source: applied.clone() },
+        None => ResolvedField { value: ChangeDetection::default(), source: None }
+    };
+
+    ResolvedPolicy { applied, matches, change_detection }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::config::{ChangeDetection, PolicyConfig};
+
+    use super::{literal_prefix_len, resolve_policy, PolicyMatch};
+
+    fn policy(globs: &[&str], change_detection: Option<ChangeDetection>) -> PolicyConfig {
+        PolicyConfig { globs: globs.iter().map(|glob| glob.to_string()).collect(), change_detection }
+    }
+
+    fn applied_index(policies: &[PolicyConfig], path: &str) -> Option<usize> {
+        resolve_policy(policies, Path::new(path)).applied.map(|applied| applied.policy_index)
+    }
+
+    #[test]
+    fn test_literal_prefix_len() {
+        assert_eq!(literal_prefix_len("/home/me/projects/**"), 18);
+        assert_eq!(literal_prefix_len("/home/me/**/*.iso"), 9);
+        assert_eq!(literal_prefix_len("/home/me/file?.txt"), 13);
+        assert_eq!(literal_prefix_len("/home/[mn]e/*"), 6);
+        assert_eq!(literal_prefix_len("/home/me/notes.txt"), 18);
+        assert_eq!(literal_prefix_len("**/*.iso"), 0);
+    }
+
+    #[test]
+    fn test_most_specific_glob_wins_in_either_order() {
+        let projects = policy(&["/home/me/projects/**"], Some(ChangeDetection::Hash));
+        let isos = policy(&["/home/me/**/*.iso"], Some(ChangeDetection::Metadata));
+
+        for policies in [vec![projects.clone(), isos.clone()], vec![isos.clone(), projects.clone()]] {
+            let resolved = resolve_policy(&policies, Path::new("/home/me/projects/os.iso"));
+            assert_eq!(resolved.applied.unwrap().glob, "/home/me/projects/**");
+            assert_eq!(resolved.change_detection.value, ChangeDetection::Hash);
+            assert_eq!(resolved.matches.iter().map(|m| m.glob.as_str()).collect::<Vec<_>>(), ["/home/me/projects/**", "/home/me/**/*.iso"]);
+        }
+        // Outside the projects, only the ISO policy matches
+        assert_eq!(resolve_policy(&[projects, isos], Path::new("/home/me/os.iso")).change_detection.value, ChangeDetection::Metadata);
+    }
+
+    #[test]
+    fn test_ties_go_to_the_first_policy() {
+        let policies = [
+            policy(&["/data/*.log"], Some(ChangeDetection::Metadata)),
+            policy(&["/data/**"], Some(ChangeDetection::Hash))
+        ];
+        assert_eq!(applied_index(&policies, "/data/app.log"), Some(0));
+
+        let reversed = [policies[1].clone(), policies[0].clone()];
+        assert_eq!(applied_index(&reversed, "/data/app.log"), Some(0));
+    }
+
+    #[test]
+    fn test_a_policy_is_ranked_by_its_most_specific_matching_glob() {
+        let policies = [
+            policy(&["/home/me/**/*.iso"], None),
+            policy(&["**/*.iso", "/home/me/projects/**"], None),
+            policy(&["/home/me/projects/os.iso"], None)
+        ];
+
+        let resolved = resolve_policy(&policies, Path::new("/home/me/projects/os.iso"));
+        assert_eq!(resolved.matches, [
+            PolicyMatch { policy_index: 2, glob: "/home/me/projects/os.iso".to_string(), literal_prefix_len: 24 },
+            PolicyMatch { policy_index: 1, glob: "/home/me/projects/**".to_string(), literal_prefix_len: 18 },
+            PolicyMatch { policy_index: 0, glob: "/home/me/**/*.iso".to_string(), literal_prefix_len: 9 }
+        ]);
+        // Only the less specific glob of the second policy matches elsewhere
+        assert_eq!(resolve_policy(&policies, Path::new("/srv/os.iso")).matches[0].glob, "**/*.iso");
+    }
+
+    #[test]
+    fn test_unset_fields_are_the_default() {
+        let policies = [
+            policy(&["/home/photos/raw/**"], None),
+            policy(&["/home/photos/**"], Some(ChangeDetection::Metadata))
+        ];
+
+        // The applied policy leaves change detection unset, so the less specific policy doesn't fill it in
+        let raw = resolve_policy(&policies, Path::new("/home/photos/raw/a.cr2"));
+        assert_eq!(raw.applied.unwrap().policy_index, 0);
+        assert_eq!(raw.change_detection.value, ChangeDetection::Hash);
+        assert_eq!(raw.change_detection.source, None);
+
+        let jpg = resolve_policy(&policies, Path::new("/home/photos/2024/a.jpg"));
+        assert_eq!(jpg.change_detection.value, ChangeDetection::Metadata);
+        assert_eq!(jpg.change_detection.source.unwrap().policy_index, 1);
+
+        let unmatched = resolve_policy(&policies, Path::new("/home/src/main.rs"));
+        assert_eq!((unmatched.applied, unmatched.matches.len()), (None, 0));
+        assert_eq!(unmatched.change_detection.value, ChangeDetection::Hash);
+    }
+
+    #[test]
+    fn test_invalid_globs_never_match() {
+        let policies = [policy(&["/home/[me/**"], Some(ChangeDetection::Metadata))];
+        assert_eq!(applied_index(&policies, "/home/[me/a.txt"), None);
+        assert_eq!(applied_index(&[], "/home/me/a.txt"), None);
+    }
+}
@@ -5,6 +5,13 @@ pub struct DataLayerError {
 }
 pub type Result<T> = std::result::Result<T, DataLayerError>;
 
+impl From<std::io::Error> for DataLayerError {
+    fn from(value: std::io::Error) -> Self {
+        Self { err: Box::new(value) }
+    }
+}
+
+#[cfg(feature = "sqlite")]
 impl From<sqlx::Error> for DataLayerError {
     fn from(value: sqlx::Error) -> Self {
         Self { err: Box::new(value) }
@@ -0,0 +1,282 @@
+use std::fmt::Display;
+
+use chrono::{DateTime, Duration, Local, SecondsFormat, TimeZone, Utc};
+
+use crate::history_service::models::{RunModel, RunStatus, RunTrigger};
+
+///
+/// An estimate of the work a backup run will do, based on previous runs
+/// 
+#[derive(Debug, PartialEq)]
+pub struct Estimate {
+    /// The number of files discovered to examine in this run
+    pub files_to_examine: u64,
+    /// How long the previous completed run took
+    pub last_run_duration: Option<Duration>,
+    /// How long this run is expected to take
+    pub expected_duration: Option<Duration>,
+    /// The number of bytes expected to be backed up in this run
+    pub expected_bytes_changed: Option<u64>
+}
+
+///
+/// Estimates the work of a run examining `files_discovered` files, from the
+/// per-file rates of the given `history` of runs, newest first.
+/// Runs which never completed, stopped at their deadline, or only backed up a given list of paths, are ignored.
+/// 
+pub fn estimate(files_discovered: u64, history: &[RunModel]) -> Estimate {
+    let completed: Vec<(Duration, i64, i64)> = history.iter()
+        .filter(|r| r.status == RunStatus::Completed && r.origin.trigger != RunTrigger::AdHoc)
+        .filter_map(|r| Some((r.completed_at? - r.started_at, r.files_scanned?, r.bytes_backed_up?)))
+        .collect();
+
+    let total_duration = completed.iter().fold(Duration::zero(), |acc, r| acc + r.0);
+    let total_files: i64 = completed.iter().map(|r| r.1).sum();
+    let total_bytes: i64 = completed.iter().map(|r| r.2).sum();
+
+    // Without any files scanned previously, there are no per-file rates to work from
+    let (expected_duration, expected_bytes_changed) = if total_files > 0 {
+        let files = files_discovered as i64;
+        (
+            Some(Duration::milliseconds(total_duration.num_milliseconds() * files / total_files)),
+            Some((total_bytes * files / total_files) as u64)
+        )
+    } else {
+        (None, None)
+    };
+
+    Estimate {
+        files_to_examine: files_discovered,
+        last_run_duration: completed.first().map(|r| r.0),
+        expected_duration,
+        expected_bytes_changed
+    }
+}
+
+impl Display for Estimate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "about {} files to examine", format_count(self.files_to_examine))?;
+        if let Some(duration) = self.last_run_duration {
+            write!(f, ", last run took {}", format_duration(duration))?;
+        }
+        if let Some(duration) = self.expected_duration {
+            write!(f, ", ~{} expected", format_duration(duration))?;
+        }
+        if let Some(bytes) = self.expected_bytes_changed {
+            write!(f, ", ~{} expected to change", format_bytes(bytes))?;
+        }
+
+        Ok(())
+    }
+}
+
+///
+/// Parses a duration written as whole numbers of hours, minutes and seconds, 
+/// largest first, ie. "4h", "90m" or "1h30m". Returns `None` if it's malformed.
+/// 
+pub fn parse_duration(duration: &str) -> Option<Duration> {
+    let mut total = Duration::zero();
+    let mut digits = String::new();
+    let mut last_unit = None;
+    for c in duration.trim().chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let (rank, unit) = match c {
+            'h' => (0, Duration::hours(1)),
+            'm' => (1, Duration::minutes(1)),
+            's' => (2, Duration::seconds(1)),
+            _ => return None
+        };
+        if digits.is_empty() || last_unit.is_some_and(|last| last >= rank) {
+            return None;
+        }
+        total += unit * digits.parse::<i32>().ok()?;
+        digits.clear();
+        last_unit = Some(rank);
+    }
+
+    (digits.is_empty() && last_unit.is_some()).then_some(total)
+}
+
+///
+/// Formats `count` with commas separating each group of thousands
+/// 
+pub fn format_count(count: u64) -> String {
+    let digits = count.to_string();
+    let mut formatted = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            formatted.push(',');
+        }
+        formatted.push(c);
+    }
+
+    formatted
+}
+
+///
+/// Formats `duration` in its two largest units, ie. "1h 5m", "14m" or "30s"
+/// 
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.num_seconds().max(0);
+    let (hours, mins, secs) = (secs / 3600, secs % 3600 / 60, secs % 60);
+    match (hours, mins) {
+        (0, 0) => format!("{}s", secs),
+        (0, mins) if secs == 0 => format!("{}m", mins),
+        (0, mins) => format!("{}m {}s", mins, secs),
+        (hours, 0) => format!("{}h", hours),
+        (hours, mins) => format!("{}h {}m", hours, mins),
+    }
+}
+
+///
+/// Formats a number of `bytes` in the largest unit that keeps it at least 1
+/// 
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 { format!("{} B", bytes) } else { format!("{:.1} {}", size, UNITS[unit]) }
+}
+
+///
+/// How timestamps are shown to the user
+/// 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimestampStyle {
+    /// In the local time zone with its offset, ie. "2024-03-10 01:30:00 -08:00"
+    #[default]
+    Local,
+    /// In UTC as ISO-8601, ie. "2024-03-10T09:30:00Z", for reading by other programs
+    Utc
+}
+
+///
+/// Formats an `instant` from the catalog in the given `style`. Every timestamp
+/// shown to the user is formatted here, so that every command agrees
+/// 
+pub fn format_timestamp(instant: &DateTime<Utc>, style: TimestampStyle) -> String {
+    match style {
+        TimestampStyle::Local => format_in(instant, &Local),
+        TimestampStyle::Utc => instant.to_rfc3339_opts(SecondsFormat::Secs, true)
+    }
+}
+
+///
+/// Formats an `instant` from the catalog in the time zone `tz`, with its offset
+/// 
+pub fn format_in<Tz: TimeZone>(instant: &DateTime<Utc>, tz: &Tz) -> String where Tz::Offset: Display {
+    instant.with_timezone(tz).format("%Y-%m-%d %H:%M:%S %:z").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Duration, FixedOffset, TimeZone, Utc};
+
+    use crate::history_service::models::{RunModel, RunOrigin, RunStatus, RunTrigger};
+
+    use super::{estimate, format_bytes, format_count, format_duration, format_in, format_timestamp, parse_duration, TimestampStyle};
+
+    fn ts(hour: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, 1, hour, min, 0).unwrap()
+    }
+    fn run(id: i64, start: DateTime<Utc>, end: Option<DateTime<Utc>>, files: i64, bytes: i64) -> RunModel {
+        RunModel { 
+            id, started_at: start, completed_at: end, files_scanned: Some(files), bytes_backed_up: Some(bytes), config_snapshot: None,
+            status: if end.is_some() { RunStatus::Completed } else { RunStatus::Running }, files_backed_up: None, files_skipped: None, errors: Vec::new(),
+            origin: RunOrigin::default()
+        }
+    }
+
+    #[test]
+    fn test_estimate_with_empty_history() {
+        let estimate = estimate(2_400, &[]);
+
+        assert_eq!(estimate.last_run_duration, None);
+        assert_eq!(estimate.expected_duration, None);
+        assert_eq!(estimate.expected_bytes_changed, None);
+        assert_eq!(estimate.to_string(), "about 2,400 files to examine");
+    }
+
+    #[test]
+    fn test_estimate_scales_by_files_discovered() {
+        let history = [
+            run(3, ts(2, 0), Some(ts(2, 14)), 2_000, 3_000),
+            run(2, ts(1, 0), Some(ts(1, 6)), 1_000, 1_000),
+            // Never completed, so it's ignored
+            run(1, ts(0, 0), None, 5_000, 5_000),
+        ];
+        let estimate = estimate(6_000, &history);
+
+        assert_eq!(estimate.last_run_duration, Some(Duration::minutes(14)));
+        // 20 minutes over 3,000 files
+        assert_eq!(estimate.expected_duration, Some(Duration::minutes(40)));
+        // 4,000 bytes over 3,000 files
+        assert_eq!(estimate.expected_bytes_changed, Some(8_000));
+        assert_eq!(estimate.to_string(), "about 6,000 files to examine, last run took 14m, ~40m expected, ~7.8 KB expected to change");
+    }
+
+    #[test]
+    fn test_estimate_formatting() {
+        assert_eq!(format_count(0), "0");
+        assert_eq!(format_count(999), "999");
+        assert_eq!(format_count(1_234_567), "1,234,567");
+        assert_eq!(format_duration(Duration::seconds(30)), "30s");
+        assert_eq!(format_duration(Duration::seconds(95)), "1m 35s");
+        assert_eq!(format_duration(Duration::minutes(65)), "1h 5m");
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GB");
+    }
+
+    #[test]
+    fn test_estimate_ignores_partial_and_ad_hoc_runs() {
+        let mut partial = run(2, ts(2, 0), Some(ts(6, 0)), 1_000, 1_000);
+        partial.status = RunStatus::Partial;
+        let mut ad_hoc = run(3, ts(7, 0), Some(ts(7, 1)), 10, 10);
+        ad_hoc.origin.trigger = RunTrigger::AdHoc;
+        let history = [ad_hoc, partial, run(1, ts(0, 0), Some(ts(0, 10)), 1_000, 1_000)];
+
+        let estimate = estimate(1_000, &history);
+
+        assert_eq!(estimate.last_run_duration, Some(Duration::minutes(10)));
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("4h"), Some(Duration::hours(4)));
+        assert_eq!(parse_duration("90m"), Some(Duration::minutes(90)));
+        assert_eq!(parse_duration("1h30m15s"), Some(Duration::seconds(5_415)));
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("4"), None);
+        assert_eq!(parse_duration("30m1h"), None);
+        assert_eq!(parse_duration("h"), None);
+        assert_eq!(parse_duration("4d"), None);
+    }
+
+    #[test]
+    fn test_timestamps_round_trip() {
+        let instant = Utc.with_ymd_and_hms(2024, 3, 10, 9, 30, 15).unwrap();
+        let pacific = FixedOffset::west_opt(8 * 3600).unwrap();
+        let kolkata = FixedOffset::east_opt(5 * 3600 + 1800).unwrap();
+
+        let rendered = format_in(&instant, &pacific);
+        assert_eq!(rendered, "2024-03-10 01:30:15 -08:00");
+        for tz in [pacific, kolkata] {
+            let parsed = DateTime::parse_from_str(&format_in(&instant, &tz), "%Y-%m-%d %H:%M:%S %:z").unwrap();
+            assert_eq!(parsed, instant);
+        }
+
+        let rendered = format_timestamp(&instant, TimestampStyle::Utc);
+        assert_eq!(rendered, "2024-03-10T09:30:15Z");
+        assert_eq!(DateTime::parse_from_rfc3339(&rendered).unwrap(), instant);
+        let local = format_timestamp(&instant, TimestampStyle::Local);
+        assert_eq!(DateTime::parse_from_str(&local, "%Y-%m-%d %H:%M:%S %:z").unwrap(), instant);
+    }
+}
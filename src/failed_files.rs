@@ -0,0 +1,130 @@
+use std::{collections::HashSet, path::{Path, PathBuf}};
+
+use serde::{Deserialize, Serialize};
+
+///
+/// The name of the file in the backup path listing the files whose backups failed
+/// 
+pub const FAILED_FILES_NAME: &str = "failed_files.json";
+
+///
+/// A file whose backup failed, to be retried first by the next run
+/// 
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FailedFile {
+    pub path: PathBuf,
+    /// The number of runs in a row its backup failed in
+    pub attempts: u32,
+    /// Why its latest attempt failed
+    pub error: String
+}
+
+///
+/// Every file whose backup failed in a recent run, kept between runs in `failed_files.json`
+/// 
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FailedFiles {
+    files: Vec<FailedFile>
+}
+
+impl FailedFiles {
+    ///
+    /// Reads the list at `path`, which is empty if it doesn't exist yet
+    /// 
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents).map_err(std::io::Error::from),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e)
+        }
+    }
+
+    ///
+    /// Writes the list to `path`, removing it instead once it's empty
+    /// 
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        if self.files.is_empty() {
+            return match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+                _ => Ok(())
+            };
+        }
+        std::fs::write(path, serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn files(&self) -> &[FailedFile] {
+        &self.files
+    }
+
+    ///
+    /// Moves every file in `files` which is on the list to the front, keeping the order of the rest
+    /// 
+    pub fn move_to_front<T>(&self, files: &mut [T], path_of: impl Fn(&T) -> &Path) {
+        let failed: HashSet<&Path> = self.files.iter().map(|file| file.path.as_path()).collect();
+        // The sort is stable, so both the failed files and the rest keep their scanned order
+        files.sort_by_key(|file| !failed.contains(path_of(file)));
+    }
+
+    ///
+    /// Records that the backup of the file at `path` failed with `error`. Once it has failed in
+    /// `max_attempts` runs in a row, it's taken off the list rather than retried again, and `false` is returned
+    /// 
+    pub fn record_failure(&mut self, path: &Path, error: &str, max_attempts: u32) -> bool {
+        let attempts = self.files.iter().find(|file| file.path == path).map_or(0, |file| file.attempts) + 1;
+        self.record_success(path);
+        if attempts >= max_attempts {
+            return false;
+        }
+
+        self.files.push(FailedFile { path: path.to_path_buf(), attempts, error: error.to_string() });
+        true
+    }
+
+    ///
+    /// Takes the file at `path` off the list, its backup having succeeded, or been found unneeded
+    /// 
+    pub fn record_success(&mut self, path: &Path) {
+        self.files.retain(|file| file.path != path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::FailedFiles;
+
+    #[test]
+    fn test_retries_until_attempts_are_exhausted() {
+        let dir = tempfile::tempdir().unwrap();
+        let list_path = dir.path().join(super::FAILED_FILES_NAME);
+        let (locked, fixed) = (Path::new("/data/locked.db"), Path::new("/data/fixed.txt"));
+        let mut failed = FailedFiles::load(&list_path).unwrap();
+        assert!(failed.record_failure(locked, "the file is in use", 3));
+        assert!(failed.record_failure(fixed, "the file is in use", 3));
+        failed.save(&list_path).unwrap();
+
+        let mut failed = FailedFiles::load(&list_path).unwrap();
+        assert!(failed.record_failure(locked, "the file is in use", 3));
+        failed.record_success(fixed);
+        assert_eq!(failed.files().len(), 1);
+        assert_eq!(failed.files()[0].attempts, 2);
+
+        // The third failure in a row is the last
+        assert!(!failed.record_failure(locked, "the file is in use", 3));
+        failed.save(&list_path).unwrap();
+        assert!(!list_path.exists());
+    }
+
+    #[test]
+    fn test_move_to_front() {
+        let mut failed = FailedFiles::default();
+        failed.record_failure(Path::new("/c"), "locked", 3);
+        failed.record_failure(Path::new("/a"), "locked", 3);
+        let mut files: Vec<PathBuf> = ["/a", "/b", "/c", "/d"].into_iter().map(PathBuf::from).collect();
+
+        failed.move_to_front(&mut files, |path| path.as_path());
+
+        assert_eq!(files, ["/a", "/c", "/b", "/d"].map(PathBuf::from));
+    }
+}
@@ -0,0 +1,34 @@
+use std::path::{Path, PathBuf};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    GlobPatternError(glob::PatternError),
+    /// The metadata of the file at the given path could not be read
+    MetadataError(PathBuf, std::io::Error),
+    /// The directory at the given path could not be read, so none of the files beneath it were found
+    UnreadableDir(PathBuf, std::io::Error),
+    /// The path given to back up isn't a regular file
+    NotAFile(PathBuf),
+    /// The file listing glob patterns at the given path could not be read
+    UnreadableGlobList(PathBuf, std::io::Error),
+}
+
+impl Error {
+    ///
+    /// Gets the path of the file or directory which couldn't be read, if the error has one
+    /// 
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Error::MetadataError(path, _) | Error::UnreadableDir(path, _) | Error::NotAFile(path) => Some(path),
+            Error::GlobPatternError(_) | Error::UnreadableGlobList(..) => None
+        }
+    }
+}
+
+impl From<glob::PatternError> for Error {
+    fn from(value: glob::PatternError) -> Self {
+        Error::GlobPatternError(value)
+    }
+}
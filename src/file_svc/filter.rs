@@ -0,0 +1,88 @@
+use std::{fmt::Debug, fs::Metadata, path::Path, sync::Arc};
+
+use glob::Pattern;
+
+///
+/// What a `PathFilter` decided about a path found by a scan
+/// 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilterDecision {
+    /// The path is passed on to the next filter, and is backed up if every filter includes it
+    Include,
+    /// The path isn't backed up, but if it's a directory, the paths beneath it are still filtered
+    Exclude,
+    /// The path isn't backed up, and if it's a directory, neither is anything beneath it,
+    /// which is skipped without being given to any filter
+    ExcludeSubtree
+}
+
+///
+/// Decides whether a path found by a scan is backed up, from the path and its metadata.
+/// Directories matched by the globs are given to filters as well as files, so a filter
+/// can leave out a whole directory with `ExcludeSubtree`.
+/// 
+pub trait PathFilter : Send + Sync {
+    ///
+    /// Names the filter in the counts of the files it excluded
+    /// 
+    fn name(&self) -> &str;
+    fn include(&self, path: &Path, meta: &Metadata) -> FilterDecision;
+}
+
+impl Debug for dyn PathFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PathFilter({})", self.name())
+    }
+}
+
+///
+/// Excludes the paths matching any of the `exclude_globs`
+/// 
+pub struct ExcludeGlobFilter {
+    pub patterns: Vec<Pattern>
+}
+
+impl PathFilter for ExcludeGlobFilter {
+    fn name(&self) -> &str {
+        "exclude_globs"
+    }
+    fn include(&self, path: &Path, _meta: &Metadata) -> FilterDecision {
+        match self.patterns.iter().any(|ptn| ptn.matches_path(path)) {
+            true => FilterDecision::Exclude,
+            false => FilterDecision::Include
+        }
+    }
+}
+
+///
+/// Excludes the files smaller than `min_size` or larger than `max_size` bytes.
+/// Directories are always included
+/// 
+pub struct SizeFilter {
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>
+}
+
+impl PathFilter for SizeFilter {
+    fn name(&self) -> &str {
+        "file size"
+    }
+    fn include(&self, _path: &Path, meta: &Metadata) -> FilterDecision {
+        let size = meta.len();
+        let fits = self.min_size.is_none_or(|min| size >= min) && self.max_size.is_none_or(|max| size <= max);
+        match meta.is_dir() || fits {
+            true => FilterDecision::Include,
+            false => FilterDecision::Exclude
+        }
+    }
+}
+
+///
+/// Gives the path at `path` to each of the `filters` in order, stopping at the first which
+/// doesn't include it. Returns that filter's index and decision, or `None` if every filter includes it
+/// 
+pub fn apply_filters(filters: &[Arc<dyn PathFilter>], path: &Path, meta: &Metadata) -> Option<(usize, FilterDecision)> {
+    filters.iter().enumerate()
+        .map(|(i, filter)| (i, filter.include(path, meta)))
+        .find(|(_, decision)| *decision != FilterDecision::Include)
+}
@@ -0,0 +1,116 @@
+use std::{borrow::Cow, io, path::Path};
+
+///
+/// The longest file name, in bytes, most filesystems allow
+/// 
+pub const MAX_NAME_BYTES: usize = 255;
+
+///
+/// Gets `path` as it should be given to the filesystem. On Windows, absolute paths are given
+/// the `\\?\` extended-length prefix, so they aren't limited to 260 characters. Elsewhere,
+/// `path` is returned as it is. Only paths given to the filesystem are prefixed; paths are
+/// recorded in the catalog exactly as they were found.
+/// 
+pub fn long_path(path: &Path) -> Cow<'_, Path> {
+    #[cfg(windows)]
+    if let Some(prefixed) = path.to_str().and_then(extended_length) {
+        return Cow::Owned(std::path::PathBuf::from(prefixed));
+    }
+
+    Cow::Borrowed(path)
+}
+
+///
+/// Gets the Windows `path` with the extended-length prefix, ie. `C:\dir` becomes `\\?\C:\dir`
+/// and `\\server\share` becomes `\\?\UNC\server\share`. Windows doesn't normalize prefixed
+/// paths, so forward slashes are replaced. Returns `None` for paths already prefixed, and
+/// relative paths or paths holding `.` or `..`, which can't be prefixed.
+/// 
+pub fn extended_length(path: &str) -> Option<String> {
+    if path.starts_with(r"\\?\") || path.starts_with(r"\\.\") {
+        return None;
+    }
+    let path = path.replace('/', "\\");
+    if path.split('\\').skip(1).any(|component| component == "." || component == "..") {
+        return None;
+    }
+
+    if let Some(share) = path.strip_prefix(r"\\") {
+        return Some(format!(r"\\?\UNC\{}", share));
+    }
+    let bytes = path.as_bytes();
+    match bytes.len() >= 3 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' && bytes[2] == b'\\' {
+        true => Some(format!(r"\\?\{}", path)),
+        false => None
+    }
+}
+
+///
+/// Shortens `file_name` to at most `MAX_NAME_BYTES` bytes, if it's longer, by replacing its
+/// overflow with a short hash of the whole name, keeping any extension. The same name is
+/// always shortened the same way, and names sharing a long prefix are shortened differently.
+/// 
+pub fn clamp_file_name(file_name: &str) -> Cow<'_, str> {
+    if file_name.len() <= MAX_NAME_BYTES {
+        return Cow::Borrowed(file_name);
+    }
+
+    let hsh = format!("{:x}", md5::compute(file_name.as_bytes()));
+    let ext = match file_name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && ext.len() <= 16 => &file_name[stem.len()..],
+        _ => ""
+    };
+    let suffix = format!("~{}{}", &hsh[..8], ext);
+    let mut end = MAX_NAME_BYTES - suffix.len();
+    while !file_name.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    Cow::Owned(format!("{}{}", &file_name[..end], suffix))
+}
+
+///
+/// Adds the `path` which was being used to the message of the error `e`, keeping its kind,
+/// so errors such as a path or name being too long say which path it was
+/// 
+pub fn naming_path(e: io::Error, path: &Path) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{clamp_file_name, extended_length, MAX_NAME_BYTES};
+
+    #[test]
+    fn test_extended_length() {
+        assert_eq!(extended_length(r"C:\Users\chris\file.txt").as_deref(), Some(r"\\?\C:\Users\chris\file.txt"));
+        assert_eq!(extended_length("C:/Users/chris").as_deref(), Some(r"\\?\C:\Users\chris"));
+        assert_eq!(extended_length(r"\\nas\share\file.txt").as_deref(), Some(r"\\?\UNC\nas\share\file.txt"));
+        // Already prefixed, relative, or holding components Windows won't resolve once prefixed
+        assert_eq!(extended_length(r"\\?\C:\Users"), None);
+        assert_eq!(extended_length(r"\\.\pipe\name"), None);
+        assert_eq!(extended_length(r"Users\chris"), None);
+        assert_eq!(extended_length(r"C:\Users\..\chris"), None);
+        assert_eq!(extended_length("/home/chris"), None);
+    }
+
+    #[test]
+    fn test_clamp_file_name() {
+        assert_eq!(clamp_file_name("file.txt"), "file.txt");
+        let exact = "a".repeat(MAX_NAME_BYTES);
+        assert_eq!(clamp_file_name(&exact), exact);
+
+        let long = format!("{}.json", "a".repeat(300));
+        let clamped = clamp_file_name(&long);
+        assert_eq!(clamped.len(), MAX_NAME_BYTES);
+        assert!(clamped.ends_with(".json"));
+        assert_eq!(clamped, clamp_file_name(&long));
+        assert_ne!(clamped, clamp_file_name(&format!("{}b.json", "a".repeat(300))));
+
+        // Multi-byte characters are never split
+        let accented = "é".repeat(200);
+        let clamped = clamp_file_name(&accented);
+        assert!(clamped.len() <= MAX_NAME_BYTES);
+        assert!(clamped.starts_with("éé"));
+    }
+}
@@ -1,11 +1,1015 @@
-use glob::glob;
-use std::path::PathBuf;
-
-pub fn get_glob_files(glob_iter: impl Iterator<Item = String>) -> impl Iterator<Item = PathBuf> {
-    // For every glob pattern given, generate iterators finding
-    // each file that matches the pattern
-    // TODO - add tracing for each unwrap
-    glob_iter.flat_map(|glob_ptn| glob(&glob_ptn).unwrap()) 
-        .map(|path| std::fs::canonicalize(path.unwrap()).unwrap())
-        .filter(|path| !path.is_dir())
-}
\ No newline at end of file
+pub mod error;
+pub mod filter;
+pub mod long_path;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use glob::{glob, Paths, Pattern};
+use std::{collections::{HashMap, HashSet}, fs::Metadata, io::Read, path::{Component, Path, PathBuf}, sync::{Arc, Mutex}};
+
+#[cfg(test)]
+use mockall::automock;
+
+use crate::config::{CanonicalizePolicy, Config};
+use error::*;
+use filter::{apply_filters, ExcludeGlobFilter, FilterDecision, PathFilter, SizeFilter};
+use long_path::long_path;
+
+///
+/// The files found by a scan, each with its metadata, or the error which kept it from being read
+/// 
+pub type ScannedFiles = Box<dyn Iterator<Item = Result<(PathBuf, Metadata)>>>;
+
+///
+/// Finds the files to back up
+/// 
+#[cfg_attr(test, automock)]
+pub trait FileScannerTrait {
+    ///
+    /// Gets the path of every file which should be backed up.
+    /// Fails if any of the configured patterns are invalid.
+    /// 
+    fn scan(&self) -> Result<Box<dyn Iterator<Item = PathBuf>>>;
+}
+
+///
+/// Selects the files to back up, by the globs they match and 
+/// filters on their paths and metadata
+/// 
+#[derive(Clone, Debug, Default)]
+pub struct FileScanner {
+    /// Files matching any of these globs are backed up
+    pub globs: Vec<String>,
+    /// A file listing more globs to back up files matching, one per line
+    pub glob_list_file: Option<PathBuf>,
+    /// Files matching any of these globs are not backed up, even if they match `globs`
+    pub exclude_globs: Vec<String>,
+    /// Files smaller than this many bytes are not backed up
+    pub min_size: Option<u64>,
+    /// Files larger than this many bytes are not backed up
+    pub max_size: Option<u64>,
+    /// Whether files matched through a symlink are backed up
+    pub follow_symlinks: bool,
+    /// Whether named pipes, device files and sockets are backed up. If not, they're skipped with a warning
+    pub include_special_files: bool,
+    /// How matched paths are normalized
+    pub canonicalize: CanonicalizePolicy,
+    /// More filters each path found is given to, in order, after the exclude globs and size limits
+    pub filters: Vec<Arc<dyn PathFilter>>,
+    /// Every path found which couldn't be canonicalized, so was only made absolute
+    non_canonical: Arc<Mutex<HashSet<PathBuf>>>,
+    /// How many files each filter excluded during the latest scan, by its name, in the order they ran
+    exclusions: Arc<Mutex<Vec<(String, u64)>>>
+}
+
+///
+/// The kind of file a path names
+/// 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PathClassification {
+    RegularFile,
+    Symlink,
+    Directory,
+    NamedPipe,
+    /// A block or character device
+    DeviceFile,
+    /// A socket, or anything else the platform doesn't describe
+    Other
+}
+
+impl PathClassification {
+    ///
+    /// Classifies the file described by `metadata`. Metadata read through a 
+    /// symlink describes its target, never the symlink itself.
+    /// 
+    pub fn of(metadata: &Metadata) -> Self {
+        let file_type = metadata.file_type();
+        if file_type.is_file() {
+            return PathClassification::RegularFile;
+        } else if file_type.is_symlink() {
+            return PathClassification::Symlink;
+        } else if file_type.is_dir() {
+            return PathClassification::Directory;
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt;
+            if file_type.is_fifo() {
+                return PathClassification::NamedPipe;
+            } else if file_type.is_block_device() || file_type.is_char_device() {
+                return PathClassification::DeviceFile;
+            }
+        }
+
+        PathClassification::Other
+    }
+
+    ///
+    /// Whether the file isn't a regular file, directory or symlink, so reading 
+    /// it may block or never end
+    /// 
+    pub fn is_special(&self) -> bool {
+        matches!(self, PathClassification::NamedPipe | PathClassification::DeviceFile | PathClassification::Other)
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            PathClassification::RegularFile => "regular file",
+            PathClassification::Symlink => "symlink",
+            PathClassification::Directory => "directory",
+            PathClassification::NamedPipe => "named pipe",
+            PathClassification::DeviceFile => "device file",
+            PathClassification::Other => "special file",
+        }
+    }
+}
+
+///
+/// Classifies the file at `path` without following a final symlink, or without 
+/// opening it, so it never blocks on a named pipe. A path which can't be read is `Other`.
+/// 
+pub fn classify_path(path: &Path) -> PathClassification {
+    std::fs::symlink_metadata(long_path(path)).map_or(PathClassification::Other, |metadata| PathClassification::of(&metadata))
+}
+
+///
+/// Whether the file at `path` of the given `class` is skipped, warning if it is
+/// 
+fn skip_special(path: &Path, class: PathClassification) -> bool {
+    if class.is_special() {
+        tracing::warn!("Skipping {}, which is a {}", path.display(), class.description());
+    }
+    class.is_special()
+}
+
+impl FileScanner {
+    ///
+    /// Creates a `FileScanner` selecting the files described by the given `config`
+    /// 
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            globs: config.backup_globs.clone(),
+            glob_list_file: config.glob_list_file.clone(),
+            exclude_globs: config.exclude_globs.clone(),
+            min_size: config.min_file_size,
+            max_size: config.max_file_size,
+            follow_symlinks: config.follow_symlinks,
+            include_special_files: !config.skip_special_files,
+            canonicalize: config.canonicalize,
+            filters: Vec::new(),
+            non_canonical: Arc::default(),
+            exclusions: Arc::default()
+        }
+    }
+
+    ///
+    /// Adds `filter` after every filter already added, so it's only given the paths they include
+    /// 
+    pub fn with_filter(mut self, filter: Arc<dyn PathFilter>) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    ///
+    /// Whether the found `path` is one the configured policy couldn't canonicalize,
+    /// so was only made absolute, as the `None` policy does
+    /// 
+    pub fn is_non_canonical(&self, path: &Path) -> bool {
+        self.non_canonical.lock().unwrap().contains(path)
+    }
+
+    ///
+    /// Gets how many files each filter excluded during the latest scan so far, by the filter's
+    /// name, in the order the filters ran. Directories and the paths beneath a directory 
+    /// whose subtree was excluded aren't counted
+    /// 
+    pub fn exclusion_counts(&self) -> Vec<(String, u64)> {
+        self.exclusions.lock().unwrap().clone()
+    }
+}
+
+impl FileScanner {
+    ///
+    /// Gets the path of every file which should be backed up, along with its metadata.
+    /// Each file is stated once, right after its path is normalized, and the metadata
+    /// is reused for filtering so callers don't need to stat the file again.
+    /// Files and directories which couldn't be read are returned as errors naming them.
+    /// Special files are classified before they're normalized or stated, so they're 
+    /// never opened unless `include_special_files` is set.
+    /// Every file and directory found is then given to the filters in order, and nothing
+    /// beneath a directory a filter excludes the subtree of is given to them, or returned.
+    /// Fails if any of the configured patterns are invalid.
+    /// 
+    pub fn scan_with_metadata(&self) -> Result<ScannedFiles> {
+        let (globs, excludes) = self.parse_patterns()?;
+        let (follow_symlinks, include_special, policy) = (self.follow_symlinks, self.include_special_files, self.canonicalize);
+        let non_canonical = self.non_canonical.clone();
+        let filters = self.all_filters(excludes.clone());
+        let exclusions = self.exclusions.clone();
+        *exclusions.lock().unwrap() = filters.iter().map(|filter| (filter.name().to_string(), 0)).collect();
+        // Every directory whose subtree was excluded. The globs find a directory before anything beneath it
+        let mut excluded_subtrees: Vec<PathBuf> = Vec::new();
+
+        // For every glob pattern given, generate iterators finding
+        // each file that matches the pattern
+        let paths = globs.into_iter().flatten()
+            .map(|path| path.map_err(|e| Error::UnreadableDir(e.path().to_path_buf(), e.into_error())))
+            .filter(move |path| path.as_ref().map_or(true, |path| follow_symlinks || !path.is_symlink()))
+            .filter(move |path| match path {
+                // A path which can't be read fails when it's stated below
+                Ok(path) if !include_special => std::fs::symlink_metadata(long_path(path))
+                    .map_or(true, |metadata| !skip_special(path, PathClassification::of(&metadata))),
+                _ => true
+            })
+            .map(move |path| path.and_then(|path| match normalize_path_as(&path, policy) {
+                Ok((normalized, applied)) => {
+                    if applied != policy {
+                        non_canonical.lock().unwrap().insert(normalized.clone());
+                    }
+                    Ok(normalized)
+                },
+                Err(e) => Err(Error::MetadataError(path, e))
+            }))
+            .map(|path| path.and_then(|path| match std::fs::metadata(long_path(&path)) {
+                Ok(metadata) => Ok((path, metadata)),
+                Err(e) => Err(Error::MetadataError(path, e))
+            }))
+            // A symlink to a special file is only found to be one once it's followed
+            .filter(move |file| match file {
+                Ok((path, metadata)) if !include_special => !skip_special(path, PathClassification::of(metadata)),
+                _ => true
+            })
+            .filter(move |file| {
+                let path = match file {
+                    Ok((path, _)) => Some(path.as_path()),
+                    Err(e) => e.path()
+                };
+                if path.is_some_and(|path| excluded_subtrees.iter().any(|dir| path.starts_with(dir))) {
+                    return false;
+                }
+                let (path, metadata) = match file {
+                    Ok(file) => file,
+                    // A path which couldn't be read has no metadata to filter on, so is only left out by the exclude globs
+                    Err(e) => return !e.path().is_some_and(|path| excludes.iter().any(|ptn| ptn.matches_path(path)))
+                };
+                match apply_filters(&filters, path, metadata) {
+                    None => true,
+                    Some((i, decision)) => {
+                        if metadata.is_dir() {
+                            if decision == FilterDecision::ExcludeSubtree {
+                                excluded_subtrees.push(path.clone());
+                            }
+                        } else {
+                            exclusions.lock().unwrap()[i].1 += 1;
+                        }
+                        false
+                    }
+                }
+            })
+            .filter(|file| file.as_ref().map_or(true, |(_, metadata)| !metadata.is_dir()));
+
+        Ok(Box::new(paths))
+    }
+
+    ///
+    /// Gets each of the given `paths` to back up, normalized, along with its metadata, in place
+    /// of the files matching the configured globs. None of the configured filters are applied,
+    /// but paths which don't exist, or aren't regular files, are returned as errors naming them.
+    /// 
+    pub fn scan_listed(&self, paths: Vec<PathBuf>) -> impl Iterator<Item = Result<(PathBuf, Metadata)>> {
+        let policy = self.canonicalize;
+        let non_canonical = self.non_canonical.clone();
+        paths.into_iter().map(move |path| {
+            let (path, applied) = normalize_path_as(&path, policy).map_err(|e| Error::MetadataError(path, e))?;
+            if applied != policy {
+                non_canonical.lock().unwrap().insert(path.clone());
+            }
+            match std::fs::metadata(long_path(&path)) {
+                Ok(metadata) if metadata.is_file() => Ok((path, metadata)),
+                Ok(_) => Err(Error::NotAFile(path)),
+                Err(e) => Err(Error::MetadataError(path, e))
+            }
+        })
+    }
+
+    ///
+    /// Gets every directory matching the configured globs which holds none of the files to back up, 
+    /// at any depth, given the `file_dirs` directly holding them, along with its metadata. 
+    /// Each directory inside an empty directory is reported as well.
+    /// 
+    pub fn scan_empty_dirs<'a>(&self, file_dirs: impl IntoIterator<Item = &'a Path>) -> Result<Vec<(PathBuf, Metadata)>> {
+        let (globs, excludes) = self.parse_patterns()?;
+        // Every directory holding a file to back up, however deeply
+        let occupied: HashSet<&Path> = file_dirs.into_iter().flat_map(Path::ancestors).collect();
+
+        let mut dirs: Vec<(PathBuf, Metadata)> = globs.into_iter().flatten()
+            .filter_map(|path| path.ok())
+            .filter(|path| self.follow_symlinks || !path.is_symlink())
+            .filter_map(|path| normalize_path(&path, self.canonicalize).ok())
+            .filter(|path| !occupied.contains(path.as_path()))
+            .filter(|path| !excludes.iter().any(|ptn| ptn.matches_path(path)))
+            .filter_map(|path| std::fs::metadata(long_path(&path)).ok().filter(|m| m.is_dir()).map(|m| (path, m)))
+            .collect();
+        // The same directory can match more than one glob
+        dirs.sort_by(|a, b| a.0.cmp(&b.0));
+        dirs.dedup_by(|a, b| a.0 == b.0);
+
+        Ok(dirs)
+    }
+
+    ///
+    /// Gets the directory each glob searches beneath, being its leading components 
+    /// which hold no pattern characters, normalized where they can be
+    /// 
+    pub fn glob_roots(&self) -> Vec<PathBuf> {
+        // An unreadable glob list fails the scan itself
+        self.all_globs().unwrap_or_else(|_| self.globs.clone()).iter().map(|ptn| {
+            let root: PathBuf = Path::new(ptn).components()
+                .take_while(|component| !component.as_os_str().to_string_lossy().contains(['*', '?', '[']))
+                .collect();
+            normalize_path(&root, self.canonicalize).unwrap_or(root)
+        }).collect()
+    }
+
+    ///
+    /// Gets those of the given `paths` whose files still exist, but are left out of the
+    /// backup by the filters: by matching an exclude pattern, by their size, by any added 
+    /// filter, or by being beneath a directory whose subtree an added filter excludes.
+    /// A file which no longer exists isn't excluded, but deleted.
+    /// Fails if any of the configured patterns are invalid.
+    /// 
+    pub fn excluded_paths(&self, paths: impl IntoIterator<Item = PathBuf>) -> Result<Vec<PathBuf>> {
+        let (_, excludes) = self.parse_patterns()?;
+        let filters = self.all_filters(excludes);
+        if filters.is_empty() {
+            return Ok(Vec::new());
+        }
+        let roots = self.glob_roots();
+        // Whether each directory's subtree is excluded, since catalogued files share their directories
+        let mut excluded_subtrees: HashMap<PathBuf, bool> = HashMap::new();
+
+        Ok(paths.into_iter().filter(|path| {
+            let Ok(metadata) = std::fs::metadata(long_path(path)) else { return false };
+            if apply_filters(&filters, path, &metadata).is_some() {
+                return true;
+            }
+            // Only the directories the globs searched were given to the filters
+            path.ancestors().skip(1)
+                .take_while(|dir| roots.iter().any(|root| dir.starts_with(root) && dir != root))
+                .any(|dir| *excluded_subtrees.entry(dir.to_path_buf()).or_insert_with(|| {
+                    std::fs::metadata(long_path(dir)).is_ok_and(|metadata| 
+                        matches!(apply_filters(&filters, dir, &metadata), Some((_, FilterDecision::ExcludeSubtree))))
+                }))
+        }).collect())
+    }
+
+    ///
+    /// Gets every filter paths are given to in order: the exclude globs and size limits
+    /// if they're configured, followed by the added filters
+    /// 
+    fn all_filters(&self, excludes: Vec<Pattern>) -> Vec<Arc<dyn PathFilter>> {
+        let mut filters: Vec<Arc<dyn PathFilter>> = Vec::new();
+        if !excludes.is_empty() {
+            filters.push(Arc::new(ExcludeGlobFilter { patterns: excludes }));
+        }
+        if self.min_size.is_some() || self.max_size.is_some() {
+            filters.push(Arc::new(SizeFilter { min_size: self.min_size, max_size: self.max_size }));
+        }
+        filters.extend(self.filters.iter().cloned());
+
+        filters
+    }
+
+    ///
+    /// Parses every glob and exclude pattern up front, so an invalid 
+    /// pattern fails a scan before any files are found
+    /// 
+    fn parse_patterns(&self) -> Result<(Vec<Paths>, Vec<Pattern>)> {
+        let globs = self.all_globs()?.iter()
+            .map(|ptn| glob(ptn))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let excludes = self.exclude_globs.iter()
+            .map(|ptn| Pattern::new(ptn))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        Ok((globs, excludes))
+    }
+
+    ///
+    /// Gets the configured globs, followed by those in the glob list file if one is set
+    /// 
+    fn all_globs(&self) -> Result<Vec<String>> {
+        let mut globs = self.globs.clone();
+        if let Some(list) = &self.glob_list_file {
+            globs.extend(load_glob_patterns_from_file(list)?);
+        }
+
+        Ok(globs)
+    }
+}
+
+impl FileScannerTrait for FileScanner {
+    fn scan(&self) -> Result<Box<dyn Iterator<Item = PathBuf>>> {
+        let paths = self.scan_with_metadata()?
+            .filter_map(|file| match file {
+                Ok((path, _)) => Some(path),
+                Err(Error::MetadataError(path, e) | Error::UnreadableDir(path, e)) => {
+                    tracing::warn!("Could not read {}: {}", path.display(), e);
+                    None
+                },
+                Err(_) => None
+            });
+
+        Ok(Box::new(paths))
+    }
+}
+
+///
+/// Whether the directory at `dir` keeps apart files whose names differ only in case,
+/// found by creating two probe files named so in it, then deleting them
+/// 
+pub fn probe_case_sensitivity(dir: &Path) -> std::io::Result<bool> {
+    let (lower, upper) = (dir.join(".drive_backup_case_probe"), dir.join(".DRIVE_BACKUP_CASE_PROBE"));
+    std::fs::File::create(&lower)?;
+    let sensitive = match std::fs::OpenOptions::new().write(true).create_new(true).open(&upper) {
+        Ok(_) => true,
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => false,
+        Err(e) => {
+            let _ = std::fs::remove_file(&lower);
+            return Err(e);
+        }
+    };
+    if sensitive {
+        std::fs::remove_file(&upper)?;
+    }
+    std::fs::remove_file(&lower)?;
+
+    Ok(sensitive)
+}
+
+///
+/// The filesystem type numbers `statfs` reports for network filesystems: NFS, SMB, CIFS, SMB2,
+/// Coda, AFS, 9P and Ceph
+/// 
+#[cfg(target_os = "linux")]
+const NETWORK_FS_MAGICS: [u32; 8] = [0x6969, 0x517B, 0xFF53_4D42, 0xFE53_4D42, 0x7375_7245, 0x5346_414F, 0x0102_1997, 0x00C3_6400];
+
+///
+/// Whether the directory at `dir` is on a network filesystem, such as a share on a NAS,
+/// told by the type `statfs` reports for it
+/// 
+#[cfg(target_os = "linux")]
+pub fn is_network_filesystem(dir: &Path) -> std::io::Result<bool> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = std::ffi::CString::new(dir.as_os_str().as_bytes())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let mut stat = std::mem::MaybeUninit::<libc::statfs>::uninit();
+    if unsafe { libc::statfs(dir.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // The type is signed on some targets, so only its bits are compared
+    let fs_type = unsafe { stat.assume_init() }.f_type as u32;
+
+    Ok(NETWORK_FS_MAGICS.contains(&fs_type))
+}
+///
+/// Whether the directory at `dir` is on a network filesystem, such as a share on a NAS.
+/// Only UNC paths, like `\\server\share`, are known to be, as mapped drives look like any other.
+/// 
+#[cfg(windows)]
+pub fn is_network_filesystem(dir: &Path) -> std::io::Result<bool> {
+    let dir = dir.to_string_lossy();
+    let dir = dir.strip_prefix(r"\\?\UNC\").map(|share| format!(r"\\{}", share)).unwrap_or(dir.to_string());
+    Ok(dir.starts_with(r"\\") && !dir.starts_with(r"\\?\") && !dir.starts_with(r"\\.\"))
+}
+///
+/// Network filesystems can't be told apart on other platforms
+/// 
+#[cfg(not(any(target_os = "linux", windows)))]
+pub fn is_network_filesystem(_dir: &Path) -> std::io::Result<bool> {
+    Ok(false)
+}
+
+///
+/// Reads a list of paths from `reader`, one per line, or separated by NUL bytes
+/// if `null_separated` is set, as written by `find -print0`. Empty entries are ignored.
+/// 
+pub fn read_path_list(mut reader: impl Read, null_separated: bool) -> std::io::Result<Vec<PathBuf>> {
+    let mut contents = Vec::new();
+    reader.read_to_end(&mut contents)?;
+    let separator = if null_separated { b'\0' } else { b'\n' };
+
+    Ok(contents.split(|b| *b == separator).filter(|entry| !entry.is_empty()).map(path_from_bytes).collect())
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(<std::ffi::OsStr as std::os::unix::ffi::OsStrExt>::from_bytes(bytes))
+}
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+///
+/// Reads the glob patterns listed in the file at `path`, one per line, like `rsync --include-from`.
+/// Empty lines, and comments starting with `#`, are skipped.
+/// 
+pub fn load_glob_patterns_from_file(path: &Path) -> Result<Vec<String>> {
+    let contents = std::fs::read_to_string(path).map_err(|e| Error::UnreadableGlobList(path.to_path_buf(), e))?;
+
+    Ok(contents.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+///
+/// Gets every file matching any of the globs in `glob_iter`, along with its metadata
+/// 
+pub fn get_glob_files_with_metadata(glob_iter: impl Iterator<Item = String>) -> Result<ScannedFiles> {
+    FileScanner { globs: glob_iter.collect(), follow_symlinks: true, ..Default::default() }.scan_with_metadata()
+}
+
+///
+/// Leaves out every file in `paths` which wasn't modified after `cutoff`, a UTC time, judged by 
+/// the modification time in its metadata. Files whose modification time can't be read are kept.
+/// 
+pub fn filter_unmodified_since(
+    paths: impl Iterator<Item = (PathBuf, Metadata)>, cutoff: NaiveDateTime
+) -> impl Iterator<Item = (PathBuf, Metadata)> {
+    paths.filter(move |(_, metadata)| is_modified_since(metadata, cutoff))
+}
+
+///
+/// Whether the file with the given `metadata` was modified after `cutoff`, a UTC time.
+/// A file whose modification time can't be read is taken as modified
+/// 
+pub fn is_modified_since(metadata: &Metadata, cutoff: NaiveDateTime) -> bool {
+    match metadata.modified() {
+        Ok(modified) => DateTime::<Utc>::from(modified).naive_utc() > cutoff,
+        Err(_) => true
+    }
+}
+
+///
+/// Gets the unix permission bits of a file from its `metadata`, or `None` on other platforms
+/// 
+pub fn permissions_of(metadata: &Metadata) -> Option<u32> {
+    #[cfg(unix)]
+    return Some(std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()));
+    #[cfg(not(unix))]
+    return { let _ = metadata; None };
+}
+
+///
+/// Sets the unix permission bits of the file at `path` to `mode`. Does nothing on other platforms
+/// 
+pub fn set_permissions(path: &Path, mode: u32) -> std::io::Result<()> {
+    #[cfg(unix)]
+    return std::fs::set_permissions(path, std::os::unix::fs::PermissionsExt::from_mode(mode));
+    #[cfg(not(unix))]
+    return { let _ = (path, mode); Ok(()) };
+}
+
+///
+/// Converts the given `path` into an absolute path, resolving it as
+/// far as the given `policy` allows
+/// 
+pub fn normalize_path(path: &Path, policy: CanonicalizePolicy) -> std::io::Result<PathBuf> {
+    normalize_path_as(path, policy).map(|(path, _)| path)
+}
+
+///
+/// Converts the given `path` into an absolute path like `normalize_path`, also returning 
+/// the policy it was actually normalized with. Some FUSE and overlay filesystems fail to 
+/// canonicalize paths to files which can still be read, so those are made absolute lexically
+/// instead, as the `None` policy does, rather than failing
+/// 
+pub fn normalize_path_as(path: &Path, policy: CanonicalizePolicy) -> std::io::Result<(PathBuf, CanonicalizePolicy)> {
+    let canonicalized = match policy {
+        CanonicalizePolicy::Full => std::fs::canonicalize(path),
+        CanonicalizePolicy::ParentsOnly => match (path.parent(), path.file_name()) {
+            // Canonicalize everything up to the final component, so that
+            // `..` is still resolved relative to any symlinks before it
+            (Some(parent), Some(file_name)) => {
+                let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+                std::fs::canonicalize(parent).map(|parent| parent.join(file_name))
+            },
+            // The path ends in a root or `..`, which have no name to keep
+            _ => std::fs::canonicalize(path)
+        },
+        CanonicalizePolicy::None => return Ok((absolutize(path)?, policy)),
+    };
+
+    match canonicalized {
+        Ok(canonical) => Ok((normalize_drive_letter(&canonical), policy)),
+        // A dangling symlink can't be read either, so still fails
+        Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound | std::io::ErrorKind::PermissionDenied) 
+            && std::fs::metadata(long_path(path)).is_ok() => {
+            tracing::debug!("Couldn't canonicalize {}, so it's only made absolute: {}", path.display(), e);
+            Ok((absolutize(path)?, CanonicalizePolicy::None))
+        },
+        Err(e) => Err(e)
+    }
+}
+
+///
+/// Makes `path` absolute against the current directory, resolving `.` and `..` lexically
+/// 
+fn absolutize(path: &Path) -> std::io::Result<PathBuf> {
+    Ok(normalize_drive_letter(&normalize_lexically(&std::env::current_dir()?.join(path))))
+}
+
+///
+/// Uppercases the drive letter of the Windows `path`, since Windows paths can start with either
+/// `C:` or `c:` depending on how they were given, but the catalog records the drive as one directory.
+/// Elsewhere, `path` is returned as it is.
+/// 
+pub fn normalize_drive_letter(path: &Path) -> PathBuf {
+    #[cfg(windows)]
+    if let Some(normalized) = path.to_str().and_then(uppercase_drive_letter) {
+        return PathBuf::from(normalized);
+    }
+
+    path.to_path_buf()
+}
+
+///
+/// Gets `path` with its drive letter uppercased, allowing for the `\\?\` extended-length prefix,
+/// or `None` if it has no lowercase drive letter
+/// 
+#[cfg_attr(not(windows), allow(dead_code))]
+fn uppercase_drive_letter(path: &str) -> Option<String> {
+    let start = if path.starts_with(r"\\?\") { 4 } else { 0 };
+    let bytes = path.as_bytes();
+    if bytes.len() < start + 2 || !bytes[start].is_ascii_lowercase() || bytes[start + 1] != b':' {
+        return None;
+    }
+
+    let mut path = path.to_string();
+    path[start..start + 1].make_ascii_uppercase();
+    Some(path)
+}
+
+///
+/// Removes all `.` components from the given `path`, and resolves each `..`
+/// by removing the component before it, without touching the filesystem
+/// 
+fn normalize_lexically(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => { },
+            Component::ParentDir => { normalized.pop(); },
+            component => normalized.push(component),
+        }
+    }
+
+    normalized
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{fs::Metadata, os::unix::{ffi::OsStrExt, fs::symlink}, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::{Duration, SystemTime}};
+
+    use chrono::{DateTime, Utc};
+
+    use crate::config::CanonicalizePolicy;
+
+    use super::{classify_path, error::Error, filter::{FilterDecision, PathFilter}, filter_unmodified_since, get_glob_files_with_metadata, load_glob_patterns_from_file, normalize_drive_letter, normalize_path, normalize_path_as, probe_case_sensitivity, uppercase_drive_letter, read_path_list, set_permissions, FileScanner, FileScannerTrait, PathClassification};
+
+    ///
+    /// Builds the tree `real/file.txt`, `link_dir -> real` and `link_file -> real/file.txt`
+    /// 
+    fn build_symlink_tree() -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap();
+        std::fs::create_dir(root.join("real")).unwrap();
+        std::fs::write(root.join("real/file.txt"), "contents").unwrap();
+        symlink(root.join("real"), root.join("link_dir")).unwrap();
+        symlink(root.join("real/file.txt"), root.join("link_file")).unwrap();
+
+        (dir, root)
+    }
+
+    #[test]
+    fn test_normalize_full_resolves_every_symlink() {
+        let (_dir, root) = build_symlink_tree();
+        let policy = CanonicalizePolicy::Full;
+
+        assert_eq!(normalize_path(&root.join("link_dir/file.txt"), policy).unwrap(), root.join("real/file.txt"));
+        assert_eq!(normalize_path(&root.join("link_file"), policy).unwrap(), root.join("real/file.txt"));
+    }
+
+    #[test]
+    fn test_normalize_parents_only_keeps_final_symlink() {
+        let (_dir, root) = build_symlink_tree();
+        let policy = CanonicalizePolicy::ParentsOnly;
+
+        assert_eq!(normalize_path(&root.join("link_dir/file.txt"), policy).unwrap(), root.join("real/file.txt"));
+        assert_eq!(normalize_path(&root.join("link_file"), policy).unwrap(), root.join("link_file"));
+        assert_eq!(normalize_path(&root.join("link_dir/../link_file"), policy).unwrap(), root.join("link_file"));
+    }
+
+    #[test]
+    fn test_normalize_none_resolves_lexically() {
+        let (_dir, root) = build_symlink_tree();
+        let policy = CanonicalizePolicy::None;
+
+        assert_eq!(normalize_path(&root.join("link_dir/file.txt"), policy).unwrap(), root.join("link_dir/file.txt"));
+        assert_eq!(normalize_path(&root.join("link_file"), policy).unwrap(), root.join("link_file"));
+        assert_eq!(normalize_path(&root.join("real/./../link_file"), policy).unwrap(), root.join("link_file"));
+    }
+
+    #[test]
+    fn test_file_scanner_filters() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap();
+        std::fs::write(root.join("file.txt"), "contents").unwrap();
+        std::fs::write(root.join("empty.txt"), "").unwrap();
+        std::fs::write(root.join("skip.log"), "contents").unwrap();
+        symlink(root.join("file.txt"), root.join("link.txt")).unwrap();
+
+        let scanner = FileScanner {
+            globs: vec![format!("{}/*", root.display())],
+            exclude_globs: vec!["**/*.log".to_string()],
+            min_size: Some(1),
+            ..Default::default()
+        };
+        let paths: Vec<PathBuf> = scanner.scan().unwrap().collect();
+
+        // The symlink isn't followed, and the empty and excluded files are filtered out
+        assert_eq!(paths, vec![root.join("file.txt")]);
+    }
+
+    #[test]
+    fn test_excluded_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap();
+        std::fs::write(root.join("file.txt"), "contents").unwrap();
+        std::fs::write(root.join("large.txt"), "a".repeat(100)).unwrap();
+        std::fs::write(root.join("skip.log"), "contents").unwrap();
+
+        let scanner = FileScanner {
+            globs: vec![format!("{}/*", root.display())],
+            exclude_globs: vec!["**/*.log".to_string()],
+            max_size: Some(50),
+            ..Default::default()
+        };
+
+        let paths = ["file.txt", "large.txt", "skip.log", "gone.log"].map(|name| root.join(name));
+
+        // A file which is gone was deleted, whatever it matches
+        assert_eq!(scanner.excluded_paths(paths).unwrap(), [root.join("large.txt"), root.join("skip.log")]);
+    }
+
+    #[test]
+    fn test_file_scanner_skips_special_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap();
+        std::fs::write(root.join("file.txt"), "contents").unwrap();
+        let fifo = std::ffi::CString::new(root.join("pipe").as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+        symlink(root.join("pipe"), root.join("link_to_pipe")).unwrap();
+
+        assert_eq!(classify_path(&root.join("file.txt")), PathClassification::RegularFile);
+        assert_eq!(classify_path(&root.join("pipe")), PathClassification::NamedPipe);
+        assert_eq!(classify_path(&root.join("link_to_pipe")), PathClassification::Symlink);
+        assert_eq!(classify_path(&root), PathClassification::Directory);
+        assert_eq!(classify_path(std::path::Path::new("/dev/null")), PathClassification::DeviceFile);
+
+        // The pipe is skipped, whether it's matched directly or through the symlink
+        let scanner = FileScanner { globs: vec![format!("{}/*", root.display())], follow_symlinks: true, ..Default::default() };
+        let paths: Vec<PathBuf> = scanner.scan().unwrap().collect();
+        assert_eq!(paths, vec![root.join("file.txt")]);
+    }
+
+    #[test]
+    fn test_scan_listed_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap();
+        std::fs::write(root.join("first.txt"), "first").unwrap();
+        std::fs::write(root.join("second\nline.txt"), "second").unwrap();
+        let list = format!("{}\n{}\n\n{}\n", root.join("first.txt").display(), root.join("missing.txt").display(), root.display());
+
+        let paths = read_path_list(list.as_bytes(), false).unwrap();
+        assert_eq!(paths, vec![root.join("first.txt"), root.join("missing.txt"), root.clone()]);
+        // Separated by NUL bytes, a path can hold a newline
+        let list = [root.join("second\nline.txt").as_os_str().as_bytes(), b"\0", root.join("first.txt").as_os_str().as_bytes()].concat();
+        assert_eq!(read_path_list(list.as_slice(), true).unwrap(), vec![root.join("second\nline.txt"), root.join("first.txt")]);
+
+        let scanner = FileScanner { canonicalize: CanonicalizePolicy::Full, ..Default::default() };
+        let files: Vec<_> = scanner.scan_listed(paths).collect();
+        assert_eq!(files.len(), 3);
+        assert_eq!(files[0].as_ref().unwrap().0, root.join("first.txt"));
+        assert!(matches!(&files[1], Err(Error::MetadataError(path, _)) if *path == root.join("missing.txt")));
+        assert!(matches!(&files[2], Err(Error::NotAFile(path)) if *path == root));
+    }
+
+    #[test]
+    fn test_probe_case_sensitivity() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // Linux filesystems are case-sensitive, and the probe files are cleaned up
+        assert!(probe_case_sensitivity(dir.path()).unwrap());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+        assert!(probe_case_sensitivity(&dir.path().join("missing")).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_is_network_filesystem() {
+        let dir = tempfile::tempdir().unwrap();
+
+        assert!(!super::is_network_filesystem(dir.path()).unwrap());
+        assert!(super::is_network_filesystem(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_get_glob_files_with_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap();
+        std::fs::create_dir(root.join("sub")).unwrap();
+        std::fs::write(root.join("a.txt"), "12345").unwrap();
+        std::fs::write(root.join("b.txt"), "").unwrap();
+
+        let mut files: Vec<(PathBuf, u64)> = get_glob_files_with_metadata(vec![format!("{}/*", root.display())].into_iter())
+            .unwrap()
+            .map(|file| file.map(|(path, metadata)| (path, metadata.len())).unwrap())
+            .collect();
+        files.sort();
+
+        // The directory is skipped, and each file's size comes from the metadata found during the scan
+        assert_eq!(files, vec![(root.join("a.txt"), 5), (root.join("b.txt"), 0)]);
+    }
+
+    #[test]
+    fn test_filter_unmodified_since() {
+        let dir = tempfile::tempdir().unwrap();
+        let cutoff = SystemTime::now() - Duration::from_secs(3600);
+        for (name, modified) in [("old.txt", cutoff - Duration::from_secs(60)), ("at_cutoff.txt", cutoff), ("new.txt", SystemTime::now())] {
+            let path = dir.path().join(name);
+            std::fs::write(&path, "contents").unwrap();
+            std::fs::File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+        }
+        let files = std::fs::read_dir(dir.path()).unwrap()
+            .map(|entry| { let entry = entry.unwrap(); (entry.path(), entry.metadata().unwrap()) });
+
+        let kept: Vec<_> = filter_unmodified_since(files, DateTime::<Utc>::from(cutoff).naive_utc())
+            .map(|(path, _)| path)
+            .collect();
+
+        assert_eq!(kept, vec![dir.path().join("new.txt")]);
+    }
+
+    #[test]
+    fn test_scan_empty_dirs_includes_nested_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap();
+        std::fs::create_dir_all(root.join("skeleton/src/bin")).unwrap();
+        std::fs::create_dir_all(root.join("project/docs")).unwrap();
+        std::fs::write(root.join("project/docs/readme.md"), "contents").unwrap();
+        std::fs::create_dir(root.join("logs")).unwrap();
+        std::fs::write(root.join("logs/run.log"), "contents").unwrap();
+
+        let scanner = FileScanner {
+            globs: vec![format!("{}/**/*", root.display())],
+            exclude_globs: vec!["**/*.log".to_string()],
+            ..Default::default()
+        };
+        let files: Vec<PathBuf> = scanner.scan().unwrap().collect();
+        let dirs: Vec<PathBuf> = scanner.scan_empty_dirs(files.iter().filter_map(|path| path.parent())).unwrap()
+            .into_iter().map(|(path, _)| path).collect();
+
+        // A directory holding only excluded files has nothing to back up either
+        assert_eq!(dirs, vec![root.join("logs"), root.join("skeleton"), root.join("skeleton/src"), root.join("skeleton/src/bin")]);
+    }
+
+    #[test]
+    fn test_glob_roots_stop_at_first_wildcard() {
+        let scanner = FileScanner {
+            globs: vec!["/home/user/docs/**/*.md".to_string(), "/mnt/photos/20[0-9][0-9]/*".to_string(), "/srv/notes.txt".to_string()],
+            canonicalize: CanonicalizePolicy::None,
+            ..Default::default()
+        };
+
+        assert_eq!(scanner.glob_roots(), vec![
+            PathBuf::from("/home/user/docs"), PathBuf::from("/mnt/photos"), PathBuf::from("/srv/notes.txt")
+        ]);
+    }
+
+    #[test]
+    fn test_normalize_path_under_unreadable_parent() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap();
+        let locked = root.join("locked");
+        std::fs::create_dir(&locked).unwrap();
+        std::fs::write(locked.join("file.txt"), "contents").unwrap();
+        set_permissions(&locked, 0o311).unwrap();
+
+        // Whether it's canonicalized or only made absolute, the file is found without panicking
+        for policy in [CanonicalizePolicy::Full, CanonicalizePolicy::ParentsOnly, CanonicalizePolicy::None] {
+            let (path, _) = normalize_path_as(&locked.join("../locked/file.txt"), policy).unwrap();
+            assert_eq!(path, locked.join("file.txt"));
+        }
+        // A file which can't be read at all still fails
+        assert!(normalize_path_as(&locked.join("missing.txt"), CanonicalizePolicy::Full).is_err());
+        set_permissions(&locked, 0o755).unwrap();
+    }
+
+    #[test]
+    fn test_glob_list_file_is_merged_with_globs() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap();
+        for name in ["a.txt", "b.log", "c.md"] {
+            std::fs::write(root.join(name), "contents").unwrap();
+        }
+        let list = root.join(".backuplist");
+        std::fs::write(&list, format!("# Logs\n{}/*.log\n\n  # Notes\n{}/*.md\r\n", root.display(), root.display())).unwrap();
+        assert_eq!(load_glob_patterns_from_file(&list).unwrap(), [format!("{}/*.log", root.display()), format!("{}/*.md", root.display())]);
+
+        let scanner = FileScanner { globs: vec![format!("{}/*.txt", root.display())], glob_list_file: Some(list), ..Default::default() };
+        let mut found: Vec<PathBuf> = scanner.scan_with_metadata().unwrap().map(|file| file.unwrap().0).collect();
+        found.sort();
+        assert_eq!(found, [root.join("a.txt"), root.join("b.log"), root.join("c.md")]);
+
+        let missing = FileScanner { glob_list_file: Some(root.join("missing")), ..Default::default() };
+        assert!(matches!(missing.scan_with_metadata(), Err(Error::UnreadableGlobList(..))));
+    }
+
+    #[test]
+    fn test_normalize_drive_letter() {
+        assert_eq!(uppercase_drive_letter(r"c:\Users\Test").as_deref(), Some(r"C:\Users\Test"));
+        assert_eq!(uppercase_drive_letter(r"\\?\d:\Users\Test").as_deref(), Some(r"\\?\D:\Users\Test"));
+        assert_eq!(uppercase_drive_letter(r"C:\Users\Test"), None);
+        assert_eq!(uppercase_drive_letter("/home/test"), None);
+
+        // Only Windows paths have drive letters
+        assert_eq!(normalize_drive_letter(Path::new(r"c:\Users\Test")), PathBuf::from(r"c:\Users\Test"));
+    }
+
+    ///
+    /// Decides by `decide`, recording every path it's given
+    /// 
+    struct RecordingFilter {
+        name: &'static str,
+        decide: fn(&Path) -> FilterDecision,
+        seen: Mutex<Vec<PathBuf>>
+    }
+
+    impl RecordingFilter {
+        fn new(name: &'static str, decide: fn(&Path) -> FilterDecision) -> Arc<Self> {
+            Arc::new(Self { name, decide, seen: Mutex::default() })
+        }
+    }
+
+    impl PathFilter for RecordingFilter {
+        fn name(&self) -> &str {
+            self.name
+        }
+        fn include(&self, path: &Path, _meta: &Metadata) -> FilterDecision {
+            self.seen.lock().unwrap().push(path.to_path_buf());
+            (self.decide)(path)
+        }
+    }
+
+    #[test]
+    fn test_added_filters_run_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap();
+        std::fs::create_dir_all(root.join("assets/textures")).unwrap();
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        for name in ["assets/model.obj", "assets/textures/wood.png", "docs/notes.txt", "docs/draft.tmp", "docs/debug.log"] {
+            std::fs::write(root.join(name), "contents").unwrap();
+        }
+        let no_temp = RecordingFilter::new("no temp files", |path| match path.extension().is_some_and(|ext| ext == "tmp") {
+            true => FilterDecision::Exclude,
+            false => FilterDecision::Include
+        });
+        let asset_db = RecordingFilter::new("asset database", |path| match path.ends_with("assets") {
+            true => FilterDecision::ExcludeSubtree,
+            false => FilterDecision::Include
+        });
+        let scanner = FileScanner { globs: vec![format!("{}/**/*", root.display())], exclude_globs: vec!["**/*.log".to_string()], ..Default::default() }
+            .with_filter(no_temp.clone())
+            .with_filter(asset_db.clone());
+
+        let found: Vec<PathBuf> = scanner.scan().unwrap().collect();
+
+        assert_eq!(found, [root.join("docs/notes.txt")]);
+        // Each filter is only given what the filters before it included
+        assert!(!no_temp.seen.lock().unwrap().contains(&root.join("docs/debug.log")));
+        assert!(!asset_db.seen.lock().unwrap().contains(&root.join("docs/draft.tmp")));
+        // Nothing beneath the excluded subtree reaches any filter
+        assert!(no_temp.seen.lock().unwrap().iter().all(|path| !path.starts_with(root.join("assets/")) || *path == root.join("assets")));
+        assert_eq!(scanner.exclusion_counts(), [
+            ("exclude_globs".to_string(), 1), ("no temp files".to_string(), 1), ("asset database".to_string(), 0)
+        ]);
+
+        // Files already backed up beneath the excluded subtree still exist, so are excluded rather than deleted
+        let catalog = ["assets/textures/wood.png", "docs/notes.txt", "docs/draft.tmp"].map(|name| root.join(name));
+        assert_eq!(scanner.excluded_paths(catalog).unwrap(), [root.join("assets/textures/wood.png"), root.join("docs/draft.tmp")]);
+    }
+}
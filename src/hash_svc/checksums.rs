@@ -0,0 +1,186 @@
+use std::{collections::HashMap, path::{Path, PathBuf}};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use crate::config::HashEncoding;
+
+///
+/// A line of a checksum file which disagrees with the catalog
+/// 
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChecksumMismatch {
+    /// The file's latest version in the catalog has a different hash, both given as hex
+    Differs { path: PathBuf, listed: String, recorded: String },
+    /// The catalog has no version of the file which hasn't been deleted
+    NotInCatalog { path: PathBuf }
+}
+
+///
+/// Encodes a hash as stored in the catalog, which is always base64, with the given `encoding`.
+/// A hash which isn't valid base64 is returned as it is.
+/// 
+pub fn encode_hash(hsh: &str, encoding: HashEncoding) -> String {
+    match (encoding, STANDARD.decode(hsh)) {
+        (HashEncoding::Hex, Ok(bytes)) => bytes.iter().map(|byte| format!("{:02x}", byte)).collect(),
+        _ => hsh.to_string()
+    }
+}
+
+///
+/// Decodes a hash written in hex, in either case, into the base64 it's stored as in the catalog
+/// 
+pub fn decode_hex_hash(hex: &str) -> Option<String> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    let bytes = (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+
+    Some(STANDARD.encode(bytes))
+}
+
+///
+/// Formats a line of a checksum file as written by coreutils' `md5sum`, from a hash as 
+/// stored in the catalog. Paths holding a backslash, carriage return or newline are 
+/// escaped the way coreutils escapes them, with the line marked by a leading backslash.
+/// 
+pub fn format_checksum_line(hsh: &str, path: &Path) -> String {
+    let hex = encode_hash(hsh, HashEncoding::Hex);
+    let path = path.to_string_lossy();
+    if !path.contains(['\\', '\n', '\r']) {
+        return format!("{}  {}", hex, path);
+    }
+
+    let escaped = path.replace('\\', "\\\\").replace('\n', "\\n").replace('\r', "\\r");
+    format!("\\{}  {}", hex, escaped)
+}
+
+///
+/// Parses a line of a checksum file written by coreutils, in text or binary mode, into 
+/// the hash as it would be stored in the catalog and the path. Returns `None` if it's malformed.
+/// 
+pub fn parse_checksum_line(line: &str) -> Option<(String, PathBuf)> {
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(line) => (true, line),
+        None => (false, line)
+    };
+    let (hex, path) = line.split_once(' ')?;
+    // A second space marks text mode, and an asterisk binary mode
+    let path = path.strip_prefix([' ', '*'])?;
+    if path.is_empty() {
+        return None;
+    }
+    let path = match escaped {
+        true => unescape(path)?,
+        false => path.to_string()
+    };
+
+    Some((decode_hex_hash(hex)?, PathBuf::from(path)))
+}
+
+fn unescape(path: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(path.len());
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next()? {
+            '\\' => unescaped.push('\\'),
+            'n' => unescaped.push('\n'),
+            'r' => unescaped.push('\r'),
+            _ => return None
+        }
+    }
+
+    Some(unescaped)
+}
+
+///
+/// Compares every line of a coreutils checksum file against the `catalog`, mapping the full
+/// path of every file to the hash of its latest version. Malformed lines are warned about and skipped.
+/// 
+pub fn check_checksums(checksums: &str, catalog: &HashMap<PathBuf, String>) -> Vec<ChecksumMismatch> {
+    let mut mismatches = Vec::new();
+    for (i, line) in checksums.lines().enumerate().filter(|(_, line)| !line.is_empty()) {
+        let Some((listed, path)) = parse_checksum_line(line) else {
+            tracing::warn!("Skipping line {} of the checksum file, which isn't formatted like md5sum's output", i + 1);
+            continue;
+        };
+        match catalog.get(&path) {
+            None => mismatches.push(ChecksumMismatch::NotInCatalog { path }),
+            Some(recorded) if *recorded != listed => mismatches.push(ChecksumMismatch::Differs { 
+                path, listed: encode_hash(&listed, HashEncoding::Hex), recorded: encode_hash(recorded, HashEncoding::Hex) 
+            }),
+            Some(_) => { }
+        }
+    }
+
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::PathBuf};
+
+    use crate::{config::{HashAlgorithm, HashEncoding}, hash_svc::hash_reader};
+
+    use super::{check_checksums, encode_hash, format_checksum_line, parse_checksum_line, ChecksumMismatch};
+
+    #[test]
+    fn test_hex_encoding_matches_md5sum() {
+        // As printed by `printf 'hello' | md5sum`
+        let hsh = hash_reader("hello".as_bytes(), HashAlgorithm::Md5).unwrap().0;
+
+        assert_eq!(encode_hash(&hsh, HashEncoding::Hex), "5d41402abc4b2a76b9719d911017c592");
+        assert_eq!(encode_hash(&hsh, HashEncoding::Base64), hsh);
+    }
+
+    #[test]
+    fn test_checksum_lines_round_trip() {
+        let hsh = hash_reader("hello".as_bytes(), HashAlgorithm::Md5).unwrap().0;
+        let paths = ["/home/user/plain.txt", "/home/user/with spaces.txt", "/home/user/new\nline.txt", "/home/user/back\\slash.txt"];
+
+        for path in paths.map(PathBuf::from) {
+            let line = format_checksum_line(&hsh, &path);
+            assert!(!line.contains('\n'));
+            assert_eq!(parse_checksum_line(&line), Some((hsh.clone(), path)));
+        }
+        assert_eq!(
+            format_checksum_line(&hsh, &PathBuf::from("/home/user/new\nline.txt")),
+            "\\5d41402abc4b2a76b9719d911017c592  /home/user/new\\nline.txt"
+        );
+        // Binary mode lines, and hashes in upper case, are read too
+        assert_eq!(
+            parse_checksum_line("5D41402ABC4B2A76B9719D911017C592 */data/file.bin"),
+            Some((hsh, PathBuf::from("/data/file.bin")))
+        );
+        assert_eq!(parse_checksum_line("not a checksum line"), None);
+    }
+
+    #[test]
+    fn test_check_checksums_finds_planted_mismatch() {
+        let (hello, world) = (hash_reader("hello".as_bytes(), HashAlgorithm::Md5).unwrap().0, hash_reader("world".as_bytes(), HashAlgorithm::Md5).unwrap().0);
+        let catalog = HashMap::from([
+            (PathBuf::from("/docs/a file.txt"), hello.clone()),
+            (PathBuf::from("/docs/b.txt"), world.clone()),
+        ]);
+        let checksums = [
+            format_checksum_line(&hello, &PathBuf::from("/docs/a file.txt")),
+            // Planted: the other system holds different contents for b.txt
+            format_checksum_line(&hello, &PathBuf::from("/docs/b.txt")),
+            format_checksum_line(&hello, &PathBuf::from("/docs/unknown.txt")),
+            "garbage".to_string(),
+        ].join("\n");
+
+        assert_eq!(check_checksums(&checksums, &catalog), vec![
+            ChecksumMismatch::Differs { 
+                path: PathBuf::from("/docs/b.txt"), 
+                listed: encode_hash(&hello, HashEncoding::Hex), recorded: encode_hash(&world, HashEncoding::Hex) 
+            },
+            ChecksumMismatch::NotInCatalog { path: PathBuf::from("/docs/unknown.txt") },
+        ]);
+    }
+}
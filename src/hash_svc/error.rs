@@ -1,3 +1,5 @@
+use std::{fmt::Display, path::{Path, PathBuf}};
+
 use tokio::task::JoinError;
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -5,7 +7,54 @@ pub type Result<T> = std::result::Result<T, Error>;
 #[derive(Debug)]
 pub enum Error {
     JoinError(JoinError),
-    FileReadError(tokio::io::Error)
+    FileReadError(tokio::io::Error),
+    /// Reading the file at `path` failed while it was being hashed
+    FileError { path: PathBuf, source: tokio::io::Error },
+    /// The task hashing the file at `path` panicked
+    TaskPanicked { path: PathBuf }
+}
+
+impl Error {
+    ///
+    /// Gets the path of the file the error occurred while hashing, if it's known
+    /// 
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Error::FileError { path, .. } | Error::TaskPanicked { path } => Some(path),
+            Error::JoinError(_) | Error::FileReadError(_) => None
+        }
+    }
+
+    ///
+    /// Attaches the `path` of the file being hashed to a read error
+    /// 
+    pub(crate) fn with_path(self, path: &Path) -> Self {
+        match self {
+            Error::FileReadError(source) => Error::FileError { path: path.to_path_buf(), source },
+            e => e
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::JoinError(e) => write!(f, "{}", e),
+            Error::FileReadError(e) => write!(f, "{}", e),
+            Error::FileError { path, source } => write!(f, "could not hash {}: {}", path.display(), source),
+            Error::TaskPanicked { path } => write!(f, "hashing {} panicked", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::JoinError(e) => Some(e),
+            Error::FileReadError(e) | Error::FileError { source: e, .. } => Some(e),
+            Error::TaskPanicked { .. } => None
+        }
+    }
 }
 
 impl From<JoinError> for Error {
@@ -18,4 +67,4 @@ impl From<tokio::io::Error> for Error {
     fn from(value: tokio::io::Error) -> Self {
         Error::FileReadError(value)
     }
-}
\ No newline at end of file
+}
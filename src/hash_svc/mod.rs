@@ -1,71 +1,452 @@
+pub mod checksums;
 pub mod error;
 
-use std::path::PathBuf;
+use std::{fs::Metadata, io::Read, path::PathBuf, sync::OnceLock};
 
-use async_stream::stream;
+use async_stream::{stream, try_stream};
 use base64::{engine::general_purpose::STANDARD, Engine};
-use futures_util::Stream;
-use lazy_static::lazy_static;
-use tokio::{io::AsyncReadExt, sync::Semaphore, task::JoinSet};
+use futures_util::{pin_mut, stream::FuturesUnordered, Stream, StreamExt};
+use tokio::{io::AsyncReadExt, sync::Semaphore, task::JoinHandle};
+use sha2::{Digest, Sha256};
+use tokio_util::bytes::{Bytes, BytesMut};
+
+use crate::{config::HashAlgorithm, file_svc::long_path::long_path};
 
 use error::*;
 
-lazy_static! {
-    static ref POOL: Semaphore = Semaphore::new(num_cpus::get());
+///
+/// Limits the number of files hashed at once to the number of CPUs
+/// 
+static POOL: OnceLock<Semaphore> = OnceLock::new();
+
+///
+/// The number of bytes of a file held in memory at once while it's hashed
+/// 
+const HASH_CHUNK_SIZE: usize = 64 * 1024;
+
+///
+/// The number of files `gen_hashes` spawns tasks for at once, bounding the memory
+/// held by the tasks however many files are found
+/// 
+pub const HASH_BATCH_SIZE: usize = 1024;
+
+///
+/// Builds up the hash of bytes consumed in turn, with the algorithm it was created for
+/// 
+pub enum Hasher {
+    Md5(md5::Context),
+    Sha256(Sha256)
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Md5 => Hasher::Md5(md5::Context::new()),
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+        }
+    }
+    ///
+    /// Adds `bytes` to the hash
+    /// 
+    pub fn consume(&mut self, bytes: &[u8]) {
+        match self {
+            Hasher::Md5(ctx) => ctx.consume(bytes),
+            Hasher::Sha256(ctx) => ctx.update(bytes),
+        }
+    }
+    ///
+    /// Gets the hash of every byte consumed, encoded in base64 as it's stored in the catalog
+    /// 
+    pub fn finish(self) -> String {
+        match self {
+            Hasher::Md5(ctx) => STANDARD.encode(ctx.compute().0),
+            Hasher::Sha256(ctx) => STANDARD.encode(ctx.finalize()),
+        }
+    }
+}
+
+///
+/// Gets the algorithm the hash `hsh`, as stored in the catalog, was made with, 
+/// told apart by its length. Returns `None` if it isn't a hash either could make.
+/// 
+pub fn algorithm_of(hsh: &str) -> Option<HashAlgorithm> {
+    match STANDARD.decode(hsh).ok()?.len() {
+        16 => Some(HashAlgorithm::Md5),
+        32 => Some(HashAlgorithm::Sha256),
+        _ => None
+    }
 }
 
 ///
-/// Generates a collection of MD5 hashes for all files provided with the given PathBufs
-/// Returns mapped with the path to the file.
+/// Generates a collection of hashes for all files provided with the given PathBufs, made with `algorithm`,
+/// along with any metadata already read for them while scanning, so they aren't stated again.
+/// Returns mapped with the path to the file, and the number of bytes hashed.
+/// Results are returned in completion order, not input order, use `gen_hashes_ordered`
+/// if order matters. A file which can't be hashed is returned as an error naming it,
+/// without ending the stream. Files are hashed `HASH_BATCH_SIZE` at a time, see `gen_hashes_chunked`.
 /// 
-pub fn gen_hashes(file_paths: impl Iterator<Item = PathBuf>) -> impl Stream<Item = Result<(PathBuf, String)>> {
+pub fn gen_hashes(
+    file_paths: impl Iterator<Item = (PathBuf, Option<Metadata>)>, algorithm: HashAlgorithm
+) -> impl Stream<Item = Result<(PathBuf, String, u64)>> {
+    gen_hashes_chunked(file_paths, HASH_BATCH_SIZE, algorithm)
+}
+
+///
+/// Generates the same hashes as `gen_hashes`, taking `chunk` files at a time from `file_paths`
+/// and finishing every one of them before taking the next, so no more than `chunk` tasks are
+/// held at once. Within a chunk, results are returned in completion order.
+/// 
+pub fn gen_hashes_chunked(
+    mut file_paths: impl Iterator<Item = (PathBuf, Option<Metadata>)>, chunk: usize, algorithm: HashAlgorithm
+) -> impl Stream<Item = Result<(PathBuf, String, u64)>> {
+    let chunk = chunk.max(1);
     // Create an async Stream
     stream! {
-        // All tasks joined together at the end of the process
-        let mut tasks = JoinSet::new();
-        // For every PathBuf found, if that PathBuf is a file, generate
-        // a new task to create an MD5 hash for it, to be returned
-        for path in file_paths {
-            tasks.spawn(hash_file_path(path));
-        }
+        loop {
+            // For each PathBuf in the next chunk, generate a new task to create 
+            // a hash for it, to be returned
+            let mut tasks: FuturesUnordered<_> = file_paths.by_ref()
+                .take(chunk)
+                .map(|(path, metadata)| join_hash_task(path.clone(), tokio::spawn(hash_file_path(path, metadata, algorithm))))
+                .collect();
+            if tasks.is_empty() {
+                break;
+            }
 
-        // Yield each PathBuf/MD5 hash generated from the tasks spawned above
-        while let Some(cx) = tasks.join_next().await {
-            yield Ok(cx??);
+            // Yield each PathBuf/hash generated from the tasks spawned above
+            while let Some(hashed) = tasks.next().await {
+                yield hashed;
+            }
         }
     }
 }
 
 ///
-/// Generates an MD5 hash for the given file, found at the given PathBuf
+/// Generates the same hashes as `gen_hashes`, but returned in the order of `file_paths`.
+/// A file which takes a long time to hash holds back the results of the files after it.
 /// 
-async fn hash_file_path(path: PathBuf) -> Result<(PathBuf, String)> {
+pub fn gen_hashes_ordered(
+    file_paths: impl Iterator<Item = (PathBuf, Option<Metadata>)>, algorithm: HashAlgorithm
+) -> impl Stream<Item = Result<(PathBuf, String, u64)>> {
+    gen_hashes_buffered(file_paths, num_cpus::get(), algorithm)
+}
+
+///
+/// Generates the same hashes as `gen_hashes_ordered`, hashing at most `concurrency` files at once.
+/// No more files are hashed at once than there are CPUs, however high `concurrency` is.
+/// 
+pub fn gen_hashes_buffered(
+    file_paths: impl Iterator<Item = (PathBuf, Option<Metadata>)>, concurrency: usize, algorithm: HashAlgorithm
+) -> impl Stream<Item = Result<(PathBuf, String, u64)>> {
+    futures_util::stream::iter(file_paths)
+        .map(move |(path, metadata)| join_hash_task(path.clone(), tokio::spawn(hash_file_path(path, metadata, algorithm))))
+        .buffered(concurrency.max(1))
+}
+
+///
+/// Generates the same hashes as `gen_hashes`, for files taken from the `files` stream as they arrive
+/// rather than all known beforehand. Each is given with a `key`, returned alongside its result.
+/// No more than `HASH_BATCH_SIZE` files are waited on at once, and results are returned in completion order.
+/// 
+pub fn gen_hashes_streamed<K>(
+    files: impl Stream<Item = (K, PathBuf, Option<Metadata>)>, algorithm: HashAlgorithm
+) -> impl Stream<Item = (K, Result<(PathBuf, String, u64)>)> {
+    files
+        .map(move |(key, path, metadata)| {
+            let hashed = join_hash_task(path.clone(), tokio::spawn(hash_file_path(path, metadata, algorithm)));
+            async move { (key, hashed.await) }
+        })
+        .buffer_unordered(HASH_BATCH_SIZE)
+}
+
+///
+/// Waits for the `task` hashing the file at `path`. The path is kept outside the task,
+/// so that if it panics, the error still names the file.
+/// 
+async fn join_hash_task(path: PathBuf, task: JoinHandle<Result<(PathBuf, String, u64)>>) -> Result<(PathBuf, String, u64)> {
+    match task.await {
+        Ok(hashed) => hashed,
+        Err(e) if e.is_panic() => Err(Error::TaskPanicked { path }),
+        Err(e) => Err(e.into())
+    }
+}
+
+///
+/// Generates a hash with `algorithm` for the given file, found at the given PathBuf,
+/// along with the file's size. If the file's `metadata` was read beforehand, 
+/// a file which changed size in the meantime is warned about.
+/// 
+async fn hash_file_path(path: PathBuf, metadata: Option<Metadata>, algorithm: HashAlgorithm) -> Result<(PathBuf, String, u64)> {
     // Get a lock on the static semaphore
-    let _permit = POOL.acquire().await.unwrap();
+    let _permit = POOL.get_or_init(|| Semaphore::new(num_cpus::get())).acquire().await.unwrap();
 
-    // The MD5 hash, generated over time while the file is being
+    // The hash, generated over time while the file is being
     // asynchronously processed
-    let mut md5_ctx = md5::Context::new();
-    // Buffer for the current bytes being read from the file
-    let mut bytes = [0u8;1024];
+    let mut hasher = Hasher::new(algorithm);
+    // The total number of bytes read from the file
+    let mut size = 0u64;
+
+    // Each chunk is added to the hash and dropped before the next is read,
+    // so only one chunk of the file is held in memory at a time
+    let chunks = hash_file_path_streaming(path.clone(), HASH_CHUNK_SIZE);
+    pin_mut!(chunks);
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.map_err(|e| e.with_path(&path))?;
+        hasher.consume(&chunk);
+        size += chunk.len() as u64;
+    }
+    if let Some(expected) = metadata.map(|m| m.len()).filter(|&len| len != size) {
+        tracing::warn!("{} changed size while being hashed ({} bytes, {} expected)", path.display(), size, expected);
+    }
+
+    Ok((path, hasher.finish(), size))
+}
+
+///
+/// Streams the contents of the file at `path` in chunks of up to `chunk_size` bytes.
+/// The file is opened when the stream is first polled, and each chunk is read only
+/// once the one before it has been taken.
+/// 
+pub fn hash_file_path_streaming(path: PathBuf, chunk_size: usize) -> impl Stream<Item = Result<Bytes>> {
+    try_stream! {
+        let mut file = tokio::fs::File::open(long_path(&path)).await?;
+        let mut buffer = BytesMut::with_capacity(chunk_size);
+        loop {
+            // Reading straight from the file, without a `BufReader`, 
+            // keeps a second buffer from being held alongside the chunk
+            buffer.reserve(chunk_size);
+            match file.read_buf(&mut buffer).await? {
+                0 => break,
+                _ => yield buffer.split().freeze()
+            }
+        }
+    }
+}
+
+///
+/// Generates a hash with `algorithm` for all bytes read from the given synchronous `reader`,
+/// encoded the same way as the hashes produced by `gen_hashes`, along with the
+/// number of bytes read. Blocks the current thread until the reader is exhausted.
+/// 
+pub fn hash_reader(reader: impl Read, algorithm: HashAlgorithm) -> Result<(String, u64)> {
+    let (mut hashes, size) = hash_reader_with_each(reader, &[algorithm])?;
+    Ok((hashes.remove(0), size))
+}
 
-    // Open the file, and create a buffered reader to read the contents
-    let file = tokio::fs::File::open(&path).await?;
-    let mut file_reader = tokio::io::BufReader::new(file);
+///
+/// Generates the same hashes as `hash_reader`, once with each of the `algorithms` in order,
+/// reading from `reader` only once
+/// 
+pub fn hash_reader_with_each(mut reader: impl Read, algorithms: &[HashAlgorithm]) -> Result<(Vec<String>, u64)> {
+    let mut hashers: Vec<Hasher> = algorithms.iter().map(|algorithm| Hasher::new(*algorithm)).collect();
+    let mut bytes = [0u8;1024];
+    let mut size = 0u64;
 
-    // Loop until the end of the file has been reached, adding the read bytes
-    // to the MD5 hash
     loop {
-        match file_reader.read(&mut bytes).await {
+        match reader.read(&mut bytes) {
             Ok(0) => break,
-            Ok(_) => {
-                md5_ctx.consume(bytes);
+            Ok(n) => {
+                hashers.iter_mut().for_each(|hasher| hasher.consume(&bytes[..n]));
+                size += n as u64;
             },
-            // TODO - add tracing error here
-            Err(e) => panic!("{:?}", e)
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into())
+        }
+    }
+
+    Ok((hashers.into_iter().map(Hasher::finish).collect(), size))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use futures_util::{pin_mut, StreamExt};
+    use tokio::task::JoinHandle;
+
+    use crate::{config::{HashAlgorithm, HashEncoding}, hash_svc::checksums::encode_hash, summary::{Change, RunSummary}};
+
+    use super::{algorithm_of, error::{Error, Result}, gen_hashes, gen_hashes_chunked, gen_hashes_ordered, gen_hashes_streamed, hash_file_path_streaming, hash_reader, join_hash_task};
+
+    ///
+    /// Creates 20 files of differing sizes, so they take differing times to hash
+    /// 
+    fn create_files(dir: &tempfile::TempDir) -> Vec<PathBuf> {
+        (0..20).map(|i| {
+            let path = dir.path().join(format!("{}.txt", i));
+            std::fs::write(&path, vec![b'a'; (20 - i) * 64 * 1024]).unwrap();
+            path
+        }).collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_gen_hashes_order_is_nondeterministic() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = create_files(&dir);
+
+        let mut orders = Vec::new();
+        for _ in 0..100 {
+            let order: Vec<PathBuf> = gen_hashes(paths.iter().map(|path| (path.clone(), None)), HashAlgorithm::Md5)
+                .map(|hashed| hashed.unwrap().0)
+                .collect().await;
+            orders.push(order);
+        }
+
+        // If this fails, `gen_hashes` now returns results in a fixed order, 
+        // and its documentation should be revisited
+        assert!(orders.iter().any(|order| order != &orders[0]));
+    }
+
+    #[tokio::test]
+    async fn test_gen_hashes_chunked_takes_one_chunk_at_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = create_files(&dir);
+        let taken = std::cell::Cell::new(0);
+
+        let hashes = gen_hashes_chunked(paths.iter().inspect(|_| taken.set(taken.get() + 1)).map(|path| (path.clone(), None)), 6, HashAlgorithm::Md5);
+        pin_mut!(hashes);
+        let mut hashed = Vec::new();
+        while let Some(result) = hashes.next().await {
+            // Only the files of the chunk being hashed have been taken
+            assert!(taken.get() <= (hashed.len() / 6 + 1) * 6);
+            hashed.push(result.unwrap().0);
+        }
+
+        hashed.sort();
+        let mut expected = paths.clone();
+        expected.sort();
+        assert_eq!(hashed, expected);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_gen_hashes_ordered_keeps_input_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = create_files(&dir);
+
+        let order: Vec<PathBuf> = gen_hashes_ordered(paths.iter().map(|path| (path.clone(), None)), HashAlgorithm::Md5)
+            .map(|hashed| hashed.unwrap().0)
+            .collect().await;
+
+        assert_eq!(order, paths);
+    }
+
+    #[tokio::test]
+    async fn test_gen_hashes_streamed_returns_each_key_with_its_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = create_files(&dir);
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        let sent = paths.clone();
+        tokio::spawn(async move {
+            for (i, path) in sent.into_iter().enumerate() {
+                tx.send((i, path, None)).await.unwrap();
+            }
+        });
+
+        let mut hashed: Vec<(usize, PathBuf)> = gen_hashes_streamed(tokio_stream::wrappers::ReceiverStream::new(rx), HashAlgorithm::Md5)
+            .map(|(i, hashed)| (i, hashed.unwrap().0))
+            .collect().await;
+
+        hashed.sort();
+        assert_eq!(hashed, paths.into_iter().enumerate().collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_hash_file_path_streaming_yields_bounded_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("large.bin");
+        let contents: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &contents).unwrap();
+
+        let chunks: Vec<_> = hash_file_path_streaming(path.clone(), 4096)
+            .map(|chunk| chunk.unwrap())
+            .collect().await;
+
+        assert!(chunks.iter().all(|chunk| !chunk.is_empty() && chunk.len() <= 4096));
+        assert_eq!(chunks.concat(), contents);
+
+        // Hashing the stream matches hashing the whole file at once
+        let (_, hsh, size) = gen_hashes_ordered(std::iter::once((path, None)), HashAlgorithm::Md5).next().await.unwrap().unwrap();
+        assert_eq!(size, contents.len() as u64);
+        assert_eq!(hsh, hash_reader(contents.as_slice(), HashAlgorithm::Md5).unwrap().0);
+    }
+
+    #[tokio::test]
+    async fn test_sha256_hashes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hello.txt");
+        std::fs::write(&path, "hello").unwrap();
+
+        let (_, hsh, _) = gen_hashes_ordered(std::iter::once((path, None)), HashAlgorithm::Sha256).next().await.unwrap().unwrap();
+
+        assert_eq!(encode_hash(&hsh, HashEncoding::Hex), "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824");
+        assert_eq!(hsh, hash_reader("hello".as_bytes(), HashAlgorithm::Sha256).unwrap().0);
+        // Hashes are told apart by their length
+        assert_eq!(algorithm_of(&hsh), Some(HashAlgorithm::Sha256));
+        assert_eq!(algorithm_of(&hash_reader("hello".as_bytes(), HashAlgorithm::Md5).unwrap().0), Some(HashAlgorithm::Md5));
+        assert_eq!(algorithm_of("not a hash"), None);
+    }
+
+    #[tokio::test]
+    async fn test_hash_file_path_streaming_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut chunks = Box::pin(hash_file_path_streaming(dir.path().join("missing.bin"), 4096));
+
+        assert!(chunks.next().await.unwrap().is_err());
+        assert!(chunks.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unhashable_file_reaches_summary() {
+        let dir = tempfile::tempdir().unwrap();
+        let (present, missing) = (dir.path().join("present.txt"), dir.path().join("missing.txt"));
+        std::fs::write(&present, "contents").unwrap();
+
+        let mut summary = RunSummary::new();
+        let hashed: Vec<_> = gen_hashes([(present.clone(), None), (missing.clone(), None)].into_iter(), HashAlgorithm::Md5).collect().await;
+        for hashed in hashed {
+            match hashed {
+                Ok((path, _, size)) => summary.record(&path, Change::New, size),
+                Err(e) => summary.record_error(e.path(), &e)
+            }
         }
+
+        // The failing file doesn't end the stream, and is named in the summary
+        assert_eq!(summary.totals().new, 1);
+        assert_eq!(summary.error_count(), 1);
+        let rendered = summary.render(None, false);
+        assert!(rendered.contains(&format!("errors  1\n  {}  could not hash {}", missing.display(), missing.display())));
     }
-    let hash = md5::compute(bytes).0;
 
-    Ok((path, STANDARD.encode(hash)))
-}
\ No newline at end of file
+    #[tokio::test]
+    async fn test_panicked_hash_task_names_file() {
+        let path = PathBuf::from("/data/panics.txt");
+        let task: JoinHandle<Result<(PathBuf, String, u64)>> = tokio::spawn(async { panic!("hashing failed") });
+
+        let hashed = join_hash_task(path.clone(), task).await;
+
+        assert!(matches!(hashed, Err(Error::TaskPanicked { path: ref p }) if p == &path));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unreadable_file_is_yielded_as_error() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let (readable, locked) = (dir.path().join("readable.txt"), dir.path().join("locked.txt"));
+        std::fs::write(&readable, "contents").unwrap();
+        std::fs::write(&locked, "contents").unwrap();
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+        // Permissions don't stop root from reading the file
+        if std::fs::File::open(&locked).is_ok() {
+            return;
+        }
+
+        let hashed: Vec<_> = gen_hashes_ordered([(locked.clone(), None), (readable.clone(), None)].into_iter(), HashAlgorithm::Md5).collect().await;
+
+        assert!(matches!(&hashed[0], Err(Error::FileError { path, source }) 
+            if *path == locked && source.kind() == std::io::ErrorKind::PermissionDenied));
+        assert_eq!(hashed[1].as_ref().unwrap().0, readable);
+    }
+}
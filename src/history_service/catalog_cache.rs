@@ -0,0 +1,214 @@
+use std::{fmt::Display, fs::OpenOptions, io::{ErrorKind, Write}, path::{Path, PathBuf}, time::SystemTime};
+
+///
+/// A copy of a catalog kept on a network filesystem, where sqlite's locking is unreliable.
+/// The catalog is copied to a local directory when checked out, worked against locally,
+/// then uploaded back whole when checked in. Each upload bumps the catalog's generation,
+/// recorded in the file `<catalog>.generation` beside it, so an upload is refused if
+/// another process uploaded in the meantime. A change made to the catalog in place, by a
+/// process not using a copy, is found by the catalog's size or modification time changing.
+/// 
+#[derive(Debug)]
+pub struct CatalogCheckout {
+    remote: PathBuf,
+    local: PathBuf,
+    /// The generation of the remote catalog when it was copied
+    generation: u64,
+    /// The size and modification time of the remote catalog when it was copied
+    remote_stamp: Stamp,
+    /// The size and modification time of the local copy once it was made,
+    /// to tell whether it was changed
+    local_stamp: Stamp
+}
+
+type Stamp = (u64, Option<SystemTime>);
+
+#[derive(Debug)]
+pub enum CacheError {
+    /// The catalog at the first path was changed since it was copied to the second path,
+    /// so uploading the copy would lose the change
+    Conflict(PathBuf, PathBuf),
+    /// The catalog at the given path holds writes in its write-ahead log, which a copy would miss
+    UncheckpointedWal(PathBuf),
+    Io(std::io::Error)
+}
+
+impl Display for CacheError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CacheError::Conflict(remote, local) => write!(
+                f, "the catalog at {} was changed by another process since it was copied, so it wasn't replaced. \
+                The changed copy was kept at {}", remote.display(), local.display()
+            ),
+            CacheError::UncheckpointedWal(remote) => write!(
+                f, "the catalog at {} has writes left in its write-ahead log. Open it once without catalog_cache, \
+                so they're written into the catalog, before copying it", remote.display()
+            ),
+            CacheError::Io(e) => write!(f, "the catalog couldn't be copied: {}", e)
+        }
+    }
+}
+
+impl std::error::Error for CacheError {}
+
+impl From<std::io::Error> for CacheError {
+    fn from(value: std::io::Error) -> Self {
+        CacheError::Io(value)
+    }
+}
+
+impl CatalogCheckout {
+    ///
+    /// Copies the catalog at `remote` into `cache_dir`, creating it if it doesn't exist
+    /// 
+    pub fn checkout(remote: &Path, cache_dir: &Path) -> Result<Self, CacheError> {
+        if std::fs::metadata(suffixed(remote, "-wal")).is_ok_and(|metadata| metadata.len() > 0) {
+            return Err(CacheError::UncheckpointedWal(remote.to_path_buf()));
+        }
+        std::fs::create_dir_all(cache_dir)?;
+        let local = cache_dir.join(remote.file_name().unwrap());
+        // Journals left beside an earlier copy would be replayed into this one
+        for suffix in ["-journal", "-wal", "-shm"] {
+            match std::fs::remove_file(suffixed(&local, suffix)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                _ => { }
+            }
+        }
+
+        let generation = read_generation(remote)?;
+        let remote_stamp = stamp(remote)?;
+        std::fs::copy(remote, &local)?;
+        let local_stamp = stamp(&local)?;
+
+        Ok(Self { remote: remote.to_path_buf(), local, generation, remote_stamp, local_stamp })
+    }
+
+    ///
+    /// Gets the path of the local copy to work against
+    /// 
+    pub fn local_path(&self) -> &Path {
+        &self.local
+    }
+
+    ///
+    /// Uploads the local copy over the remote catalog, if the copy was changed, and bumps the
+    /// catalog's generation. Nothing may have the copy open. Fails with `CacheError::Conflict`,
+    /// leaving both as they are, if the remote catalog was changed since it was copied.
+    /// Returns the remote catalog's new generation if the copy was uploaded.
+    /// 
+    pub fn checkin(self) -> Result<Option<u64>, CacheError> {
+        if stamp(&self.local)? == self.local_stamp {
+            return Ok(None);
+        }
+        let generation = read_generation(&self.remote)?;
+        if generation != self.generation || stamp(&self.remote)? != self.remote_stamp {
+            return Err(CacheError::Conflict(self.remote, self.local));
+        }
+
+        // Copied beside the catalog first, so the rename replaces it whole
+        let upload = suffixed(&self.remote, ".upload");
+        std::fs::copy(&self.local, &upload)?;
+        OpenOptions::new().write(true).open(&upload)?.sync_all()?;
+        std::fs::rename(&upload, &self.remote)?;
+        write_generation(&self.remote, generation + 1)?;
+
+        Ok(Some(generation + 1))
+    }
+}
+
+///
+/// Gets the generation of the catalog at `remote`, which is 0 until a copy of it is first uploaded
+/// 
+pub fn read_generation(remote: &Path) -> std::io::Result<u64> {
+    match std::fs::read_to_string(suffixed(remote, ".generation")) {
+        Ok(generation) => generation.trim().parse()
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e)
+    }
+}
+
+fn write_generation(remote: &Path, generation: u64) -> std::io::Result<()> {
+    let (path, temp_path) = (suffixed(remote, ".generation"), suffixed(remote, ".generation.tmp"));
+    let mut file = std::fs::File::create(&temp_path)?;
+    write!(file, "{}", generation)?;
+    file.sync_all()?;
+    std::fs::rename(temp_path, path)
+}
+
+fn stamp(path: &Path) -> std::io::Result<Stamp> {
+    let metadata = std::fs::metadata(path)?;
+    Ok((metadata.len(), metadata.modified().ok()))
+}
+
+///
+/// Gets `path` with `suffix` appended to its file name, the way sqlite names the files beside a database
+/// 
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_generation, CacheError, CatalogCheckout};
+
+    #[test]
+    fn test_checkout_and_checkin() {
+        let dir = tempfile::tempdir().unwrap();
+        let remote = dir.path().join("catalog.db");
+        std::fs::write(&remote, "original").unwrap();
+
+        // A copy which wasn't changed isn't uploaded
+        let checkout = CatalogCheckout::checkout(&remote, &dir.path().join("cache")).unwrap();
+        assert_eq!(std::fs::read_to_string(checkout.local_path()).unwrap(), "original");
+        assert_eq!(checkout.checkin().unwrap(), None);
+        assert_eq!(read_generation(&remote).unwrap(), 0);
+
+        let checkout = CatalogCheckout::checkout(&remote, &dir.path().join("cache")).unwrap();
+        std::fs::write(checkout.local_path(), "changed locally").unwrap();
+        assert_eq!(checkout.checkin().unwrap(), Some(1));
+
+        assert_eq!(std::fs::read_to_string(&remote).unwrap(), "changed locally");
+        assert_eq!(read_generation(&remote).unwrap(), 1);
+        assert!(!dir.path().join("catalog.db.upload").exists());
+    }
+
+    #[test]
+    fn test_checkin_refuses_changed_catalog() {
+        let dir = tempfile::tempdir().unwrap();
+        let remote = dir.path().join("catalog.db");
+        std::fs::write(&remote, "original").unwrap();
+        let first = CatalogCheckout::checkout(&remote, &dir.path().join("first")).unwrap();
+        let second = CatalogCheckout::checkout(&remote, &dir.path().join("second")).unwrap();
+        std::fs::write(first.local_path(), "first run").unwrap();
+        std::fs::write(second.local_path(), "second run").unwrap();
+
+        first.checkin().unwrap();
+        let second_local = second.local_path().to_path_buf();
+        let e = second.checkin().unwrap_err();
+
+        assert!(matches!(&e, CacheError::Conflict(path, local) if *path == remote && *local == second_local));
+        assert_eq!(std::fs::read_to_string(&remote).unwrap(), "first run");
+        assert_eq!(std::fs::read_to_string(&second_local).unwrap(), "second run");
+
+        // Changes made in place, without a copy, are found too
+        let third = CatalogCheckout::checkout(&remote, &dir.path().join("third")).unwrap();
+        std::fs::write(&remote, "changed in place").unwrap();
+        std::fs::write(third.local_path(), "third run").unwrap();
+        assert!(matches!(third.checkin(), Err(CacheError::Conflict(_, _))));
+    }
+
+    #[test]
+    fn test_checkout_refuses_uncheckpointed_wal() {
+        let dir = tempfile::tempdir().unwrap();
+        let remote = dir.path().join("catalog.db");
+        std::fs::write(&remote, "original").unwrap();
+        std::fs::write(dir.path().join("catalog.db-wal"), "pending writes").unwrap();
+
+        let e = CatalogCheckout::checkout(&remote, &dir.path().join("cache")).unwrap_err();
+
+        assert!(matches!(e, CacheError::UncheckpointedWal(path) if path == remote));
+    }
+}
@@ -0,0 +1,117 @@
+use std::path::{Path, PathBuf};
+
+use sqlx::{sqlite::{SqliteConnectOptions, SqlitePoolOptions}, SqlitePool};
+
+use crate::data_layer_error::*;
+
+///
+/// How much a compaction shrank the catalog
+/// 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CompactReport {
+    /// The size of the catalog's files on disk before it was compacted
+    pub size_before: u64,
+    /// The size of the catalog's files on disk after it was compacted
+    pub size_after: u64
+}
+
+impl CompactReport {
+    pub fn bytes_reclaimed(&self) -> u64 {
+        self.size_before.saturating_sub(self.size_after)
+    }
+}
+
+///
+/// Rewrites the catalog at `catalog_path`, which `db` is connected to, without any free
+/// pages, using `VACUUM INTO` a temporary copy which is then swapped in for the catalog.
+/// `db` is closed before the swap, and a pool connected to the compacted catalog is
+/// returned. If the copy fails, the catalog is left untouched. The caller must hold
+/// the catalog's `CatalogLock`, so no other process has it open.
+/// 
+pub async fn compact_catalog(db: &SqlitePool, catalog_path: &Path) -> Result<(SqlitePool, CompactReport)> {
+    let size_before = size_on_disk(catalog_path)?;
+    let compacted = sibling_path(catalog_path, ".compact");
+    // A copy left over by an earlier compaction which failed is never swapped in
+    if compacted.exists() {
+        std::fs::remove_file(&compacted)?;
+    }
+
+    // Anything still in the write-ahead log is written to the catalog first, so the
+    // log left behind is empty, and can't be replayed against the compacted catalog
+    sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)").execute(db).await?;
+    let vacuumed = sqlx::query("VACUUM INTO ?").bind(compacted.to_string_lossy().to_string()).execute(db).await;
+    if let Err(e) = vacuumed {
+        let _ = std::fs::remove_file(&compacted);
+        return Err(e.into());
+    }
+    std::fs::File::open(&compacted)?.sync_all()?;
+
+    db.close().await;
+    std::fs::rename(&compacted, catalog_path)?;
+    for suffix in ["-wal", "-shm"] {
+        let path = sibling_path(catalog_path, suffix);
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+    }
+
+    let db = SqlitePoolOptions::new().connect_with(SqliteConnectOptions::new().filename(catalog_path)).await?;
+    let check: String = sqlx::query_scalar("PRAGMA quick_check").fetch_one(&db).await?;
+    if check != "ok" {
+        return Err(DataLayerError { err: format!("the compacted catalog failed its integrity check: {}", check).into() });
+    }
+
+    Ok((db, CompactReport { size_before, size_after: size_on_disk(catalog_path)? }))
+}
+
+///
+/// Gets the path of the file named like the catalog at `catalog_path`, followed by `suffix`
+/// 
+fn sibling_path(catalog_path: &Path, suffix: &str) -> PathBuf {
+    let mut path = catalog_path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+///
+/// Gets the size of the catalog at `catalog_path`, along with its write-ahead log
+/// 
+fn size_on_disk(catalog_path: &Path) -> Result<u64> {
+    let wal_size = std::fs::metadata(sibling_path(catalog_path, "-wal")).map_or(0, |m| m.len());
+    Ok(std::fs::metadata(catalog_path)?.len() + wal_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{sqlite::{SqliteConnectOptions, SqlitePoolOptions}, Row};
+
+    use super::compact_catalog;
+
+    #[tokio::test]
+    async fn test_compact_catalog_shrinks_and_keeps_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let catalog = dir.path().join("catalog.db");
+        let db = SqlitePoolOptions::new()
+            .connect_with(SqliteConnectOptions::new().filename(&catalog).create_if_missing(true)).await.unwrap();
+        sqlx::query("CREATE TABLE files (id INTEGER PRIMARY KEY NOT NULL, file_name TEXT NOT NULL)").execute(&db).await.unwrap();
+        // Bloat the catalog, then forget most of it, leaving free pages behind
+        for id in 0..2000 {
+            sqlx::query("INSERT INTO files (id, file_name) VALUES (?, ?)")
+                .bind(id).bind(format!("{}{}", id, "x".repeat(1000)))
+                .execute(&db).await.unwrap();
+        }
+        sqlx::query("DELETE FROM files WHERE id >= 10").execute(&db).await.unwrap();
+
+        let (db, report) = compact_catalog(&db, &catalog).await.unwrap();
+
+        assert!(report.size_after < report.size_before / 10, "{:?}", report);
+        assert_eq!(report.bytes_reclaimed(), report.size_before - report.size_after);
+        assert!(!dir.path().join("catalog.db.compact").exists());
+        let rows = sqlx::query("SELECT id, file_name FROM files ORDER BY id").fetch_all(&db).await.unwrap();
+        assert_eq!(rows.len(), 10);
+        for (id, row) in rows.iter().enumerate() {
+            assert_eq!(row.get::<i64, _>("id"), id as i64);
+            assert_eq!(row.get::<String, _>("file_name"), format!("{}{}", id, "x".repeat(1000)));
+        }
+    }
+}
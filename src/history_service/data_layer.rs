@@ -1,33 +1,43 @@
 use async_trait::async_trait;
-use chrono::NaiveDateTime;
+use chrono::{DateTime, Utc};
+#[cfg(feature = "sqlite")]
 use sqlx::SqlitePool;
 
 #[cfg(test)]
 use mockall::automock;
-use tokio_stream::StreamExt; 
 
-use super::models::{DirModel, FileModel};
+use super::models::{BackupModel, DeletionExclusions, ChunkModel, DirModel, EmptyDirModel, FileDiffEntry, FileModel, FileSnapshotEntry, FileWithPath, HashCollisionEntry, CaseCollisionEntry, LatestFileEntry, RunDiff, RunModel, RunOrigin, RunStats, VerifyFailureModel};
+#[cfg(feature = "sqlite")]
+use super::models::{CURRENT_VERSION, ChangeType, FileLocation, RunStatus, RunTrigger};
 use crate::data_layer_error::*;
 
-const VERSION: i32 = 1;
-
 #[cfg_attr(test, automock)]
 #[async_trait]
 pub trait DataLayer : Send + Sync {
+    ///
+    /// Takes the ID of a new file version from the catalog, which is never given 
+    /// to another version, even by another process sharing the catalog
     /// 
-    /// Gets the max ID in the file primary key column
-    /// 
-    async fn get_max_file_id(&self) -> Result<i64>;
+    async fn allocate_file_id(&self) -> Result<i64>;
     ///
     /// Retrieves the directory with the given `dir_name` from the `DataLayer`
     /// 
     async fn get_dir(&self, dir_name: &str) -> Result<Option<DirModel>>;
     ///
+    /// Gets all directories which have no parent directory
+    /// 
+    async fn get_root_dirs(&self) -> Result<Vec<DirModel>>;
+    ///
     /// Gets all sub-directories under the directory with the given `dir_id`
     /// 
     async fn get_sub_dirs(&self, dir_id: i64) -> Result<Vec<DirModel>>;
     ///
-    /// Gets the latest updated file under the directory with the given `dir_id`, if it exists
+    /// Gets every directory in the catalog at once, to be resolved in memory with a `DirTree`
+    /// 
+    async fn load_dir_tree(&self) -> Result<Vec<DirModel>>;
+    ///
+    /// Gets the latest version of the file under the directory with the given `dir_id`, 
+    /// unless the file doesn't exist, or was deleted or excluded since it was last backed up
     /// 
     async fn get_latest_file(&self, dir_id: i64, file_name: &str) -> Result<Option<FileModel>>;
     ///
@@ -35,121 +45,1605 @@ pub trait DataLayer : Send + Sync {
     /// 
     async fn get_dir_files(&self, dir_id: i64, file_name: &str) -> Result<Vec<FileModel>>;
     ///
+    /// Gets the latest version of every file under the directory with the given `dir_id`
+    /// which has not been marked as deleted
+    /// 
+    async fn get_latest_dir_files(&self, dir_id: i64) -> Result<Vec<FileModel>>;
+    ///
     /// Creates a directory with the provided `dir_name`, and the given `parent_dir_id`
     /// for it's parent directory.
     /// 
     async fn create_dir(&self, dir_name: &str, parent_dir_id: Option<i64>) -> Result<i64>;
     ///
+    /// Creates each of the `dirs`, by name and parent dir ID, in a single transaction.
+    /// Returns the name and new ID of each, in the same order
+    /// 
+    async fn batch_create_dirs(&self, dirs: Vec<(String, Option<i64>)>) -> Result<Vec<(String, i64)>>;
+    ///
     /// Updates the file under the given `dir_id`, with the given `file_name` with a new `file_hash`,
-    /// and update `ts`
+    /// `file_size`, and update `ts`. `path_policy` records how the file's path was canonicalized,
+    /// and `run_id` the run which recorded it, if any.
     /// 
-    async fn create_file_entry(&self, dir_id: i64, file_id: i64, file_name: &str, file_hsh: &str, ts: NaiveDateTime) -> Result<()>;
+    #[allow(clippy::too_many_arguments)]
+    async fn create_file_entry(&self, dir_id: i64, file_id: i64, file_name: &str, file_hsh: &str, file_size: i64, ts: DateTime<Utc>, path_policy: &str, run_id: Option<i64>) -> Result<()>;
     ///
     /// Updates the latest file with the provided name with the provided timestamp
     /// 
-    async fn update_latest_hsh_ts(&self, dir_id: i64, file_name: &str, ts: NaiveDateTime) -> Result<()>;
+    async fn update_latest_hsh_ts(&self, dir_id: i64, file_name: &str, ts: DateTime<Utc>) -> Result<()>;
     ///
-    /// Updates the `DataLayer` to mark all files not updated in the current process as
-    /// deleted from the system.
+    /// Marks every file not updated since `current_run_ts` as deleted from the system, except those
+    /// covered by the `exclusions`, which list every dir whose files are left out, nested dirs included.
+    /// Each marker is recorded as made by the run with the given `run_id`, if any. 
+    /// Returns the directory ID and name of each file newly marked.
     /// 
-    async fn mark_all_deleted_files(&self, current_run_ts: NaiveDateTime) -> Result<()>;
+    async fn mark_deleted_under(&self, exclusions: DeletionExclusions, current_run_ts: DateTime<Utc>, run_id: Option<i64>) -> Result<Vec<(i64, String)>>;
     ///
-    /// Deletes the file entry by `file_id`
+    /// Deletes the file entry by `file_id`. If it was the latest version of its file,
+    /// the version before it becomes the latest, unless that's a marker
     /// 
     async fn delete_file_entry(&self, file_id: i64) -> Result<()>;
+    ///
+    /// Records that the file under the given `dir_id` with the given `file_name` was excluded
+    /// by policy at `ts`, by the run with the given `run_id` if any, as a marker continuing its generation
+    /// 
+    async fn create_excluded_marker(&self, dir_id: i64, file_name: &str, ts: DateTime<Utc>, run_id: Option<i64>) -> Result<()>;
+    ///
+    /// Gets up to `limit` backed-up files which have never been verified, or
+    /// were last verified before `older_than`, oldest verification first
+    /// 
+    async fn get_files_needing_reverification(&self, older_than: DateTime<Utc>, limit: u32) -> Result<Vec<FileModel>>;
+    ///
+    /// Marks the file with the given `file_id` as verified at `ts`
+    /// 
+    async fn mark_file_verified(&self, file_id: i64, ts: DateTime<Utc>) -> Result<()>;
+    ///
+    /// Replaces the hash of the file version with the given `file_id` with `new_hsh`,
+    /// for a hash of the same contents made with another algorithm
+    /// 
+    async fn update_file_hash(&self, file_id: i64, new_hsh: &str) -> Result<()>;
+    ///
+    /// Gives the file version with the given `file_id` the `label`, replacing any it had, or removes its label if `None`
+    /// 
+    async fn set_version_label(&self, file_id: i64, label: Option<String>) -> Result<()>;
+    ///
+    /// Gets every distinct canonicalization policy that paths in the `DataLayer` were recorded with
+    /// 
+    async fn get_path_policies(&self) -> Result<Vec<String>>;
+    ///
+    /// Finds every file whose name contains a match for the SQL `LIKE` pattern `file_name_pattern`,
+    /// escaped with `\`, along with its full path and a summary of its history
+    /// 
+    async fn search_files(&self, file_name_pattern: &str) -> Result<Vec<FileWithPath>>;
+    ///
+    /// Gets every file version whose hash is shared by another version of a different size,
+    /// ordered so that versions sharing a hash are adjacent
+    /// 
+    async fn find_hash_collisions(&self) -> Result<Vec<HashCollisionEntry>>;
+    ///
+    /// Gets every file, not deleted in its latest version, sharing its directory with another
+    /// whose name differs only in ASCII case, ordered so that colliding files are adjacent
+    /// 
+    async fn find_case_collisions(&self) -> Result<Vec<CaseCollisionEntry>>;
+    ///
+    /// Records the start of a new backup run at `started_at`, made with the config 
+    /// `config_snapshot` and started as told by its `origin`, returning the run's ID
+    /// 
+    async fn begin_run(&self, started_at: DateTime<Utc>, config_snapshot: &str, origin: &RunOrigin) -> Result<i64>;
+    ///
+    /// Records the run with the given `run_id` as finished at `completed_at`, 
+    /// having done what's counted in its `stats`
+    /// 
+    async fn complete_run(&self, run_id: i64, completed_at: DateTime<Utc>, stats: &RunStats) -> Result<()>;
+    ///
+    /// Gets up to `limit` of the most recently completed runs, newest first
+    /// 
+    async fn list_runs(&self, limit: u32) -> Result<Vec<RunModel>>;
+    ///
+    /// Gets the run with the given `run_id`, whether or not it completed
+    /// 
+    async fn get_run(&self, run_id: i64) -> Result<Option<RunModel>>;
+    ///
+    /// Gets the most recently completed run whose reason contains `reason`
+    /// 
+    async fn find_run_by_reason(&self, reason: &str) -> Result<Option<RunModel>>;
+    ///
+    /// Gets the time the latest run which examined every file began: one which completed
+    /// without stopping at its deadline, wasn't ad-hoc, and met no errors
+    /// 
+    async fn get_last_run_ts(&self) -> Result<Option<DateTime<Utc>>>;
+    ///
+    /// Records the file with the given `file_id` as being made up of `chunks`, in order
+    /// 
+    async fn create_file_chunks(&self, file_id: i64, chunks: &[ChunkModel]) -> Result<()>;
+    ///
+    /// Deletes every chunk no longer referenced by any file version, returning their hashes
+    /// 
+    async fn delete_unreferenced_chunks(&self) -> Result<Vec<String>>;
+    ///
+    /// Records the file version with the given `file_id` as stored in the `backend` under
+    /// `backend_key` at `backup_ts`, taking `compressed_size` bytes, returning the record's ID
+    /// 
+    #[allow(clippy::too_many_arguments)]
+    async fn create_backup_record(
+        &self, file_id: i64, destination: &str, backend: &str, backend_key: &str, backup_ts: DateTime<Utc>, compressed_size: Option<i64>, delta_base_id: Option<i64>
+    ) -> Result<i64>;
+    ///
+    /// Gets every stored copy of the file version with the given `file_id`
+    /// 
+    async fn get_file_backups(&self, file_id: i64) -> Result<Vec<BackupModel>>;
+    ///
+    /// Gets every stored copy held by a destination not among the given `destinations`
+    /// 
+    async fn get_backups_outside(&self, destinations: &[String]) -> Result<Vec<BackupModel>>;
+    ///
+    /// Gets the ID of every file version with a backup stored as a delta against the version with the given `file_id`
+    /// 
+    async fn get_delta_dependents(&self, file_id: i64) -> Result<Vec<i64>>;
+    ///
+    /// Gets the file version ID of every backup stored as a delta, along with the ID of the version it's taken against
+    /// 
+    async fn get_delta_backups(&self) -> Result<Vec<(i64, i64)>>;
+    ///
+    /// Records that the backups of the file version with the given `file_id` are stored in full
+    /// 
+    async fn clear_delta_base(&self, file_id: i64) -> Result<()>;
+    ///
+    /// Gets the ID of every version, including deletion markers, belonging to a 
+    /// generation of a file which was deleted before `deleted_before`
+    /// 
+    async fn get_expired_generation_files(&self, deleted_before: DateTime<Utc>) -> Result<Vec<i64>>;
+    ///
+    /// Gets the latest version of every file which hasn't been deleted, with its full path
+    /// 
+    async fn get_latest_files_with_paths(&self) -> Result<Vec<LatestFileEntry>>;
+    ///
+    /// Gets the latest version backed up of every file ever backed up, ordered by directory and name.
+    /// Deletion and exclusion markers are never returned: a file deleted or excluded since is returned by its last backed up version
+    /// 
+    async fn get_latest_files_snapshot(&self) -> Result<Vec<FileSnapshotEntry>>;
+    ///
+    /// Compares the latest version of every file as of `before` against its latest version
+    /// as of `after`, including files which were unchanged. Files which didn't exist at 
+    /// either point are left out.
+    /// 
+    async fn diff_snapshots(&self, before: DateTime<Utc>, after: DateTime<Utc>) -> Result<Vec<FileDiffEntry>>;
+    ///
+    /// Compares the latest version of every file as of the end of the run `run_id_a` 
+    /// against its latest version as of the end of the run `run_id_b`. Versions recorded 
+    /// outside of any run are counted as before every run
+    /// 
+    async fn get_backup_run_diff(&self, run_id_a: i64, run_id_b: i64) -> Result<RunDiff>;
+    ///
+    /// Sums the size of every file version in the catalog, excluding deletion markers
+    /// 
+    async fn total_backup_size_bytes(&self) -> Result<u64>;
+    ///
+    /// Marks exactly the given `dirs` as empty, with their permissions, 
+    /// clearing the mark from every other dir
+    /// 
+    async fn set_empty_dirs(&self, dirs: &[EmptyDirModel]) -> Result<()>;
+    ///
+    /// Gets every dir marked as empty whose name matches the SQL `LIKE` pattern `dir_name_pattern`
+    /// anywhere within it. `\` escapes the pattern's wildcards.
+    /// 
+    async fn get_empty_dirs(&self, dir_name_pattern: &str) -> Result<Vec<EmptyDirModel>>;
+    ///
+    /// Deletes every dir which holds no file versions at any depth, and isn't marked as empty,
+    /// returning how many were deleted
+    /// 
+    async fn delete_unused_dirs(&self) -> Result<u64>;
+    ///
+    /// Rebuilds the database, releasing the space left behind by deleted rows
+    /// 
+    async fn vacuum(&self) -> Result<()>;
+    ///
+    /// Gathers statistics about the database's tables and indexes for the query planner
+    /// 
+    async fn analyze(&self) -> Result<()>;
+    ///
+    /// Gets the size of the database in bytes, including its free pages
+    /// 
+    async fn database_size_bytes(&self) -> Result<u64>;
+    ///
+    /// Gets every file version with a backup, excluding deletion markers, in id order
+    /// 
+    async fn get_backed_up_files(&self) -> Result<Vec<FileModel>>;
+    ///
+    /// Gets the ID and hash of up to `limit` file versions with a backup, 
+    /// with IDs after `after_id`, in id order
+    /// 
+    async fn get_backed_up_hashes(&self, after_id: i64, limit: u32) -> Result<Vec<(i64, String)>>;
+    ///
+    /// Gets the ID of the latest file version with a backup whose hash is `hsh`, if any
+    /// 
+    async fn find_latest_by_hash(&self, hsh: &str) -> Result<Option<i64>>;
+    ///
+    /// Gets the last file id verified in each shard, as `(shard, last_file_id)`
+    /// 
+    async fn get_verify_progress(&self) -> Result<Vec<(i64, i64)>>;
+    ///
+    /// Records `last_file_id` as the last file id verified in the `shard`
+    /// 
+    async fn set_verify_progress(&self, shard: i64, last_file_id: i64) -> Result<()>;
+    ///
+    /// Records that the backup of the file version with the given `file_id` failed verification
+    /// 
+    async fn create_verify_failure(&self, file_id: i64, reason: &str, found_at: DateTime<Utc>) -> Result<()>;
+    ///
+    /// Gets every failure recorded since verification last started over, in file id order
+    /// 
+    async fn get_verify_failures(&self) -> Result<Vec<VerifyFailureModel>>;
+    ///
+    /// Clears the verification progress and failures, so verification starts over
+    /// 
+    async fn clear_verify_progress(&self) -> Result<()>;
 }
 
-pub struct DbDataLayer<'a> {
-    db: &'a SqlitePool,
+///
+/// The catalog's `user_version` from which every table and column added since the original `dirs` and `files` exists
+/// 
+#[cfg(feature = "sqlite")]
+const SCHEMA_VERSION: i64 = 1;
+
+///
+/// The catalog's `user_version` from which every timestamp is stored as RFC3339 in UTC
+/// 
+#[cfg(feature = "sqlite")]
+const UTC_TIMESTAMPS_VERSION: i64 = 2;
+
+///
+/// Every column added to a table of the original catalog, with its definition, in the order they were added
+/// 
+#[cfg(feature = "sqlite")]
+const ADDED_COLUMNS: [(&str, &str, &str); 9] = [
+    ("files", "verified_ts", "DATETIME"),
+    ("files", "path_policy", "TEXT"),
+    ("files", "file_size", "INTEGER"),
+    ("files", "generation", "INTEGER NOT NULL DEFAULT 0"),
+    ("dirs", "kept_empty", "INTEGER NOT NULL DEFAULT 0"),
+    ("dirs", "permissions", "INTEGER"),
+    ("files", "run_id", "INTEGER REFERENCES backup_runs (id)"),
+    ("files", "label", "TEXT"),
+    ("files", "excluded", "INTEGER NOT NULL DEFAULT 0"),
+];
+
+///
+/// Every table added to the original catalog, along with its indexes, as they're created by `create.sql`
+/// 
+#[cfg(feature = "sqlite")]
+const ADDED_TABLES: &str = "
+    CREATE TABLE IF NOT EXISTS backups (
+        id INTEGER PRIMARY KEY NOT NULL,
+        file_id INTEGER NOT NULL,
+        destination TEXT NOT NULL DEFAULT 'default',
+        backend TEXT NOT NULL,
+        backend_key TEXT NOT NULL,
+        backup_ts DATETIME NOT NULL,
+        compressed_size INTEGER,
+        delta_base_id INTEGER,
+        FOREIGN KEY (file_id) REFERENCES files (id) ON DELETE CASCADE
+    );
+    CREATE TABLE IF NOT EXISTS backup_runs (
+        id INTEGER PRIMARY KEY NOT NULL,
+        started_at DATETIME NOT NULL,
+        completed_at DATETIME,
+        files_scanned INTEGER,
+        bytes_backed_up INTEGER,
+        config_snapshot TEXT,
+        status TEXT NOT NULL DEFAULT 'running',
+        files_backed_up INTEGER,
+        files_skipped INTEGER,
+        errors TEXT,
+        run_trigger TEXT NOT NULL DEFAULT 'manual',
+        reason TEXT
+    );
+    CREATE TABLE IF NOT EXISTS chunks (
+        hsh TEXT PRIMARY KEY NOT NULL,
+        chunk_size INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS file_chunks (
+        file_id INTEGER NOT NULL,
+        seq INTEGER NOT NULL,
+        chunk_hsh TEXT NOT NULL,
+        PRIMARY KEY (file_id, seq),
+        FOREIGN KEY (file_id) REFERENCES files (id) ON DELETE CASCADE,
+        FOREIGN KEY (chunk_hsh) REFERENCES chunks (hsh)
+    );
+    CREATE TABLE IF NOT EXISTS verify_progress (
+        shard INTEGER PRIMARY KEY NOT NULL,
+        last_file_id INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS verify_failures (
+        file_id INTEGER PRIMARY KEY NOT NULL,
+        reason TEXT NOT NULL,
+        found_at DATETIME NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS idx_file_chunks_chunk_hsh ON file_chunks(chunk_hsh);
+    CREATE INDEX IF NOT EXISTS idx_backups_file_id ON backups(file_id);
+    CREATE INDEX IF NOT EXISTS idx_backups_delta_base_id ON backups(delta_base_id);
+";
+
+///
+/// The catalog's `user_version` from which file IDs are handed out by the `file_id_sequence` table
+/// 
+#[cfg(feature = "sqlite")]
+const FILE_ID_SEQUENCE_VERSION: i64 = 3;
+
+///
+/// The catalog's `user_version` from which the latest version of each existing file is flagged `is_latest`
+/// 
+#[cfg(feature = "sqlite")]
+const IS_LATEST_VERSION: i64 = 4;
+
+///
+/// Clears `is_latest` from every version of the file under `dir_id` named `file_name`, through `executor`,
+/// so it can be done in the same transaction as recording the version replacing it
+/// 
+#[cfg(feature = "sqlite")]
+async fn clear_latest<'c>(executor: impl sqlx::Executor<'c, Database = sqlx::Sqlite>, dir_id: i64, file_name: &str) -> Result<()> {
+    sqlx::query!("UPDATE files SET is_latest = 0 WHERE dir_id = ? AND file_name = ? AND is_latest = 1", dir_id, file_name)
+        .execute(executor).await?;
+    Ok(())
 }
 
-impl<'a> DbDataLayer<'a> {
-    pub fn new(db: &'a SqlitePool) -> Self { 
-        Self { db }
+///
+/// Reads the errors recorded with a run, which are stored as a JSON array of messages
+/// 
+#[cfg(feature = "sqlite")]
+fn parse_run_errors(errors: Option<String>) -> Vec<String> {
+    errors.and_then(|errors| serde_json::from_str(&errors).ok()).unwrap_or_default()
+}
+
+#[cfg(feature = "sqlite")]
+pub struct DbDataLayer {
+    db: SqlitePool,
+}
+
+#[cfg(feature = "sqlite")]
+impl DbDataLayer {
+    ///
+    /// Creates the data layer over `db`, sharing its connections
+    /// 
+    pub fn new(db: &SqlitePool) -> Self { 
+        Self { db: db.clone() }
+    }
+
+    ///
+    /// Adds every table and column the catalog has gained since its original `dirs` and `files`
+    /// to catalogs created before them. Run before every other migration, as they rely on them.
+    /// Catalogs created or already migrated at `user_version` 1 or above are left as they are.
+    /// 
+    pub async fn migrate_schema(&self) -> Result<()> {
+        let version: i64 = sqlx::query_scalar("PRAGMA user_version").fetch_one(&self.db).await?;
+        if version >= SCHEMA_VERSION {
+            return Ok(());
+        }
+
+        let mut tx = self.db.begin().await?;
+        sqlx::raw_sql(ADDED_TABLES).execute(&mut *tx).await?;
+        for (table, column, definition) in ADDED_COLUMNS {
+            // A catalog may have been created with some of the columns, but not yet versioned
+            let exists: bool = sqlx::query_scalar("SELECT COUNT(*) > 0 FROM pragma_table_info(?) WHERE name = ?")
+                .bind(table).bind(column)
+                .fetch_one(&mut *tx).await?;
+            if !exists {
+                sqlx::query(&format!("ALTER TABLE {table} ADD COLUMN {column} {definition}")).execute(&mut *tx).await?;
+            }
+        }
+        sqlx::query(&format!("PRAGMA user_version = {}", SCHEMA_VERSION)).execute(&mut *tx).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    ///
+    /// Rewrites every timestamp stored before the catalog held typed instants, which were
+    /// naive but always taken in UTC, as RFC3339 with an explicit UTC offset. Run after `migrate_schema`.
+    /// Catalogs created or already migrated at `user_version` 2 or above are left as they are.
+    /// 
+    pub async fn migrate_utc_timestamps(&self) -> Result<()> {
+        let version: i64 = sqlx::query_scalar("PRAGMA user_version").fetch_one(&self.db).await?;
+        if version >= UTC_TIMESTAMPS_VERSION {
+            return Ok(());
+        }
+
+        let mut tx = self.db.begin().await?;
+        for (table, column) in [
+            ("files", "backup_ts"), ("files", "verified_ts"), ("backups", "backup_ts"),
+            ("backup_runs", "started_at"), ("backup_runs", "completed_at")
+        ] {
+            sqlx::query(&format!(
+                "UPDATE {table} SET {column} = replace({column}, ' ', 'T') || '+00:00' \
+                WHERE {column} IS NOT NULL AND {column} NOT LIKE '%+00:00'"
+            ))
+                .execute(&mut *tx).await?;
+        }
+        sqlx::query(&format!("PRAGMA user_version = {}", UTC_TIMESTAMPS_VERSION)).execute(&mut *tx).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    ///
+    /// Adds the `file_id_sequence` table handing out file IDs to catalogs created before it, 
+    /// starting it after the highest ID recorded. Run after `migrate_utc_timestamps`.
+    /// Catalogs created or already migrated at `user_version` 3 or above are left as they are.
+    /// 
+    pub async fn migrate_file_id_sequence(&self) -> Result<()> {
+        let version: i64 = sqlx::query_scalar("PRAGMA user_version").fetch_one(&self.db).await?;
+        if version >= FILE_ID_SEQUENCE_VERSION {
+            return Ok(());
+        }
+
+        let mut tx = self.db.begin().await?;
+        sqlx::query("CREATE TABLE file_id_sequence (next_id INTEGER NOT NULL)").execute(&mut *tx).await?;
+        sqlx::query("INSERT INTO file_id_sequence (next_id) SELECT COALESCE(MAX(id), 0) + 1 FROM files").execute(&mut *tx).await?;
+        sqlx::query(
+            "CREATE TRIGGER trg_files_advance_id_sequence AFTER INSERT ON files \
+            BEGIN UPDATE file_id_sequence SET next_id = NEW.id + 1 WHERE next_id <= NEW.id; END"
+        )
+            .execute(&mut *tx).await?;
+        sqlx::query(&format!("PRAGMA user_version = {}", FILE_ID_SEQUENCE_VERSION)).execute(&mut *tx).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    ///
+    /// Adds the `is_latest` column to catalogs created before it, flagging the latest version
+    /// of every file whose latest version isn't a marker. Run after `migrate_file_id_sequence`.
+    /// Catalogs created or already migrated at `user_version` 4 or above are left as they are.
+    /// 
+    pub async fn migrate_is_latest(&self) -> Result<()> {
+        let version: i64 = sqlx::query_scalar("PRAGMA user_version").fetch_one(&self.db).await?;
+        if version >= IS_LATEST_VERSION {
+            return Ok(());
+        }
+
+        let mut tx = self.db.begin().await?;
+        sqlx::query("ALTER TABLE files ADD COLUMN is_latest INTEGER NOT NULL DEFAULT 0").execute(&mut *tx).await?;
+        sqlx::query(
+            "UPDATE files SET is_latest = 1 WHERE id IN (
+                SELECT id FROM (
+                    SELECT id, hsh, ROW_NUMBER() OVER (PARTITION BY dir_id, file_name ORDER BY backup_ts DESC) AS recency FROM files
+                ) WHERE recency = 1 AND hsh IS NOT NULL
+            )"
+        )
+            .execute(&mut *tx).await?;
+        sqlx::query("CREATE INDEX idx_files_latest ON files(dir_id, file_name, is_latest)").execute(&mut *tx).await?;
+        sqlx::query(&format!("PRAGMA user_version = {}", IS_LATEST_VERSION)).execute(&mut *tx).await?;
+        tx.commit().await?;
+
+        Ok(())
     }
 }
 
+#[cfg(feature = "sqlite")]
 #[async_trait]
-impl<'a> DataLayer for DbDataLayer<'a> {
-    async fn get_max_file_id(&self) -> Result<i64> {
-        Ok(sqlx::query!("SELECT MAX(id) as max_id FROM files")
-            .fetch_optional(self.db).await?.and_then(|r| r.max_id.and_then(|id| Some(id as i64))).unwrap_or(0))
+impl DataLayer for DbDataLayer {
+    async fn allocate_file_id(&self) -> Result<i64> {
+        // Taking the ID and moving the sequence past it is a single write, so it's atomic
+        Ok(sqlx::query_scalar!(r#"UPDATE file_id_sequence SET next_id = next_id + 1 RETURNING next_id - 1 as "id!: i64""#)
+            .fetch_one(&self.db).await?)
     }
     async fn get_dir(&self, dir_name: &str) -> Result<Option<DirModel>> {
         Ok(sqlx::query_as!(DirModel,
             "SELECT id, parent_dir_id, dir_name FROM dirs WHERE dir_name = ?", dir_name
         )
-            .fetch_optional(self.db).await?)
+            .fetch_optional(&self.db).await?)
+    }
+    async fn get_root_dirs(&self) -> Result<Vec<DirModel>> {
+        Ok(sqlx::query_as!(DirModel,
+            "SELECT id, parent_dir_id, dir_name FROM dirs WHERE parent_dir_id IS NULL"
+        )
+            .fetch_all(&self.db).await?)
     }
     async fn get_sub_dirs(&self, dir_id: i64) -> Result<Vec<DirModel>> {
         Ok(sqlx::query_as!(DirModel,
             "SELECT id, parent_dir_id, dir_name FROM dirs WHERE parent_dir_id = ?", dir_id
         )
-            .fetch_all(self.db).await?)
+            .fetch_all(&self.db).await?)
+    }
+    async fn load_dir_tree(&self) -> Result<Vec<DirModel>> {
+        Ok(sqlx::query_as!(DirModel,
+            "SELECT id, parent_dir_id, dir_name FROM dirs"
+        )
+            .fetch_all(&self.db).await?)
     }
     
     async fn get_latest_file(&self, dir_id: i64, file_name: &str) -> Result<Option<FileModel>> {
-        Ok(sqlx::query_as!(FileModel, "
-            SELECT version, id, file_name, backup_ts, hsh FROM files 
-            WHERE dir_id = ? AND file_name = ?
-            ORDER BY backup_ts DESC LIMIT 1
-            ", dir_id, file_name
+        Ok(sqlx::query_as!(FileModel, r#"
+            SELECT version, id, file_name, backup_ts as "backup_ts: _", hsh, verified_ts as "verified_ts: _", file_size, generation, label, excluded as "excluded: bool" FROM files 
+            WHERE dir_id = ? AND file_name = ? AND is_latest = 1
+            "#, dir_id, file_name
         )
-            .fetch_optional(self.db).await?)
+            .fetch_optional(&self.db).await?)
 
     } 
     async fn get_dir_files(&self, dir_id: i64, file_name: &str) -> Result<Vec<FileModel>> {
-        Ok(sqlx::query_as!(FileModel, "
-            SELECT version, id, file_name, backup_ts, hsh FROM files 
+        Ok(sqlx::query_as!(FileModel, r#"
+            SELECT version, id, file_name, backup_ts as "backup_ts: _", hsh, verified_ts as "verified_ts: _", file_size, generation, label, excluded as "excluded: bool" FROM files 
             WHERE dir_id = ? AND file_name = ?
-            ", dir_id, file_name
+            "#, dir_id, file_name
         )
-            .fetch_all(self.db).await?)
+            .fetch_all(&self.db).await?)
+    }
+    async fn get_latest_dir_files(&self, dir_id: i64) -> Result<Vec<FileModel>> {
+        Ok(sqlx::query_as!(FileModel, r#"
+            SELECT version, id, file_name, backup_ts as "backup_ts: _", hsh, verified_ts as "verified_ts: _", file_size, generation, label, excluded as "excluded: bool" FROM files
+            WHERE dir_id = ? AND is_latest = 1
+            "#, dir_id
+        )
+            .fetch_all(&self.db).await?)
     }
     async fn create_dir(&self, dir_name: &str, parent_dir_id: Option<i64>) -> Result<i64> {
         Ok(sqlx::query!("INSERT INTO dirs (parent_dir_id, dir_name) VALUES (? ,?)", parent_dir_id, dir_name)
-            .execute(self.db).await?.last_insert_rowid())
+            .execute(&self.db).await?.last_insert_rowid())
     }
-    async fn create_file_entry(&self, dir_id: i64, file_id: i64, file_name: &str, file_hsh: &str, ts: NaiveDateTime) -> Result<()> {
+    async fn batch_create_dirs(&self, dirs: Vec<(String, Option<i64>)>) -> Result<Vec<(String, i64)>> {
+        let mut tx = self.db.begin().await?;
+        let mut created = Vec::with_capacity(dirs.len());
+        for (dir_name, parent_dir_id) in dirs {
+            let id = sqlx::query!("INSERT INTO dirs (parent_dir_id, dir_name) VALUES (?, ?)", parent_dir_id, dir_name)
+                .execute(&mut *tx).await?.last_insert_rowid();
+            created.push((dir_name, id));
+        }
+        tx.commit().await?;
+
+        Ok(created)
+    }
+    async fn create_file_entry(&self, dir_id: i64, file_id: i64, file_name: &str, file_hsh: &str, file_size: i64, ts: DateTime<Utc>, path_policy: &str, run_id: Option<i64>) -> Result<()> {
+        let mut tx = self.db.begin().await?;
+        // A version older than one already recorded, such as an imported backup, doesn't replace it as the latest
+        let is_latest = sqlx::query_scalar!(
+            r#"SELECT NOT EXISTS (SELECT 1 FROM files WHERE dir_id = ? AND file_name = ? AND backup_ts > ?) as "is_latest!: bool""#,
+            dir_id, file_name, ts
+        )
+            .fetch_one(&mut *tx).await?;
+        if is_latest {
+            // The version it replaces stops being the latest in the same transaction, so there's always one
+            clear_latest(&mut *tx, dir_id, file_name).await?;
+        }
+        // A file recreated after its deletion starts a new generation,
+        // while one included again after being excluded carries on its own
         sqlx::query!(
-            "INSERT INTO files (version, dir_id, id, file_name, backup_ts, hsh) VALUES (?, ?, ?, ?, ?, ?)",
-            VERSION, dir_id, file_id, file_name, ts, file_hsh
+            "INSERT INTO files (version, dir_id, id, file_name, backup_ts, hsh, file_size, path_policy, run_id, generation, is_latest) 
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, COALESCE((
+                SELECT generation + (hsh IS NULL AND NOT excluded) FROM files WHERE dir_id = ? AND file_name = ?
+                ORDER BY backup_ts DESC LIMIT 1
+            ), 0), ?)",
+            CURRENT_VERSION, dir_id, file_id, file_name, ts, file_hsh, file_size, path_policy, run_id, dir_id, file_name, is_latest
         )
-            .execute(self.db).await?;
+            .execute(&mut *tx).await?;
+        tx.commit().await?;
 
         Ok(())
     }
-    async fn update_latest_hsh_ts(&self, dir_id: i64, file_name: &str, ts: NaiveDateTime) -> Result<()> {
-        let latest_id = sqlx::query!("SELECT id, MAX(backup_ts) as ts FROM files WHERE dir_id = ? and file_name = ?",
-            dir_id, file_name
-        ).fetch_one(self.db).await?.id.unwrap();
-
-        sqlx::query!("UPDATE files SET backup_ts = ? WHERE id = ?", ts, latest_id)
-            .execute(self.db).await?;
+    async fn update_latest_hsh_ts(&self, dir_id: i64, file_name: &str, ts: DateTime<Utc>) -> Result<()> {
+        // Found and updated in one statement, so a newer entry inserted meanwhile can't be missed
+        sqlx::query!(
+            "UPDATE files SET backup_ts = ? WHERE id = (
+                SELECT id FROM files WHERE dir_id = ? AND file_name = ? ORDER BY backup_ts DESC LIMIT 1
+            )",
+            ts, dir_id, file_name
+        )
+            .execute(&self.db).await?;
 
         Ok(())
     }
-    async fn mark_all_deleted_files(&self, current_run_ts: NaiveDateTime) -> Result<()> {
-        let mut rows = sqlx::query!(
-            r#"SELECT MAX(backup_ts) as "max_ts!: NaiveDateTime", dir_id, file_name, hsh FROM files
-             GROUP BY dir_id, file_name"#
-        ).fetch(self.db);
+    async fn mark_deleted_under(&self, exclusions: DeletionExclusions, current_run_ts: DateTime<Utc>, run_id: Option<i64>) -> Result<Vec<(i64, String)>> {
+        // SQLite can't bind a list, so the dir IDs are passed as a JSON array
+        let excluded_dir_ids = serde_json::to_string(&exclusions.dir_ids).unwrap();
+        // Read in full before any marker is written, as a pool of one connection
+        // can't write while a query is still being streamed from it. Files whose 
+        // latest version is already a marker have no latest version, and stay as they are
+        let rows = sqlx::query!(
+            "SELECT dir_id, file_name, generation FROM files
+            WHERE is_latest = 1 AND backup_ts < ? AND dir_id NOT IN (SELECT value FROM json_each(?))",
+            current_run_ts, excluded_dir_ids
+        ).fetch_all(&self.db).await?;
 
-        while let Some(row) = rows.next().await {
-            let row = row?;
-            if row.max_ts < current_run_ts {
+        let mut tx = self.db.begin().await?;
+        let mut deleted = Vec::new();
+        for row in rows {
+            if !exclusions.files.contains(&(row.dir_id, row.file_name.clone())) {
+                clear_latest(&mut *tx, row.dir_id, &row.file_name).await?;
                 sqlx::query!(
-                    "INSERT INTO files (version, dir_id, file_name, backup_ts, hsh)
-                    VALUES (?, ?, ?, ?, NULL)",
-                    VERSION, row.dir_id, row.file_name, current_run_ts
-                ).execute(self.db).await.unwrap();
+                    "INSERT INTO files (id, version, dir_id, file_name, backup_ts, hsh, generation, run_id)
+                    VALUES ((SELECT next_id FROM file_id_sequence), ?, ?, ?, ?, NULL, ?, ?)",
+                    CURRENT_VERSION, row.dir_id, row.file_name, current_run_ts, row.generation, run_id
+                ).execute(&mut *tx).await?;
+                deleted.push((row.dir_id, row.file_name));
             }
         }
+        tx.commit().await?;
+
+        Ok(deleted)
+    }
+    async fn create_excluded_marker(&self, dir_id: i64, file_name: &str, ts: DateTime<Utc>, run_id: Option<i64>) -> Result<()> {
+        let mut tx = self.db.begin().await?;
+        clear_latest(&mut *tx, dir_id, file_name).await?;
+        sqlx::query!(
+            "INSERT INTO files (id, version, dir_id, file_name, backup_ts, hsh, generation, run_id, excluded) 
+            VALUES ((SELECT next_id FROM file_id_sequence), ?, ?, ?, ?, NULL, COALESCE((
+                SELECT generation FROM files WHERE dir_id = ? AND file_name = ?
+                ORDER BY backup_ts DESC LIMIT 1
+            ), 0), ?, 1)",
+            CURRENT_VERSION, dir_id, file_name, ts, dir_id, file_name, run_id
+        )
+            .execute(&mut *tx).await?;
+        tx.commit().await?;
 
         Ok(())
     }
     async fn delete_file_entry(&self, file_id: i64) -> Result<()> {
-        sqlx::query!("DELETE FROM files WHERE id = ?", file_id).execute(self.db).await?;
+        let mut tx = self.db.begin().await?;
+        let Some(file) = sqlx::query!("SELECT dir_id, file_name FROM files WHERE id = ?", file_id).fetch_optional(&mut *tx).await? else {
+            return Ok(());
+        };
+        sqlx::query!("DELETE FROM files WHERE id = ?", file_id).execute(&mut *tx).await?;
+        // Flags the file's newest remaining version, which is already flagged if the deleted one wasn't the latest
+        sqlx::query!(
+            "UPDATE files SET is_latest = 1 WHERE id = (
+                SELECT id FROM files WHERE dir_id = ? AND file_name = ? ORDER BY backup_ts DESC LIMIT 1
+            ) AND hsh IS NOT NULL",
+            file.dir_id, file.file_name
+        )
+            .execute(&mut *tx).await?;
+        tx.commit().await?;
+
         Ok(())
     }
-}
\ No newline at end of file
+    async fn get_files_needing_reverification(&self, older_than: DateTime<Utc>, limit: u32) -> Result<Vec<FileModel>> {
+        Ok(sqlx::query_as!(FileModel, r#"
+            SELECT version, id, file_name, backup_ts as "backup_ts: _", hsh, verified_ts as "verified_ts: _", file_size, generation, label, excluded as "excluded: bool" FROM files
+            WHERE hsh IS NOT NULL AND (verified_ts IS NULL OR verified_ts < ?)
+            ORDER BY verified_ts ASC LIMIT ?
+            "#, older_than, limit
+        )
+            .fetch_all(&self.db).await?)
+    }
+    async fn mark_file_verified(&self, file_id: i64, ts: DateTime<Utc>) -> Result<()> {
+        sqlx::query!("UPDATE files SET verified_ts = ? WHERE id = ?", ts, file_id)
+            .execute(&self.db).await?;
+        Ok(())
+    }
+    async fn update_file_hash(&self, file_id: i64, new_hsh: &str) -> Result<()> {
+        sqlx::query!("UPDATE files SET hsh = ? WHERE id = ?", new_hsh, file_id)
+            .execute(&self.db).await?;
+        Ok(())
+    }
+    async fn set_version_label(&self, file_id: i64, label: Option<String>) -> Result<()> {
+        sqlx::query!("UPDATE files SET label = ? WHERE id = ?", label, file_id)
+            .execute(&self.db).await?;
+        Ok(())
+    }
+    async fn get_path_policies(&self) -> Result<Vec<String>> {
+        Ok(sqlx::query!("SELECT DISTINCT path_policy as \"path_policy!\" FROM files WHERE path_policy IS NOT NULL")
+            .fetch_all(&self.db).await?.into_iter().map(|r| r.path_policy).collect())
+    }
+    async fn search_files(&self, file_name_pattern: &str) -> Result<Vec<FileWithPath>> {
+        let file_name_pattern = format!("%{}%", file_name_pattern);
+        // Separator directories ("/" on unix, "\" under a windows drive) 
+        // only contribute a separator to the paths built under them
+        Ok(sqlx::query_as!(FileWithPath, r#"
+            WITH RECURSIVE dir_paths(id, full_path) AS (
+                SELECT id, CASE WHEN dir_name IN ('/', '\') THEN '/' ELSE dir_name END
+                FROM dirs WHERE parent_dir_id IS NULL
+                UNION ALL
+                SELECT d.id, CASE 
+                    WHEN d.dir_name IN ('/', '\') THEN p.full_path || '/'
+                    WHEN p.full_path LIKE '%/' THEN p.full_path || d.dir_name
+                    ELSE p.full_path || '/' || d.dir_name END
+                FROM dirs d JOIN dir_paths p ON d.parent_dir_id = p.id
+            )
+            SELECT 
+                f.dir_id as "dir_id!: i64", 
+                f.file_name as "file_name!: String",
+                CASE WHEN p.full_path LIKE '%/' THEN p.full_path || f.file_name
+                    ELSE p.full_path || '/' || f.file_name END as "full_path!: String",
+                MAX(f.backup_ts) as "latest_backup_ts!: DateTime<Utc>",
+                COUNT(f.hsh) as "version_count!: i64"
+            FROM files f JOIN dir_paths p ON f.dir_id = p.id
+            WHERE f.file_name LIKE ? ESCAPE '\'
+            GROUP BY f.dir_id, f.file_name
+            ORDER BY 3
+            "#, file_name_pattern
+        )
+            .fetch_all(&self.db).await?)
+    }
+    async fn find_case_collisions(&self) -> Result<Vec<CaseCollisionEntry>> {
+        Ok(sqlx::query_as!(CaseCollisionEntry, r#"
+            WITH latest AS (
+                SELECT dir_id, file_name FROM files WHERE is_latest = 1
+            )
+            SELECT l.dir_id as "dir_id!: i64", l.file_name as "file_name!: String" FROM latest l
+            JOIN (
+                SELECT dir_id, lower(file_name) as lower_name FROM latest
+                GROUP BY dir_id, lower(file_name) HAVING COUNT(*) > 1
+            ) c ON c.dir_id = l.dir_id AND c.lower_name = lower(l.file_name)
+            ORDER BY l.dir_id, lower(l.file_name), l.file_name
+            "#
+        )
+            .fetch_all(&self.db).await?)
+    }
+    async fn find_hash_collisions(&self) -> Result<Vec<HashCollisionEntry>> {
+        Ok(sqlx::query_as!(HashCollisionEntry, r#"
+            SELECT id, dir_id, file_name, hsh as "hsh!", file_size as "file_size!" FROM files
+            WHERE file_size IS NOT NULL AND hsh IN (
+                SELECT hsh FROM files WHERE hsh IS NOT NULL AND file_size IS NOT NULL
+                GROUP BY hsh HAVING COUNT(DISTINCT file_size) > 1
+            )
+            ORDER BY hsh, file_size
+            "#
+        )
+            .fetch_all(&self.db).await?)
+    }
+    async fn begin_run(&self, started_at: DateTime<Utc>, config_snapshot: &str, origin: &RunOrigin) -> Result<i64> {
+        let trigger = origin.trigger.as_str();
+        Ok(sqlx::query!(
+            "INSERT INTO backup_runs (started_at, config_snapshot, run_trigger, reason) VALUES (?, ?, ?, ?)", 
+            started_at, config_snapshot, trigger, origin.reason
+        )
+            .execute(&self.db).await?.last_insert_rowid())
+    }
+    async fn complete_run(&self, run_id: i64, completed_at: DateTime<Utc>, stats: &RunStats) -> Result<()> {
+        let status = if stats.partial { RunStatus::Partial } else { RunStatus::Completed }.as_str();
+        let (files_scanned, files_backed_up, files_skipped, bytes_backed_up) = 
+            (stats.files_scanned as i64, stats.files_backed_up as i64, stats.files_skipped as i64, stats.bytes_backed_up as i64);
+        let errors = (!stats.errors.is_empty()).then(|| serde_json::to_string(&stats.errors).unwrap());
+        sqlx::query!(
+            "UPDATE backup_runs SET completed_at = ?, status = ?, files_scanned = ?, files_backed_up = ?, files_skipped = ?, 
+            bytes_backed_up = ?, errors = ? WHERE id = ?",
+            completed_at, status, files_scanned, files_backed_up, files_skipped, bytes_backed_up, errors, run_id
+        )
+            .execute(&self.db).await?;
+        Ok(())
+    }
+    async fn list_runs(&self, limit: u32) -> Result<Vec<RunModel>> {
+        let rows = sqlx::query!(r#"
+            SELECT id, started_at as "started_at: DateTime<Utc>", completed_at as "completed_at: DateTime<Utc>", files_scanned, bytes_backed_up, 
+                config_snapshot, status, files_backed_up, files_skipped, errors, run_trigger, reason
+            FROM backup_runs
+            WHERE completed_at IS NOT NULL
+            ORDER BY started_at DESC LIMIT ?
+            "#, limit
+        )
+            .fetch_all(&self.db).await?;
+
+        Ok(rows.into_iter().map(|row| RunModel {
+            id: row.id, started_at: row.started_at, completed_at: row.completed_at, files_scanned: row.files_scanned, 
+            bytes_backed_up: row.bytes_backed_up, config_snapshot: row.config_snapshot, status: RunStatus::parse(&row.status),
+            files_backed_up: row.files_backed_up, files_skipped: row.files_skipped, errors: parse_run_errors(row.errors),
+            origin: RunOrigin { trigger: RunTrigger::parse(&row.run_trigger), reason: row.reason }
+        }).collect())
+    }
+    async fn get_run(&self, run_id: i64) -> Result<Option<RunModel>> {
+        let row = sqlx::query!(r#"
+            SELECT id, started_at as "started_at: DateTime<Utc>", completed_at as "completed_at: DateTime<Utc>", files_scanned, bytes_backed_up, 
+                config_snapshot, status, files_backed_up, files_skipped, errors, run_trigger, reason
+            FROM backup_runs
+            WHERE id = ?
+            "#, run_id
+        )
+            .fetch_optional(&self.db).await?;
+
+        Ok(row.map(|row| RunModel {
+            id: row.id, started_at: row.started_at, completed_at: row.completed_at, files_scanned: row.files_scanned, 
+            bytes_backed_up: row.bytes_backed_up, config_snapshot: row.config_snapshot, status: RunStatus::parse(&row.status),
+            files_backed_up: row.files_backed_up, files_skipped: row.files_skipped, errors: parse_run_errors(row.errors),
+            origin: RunOrigin { trigger: RunTrigger::parse(&row.run_trigger), reason: row.reason }
+        }))
+    }
+    async fn find_run_by_reason(&self, reason: &str) -> Result<Option<RunModel>> {
+        let row = sqlx::query!(r#"
+            SELECT id, started_at as "started_at: DateTime<Utc>", completed_at as "completed_at: DateTime<Utc>", files_scanned, bytes_backed_up, 
+                config_snapshot, status, files_backed_up, files_skipped, errors, run_trigger, reason
+            FROM backup_runs
+            WHERE completed_at IS NOT NULL AND instr(reason, ?) > 0
+            ORDER BY started_at DESC LIMIT 1
+            "#, reason
+        )
+            .fetch_optional(&self.db).await?;
+
+        Ok(row.map(|row| RunModel {
+            id: row.id, started_at: row.started_at, completed_at: row.completed_at, files_scanned: row.files_scanned, 
+            bytes_backed_up: row.bytes_backed_up, config_snapshot: row.config_snapshot, status: RunStatus::parse(&row.status),
+            files_backed_up: row.files_backed_up, files_skipped: row.files_skipped, errors: parse_run_errors(row.errors),
+            origin: RunOrigin { trigger: RunTrigger::parse(&row.run_trigger), reason: row.reason }
+        }))
+    }
+    async fn get_last_run_ts(&self) -> Result<Option<DateTime<Utc>>> {
+        Ok(sqlx::query_scalar!(r#"
+            SELECT started_at as "started_at: DateTime<Utc>" FROM backup_runs
+            WHERE status = 'completed' AND run_trigger != 'ad_hoc' AND errors IS NULL
+            ORDER BY started_at DESC LIMIT 1
+            "#
+        )
+            .fetch_optional(&self.db).await?)
+    }
+    async fn create_file_chunks(&self, file_id: i64, chunks: &[ChunkModel]) -> Result<()> {
+        let mut tx = self.db.begin().await?;
+        for (seq, chunk) in chunks.iter().enumerate() {
+            let seq = seq as i64;
+            sqlx::query!("INSERT OR IGNORE INTO chunks (hsh, chunk_size) VALUES (?, ?)", chunk.hsh, chunk.chunk_size)
+                .execute(&mut *tx).await?;
+            sqlx::query!("INSERT INTO file_chunks (file_id, seq, chunk_hsh) VALUES (?, ?, ?)", file_id, seq, chunk.hsh)
+                .execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+    async fn delete_unreferenced_chunks(&self) -> Result<Vec<String>> {
+        Ok(sqlx::query!("
+            DELETE FROM chunks WHERE NOT EXISTS (SELECT 1 FROM file_chunks WHERE chunk_hsh = chunks.hsh)
+            RETURNING hsh
+            "
+        )
+            .fetch_all(&self.db).await?.into_iter().map(|r| r.hsh).collect())
+    }
+    async fn create_backup_record(
+        &self, file_id: i64, destination: &str, backend: &str, backend_key: &str, backup_ts: DateTime<Utc>, compressed_size: Option<i64>, delta_base_id: Option<i64>
+    ) -> Result<i64> {
+        Ok(sqlx::query!(
+            "INSERT INTO backups (file_id, destination, backend, backend_key, backup_ts, compressed_size, delta_base_id) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            file_id, destination, backend, backend_key, backup_ts, compressed_size, delta_base_id
+        )
+            .execute(&self.db).await?.last_insert_rowid())
+    }
+    async fn get_file_backups(&self, file_id: i64) -> Result<Vec<BackupModel>> {
+        Ok(sqlx::query_as!(BackupModel, r#"
+            SELECT id, file_id, destination, backend, backend_key, backup_ts as "backup_ts: _", compressed_size, delta_base_id FROM backups
+            WHERE file_id = ? ORDER BY backup_ts
+            "#, file_id
+        )
+            .fetch_all(&self.db).await?)
+    }
+    async fn get_backups_outside(&self, destinations: &[String]) -> Result<Vec<BackupModel>> {
+        // SQLite can't bind a list, so the destinations are passed as a JSON array
+        let destinations = serde_json::to_string(destinations).unwrap();
+        Ok(sqlx::query_as!(BackupModel, r#"
+            SELECT id, file_id, destination, backend, backend_key, backup_ts as "backup_ts: _", compressed_size, delta_base_id FROM backups
+            WHERE destination NOT IN (SELECT value FROM json_each(?)) ORDER BY file_id
+            "#, destinations
+        )
+            .fetch_all(&self.db).await?)
+    }
+    async fn get_delta_dependents(&self, file_id: i64) -> Result<Vec<i64>> {
+        Ok(sqlx::query!("SELECT DISTINCT file_id FROM backups WHERE delta_base_id = ?", file_id)
+            .fetch_all(&self.db).await?.into_iter().map(|r| r.file_id).collect())
+    }
+    async fn get_delta_backups(&self) -> Result<Vec<(i64, i64)>> {
+        Ok(sqlx::query!(r#"
+            SELECT DISTINCT file_id, delta_base_id as "delta_base_id!: i64" FROM backups 
+            WHERE delta_base_id IS NOT NULL
+            "#
+        )
+            .fetch_all(&self.db).await?.into_iter().map(|r| (r.file_id, r.delta_base_id)).collect())
+    }
+    async fn clear_delta_base(&self, file_id: i64) -> Result<()> {
+        sqlx::query!("UPDATE backups SET delta_base_id = NULL WHERE file_id = ?", file_id)
+            .execute(&self.db).await?;
+        Ok(())
+    }
+    async fn get_expired_generation_files(&self, deleted_before: DateTime<Utc>) -> Result<Vec<i64>> {
+        Ok(sqlx::query!("
+            SELECT f.id FROM files f JOIN files t
+                ON t.dir_id = f.dir_id AND t.file_name = f.file_name AND t.generation = f.generation
+            WHERE t.hsh IS NULL AND NOT t.excluded AND t.backup_ts < ?
+            ", deleted_before
+        )
+            .fetch_all(&self.db).await?.into_iter().map(|r| r.id).collect())
+    }
+    async fn get_latest_files_with_paths(&self) -> Result<Vec<LatestFileEntry>> {
+        Ok(sqlx::query_as!(LatestFileEntry, r#"
+            WITH RECURSIVE dir_paths(id, full_path) AS (
+                SELECT id, CASE WHEN dir_name IN ('/', '\') THEN '/' ELSE dir_name END
+                FROM dirs WHERE parent_dir_id IS NULL
+                UNION ALL
+                SELECT d.id, CASE 
+                    WHEN d.dir_name IN ('/', '\') THEN p.full_path || '/'
+                    WHEN p.full_path LIKE '%/' THEN p.full_path || d.dir_name
+                    ELSE p.full_path || '/' || d.dir_name END
+                FROM dirs d JOIN dir_paths p ON d.parent_dir_id = p.id
+            )
+            SELECT 
+                CASE WHEN p.full_path LIKE '%/' THEN p.full_path || f.file_name
+                    ELSE p.full_path || '/' || f.file_name END as "full_path!: String",
+                f.file_size,
+                f.backup_ts as "backup_ts: _"
+            FROM files f JOIN dir_paths p ON f.dir_id = p.id
+            WHERE f.is_latest = 1
+            "#
+        )
+            .fetch_all(&self.db).await?)
+    }
+    async fn get_latest_files_snapshot(&self) -> Result<Vec<FileSnapshotEntry>> {
+        // SQLite takes the bare columns from the row holding the MAX. Deletion and exclusion
+        // markers have no hash, so they're left out of the versions backed up.
+        // A file with no latest version has been deleted since, unless it was excluded
+        Ok(sqlx::query_as!(FileSnapshotEntry, r#"
+            WITH latest_backed_up AS (
+                SELECT dir_id, file_name, id, MAX(backup_ts) as backup_ts, hsh FROM files
+                WHERE hsh IS NOT NULL GROUP BY dir_id, file_name
+            )
+            SELECT b.dir_id as "dir_id!", b.file_name as "file_name!", b.id as "latest_id!", b.backup_ts as "latest_ts!: _", 
+                b.hsh as "latest_hsh!", NOT EXISTS (
+                    SELECT 1 FROM files l WHERE l.dir_id = b.dir_id AND l.file_name = b.file_name AND l.is_latest = 1
+                ) AND NOT EXISTS (
+                    SELECT 1 FROM files x WHERE x.dir_id = b.dir_id AND x.file_name = b.file_name 
+                        AND x.backup_ts > b.backup_ts AND x.excluded = 1
+                ) as "is_deleted!: bool"
+            FROM latest_backed_up b
+            ORDER BY b.dir_id, b.file_name
+            "#
+        )
+            .fetch_all(&self.db).await?)
+    }
+    async fn diff_snapshots(&self, before: DateTime<Utc>, after: DateTime<Utc>) -> Result<Vec<FileDiffEntry>> {
+        // A file's history only grows, so every file known `before` is also known `after`
+        let rows = sqlx::query!(r#"
+            WITH before_snapshot AS (
+                SELECT dir_id, file_name, hsh, MAX(backup_ts) FROM files
+                WHERE backup_ts <= ? GROUP BY dir_id, file_name
+            ), after_snapshot AS (
+                SELECT dir_id, file_name, hsh, MAX(backup_ts) FROM files
+                WHERE backup_ts <= ? GROUP BY dir_id, file_name
+            )
+            SELECT a.dir_id as "dir_id!", a.file_name as "file_name!", 
+                b.hsh as "before_hsh?: String", a.hsh as "after_hsh?: String"
+            FROM after_snapshot a LEFT JOIN before_snapshot b 
+                ON b.dir_id = a.dir_id AND b.file_name = a.file_name
+            ORDER BY a.dir_id, a.file_name
+            "#, before, after
+        )
+            .fetch_all(&self.db).await?;
+
+        Ok(rows.into_iter().filter_map(|row| {
+            let change_type = ChangeType::between(row.before_hsh.as_deref(), row.after_hsh.as_deref())?;
+            Some(FileDiffEntry { dir_id: row.dir_id, file_name: row.file_name, change_type })
+        }).collect())
+    }
+    async fn get_backup_run_diff(&self, run_id_a: i64, run_id_b: i64) -> Result<RunDiff> {
+        // Either run may be the later one, so files known to either are compared
+        let rows = sqlx::query!(r#"
+            WITH snapshot_a AS (
+                SELECT dir_id, file_name, hsh, MAX(backup_ts) FROM files
+                WHERE COALESCE(run_id, 0) <= ? GROUP BY dir_id, file_name
+            ), snapshot_b AS (
+                SELECT dir_id, file_name, hsh, MAX(backup_ts) FROM files
+                WHERE COALESCE(run_id, 0) <= ? GROUP BY dir_id, file_name
+            ), known AS (
+                SELECT dir_id, file_name FROM snapshot_a UNION SELECT dir_id, file_name FROM snapshot_b
+            )
+            SELECT k.dir_id as "dir_id!", k.file_name as "file_name!", 
+                a.hsh as "hsh_a?: String", b.hsh as "hsh_b?: String"
+            FROM known k 
+                LEFT JOIN snapshot_a a ON a.dir_id = k.dir_id AND a.file_name = k.file_name
+                LEFT JOIN snapshot_b b ON b.dir_id = k.dir_id AND b.file_name = k.file_name
+            ORDER BY k.dir_id, k.file_name
+            "#, run_id_a, run_id_b
+        )
+            .fetch_all(&self.db).await?;
+
+        let mut diff = RunDiff { added: Vec::new(), modified: Vec::new(), deleted: Vec::new() };
+        for row in rows {
+            let location = FileLocation { dir_id: row.dir_id, file_name: row.file_name };
+            match (row.hsh_a, row.hsh_b) {
+                (None, Some(_)) => diff.added.push(location),
+                (Some(_), None) => diff.deleted.push(location),
+                (Some(hsh_a), Some(hsh_b)) if hsh_a != hsh_b => diff.modified.push((location, hsh_a, hsh_b)),
+                _ => {}
+            }
+        }
+
+        Ok(diff)
+    }
+    async fn set_empty_dirs(&self, dirs: &[EmptyDirModel]) -> Result<()> {
+        let mut tx = self.db.begin().await?;
+        sqlx::query!("UPDATE dirs SET kept_empty = 0, permissions = NULL WHERE kept_empty = 1")
+            .execute(&mut *tx).await?;
+        for dir in dirs {
+            sqlx::query!("UPDATE dirs SET kept_empty = 1, permissions = ? WHERE id = ?", dir.permissions, dir.id)
+                .execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+    async fn get_empty_dirs(&self, dir_name_pattern: &str) -> Result<Vec<EmptyDirModel>> {
+        let dir_name_pattern = format!("%{}%", dir_name_pattern);
+        Ok(sqlx::query_as!(EmptyDirModel, r#"
+            SELECT id, permissions FROM dirs 
+            WHERE kept_empty = 1 AND dir_name LIKE ? ESCAPE '\'
+            ORDER BY id
+            "#, dir_name_pattern
+        )
+            .fetch_all(&self.db).await?)
+    }
+    async fn total_backup_size_bytes(&self) -> Result<u64> {
+        Ok(sqlx::query!(r#"SELECT COALESCE(SUM(file_size), 0) as "total!: i64" FROM files WHERE hsh IS NOT NULL"#)
+            .fetch_one(&self.db).await?.total as u64)
+    }
+    async fn delete_unused_dirs(&self) -> Result<u64> {
+        // A dir is used if it holds a file or is kept empty, as is every dir above it
+        Ok(sqlx::query!(
+            "WITH RECURSIVE used(id) AS (
+                SELECT dir_id FROM files
+                UNION SELECT id FROM dirs WHERE kept_empty = 1
+                UNION SELECT d.parent_dir_id FROM dirs d JOIN used u ON d.id = u.id WHERE d.parent_dir_id IS NOT NULL
+            )
+            DELETE FROM dirs WHERE id NOT IN used"
+        )
+            .execute(&self.db).await?.rows_affected())
+    }
+    async fn vacuum(&self) -> Result<()> {
+        sqlx::query("VACUUM").execute(&self.db).await?;
+        Ok(())
+    }
+    async fn analyze(&self) -> Result<()> {
+        sqlx::query("ANALYZE").execute(&self.db).await?;
+        Ok(())
+    }
+    async fn database_size_bytes(&self) -> Result<u64> {
+        let page_count: i64 = sqlx::query_scalar("PRAGMA page_count").fetch_one(&self.db).await?;
+        let page_size: i64 = sqlx::query_scalar("PRAGMA page_size").fetch_one(&self.db).await?;
+        Ok((page_count * page_size) as u64)
+    }
+    async fn get_backed_up_files(&self) -> Result<Vec<FileModel>> {
+        Ok(sqlx::query_as!(FileModel, r#"
+            SELECT version, id, file_name, backup_ts as "backup_ts: _", hsh, verified_ts as "verified_ts: _", file_size, generation, label, excluded as "excluded: bool" FROM files
+            WHERE hsh IS NOT NULL ORDER BY id
+            "#
+        )
+            .fetch_all(&self.db).await?)
+    }
+    async fn get_backed_up_hashes(&self, after_id: i64, limit: u32) -> Result<Vec<(i64, String)>> {
+        Ok(sqlx::query!(r#"SELECT id, hsh as "hsh!" FROM files WHERE hsh IS NOT NULL AND id > ? ORDER BY id LIMIT ?"#, after_id, limit)
+            .fetch_all(&self.db).await?
+            .into_iter().map(|row| (row.id, row.hsh)).collect())
+    }
+    async fn find_latest_by_hash(&self, hsh: &str) -> Result<Option<i64>> {
+        Ok(sqlx::query_scalar!("SELECT id FROM files WHERE hsh = ? ORDER BY backup_ts DESC LIMIT 1", hsh)
+            .fetch_optional(&self.db).await?)
+    }
+    async fn get_verify_progress(&self) -> Result<Vec<(i64, i64)>> {
+        Ok(sqlx::query!("SELECT shard, last_file_id FROM verify_progress")
+            .fetch_all(&self.db).await?
+            .into_iter().map(|row| (row.shard, row.last_file_id)).collect())
+    }
+    async fn set_verify_progress(&self, shard: i64, last_file_id: i64) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO verify_progress (shard, last_file_id) VALUES (?, ?) 
+            ON CONFLICT (shard) DO UPDATE SET last_file_id = excluded.last_file_id",
+            shard, last_file_id
+        )
+            .execute(&self.db).await?;
+        Ok(())
+    }
+    async fn create_verify_failure(&self, file_id: i64, reason: &str, found_at: DateTime<Utc>) -> Result<()> {
+        sqlx::query!(
+            "INSERT OR REPLACE INTO verify_failures (file_id, reason, found_at) VALUES (?, ?, ?)",
+            file_id, reason, found_at
+        )
+            .execute(&self.db).await?;
+        Ok(())
+    }
+    async fn get_verify_failures(&self) -> Result<Vec<VerifyFailureModel>> {
+        Ok(sqlx::query_as!(VerifyFailureModel, 
+            r#"SELECT file_id, reason, found_at as "found_at: _" FROM verify_failures ORDER BY file_id"#
+        )
+            .fetch_all(&self.db).await?)
+    }
+    async fn clear_verify_progress(&self) -> Result<()> {
+        let mut tx = self.db.begin().await?;
+        sqlx::query!("DELETE FROM verify_progress").execute(&mut *tx).await?;
+        sqlx::query!("DELETE FROM verify_failures").execute(&mut *tx).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use std::{path::{Path, PathBuf}, str::FromStr, sync::Arc};
+
+    use chrono::{TimeZone, Utc};
+    use sqlx::{sqlite::{SqliteConnectOptions, SqlitePoolOptions}, Executor, SqlitePool};
+
+    use crate::{
+        config::CanonicalizePolicy, 
+        history_service::{models::{CaseCollisionEntry, DeletionExclusions, FileLocation, FileModel, RunDiff, RunOrigin, RunStats, RunTrigger}, FileHistoryService, HistoryService}, 
+        time_provider::CoreTimeProvider
+    };
+
+    use super::{DataLayer, DbDataLayer};
+
+    ///
+    /// Opens an empty catalog in memory
+    /// 
+    async fn in_memory_catalog() -> SqlitePool {
+        // Every connection to an in-memory database opens a database of its own
+        let db = SqlitePoolOptions::new().max_connections(1).connect_with(SqliteConnectOptions::from_str("sqlite::memory:").unwrap()).await.unwrap();
+        db.execute(include_str!("../../sql/create.sql")).await.unwrap();
+        db
+    }
+
+    #[tokio::test]
+    async fn test_find_case_collisions() {
+        let db = in_memory_catalog().await;
+        db.execute(r#"
+            INSERT INTO dirs (id, parent_dir_id, dir_name) VALUES (1, NULL, '/'), (2, 1, 'src'), (3, 1, 'other');
+            INSERT INTO files (version, dir_id, file_name, backup_ts, hsh, file_size, is_latest) VALUES
+                (1, 2, 'Makefile', '2024-01-01T00:00:00Z', 'a', 1, 1),
+                (1, 2, 'makefile', '2024-01-01T00:00:00Z', 'b', 1, 1),
+                (1, 2, 'README', '2024-01-01T00:00:00Z', 'c', 1, 1),
+                (1, 2, 'Readme', '2024-01-01T00:00:00Z', 'd', 1, 0),
+                (1, 2, 'Readme', '2024-01-02T00:00:00Z', NULL, NULL, 0),
+                (1, 2, 'notes.txt', '2024-01-01T00:00:00Z', 'e', 1, 1),
+                (1, 3, 'MAKEFILE', '2024-01-01T00:00:00Z', 'f', 1, 1);
+        "#).await.unwrap();
+
+        let collisions = DbDataLayer::new(&db).find_case_collisions().await.unwrap();
+
+        // Readme was deleted, and files in other directories never collide
+        assert_eq!(collisions, vec![
+            CaseCollisionEntry { dir_id: 2, file_name: "Makefile".to_string() },
+            CaseCollisionEntry { dir_id: 2, file_name: "makefile".to_string() }
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_run_origin_is_recorded() {
+        let db = in_memory_catalog().await;
+        let data_layer = DbDataLayer::new(&db);
+        let origins = [
+            RunOrigin::default(),
+            RunOrigin { trigger: RunTrigger::Scheduled, reason: Some("nightly".to_string()) },
+            RunOrigin { trigger: RunTrigger::AdHoc, reason: Some("pre-upgrade snapshot".to_string()) },
+            RunOrigin { trigger: RunTrigger::Watch, reason: Some("pre-upgrade retry".to_string()) },
+        ];
+        let mut run_ids = Vec::new();
+        for (day, origin) in origins.iter().enumerate() {
+            let started_at = Utc.with_ymd_and_hms(2024, 1, day as u32 + 1, 0, 0, 0).unwrap();
+            run_ids.push(data_layer.begin_run(started_at, "{}", origin).await.unwrap());
+        }
+        // The last run never finished
+        for run_id in &run_ids[..3] {
+            data_layer.complete_run(*run_id, Utc::now(), &RunStats::default()).await.unwrap();
+        }
+
+        for (run_id, origin) in run_ids.iter().zip(&origins) {
+            assert_eq!(&data_layer.get_run(*run_id).await.unwrap().unwrap().origin, origin);
+        }
+        let found = data_layer.find_run_by_reason("upgrade").await.unwrap().unwrap();
+        assert_eq!(found.id, run_ids[2]);
+        assert!(data_layer.find_run_by_reason("weekly").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get_last_run_ts() {
+        let db = in_memory_catalog().await;
+        let data_layer = DbDataLayer::new(&db);
+        assert_eq!(data_layer.get_last_run_ts().await.unwrap(), None);
+
+        let day = |day| Utc.with_ymd_and_hms(2024, 1, day, 0, 0, 0).unwrap();
+        let full = data_layer.begin_run(day(1), "{}", &RunOrigin::default()).await.unwrap();
+        data_layer.complete_run(full, day(1), &RunStats::default()).await.unwrap();
+        // None of the later runs examined every file
+        let partial = data_layer.begin_run(day(2), "{}", &RunOrigin::default()).await.unwrap();
+        data_layer.complete_run(partial, day(2), &RunStats { partial: true, ..Default::default() }).await.unwrap();
+        let ad_hoc = data_layer.begin_run(day(3), "{}", &RunOrigin { trigger: RunTrigger::AdHoc, reason: None }).await.unwrap();
+        data_layer.complete_run(ad_hoc, day(3), &RunStats::default()).await.unwrap();
+        let failed = data_layer.begin_run(day(4), "{}", &RunOrigin::default()).await.unwrap();
+        data_layer.complete_run(failed, day(4), &RunStats { errors: vec!["unreadable".to_string()], ..Default::default() }).await.unwrap();
+        data_layer.begin_run(day(5), "{}", &RunOrigin::default()).await.unwrap();
+
+        assert_eq!(data_layer.get_last_run_ts().await.unwrap(), Some(day(1)));
+    }
+
+    #[tokio::test]
+    async fn test_update_file_hash() {
+        let db = in_memory_catalog().await;
+        db.execute(r#"
+            INSERT INTO dirs (id, parent_dir_id, dir_name) VALUES (1, NULL, '/');
+            INSERT INTO files (id, version, dir_id, file_name, backup_ts, hsh, file_size) VALUES
+                (1, 1, 1, 'a.txt', '2024-01-01T00:00:00Z', 'old', 1),
+                (2, 1, 1, 'b.txt', '2024-01-01T00:00:00Z', 'old', 1);
+        "#).await.unwrap();
+        let data_layer = DbDataLayer::new(&db);
+
+        data_layer.update_file_hash(1, "new").await.unwrap();
+
+        let hashes: Vec<_> = data_layer.get_backed_up_files().await.unwrap().into_iter().map(|file| file.hsh.unwrap()).collect();
+        assert_eq!(hashes, ["new", "old"]);
+    }
+
+    #[tokio::test]
+    async fn test_set_version_label() {
+        let db = in_memory_catalog().await;
+        db.execute(r#"
+            INSERT INTO dirs (id, parent_dir_id, dir_name) VALUES (1, NULL, '/');
+            INSERT INTO files (id, version, dir_id, file_name, backup_ts, hsh, file_size) VALUES
+                (1, 1, 1, 'budget.xlsx', '2024-01-01T00:00:00Z', 'a', 1),
+                (2, 1, 1, 'budget.xlsx', '2024-01-02T00:00:00Z', 'b', 1);
+        "#).await.unwrap();
+        let data_layer = DbDataLayer::new(&db);
+
+        data_layer.set_version_label(1, Some("before reformatting".to_string())).await.unwrap();
+        let labels = |files: Vec<FileModel>| files.into_iter().map(|file| (file.id, file.label)).collect::<Vec<_>>();
+        assert_eq!(
+            labels(data_layer.get_dir_files(1, "budget.xlsx").await.unwrap()),
+            [(1, Some("before reformatting".to_string())), (2, None)]
+        );
+
+        data_layer.set_version_label(1, None).await.unwrap();
+        assert_eq!(labels(data_layer.get_dir_files(1, "budget.xlsx").await.unwrap()), [(1, None), (2, None)]);
+    }
+
+    #[tokio::test]
+    async fn test_batch_create_dirs() {
+        let db = in_memory_catalog().await;
+        let data_layer = DbDataLayer::new(&db);
+        let root_id = data_layer.create_dir("/", None).await.unwrap();
+
+        let created = data_layer.batch_create_dirs(vec![
+            ("docs".to_string(), Some(root_id)),
+            ("music".to_string(), Some(root_id))
+        ]).await.unwrap();
+
+        assert_eq!(created.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>(), ["docs", "music"]);
+        let sub_dirs: Vec<_> = data_layer.get_sub_dirs(root_id).await.unwrap().into_iter().map(|dir| (dir.dir_name, dir.id)).collect();
+        assert_eq!(sub_dirs, created);
+    }
+
+    #[tokio::test]
+    async fn test_get_backed_up_hashes_and_find_latest_by_hash() {
+        let db = in_memory_catalog().await;
+        db.execute(r#"
+            INSERT INTO dirs (id, parent_dir_id, dir_name) VALUES (1, NULL, '/');
+            INSERT INTO files (id, version, dir_id, file_name, backup_ts, hsh, file_size) VALUES
+                (1, 1, 1, 'a.txt', '2024-01-01T00:00:00Z', 'same', 1),
+                (2, 1, 1, 'a.txt', '2024-01-02T00:00:00Z', NULL, NULL),
+                (3, 1, 1, 'b.txt', '2024-01-03T00:00:00Z', 'same', 1),
+                (4, 1, 1, 'c.txt', '2024-01-04T00:00:00Z', 'other', 1);
+        "#).await.unwrap();
+        let data_layer = DbDataLayer::new(&db);
+
+        // Deletion markers have no hash to be found by
+        assert_eq!(data_layer.get_backed_up_hashes(0, 2).await.unwrap(), [(1, "same".to_string()), (3, "same".to_string())]);
+        assert_eq!(data_layer.get_backed_up_hashes(3, 2).await.unwrap(), [(4, "other".to_string())]);
+        assert_eq!(data_layer.find_latest_by_hash("same").await.unwrap(), Some(3));
+        assert_eq!(data_layer.find_latest_by_hash("missing").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_excluded_file_resumes_its_generation() {
+        let db = in_memory_catalog().await;
+        let data_layer = DbDataLayer::new(&db);
+        let dir_id = data_layer.create_dir("/", None).await.unwrap();
+        let day = |day| Utc.with_ymd_and_hms(2024, 1, day, 0, 0, 0).unwrap();
+        // Markers take their IDs from the sequence too, so every ID is allocated rather than chosen
+        let file_id = data_layer.allocate_file_id().await.unwrap();
+        data_layer.create_file_entry(dir_id, file_id, "video.mkv", "a", 1, day(1), "full", None).await.unwrap();
+
+        // An exclude added before the second run
+        data_layer.create_excluded_marker(dir_id, "video.mkv", day(2), None).await.unwrap();
+        let deleted = data_layer.mark_deleted_under(DeletionExclusions::default(), day(3), None).await.unwrap();
+        assert!(deleted.is_empty());
+        // Removed again before the fourth
+        let file_id = data_layer.allocate_file_id().await.unwrap();
+        data_layer.create_file_entry(dir_id, file_id, "video.mkv", "b", 1, day(4), "full", None).await.unwrap();
+
+        let versions: Vec<_> = data_layer.get_dir_files(dir_id, "video.mkv").await.unwrap().into_iter()
+            .map(|file| (file.hsh, file.excluded, file.generation))
+            .collect();
+        assert_eq!(versions, [(Some("a".to_string()), false, 0), (None, true, 0), (Some("b".to_string()), false, 0)]);
+        // Only deletion markers expire the generation they end
+        assert!(data_layer.get_expired_generation_files(day(10)).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_subtree_is_not_marked_deleted() {
+        let db = in_memory_catalog().await;
+        let data_layer = Arc::new(DbDataLayer::new(&db));
+        let root_id = data_layer.create_dir("/", None).await.unwrap();
+        let data_id = data_layer.create_dir("data", Some(root_id)).await.unwrap();
+        let locked_id = data_layer.create_dir("locked", Some(data_id)).await.unwrap();
+        let deep_id = data_layer.create_dir("deep", Some(locked_id)).await.unwrap();
+        let day = |day| Utc.with_ymd_and_hms(2024, 1, day, 0, 0, 0).unwrap();
+        for (dir_id, file_name) in [(data_id, "gone.txt"), (locked_id, "a.txt"), (deep_id, "b.txt")] {
+            let file_id = data_layer.allocate_file_id().await.unwrap();
+            data_layer.create_file_entry(dir_id, file_id, file_name, "hash", 1, day(1), "full", None).await.unwrap();
+        }
+        let history = FileHistoryService::new(data_layer.clone(), Arc::new(CoreTimeProvider::new()), 2, CanonicalizePolicy::Full).await.unwrap();
+
+        // "locked" couldn't be read this run, so nothing beneath it, at any depth, is known to be gone
+        let deleted = history.mark_all_deleted_files(&[PathBuf::from("/data/locked")]).await.unwrap();
+
+        assert_eq!(deleted, [PathBuf::from("/data/gone.txt")]);
+        let markers: Vec<(i64, String)> = sqlx::query_as("SELECT dir_id, file_name FROM files WHERE hsh IS NULL")
+            .fetch_all(&db).await.unwrap();
+        assert_eq!(markers, [(data_id, "gone.txt".to_string())]);
+    }
+
+
+    #[tokio::test]
+    async fn test_get_backup_run_diff() {
+        let db = in_memory_catalog().await;
+        let data_layer = DbDataLayer::new(&db);
+        for day in 1..=3 {
+            data_layer.begin_run(Utc.with_ymd_and_hms(2024, 1, day, 0, 0, 0).unwrap(), "{}", &RunOrigin::default()).await.unwrap();
+        }
+        db.execute(r#"
+            INSERT INTO dirs (id, parent_dir_id, dir_name) VALUES (1, NULL, '/');
+            INSERT INTO files (version, dir_id, file_name, backup_ts, hsh, file_size, run_id) VALUES
+                (1, 1, 'imported.txt', '2023-12-01T00:00:00Z', 'i', 1, NULL),
+                (1, 1, 'edited.txt', '2024-01-01T00:00:00Z', 'a', 1, 1),
+                (1, 1, 'removed.txt', '2024-01-01T00:00:00Z', 'b', 1, 1),
+                (1, 1, 'edited.txt', '2024-01-02T00:00:00Z', 'c', 1, 2),
+                (1, 1, 'removed.txt', '2024-01-02T00:00:00Z', NULL, NULL, 2),
+                (1, 1, 'new.txt', '2024-01-02T00:00:00Z', 'd', 1, 2),
+                (1, 1, 'later.txt', '2024-01-03T00:00:00Z', 'e', 1, 3);
+        "#).await.unwrap();
+        let location = |file_name: &str| FileLocation { dir_id: 1, file_name: file_name.to_string() };
+
+        let diff = data_layer.get_backup_run_diff(1, 2).await.unwrap();
+
+        assert_eq!(diff, RunDiff {
+            added: vec![location("new.txt")],
+            modified: vec![(location("edited.txt"), "a".to_string(), "c".to_string())],
+            deleted: vec![location("removed.txt")]
+        });
+        // Comparing backwards swaps what was added and deleted
+        let diff = data_layer.get_backup_run_diff(3, 2).await.unwrap();
+        assert_eq!(diff.added, Vec::<FileLocation>::new());
+        assert_eq!(diff.deleted, vec![location("later.txt")]);
+        assert!(diff.modified.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_allocate_file_id() {
+        let db = in_memory_catalog().await;
+        db.execute(r#"INSERT INTO dirs (id, parent_dir_id, dir_name) VALUES (1, NULL, '/');"#).await.unwrap();
+        let data_layer = DbDataLayer::new(&db);
+        let ts = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+
+        assert_eq!(data_layer.allocate_file_id().await.unwrap(), 1);
+        assert_eq!(data_layer.allocate_file_id().await.unwrap(), 2);
+        // A version recorded with an ID from elsewhere, ie. an imported backup, moves the sequence past it
+        data_layer.create_file_entry(1, 50, "imported.txt", "hsh", 1, ts, "full", None).await.unwrap();
+        assert_eq!(data_layer.allocate_file_id().await.unwrap(), 51);
+        // Deletion markers take their ID from the sequence, so it isn't given out again
+        data_layer.create_excluded_marker(1, "imported.txt", ts, None).await.unwrap();
+        let marker_id = data_layer.get_dir_files(1, "imported.txt").await.unwrap().iter().map(|file| file.id).max();
+        assert_eq!(marker_id, Some(52));
+        assert_eq!(data_layer.allocate_file_id().await.unwrap(), 53);
+    }
+
+    #[tokio::test]
+    async fn test_migrate_file_id_sequence() {
+        let db = SqlitePoolOptions::new().max_connections(1).connect_with(SqliteConnectOptions::from_str("sqlite::memory:").unwrap()).await.unwrap();
+        db.execute(r#"
+            PRAGMA user_version = 2;
+            CREATE TABLE files (id INTEGER PRIMARY KEY NOT NULL, file_name TEXT NOT NULL);
+            INSERT INTO files (id, file_name) VALUES (1, 'a.txt'), (7, 'b.txt');
+        "#).await.unwrap();
+        let data_layer = DbDataLayer::new(&db);
+
+        data_layer.migrate_file_id_sequence().await.unwrap();
+        // Migrating again leaves the catalog as it is
+        data_layer.migrate_file_id_sequence().await.unwrap();
+
+        assert_eq!(data_layer.allocate_file_id().await.unwrap(), 8);
+        let version: i64 = sqlx::query_scalar("PRAGMA user_version").fetch_one(&db).await.unwrap();
+        assert_eq!(version, 3);
+    }
+
+    #[tokio::test]
+    async fn test_root_is_recorded_as_slash() {
+        let db = in_memory_catalog().await;
+        let data_layer = Arc::new(DbDataLayer::new(&db));
+        let mut history = FileHistoryService::new(data_layer.clone(), Arc::new(CoreTimeProvider::new()), 2, CanonicalizePolicy::Full).await.unwrap();
+
+        history.get_file_status(Path::new("/etc/hosts"), "hash", 1).await.unwrap();
+        // A path split as a string starts with an empty component, which is the same root
+        let etc_id = history.traverse_to_subdir(["", "etc", "hosts"].into_iter(), false).await.unwrap();
+
+        let dirs: Vec<(i64, Option<i64>, String)> = sqlx::query_as("SELECT id, parent_dir_id, dir_name FROM dirs ORDER BY id")
+            .fetch_all(&db).await.unwrap();
+        assert_eq!(dirs, [(1, None, "/".to_string()), (2, Some(1), "etc".to_string())]);
+        assert_eq!(etc_id, Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_create_file_entry_rejects_a_reused_id() {
+        let db = in_memory_catalog().await;
+        db.execute("INSERT INTO dirs (id, parent_dir_id, dir_name) VALUES (1, NULL, '/')").await.unwrap();
+        let data_layer = DbDataLayer::new(&db);
+        let ts = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+
+        data_layer.create_file_entry(1, 7, "notes.txt", "a", 1, ts, "full", None).await.unwrap();
+        // A retry with the same id, even of the same file, fails rather than recording it twice
+        assert!(data_layer.create_file_entry(1, 7, "notes.txt", "a", 1, ts, "full", None).await.is_err());
+        assert!(data_layer.create_file_entry(1, 7, "other.txt", "b", 2, ts, "full", None).await.is_err());
+
+        let rows: Vec<(i64, String)> = sqlx::query_as("SELECT id, hsh FROM files").fetch_all(&db).await.unwrap();
+        assert_eq!(rows, [(7, "a".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn test_update_latest_hsh_ts_only_updates_the_latest_entry() {
+        let db = in_memory_catalog().await;
+        db.execute(r#"
+            INSERT INTO dirs (id, parent_dir_id, dir_name) VALUES (1, NULL, '/');
+            INSERT INTO files (id, version, dir_id, file_name, backup_ts, hsh, file_size) VALUES
+                (1, 1, 1, 'notes.txt', '2024-01-01T00:00:00Z', 'a', 1),
+                (2, 1, 1, 'notes.txt', '2024-03-01T00:00:00Z', 'c', 1),
+                (3, 1, 1, 'notes.txt', '2024-02-01T00:00:00Z', 'b', 1),
+                (4, 1, 1, 'other.txt', '2024-03-01T00:00:00Z', 'd', 1);
+        "#).await.unwrap();
+        let ts = Utc.with_ymd_and_hms(2024, 4, 1, 0, 0, 0).unwrap();
+
+        DbDataLayer::new(&db).update_latest_hsh_ts(1, "notes.txt", ts).await.unwrap();
+
+        let rows: Vec<(i64, String)> = sqlx::query_as("SELECT id, backup_ts FROM files ORDER BY id").fetch_all(&db).await.unwrap();
+        let updated: Vec<i64> = rows.iter().filter(|(_, backup_ts)| backup_ts.starts_with("2024-04-01")).map(|(id, _)| *id).collect();
+        assert_eq!(updated, [2]);
+        // A file with no entries has nothing to update
+        DbDataLayer::new(&db).update_latest_hsh_ts(1, "missing.txt", ts).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_only_the_latest_version_of_an_existing_file_is_latest() {
+        let db = in_memory_catalog().await;
+        db.execute("INSERT INTO dirs (id, parent_dir_id, dir_name) VALUES (1, NULL, '/')").await.unwrap();
+        let data_layer = DbDataLayer::new(&db);
+        let day = |day| Utc.with_ymd_and_hms(2024, 1, day, 0, 0, 0).unwrap();
+        let latest = || async {
+            sqlx::query_scalar::<_, i64>("SELECT id FROM files WHERE is_latest = 1 ORDER BY id").fetch_all(&db).await.unwrap()
+        };
+
+        data_layer.create_file_entry(1, 1, "notes.txt", "a", 1, day(1), "full", None).await.unwrap();
+        data_layer.create_file_entry(1, 2, "other.txt", "b", 1, day(1), "full", None).await.unwrap();
+        data_layer.create_file_entry(1, 3, "notes.txt", "c", 1, day(2), "full", None).await.unwrap();
+        assert_eq!(latest().await, [2, 3]);
+
+        // A file which left the backup set has no latest version
+        data_layer.create_excluded_marker(1, "other.txt", day(3), None).await.unwrap();
+        assert_eq!(latest().await, [3]);
+        // A version older than the latest, as when a backup is imported, doesn't replace it
+        let imported_id = data_layer.allocate_file_id().await.unwrap();
+        let before = Utc.with_ymd_and_hms(2023, 12, 31, 0, 0, 0).unwrap();
+        data_layer.create_file_entry(1, imported_id, "notes.txt", "z", 1, before, "full", None).await.unwrap();
+        assert_eq!(latest().await, [3]);
+        // Removing the latest version makes the one before it the latest
+        data_layer.delete_file_entry(3).await.unwrap();
+        assert_eq!(latest().await, [1]);
+        // while a file whose latest version is a marker is left without one
+        data_layer.delete_file_entry(2).await.unwrap();
+        assert_eq!(latest().await, [1]);
+    }
+
+    #[tokio::test]
+    async fn test_migrate_is_latest() {
+        let db = SqlitePoolOptions::new().max_connections(1).connect_with(SqliteConnectOptions::from_str("sqlite::memory:").unwrap()).await.unwrap();
+        db.execute(r#"
+            PRAGMA user_version = 3;
+            CREATE TABLE files (id INTEGER PRIMARY KEY NOT NULL, dir_id INTEGER NOT NULL, file_name TEXT NOT NULL, backup_ts DATETIME NOT NULL, hsh TEXT);
+            INSERT INTO files (id, dir_id, file_name, backup_ts, hsh) VALUES
+                (1, 1, 'notes.txt', '2024-01-01T00:00:00+00:00', 'a'),
+                (2, 1, 'notes.txt', '2024-01-03T00:00:00+00:00', 'c'),
+                (3, 1, 'notes.txt', '2024-01-02T00:00:00+00:00', 'b'),
+                (4, 2, 'notes.txt', '2024-01-01T00:00:00+00:00', 'd'),
+                (5, 1, 'deleted.txt', '2024-01-01T00:00:00+00:00', 'e'),
+                (6, 1, 'deleted.txt', '2024-01-02T00:00:00+00:00', NULL);
+        "#).await.unwrap();
+        let data_layer = DbDataLayer::new(&db);
+
+        data_layer.migrate_is_latest().await.unwrap();
+        // Migrating again leaves the catalog as it is
+        data_layer.migrate_is_latest().await.unwrap();
+
+        let latest: Vec<i64> = sqlx::query_scalar("SELECT id FROM files WHERE is_latest = 1 ORDER BY id").fetch_all(&db).await.unwrap();
+        assert_eq!(latest, [2, 4]);
+        let version: i64 = sqlx::query_scalar("PRAGMA user_version").fetch_one(&db).await.unwrap();
+        assert_eq!(version, 4);
+    }
+
+    ///
+    /// Gets every column of every table in `db`, and the name of every index and trigger, in order
+    /// 
+    async fn schema_of(db: &SqlitePool) -> (Vec<(String, String)>, Vec<String>) {
+        let columns = sqlx::query_as(
+            "SELECT m.name, c.name FROM sqlite_master m JOIN pragma_table_info(m.name) c WHERE m.type = 'table' ORDER BY 1, 2"
+        )
+            .fetch_all(db).await.unwrap();
+        let others = sqlx::query_scalar("SELECT name FROM sqlite_master WHERE type IN ('index', 'trigger') AND sql IS NOT NULL ORDER BY 1")
+            .fetch_all(db).await.unwrap();
+        (columns, others)
+    }
+
+    #[tokio::test]
+    async fn test_migrate_original_catalog() {
+        let db = SqlitePoolOptions::new().max_connections(1).connect_with(SqliteConnectOptions::from_str("sqlite::memory:").unwrap()).await.unwrap();
+        // The catalog as the first version of drive_backup created it
+        db.execute(r#"
+            CREATE TABLE dirs (
+                id INTEGER PRIMARY KEY NOT NULL,
+                parent_dir_id INTEGER,
+                dir_name TEXT NOT NULL,
+                FOREIGN KEY (parent_dir_id) REFERENCES dirs (id)
+            );
+            CREATE TABLE files (
+                id INTEGER PRIMARY KEY NOT NULL,
+                version INTEGER NOT NULL,
+                dir_id INTEGER NOT NULL,
+                file_name TEXT NOT NULL,
+                backup_ts DATETIME NOT NULL,
+                hsh TEXT,
+                FOREIGN KEY(dir_id) REFERENCES dirs (id) ON DELETE CASCADE
+            );
+            CREATE INDEX idx_dirs_path_name ON dirs(dir_name);
+            CREATE INDEX idx_entrs_file_name ON files(file_name);
+            INSERT INTO dirs (id, parent_dir_id, dir_name) VALUES (1, NULL, '/');
+            INSERT INTO files (id, version, dir_id, file_name, backup_ts, hsh) VALUES
+                (1, 1, 1, 'notes.txt', '2024-01-01 00:00:00', 'a'),
+                (2, 1, 1, 'notes.txt', '2024-01-02 00:00:00', 'b'),
+                (3, 1, 1, 'gone.txt', '2024-01-01 00:00:00', 'c'),
+                (4, 1, 1, 'gone.txt', '2024-01-02 00:00:00', NULL);
+        "#).await.unwrap();
+        let data_layer = DbDataLayer::new(&db);
+
+        // In the order the catalog is migrated when opened
+        data_layer.migrate_schema().await.unwrap();
+        data_layer.migrate_utc_timestamps().await.unwrap();
+        data_layer.migrate_file_id_sequence().await.unwrap();
+        data_layer.migrate_is_latest().await.unwrap();
+
+        // The migrated catalog has everything a new one is created with
+        assert_eq!(schema_of(&db).await, schema_of(&in_memory_catalog().await).await);
+        let version: i64 = sqlx::query_scalar("PRAGMA user_version").fetch_one(&db).await.unwrap();
+        assert_eq!(version, 4);
+
+        // and records a run as a new one does
+        let day = |day| Utc.with_ymd_and_hms(2024, 1, day, 0, 0, 0).unwrap();
+        let run_id = data_layer.begin_run(day(3), "{}", &RunOrigin::default()).await.unwrap();
+        let file_id = data_layer.allocate_file_id().await.unwrap();
+        assert_eq!(file_id, 5);
+        data_layer.create_file_entry(1, file_id, "notes.txt", "c", 1, day(3), "full", Some(run_id)).await.unwrap();
+        data_layer.complete_run(run_id, day(3), &RunStats::default()).await.unwrap();
+
+        let snapshot: Vec<_> = data_layer.get_latest_files_snapshot().await.unwrap().into_iter()
+            .map(|entry| (entry.file_name, entry.latest_id, entry.latest_ts, entry.is_deleted))
+            .collect();
+        assert_eq!(snapshot, [("gone.txt".to_string(), 3, day(1), true), ("notes.txt".to_string(), 5, day(3), false)]);
+        assert_eq!(data_layer.get_run(run_id).await.unwrap().unwrap().started_at, day(3));
+    }
+
+    #[tokio::test]
+    async fn test_latest_files_snapshot_leaves_out_markers() {
+        let db = in_memory_catalog().await;
+        let data_layer = DbDataLayer::new(&db);
+        let dir_id = data_layer.create_dir("/", None).await.unwrap();
+        let day = |day| Utc.with_ymd_and_hms(2024, 1, day, 0, 0, 0).unwrap();
+        for (file_name, hsh, ts) in [("kept.txt", "a", day(1)), ("kept.txt", "b", day(2)), ("deleted.txt", "c", day(1)), ("excluded.txt", "d", day(1))] {
+            let file_id = data_layer.allocate_file_id().await.unwrap();
+            data_layer.create_file_entry(dir_id, file_id, file_name, hsh, 1, ts, "full", None).await.unwrap();
+        }
+        data_layer.create_excluded_marker(dir_id, "excluded.txt", day(2), None).await.unwrap();
+        let exclusions = DeletionExclusions { files: [(dir_id, "kept.txt".to_string()), (dir_id, "excluded.txt".to_string())].into(), ..Default::default() };
+        data_layer.mark_deleted_under(exclusions, day(3), None).await.unwrap();
+
+        let snapshot: Vec<_> = data_layer.get_latest_files_snapshot().await.unwrap().into_iter()
+            .map(|entry| (entry.file_name, entry.latest_id, entry.latest_hsh, entry.is_deleted))
+            .collect();
+        // Each file is returned by its last version backed up, never by a marker
+        assert_eq!(snapshot, [
+            ("deleted.txt".to_string(), 3, "c".to_string(), true),
+            ("excluded.txt".to_string(), 4, "d".to_string(), false),
+            ("kept.txt".to_string(), 2, "b".to_string(), false),
+        ]);
+    }
+}
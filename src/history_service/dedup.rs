@@ -0,0 +1,196 @@
+use std::{collections::HashMap, hash::{DefaultHasher, Hash, Hasher}, sync::{Arc, Mutex}};
+
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+use super::data_layer::DataLayer;
+use crate::data_layer_error::Result;
+
+///
+/// The number of hashes read from the catalog at a time while loading the index
+/// 
+const LOAD_PAGE_SIZE: u32 = 10_000;
+
+///
+/// An index of the file version backed up with each hash, shared by everything backing up
+/// files during a run, so finding whether some contents are already stored doesn't need the catalog.
+/// Hashes are held as 64-bit digests to bound its size. Since two hashes may share a digest,
+/// a digest found in the index is confirmed against the catalog, while one missing from it
+/// means the contents were never stored
+/// 
+#[derive(Default)]
+pub struct DedupIndex {
+    /// The latest file version stored with each hash, by the hash's digest
+    ids: Mutex<HashMap<u64, i64>>,
+    /// The lock of each hash currently being looked up or stored
+    locks: Mutex<HashMap<String, Arc<AsyncMutex<()>>>>
+}
+
+impl DedupIndex {
+    ///
+    /// Builds the index from every file version with a backup in the catalog,
+    /// reading their hashes a page at a time
+    /// 
+    pub async fn load(data_layer: &dyn DataLayer) -> Result<Self> {
+        let index = Self::default();
+        let mut after_id = 0;
+        loop {
+            let page = data_layer.get_backed_up_hashes(after_id, LOAD_PAGE_SIZE).await?;
+            let Some((last_id, _)) = page.last() else { break };
+            after_id = *last_id;
+
+            let mut ids = index.ids.lock().unwrap();
+            for (id, hsh) in &page {
+                ids.insert(digest(hsh), *id);
+            }
+        }
+
+        Ok(index)
+    }
+
+    ///
+    /// Waits for any other file with the same `hsh` to be looked up and stored,
+    /// then holds the hash until the returned lock is dropped. Of several files with
+    /// the same contents, only the first to take the lock finds them missing and stores
+    /// them, while the rest wait and then find its backup
+    /// 
+    pub async fn lock(&self, hsh: &str) -> HashLock<'_> {
+        let lock = self.locks.lock().unwrap()
+            .entry(hsh.to_string())
+            .or_default()
+            .clone();
+
+        HashLock { index: self, hsh: hsh.to_string(), guard: Some(lock.lock_owned().await) }
+    }
+}
+
+///
+/// Holds a hash in the `DedupIndex`, so that no other file with the same contents
+/// is looked up or stored until it's dropped
+/// 
+pub struct HashLock<'a> {
+    index: &'a DedupIndex,
+    hsh: String,
+    guard: Option<OwnedMutexGuard<()>>
+}
+
+impl HashLock<'_> {
+    ///
+    /// Gets the ID of the latest file version stored with the hash, if any
+    /// 
+    pub async fn find(&self, data_layer: &dyn DataLayer) -> Result<Option<i64>> {
+        let indexed = self.index.ids.lock().unwrap().contains_key(&digest(&self.hsh));
+        match indexed {
+            // Another hash may share the digest, so the catalog has the final say
+            true => data_layer.find_latest_by_hash(&self.hsh).await,
+            false => Ok(None)
+        }
+    }
+
+    ///
+    /// Records the file version with the given `file_id` as stored with the hash,
+    /// once its backup is committed
+    /// 
+    pub fn insert(&self, file_id: i64) {
+        self.index.ids.lock().unwrap().insert(digest(&self.hsh), file_id);
+    }
+}
+
+impl Drop for HashLock<'_> {
+    fn drop(&mut self) {
+        drop(self.guard.take());
+        // The lock is only kept while another file with the hash is waiting on it
+        let mut locks = self.index.locks.lock().unwrap();
+        if locks.get(&self.hsh).is_some_and(|lock| Arc::strong_count(lock) == 1) {
+            locks.remove(&self.hsh);
+        }
+    }
+}
+
+///
+/// The 64-bit digest a hash is held as in the index
+/// 
+fn digest(hsh: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hsh.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::future::join_all;
+    use mockall::predicate::eq;
+    use tokio::sync::Mutex as AsyncMutex;
+
+    use crate::{backup_service::{BackupService, FileBackupService}, history_service::data_layer::MockDataLayer};
+
+    use super::{digest, DedupIndex};
+
+    #[tokio::test]
+    async fn test_load_reads_every_page() {
+        let mut mock_dl = MockDataLayer::new();
+        mock_dl.expect_get_backed_up_hashes()
+            .with(eq(0), eq(super::LOAD_PAGE_SIZE))
+            .returning(|_, _| Ok(vec![(1, "a".to_string()), (2, "b".to_string())]));
+        mock_dl.expect_get_backed_up_hashes()
+            .with(eq(2), eq(super::LOAD_PAGE_SIZE))
+            .returning(|_, _| Ok(vec![(5, "c".to_string())]));
+        mock_dl.expect_get_backed_up_hashes()
+            .with(eq(5), eq(super::LOAD_PAGE_SIZE))
+            .returning(|_, _| Ok(Vec::new()));
+
+        let index = DedupIndex::load(&mock_dl).await.unwrap();
+
+        let ids = index.ids.lock().unwrap();
+        assert_eq!(ids.len(), 3);
+        assert_eq!(ids[&digest("c")], 5);
+    }
+
+    #[tokio::test]
+    async fn test_find_confirms_digest_with_catalog() {
+        let mut mock_dl = MockDataLayer::new();
+        mock_dl.expect_find_latest_by_hash().withf(|hsh| hsh == "shares a digest").returning(|_| Ok(None));
+        let index = DedupIndex::default();
+        // Stands in for a different hash with the same digest
+        index.ids.lock().unwrap().insert(digest("shares a digest"), 1);
+
+        assert_eq!(index.lock("shares a digest").await.find(&mock_dl).await.unwrap(), None);
+        // Hashes missing from the index aren't looked up at all
+        assert_eq!(index.lock("never stored").await.find(&mock_dl).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_identical_files_backed_up_concurrently_are_stored_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("file.txt");
+        std::fs::write(&file_path, "identical contents").unwrap();
+        let backups_path = dir.path().join("backups");
+        let backup_service = AsyncMutex::new(FileBackupService::new(backups_path.to_string_lossy().to_string()).unwrap());
+        let mut mock_dl = MockDataLayer::new();
+        mock_dl.expect_find_latest_by_hash().returning(|_| Ok(Some(1)));
+        let index = DedupIndex::default();
+
+        let stored_ids = join_all((1..=8).map(|file_id| {
+            let (index, mock_dl, backup_service, file_path) = (&index, &mock_dl, &backup_service, &file_path);
+            async move {
+                let lock = index.lock("hash").await;
+                match lock.find(mock_dl).await.unwrap() {
+                    Some(stored_id) => stored_id,
+                    None => {
+                        // Yields while the lock is held, giving the other files the chance to race it
+                        tokio::task::yield_now().await;
+                        backup_service.lock().await.backup_data(file_id, file_path).await.unwrap();
+                        lock.insert(file_id);
+                        file_id
+                    }
+                }
+            }
+        })).await;
+
+        assert!(stored_ids.iter().all(|id| *id == 1));
+        let archives = std::fs::read_dir(backups_path.join("0")).unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension().is_some_and(|ext| ext == "gz"))
+            .count();
+        assert_eq!(archives, 1);
+        assert!(index.locks.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,183 @@
+use std::{collections::{HashMap, HashSet, VecDeque}, fmt::Display};
+
+use super::models::{DirModel, FullPath};
+
+///
+/// Why a dir's place in a `DirTree` couldn't be resolved
+/// 
+#[derive(Debug, PartialEq, Eq)]
+pub enum DirTreeError {
+    /// No dir has the given ID
+    UnknownDir(i64),
+    /// The dir with the given `dir_id` names a parent which isn't in the tree
+    OrphanDir { dir_id: i64, parent_dir_id: i64 },
+    /// The dir with the given ID is its own ancestor
+    Cycle(i64),
+}
+
+impl Display for DirTreeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DirTreeError::UnknownDir(dir_id) => write!(f, "no dir has the id {}", dir_id),
+            DirTreeError::OrphanDir { dir_id, parent_dir_id } =>
+                write!(f, "the dir with id={} has the parent id={}, which isn't a dir", dir_id, parent_dir_id),
+            DirTreeError::Cycle(dir_id) => write!(f, "the dir with id={} is its own ancestor", dir_id),
+        }
+    }
+}
+
+impl std::error::Error for DirTreeError { }
+
+///
+/// Every dir in the catalog, loaded at once so dirs' paths and subtrees are resolved in memory,
+/// rather than by walking the catalog a query per dir
+/// 
+pub struct DirTree {
+    dirs: HashMap<i64, DirModel>,
+    /// The IDs of each dir's sub-dirs, in order, by the dir's ID
+    children: HashMap<i64, Vec<i64>>
+}
+
+impl DirTree {
+    pub fn new(dirs: impl IntoIterator<Item = DirModel>) -> Self {
+        let dirs: HashMap<i64, DirModel> = dirs.into_iter().map(|dir| (dir.id, dir)).collect();
+        let mut children: HashMap<i64, Vec<i64>> = HashMap::new();
+        for dir in dirs.values() {
+            if let Some(parent_dir_id) = dir.parent_dir_id {
+                children.entry(parent_dir_id).or_default().push(dir.id);
+            }
+        }
+        for sub_dir_ids in children.values_mut() {
+            sub_dir_ids.sort_unstable();
+        }
+
+        Self { dirs, children }
+    }
+
+    ///
+    /// Gets the ID of the dir reached from the root dir named `root` through the sub-dirs named by `names`, 
+    /// if every one of them is in the tree
+    /// 
+    pub fn find<'a>(&self, root: &str, names: impl IntoIterator<Item = &'a str>) -> Option<i64> {
+        let mut dir_id = self.dirs.values().find(|dir| dir.parent_dir_id.is_none() && dir.dir_name == root)?.id;
+        for name in names {
+            dir_id = *self.children.get(&dir_id)?.iter().find(|sub_dir_id| self.dirs[sub_dir_id].dir_name == name)?;
+        }
+
+        Some(dir_id)
+    }
+
+    ///
+    /// Gets the full path of the dir with the given `dir_id`, joined from the names of the dirs
+    /// leading to it. Fails rather than looping if the dir is its own ancestor
+    /// 
+    pub fn full_path(&self, dir_id: i64) -> Result<FullPath, DirTreeError> {
+        let mut chain: Vec<DirModel> = Vec::new();
+        let mut seen = HashSet::new();
+        let mut cur_dir_id = Some(dir_id);
+        while let Some(dir_id) = cur_dir_id {
+            if !seen.insert(dir_id) {
+                return Err(DirTreeError::Cycle(dir_id));
+            }
+            let dir = match (self.dirs.get(&dir_id), chain.last()) {
+                (Some(dir), _) => dir,
+                (None, None) => return Err(DirTreeError::UnknownDir(dir_id)),
+                (None, Some(child)) => return Err(DirTreeError::OrphanDir { dir_id: child.id, parent_dir_id: dir_id })
+            };
+            cur_dir_id = dir.parent_dir_id;
+            chain.push(dir.clone());
+        }
+
+        // Followed through each dir's parent to a root, so the chain is always linked
+        Ok(FullPath::from_dir_chain(&chain).unwrap())
+    }
+
+    ///
+    /// Gets the IDs of every dir beneath the dir with the given `dir_id` at any depth,
+    /// nearest first. Fails rather than looping if the dir is its own ancestor
+    /// 
+    pub fn descendants(&self, dir_id: i64) -> Result<Vec<i64>, DirTreeError> {
+        if !self.dirs.contains_key(&dir_id) {
+            return Err(DirTreeError::UnknownDir(dir_id));
+        }
+
+        let mut descendants = Vec::new();
+        let mut seen = HashSet::from([dir_id]);
+        let mut queue = VecDeque::from([dir_id]);
+        while let Some(dir_id) = queue.pop_front() {
+            for sub_dir_id in self.children.get(&dir_id).into_iter().flatten() {
+                if !seen.insert(*sub_dir_id) {
+                    return Err(DirTreeError::Cycle(*sub_dir_id));
+                }
+                descendants.push(*sub_dir_id);
+                queue.push_back(*sub_dir_id);
+            }
+        }
+
+        Ok(descendants)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::history_service::models::DirModel;
+
+    use super::{DirTree, DirTreeError};
+
+    fn dir(id: i64, parent_dir_id: Option<i64>, dir_name: &str) -> DirModel {
+        DirModel { id, parent_dir_id, dir_name: dir_name.to_string() }
+    }
+
+    fn tree() -> DirTree {
+        DirTree::new([
+            dir(1, None, "/"), dir(2, Some(1), "home"), dir(3, Some(2), "me"),
+            dir(4, Some(3), "photos"), dir(5, Some(3), "docs"), dir(6, Some(1), "etc")
+        ])
+    }
+
+    #[test]
+    fn test_full_path() {
+        let tree = tree();
+        assert_eq!(PathBuf::from(tree.full_path(4).unwrap()), PathBuf::from("/home/me/photos"));
+        assert_eq!(PathBuf::from(tree.full_path(1).unwrap()), PathBuf::from("/"));
+        assert_eq!(tree.full_path(9).map(PathBuf::from), Err(DirTreeError::UnknownDir(9)));
+    }
+
+    #[test]
+    fn test_descendants() {
+        let tree = tree();
+        assert_eq!(tree.descendants(1).unwrap(), [2, 6, 3, 4, 5]);
+        assert_eq!(tree.descendants(3).unwrap(), [4, 5]);
+        assert!(tree.descendants(6).unwrap().is_empty());
+        assert_eq!(tree.descendants(9), Err(DirTreeError::UnknownDir(9)));
+    }
+
+    #[test]
+    fn test_find() {
+        let tree = tree();
+        assert_eq!(tree.find("/", ["home", "me", "docs"]), Some(5));
+        assert_eq!(tree.find("/", []), Some(1));
+        assert_eq!(tree.find("/", ["home", "you"]), None);
+        assert_eq!(tree.find("C:\\", ["home"]), None);
+    }
+
+    #[test]
+    fn test_orphans_fail_to_resolve() {
+        // The parent of "lost" was removed from the catalog without it
+        let tree = DirTree::new([dir(1, None, "/"), dir(7, Some(8), "lost"), dir(9, Some(7), "found")]);
+        assert_eq!(tree.full_path(9).map(PathBuf::from), Err(DirTreeError::OrphanDir { dir_id: 7, parent_dir_id: 8 }));
+        // Its own subtree is still whole
+        assert_eq!(tree.descendants(7).unwrap(), [9]);
+    }
+
+    #[test]
+    fn test_cycles_fail_rather_than_loop() {
+        let tree = DirTree::new([dir(1, None, "/"), dir(2, Some(3), "a"), dir(3, Some(2), "b"), dir(4, Some(3), "c"), dir(5, Some(5), "self")]);
+        assert_eq!(tree.full_path(4).map(PathBuf::from), Err(DirTreeError::Cycle(3)));
+        assert_eq!(tree.full_path(5).map(PathBuf::from), Err(DirTreeError::Cycle(5)));
+        assert_eq!(tree.descendants(2), Err(DirTreeError::Cycle(2)));
+        assert_eq!(tree.descendants(5), Err(DirTreeError::Cycle(5)));
+    }
+}
@@ -1,5 +1,7 @@
 use crate::data_layer_error::DataLayerError;
 
+use super::dir_tree::DirTreeError;
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug)]
@@ -7,7 +9,23 @@ pub enum Error {
     DataLayerError(DataLayerError),
     GlobPatternError(glob::PatternError),
     GlobError(glob::GlobError),
-    ConfigError(Box<dyn std::error::Error>)
+    ConfigError(Box<dyn std::error::Error>),
+    BackupServiceError(crate::backup_service::error::Error),
+    /// More than one version of a file has the given label, so it can't tell them apart. Holds every one of their IDs
+    AmbiguousLabel(String, Vec<i64>),
+    /// The catalog's dirs don't form a tree, so a dir's path or subtree can't be resolved
+    DirTree(DirTreeError),
+    /// The error, with a message explaining what failed and how it may be fixed
+    Context(String, Box<Error>)
+}
+
+impl Error {
+    ///
+    /// Wraps the error with the `message`, explaining what failed and how it may be fixed
+    /// 
+    pub fn context(self, message: impl Into<String>) -> Self {
+        Error::Context(message.into(), Box::new(self))
+    }
 }
 
 impl From<glob::PatternError> for Error {
@@ -28,8 +46,20 @@ impl From<tokio::io::Error> for Error {
     }
 }
 
+impl From<crate::backup_service::error::Error> for Error {
+    fn from(value: crate::backup_service::error::Error) -> Self {
+        Error::BackupServiceError(value)
+    }
+}
+
 impl From<DataLayerError> for Error {
     fn from(value: DataLayerError) -> Self {
         Error::DataLayerError(value)
     }
-}
\ No newline at end of file
+}
+
+impl From<DirTreeError> for Error {
+    fn from(value: DirTreeError) -> Self {
+        Error::DirTree(value)
+    }
+}
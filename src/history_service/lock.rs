@@ -0,0 +1,87 @@
+use std::{fmt::Display, fs::OpenOptions, io::{ErrorKind, Write}, path::{Path, PathBuf}};
+
+///
+/// Held by a process changing the catalog, such as a backup run or a compaction, so that
+/// the catalog is never swapped out from under another process. The lock is the file
+/// `<catalog>.lock` next to the catalog, holding the ID of the process which holds it.
+/// It's released when dropped.
+/// 
+#[derive(Debug)]
+pub struct CatalogLock {
+    path: PathBuf
+}
+
+#[derive(Debug)]
+pub enum LockError {
+    /// Another process holds the lock file at the given path, recording the given process ID if it could be read
+    Held(PathBuf, Option<u32>),
+    Io(std::io::Error)
+}
+
+impl Display for LockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockError::Held(path, Some(pid)) => write!(
+                f, "the catalog is in use by process {}. If it's no longer running, delete {}", pid, path.display()
+            ),
+            LockError::Held(path, None) => write!(
+                f, "the catalog is in use by another process. If none is running, delete {}", path.display()
+            ),
+            LockError::Io(e) => write!(f, "the catalog's lock file couldn't be created: {}", e)
+        }
+    }
+}
+
+impl std::error::Error for LockError {}
+
+impl CatalogLock {
+    ///
+    /// Takes the lock of the catalog at `catalog_path`, failing if another process holds it
+    /// 
+    pub fn acquire(catalog_path: &Path) -> Result<Self, LockError> {
+        let mut path = catalog_path.as_os_str().to_owned();
+        path.push(".lock");
+        let path = PathBuf::from(path);
+
+        // Creating the file fails if it exists, so only one process can hold it
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                write!(file, "{}", std::process::id()).map_err(LockError::Io)?;
+                Ok(Self { path })
+            },
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                let pid = std::fs::read_to_string(&path).ok().and_then(|pid| pid.trim().parse().ok());
+                Err(LockError::Held(path, pid))
+            },
+            Err(e) => Err(LockError::Io(e))
+        }
+    }
+}
+
+impl Drop for CatalogLock {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            tracing::warn!("The catalog's lock file {} couldn't be removed: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CatalogLock, LockError};
+
+    #[test]
+    fn test_catalog_lock_is_exclusive() {
+        let dir = tempfile::tempdir().unwrap();
+        let catalog = dir.path().join("catalog.db");
+
+        let lock = CatalogLock::acquire(&catalog).unwrap();
+        let held = CatalogLock::acquire(&catalog).unwrap_err();
+        assert!(matches!(held, LockError::Held(_, Some(pid)) if pid == std::process::id()));
+
+        // Once released, it can be taken again
+        drop(lock);
+        assert!(!dir.path().join("catalog.db.lock").exists());
+        CatalogLock::acquire(&catalog).unwrap();
+    }
+}
@@ -1,133 +1,1023 @@
+pub mod catalog_cache;
+#[cfg(feature = "sqlite")]
+pub mod compact;
 pub mod data_layer;
+pub mod dedup;
+pub mod dir_tree;
 pub mod error;
+pub mod lock;
 pub mod models;
 
-use std::{future::Future, path::Path};
+use std::{borrow::Cow, collections::{hash_map::Entry, BTreeSet, HashMap, HashSet}, iter::Peekable, path::{Path, PathBuf}, sync::Arc};
 
-use async_recursion::async_recursion;
-use lazy_static::lazy_static;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
 
 use data_layer::*;
+use dir_tree::DirTree;
 use error::*;
+use models::{CURRENT_VERSION, BackupModel, ChangeType, ChunkModel, DeletionExclusions, DirModel, EmptyDirModel, FileLocation, FileModel, FileWithPath, HashCollisionEntry, LatestFileEntry, RunDiff, RunModel, RunOrigin, RunStats, VerifyFailureModel, VersionSelector};
 
-use crate::time_provider::TimeProvider;
+use crate::{backup_service::BackupService, config::{CanonicalizePolicy, HashAlgorithm, UNLIMITED_COPIES}, file_svc::long_path::long_path, hash_svc::{algorithm_of, hash_reader, hash_reader_with_each}, time_provider::TimeProvider};
 
-lazy_static! {
-    ///
-    /// The base path for the operating system currently being used.
-    /// "C:" for windows, "" for linux
-    /// 
-    static ref BASE_PATH: &'static str = {
-        let os = std::env::consts::OS;
-        match os {
-            "windows" => "C:",
-            "linux" => "",
-            _ => panic!("Unsupported operating system")
-        }
-    };
+///
+/// The base path for the operating system currently being used.
+/// "C:" for windows, "" for linux. Only the disabled tests below build paths from it
+/// 
+#[cfg(test)]
+#[allow(dead_code)]
+static BASE_PATH: std::sync::OnceLock<&'static str> = std::sync::OnceLock::new();
+
+#[cfg(test)]
+#[allow(dead_code)]
+fn base_path() -> &'static str {
+    BASE_PATH.get_or_init(|| match std::env::consts::OS {
+        "windows" => "C:",
+        "linux" => "",
+        _ => panic!("Unsupported operating system")
+    })
 }
 
 pub enum FileStatus<'a> {
-    NeedsBackup { sub_dir_id: i64, file_id: i64, file_name: &'a str },
+    /// The file has changed, or `is_new` to the backup
+    NeedsBackup { sub_dir_id: i64, file_id: i64, file_name: &'a str, is_new: bool },
     DoesNotNeedBackup,
 }
 
+///
+/// A directory with no files to back up, recreated on restore
+/// 
+#[derive(Clone, Debug, PartialEq)]
+pub struct EmptyDir {
+    pub path: PathBuf,
+    /// The directory's unix permissions, if it has any
+    pub permissions: Option<u32>
+}
+
+///
+/// Where the catalog and the backups stored in the destination disagree
+/// 
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConsistencyReport {
+    /// The ids of file versions the catalog records a backup of, which the destination doesn't hold
+    pub missing_backup_files: Vec<i64>,
+    /// The ids of backups the destination holds, which the catalog has no file version for
+    pub orphaned_backup_files: Vec<i64>,
+    /// The id, recorded hash and actual hash of every backup whose contents don't match the catalog.
+    /// A backup which couldn't be read has a description of why in place of its actual hash.
+    pub hash_mismatches: Vec<(i64, String, String)>
+}
+
+impl ConsistencyReport {
+    pub fn is_consistent(&self) -> bool {
+        self.missing_backup_files.is_empty() && self.orphaned_backup_files.is_empty() && self.hash_mismatches.is_empty()
+    }
+}
+
+///
+/// What re-hashing the catalog's backups with another algorithm did
+/// 
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RehashReport {
+    /// The number of file versions whose hash was replaced
+    pub rehashed: u64,
+    /// The number of file versions already hashed with the algorithm
+    pub skipped: u64,
+    /// The id of every file version left as it was, with why
+    pub failures: Vec<(i64, String)>
+}
+
 /// 
 /// Provides implementation for accessing file backup, 
 /// previously generated hashes and more.
 /// 
-pub trait HistoryService {
+#[async_trait]
+pub trait HistoryService : Send + Sync {
     ///
-    /// Retrieves backup status of a file, given a `path` and new file `hsh`.
+    /// Retrieves backup status of a file, given a `path` and new file `hsh` and `size`.
     /// A file either needs to be backed up 
-    /// (whether newly being added to the repo or already existing, but with a different hash),
-    /// or has a matching `hsh` to the provided one, in which case a new 
-    /// backup is not required.
+    /// (whether newly being added to the repo or already existing, but with a different hash or size),
+    /// or has a matching `hsh` and `size` to the provided ones, in which case a new 
+    /// backup is not required. If the latest version's hash was made with another algorithm
+    /// than `hsh`, the file is hashed again with that algorithm, and if it still matches,
+    /// the latest version's hash is replaced with `hsh` instead of the file being backed up.
+    /// 
+    async fn get_file_status<'a>(&mut self, path: &'a Path, hsh: &str, size: u64) -> Result<FileStatus<'a>>;
+    ///
+    /// Records the file at `path` as seen unchanged since its latest version, 
+    /// for a file judged unchanged without being hashed
     /// 
-    fn get_file_status<'a>(&mut self, path: &'a Path, hsh: &str) -> impl Future<Output = Result<FileStatus<'a>>> + Send;
+    async fn mark_unchanged(&mut self, path: &Path) -> Result<()>;
     ///
-    /// Adds a new file and hash to the `BackupService` with the provided information.
-    /// Returns the ID of the oldest entry if the # of copies surpasses the total desired backup count.
+    /// Adds the `path` found this run which couldn't be canonicalized, so was only made absolute. 
+    /// Its versions are recorded with the `None` policy, which it was normalized with
     /// 
-    fn create_file_entry(&self, dir_id: i64, file_id: i64, file_name: &str, hsh: &str) -> impl Future<Output = Result<Option<i64>>> + Send;
+    fn add_non_canonical_path(&mut self, path: PathBuf);
+    ///
+    /// Adds a new file, hash and size to the `BackupService` with the provided information.
+    /// Returns the ID of the oldest entry if the # of copies in the file's current generation
+    /// surpasses the total desired backup count.
+    /// 
+    async fn create_file_entry(&self, dir_id: i64, file_id: i64, file_name: &str, hsh: &str, size: u64) -> Result<Option<i64>>;
     ///
     /// Filters all newest files by whether they have been updated since the 
-    /// service has began running. If not, the files are marked as deleted
+    /// service has began running. If not, the files are marked as deleted.
+    /// Files seen during the run, by `get_file_status` or `mark_unchanged`, are never
+    /// marked. Files at or beneath any of the `skipped` paths, which couldn't be read
+    /// this run, may still exist and are left as they are.
+    /// 
+    async fn mark_all_deleted_files(&self, skipped: &[PathBuf]) -> Result<Vec<PathBuf>>;
+    ///
+    /// Marks each file at the given `paths` which was backed up, but is now excluded by policy, 
+    /// as excluded rather than deleted, so `mark_all_deleted_files` leaves it be. Files seen 
+    /// during the run are left as they are. Including a file again later carries on its history 
+    /// where it left off. Returns the path of each file newly marked.
+    /// 
+    async fn mark_excluded(&mut self, paths: &[PathBuf]) -> Result<Vec<PathBuf>>;
+    ///
+    /// Removes every version of each generation of a file which was deleted longer than
+    /// `retention` ago, returning their IDs so their backups can be deleted
+    /// 
+    async fn prune_expired_generations(&self, retention: Duration) -> Result<Vec<i64>>;
+    ///
+    /// Gets every version of the file with the given `file_name` under the directory with
+    /// the given `dir_id`, including deletion markers, oldest generation and version first
+    /// 
+    async fn get_versions(&self, dir_id: i64, file_name: &str) -> Result<Vec<FileModel>>;
+    ///
+    /// Gives the version of the file at `path` with the given `version_id`, or its latest
+    /// version if `None`, the `label`. Returns the ID of the version labelled, or `None`
+    /// if the file has no such version. Deletion markers can't be labelled.
+    /// 
+    async fn label_version(&self, path: &Path, version_id: Option<i64>, label: &str) -> Result<Option<i64>>;
+    ///
+    /// Gets the version of the file with the given `file_name` under the directory with the
+    /// given `dir_id` which has the `label`, if any. Fails with `Error::AmbiguousLabel` 
+    /// if more than one of its versions has it.
+    /// 
+    async fn get_labelled_version(&self, dir_id: i64, file_name: &str, label: &str) -> Result<Option<FileModel>>;
+    ///
+    /// Gets the version of the file at `path` picked by the `selector`, or `None` if it has no
+    /// such version, or was deleted at the selected instant. Fails with `Error::AmbiguousLabel` 
+    /// if the selector is a label which more than one of its versions has.
+    /// 
+    async fn find_version(&self, path: &Path, selector: &VersionSelector) -> Result<Option<FileModel>>;
+    ///
+    /// Gets up to `limit` backed-up files which have not been verified 
+    /// within `max_age` of the service's start time
+    /// 
+    async fn get_files_needing_reverification(&self, max_age: Duration, limit: u32) -> Result<Vec<FileModel>>;
+    ///
+    /// Marks the file with the given `file_id` as verified by the current run
+    /// 
+    async fn mark_file_verified(&self, file_id: i64) -> Result<()>;
+    ///
+    /// Gets every canonicalization policy recorded in the history which differs 
+    /// from the one the service is currently recording paths with
+    /// 
+    async fn get_mismatched_path_policies(&self) -> Result<Vec<String>>;
+    ///
+    /// Finds every file in the history whose name matches the given `query`. 
+    /// `*` matches any run of characters and `?` any single character.
+    /// 
+    async fn search(&self, query: &str) -> Result<Vec<FileWithPath>>;
+    ///
+    /// Gets every file version whose hash is shared with a version of a different size
+    /// 
+    async fn find_hash_collisions(&self) -> Result<Vec<HashCollisionEntry>>;
+    ///
+    /// Gets the full paths of every set of files, not deleted, sharing a directory with names 
+    /// differing only in ASCII case. Restoring them onto a case-insensitive filesystem would
+    /// overwrite all but one of each set.
+    /// 
+    async fn find_case_collisions(&self) -> Result<Vec<Vec<PathBuf>>>;
+    ///
+    /// Records the start of the current run, made with the config `config_snapshot` and started
+    /// as told by its `origin`, returning its ID.
+    /// Every file version recorded from then on is recorded as made by the run.
+    /// 
+    async fn begin_run(&mut self, config_snapshot: &str, origin: &RunOrigin) -> Result<i64>;
+    ///
+    /// Records the run with the given `run_id` as having finished now, having done what's counted in its `stats`
+    /// 
+    async fn complete_run(&self, run_id: i64, stats: RunStats) -> Result<()>;
+    ///
+    /// Whether `max_run_duration` has passed since the current run started
+    /// 
+    fn run_deadline_passed(&self, max_run_duration: Duration) -> bool;
+    ///
+    /// Gets up to `limit` of the most recently completed runs, newest first
+    /// 
+    async fn get_recent_runs(&self, limit: u32) -> Result<Vec<RunModel>>;
+    ///
+    /// Gets the run with the given `run_id`
+    /// 
+    async fn get_run(&self, run_id: i64) -> Result<Option<RunModel>>;
+    ///
+    /// Gets the most recently completed run whose reason contains `reason`
+    /// 
+    async fn find_run_by_reason(&self, reason: &str) -> Result<Option<RunModel>>;
+    ///
+    /// Gets the time the latest run which examined every file began
+    /// 
+    async fn get_last_run_ts(&self) -> Result<Option<DateTime<Utc>>>;
+    ///
+    /// Records the file version with the given `file_id` as being stored as `chunks`, in order
+    /// 
+    async fn record_file_chunks(&self, file_id: i64, chunks: &[ChunkModel]) -> Result<()>;
+    ///
+    /// Removes every chunk no longer used by any file version from the history,
+    /// returning their hashes so they can be deleted from the backup
+    /// 
+    async fn take_unreferenced_chunks(&self) -> Result<Vec<String>>;
+    ///
+    /// Records the file version with the given `file_id` as stored in the `backend` 
+    /// under `backend_key` during the current run
+    /// 
+    async fn record_backup(
+        &self, file_id: i64, destination: &str, backend: &str, backend_key: &str, compressed_size: Option<u64>, delta_base_id: Option<i64>
+    ) -> Result<()>;
+    ///
+    /// Gets every stored copy of the file version with the given `file_id`
+    /// 
+    async fn get_file_backups(&self, file_id: i64) -> Result<Vec<BackupModel>>;
+    ///
+    /// Gets every stored copy held by a destination not among the given `destinations`,
+    /// such as one since removed from the config
+    /// 
+    async fn get_backups_outside(&self, destinations: &[String]) -> Result<Vec<BackupModel>>;
+    ///
+    /// Gets the ID of every file version with a backup stored as a delta against the version 
+    /// with the given `file_id`. Each must be stored in full before that version's backup is deleted.
+    /// 
+    async fn get_delta_dependents(&self, file_id: i64) -> Result<Vec<i64>>;
+    ///
+    /// Gets the file version ID of every backup stored as a delta, along with the ID of the version it's taken against
+    /// 
+    async fn get_delta_backups(&self) -> Result<Vec<(i64, i64)>>;
+    ///
+    /// Gets the number of deltas which must be applied to restore the version with the given `file_id`
+    /// 
+    async fn get_delta_chain_length(&self, file_id: i64) -> Result<usize>;
+    ///
+    /// Records that the backups of the version with the given `file_id` are now stored in full
+    /// 
+    async fn clear_delta_base(&self, file_id: i64) -> Result<()>;
+    ///
+    /// Gets the latest version of the file with the given `file_name` under the directory
+    /// with the given `dir_id`, if it has one and it hasn't been deleted
+    /// 
+    async fn get_latest_version(&self, dir_id: i64, file_name: &str) -> Result<Option<FileModel>>;
+    ///
+    /// Gets the latest version of every file which hasn't been deleted, with its full path
+    /// 
+    async fn get_latest_files(&self) -> Result<Vec<LatestFileEntry>>;
+    ///
+    /// Gets how every file changed between `before` and `after`, with its full path, ordered by path
+    /// 
+    async fn diff_snapshots(&self, before: DateTime<Utc>, after: DateTime<Utc>) -> Result<Vec<(PathBuf, ChangeType)>>;
+    ///
+    /// Gets how every file changed from the end of the run `run_id_a` to the end of 
+    /// the run `run_id_b`, with its full path, each kind of change ordered by path
+    /// 
+    async fn get_backup_run_diff(&self, run_id_a: i64, run_id_b: i64) -> Result<RunDiff<PathBuf>>;
+    ///
+    /// Gets the full path of every file which hasn't been deleted, with the hash of its latest version
+    /// 
+    async fn get_latest_hashes(&self) -> Result<Vec<(PathBuf, String)>>;
+    ///
+    /// Sums the original size of every file version in the catalog
+    /// 
+    async fn total_backup_size_bytes(&self) -> Result<u64>;
+    ///
+    /// Forgets every directory no longer holding any file version or empty directory,
+    /// returning how many were forgotten
+    /// 
+    async fn delete_unused_dirs(&self) -> Result<u64>;
+    ///
+    /// Vacuums the catalog, releasing the space left behind by pruned and deleted files
+    /// 
+    async fn vacuum(&self) -> Result<()>;
+    ///
+    /// Refreshes the statistics the catalog's queries are planned with
+    /// 
+    async fn analyze(&self) -> Result<()>;
+    ///
+    /// Gets the size of the catalog in bytes
+    /// 
+    async fn catalog_size_bytes(&self) -> Result<u64>;
+    ///
+    /// Gets every file version with a backup to verify, in id order
+    /// 
+    async fn get_backed_up_files(&self) -> Result<Vec<FileModel>>;
+    ///
+    /// Gets the last file id verified in each shard of the destination
+    /// 
+    async fn get_verify_progress(&self) -> Result<HashMap<i64, i64>>;
+    ///
+    /// Records that every file id up to `last_file_id` in the `shard` has been verified
+    /// 
+    async fn record_verify_progress(&self, shard: i64, last_file_id: i64) -> Result<()>;
+    ///
+    /// Records that the backup of the file version with the given `file_id` failed verification for the given `reason`
+    /// 
+    async fn record_verify_failure(&self, file_id: i64, reason: &str) -> Result<()>;
+    ///
+    /// Gets every verification failure found since verification last started over
+    /// 
+    async fn get_verify_failures(&self) -> Result<Vec<VerifyFailureModel>>;
+    ///
+    /// Forgets the verification progress and failures, so verification starts over
+    /// 
+    async fn reset_verify_progress(&self) -> Result<()>;
+    ///
+    /// Compares the catalog against the backups held by the `backup_svc`, re-hashing
+    /// every backup the catalog records. Changes nothing in either.
+    /// 
+    async fn check_consistency(&self, backup_svc: &dyn BackupService) -> Result<ConsistencyReport>;
+    ///
+    /// Resolves the disagreements in the given `report`, forgetting every file version whose 
+    /// backup is missing and deleting every orphaned backup. Hash mismatches are left as they
+    /// are, as the file version may still be restorable from another copy.
+    /// 
+    async fn repair_consistency(&self, report: &ConsistencyReport, backup_svc: &mut dyn BackupService) -> Result<()>;
+    ///
+    /// Re-hashes the backup, held by the `backup_svc`, of every file version whose hash wasn't made
+    /// with `algorithm`, replacing its hash with one made with `algorithm`. A backup which can't
+    /// be read, or no longer matches its old hash, is left as it is and reported.
+    /// 
+    async fn rehash(&self, backup_svc: &dyn BackupService, algorithm: HashAlgorithm) -> Result<RehashReport>;
+    ///
+    /// Registers a backup made outside of the tool, of the file at `path` with the given
+    /// `hsh` and `size`, as the backup with the given `file_id` made at `backup_ts`
+    /// 
+    async fn register_existing_backup(&mut self, path: &Path, hsh: &str, size: u64, file_id: i64, backup_ts: DateTime<Utc>) -> Result<()>;
+    ///
+    /// Records exactly the given `dirs` as the empty directories found in this run,
+    /// replacing those recorded by earlier runs
+    /// 
+    async fn record_empty_dirs(&self, dirs: &[EmptyDir]) -> Result<()>;
+    ///
+    /// Creates every directory holding one of the files at `paths` which isn't yet in the catalog,
+    /// a level at a time rather than one at a time. Returns the ID of each of those directories by its path
+    /// 
+    async fn create_dir_tree(&self, paths: &[PathBuf]) -> Result<HashMap<PathBuf, i64>>;
+    ///
+    /// Gets every recorded empty directory whose name matches the given `query`, using the same patterns as `search`
     /// 
-    fn mark_all_deleted_files(&self) -> impl Future<Output = Result<()>> + Send;
+    async fn get_empty_dirs(&self, query: &str) -> Result<Vec<EmptyDir>>;
 }
 
-pub struct FileHistoryService<'a> {
-    data_layer: &'a dyn DataLayer,
-    time_provider: &'a dyn TimeProvider,
-    next_file_id: i64,
-    max_copies: i32
+pub struct FileHistoryService {
+    data_layer: Arc<dyn DataLayer>,
+    time_provider: Arc<dyn TimeProvider>,
+    max_copies: i32,
+    path_policy: CanonicalizePolicy,
+    /// The run recording file versions, once one has begun
+    run_id: Option<i64>,
+    /// The label given to every version the run records, taken from the reason it was started for
+    run_label: Option<String>,
+    /// Every file seen during the current run, by its dir ID and name
+    processed: HashSet<(i64, String)>,
+    /// Every path found this run which couldn't be canonicalized
+    non_canonical_paths: HashSet<PathBuf>,
+    /// Every file found this run whose path couldn't be canonicalized, by its dir ID and name
+    non_canonical: HashSet<(i64, String)>
 }
-impl<'a> HistoryService for FileHistoryService<'a> {
-    async fn get_file_status<'b>(&mut self, path: &'b Path, hsh: &str) -> Result<FileStatus<'b>> {
-        let paths = path.iter().map(|p| p.to_str().unwrap());
+#[async_trait]
+impl HistoryService for FileHistoryService {
+    async fn get_file_status<'b>(&mut self, path: &'b Path, hsh: &str, size: u64) -> Result<FileStatus<'b>> {
+        // Collected, as a borrowing iterator held across the awaits below keeps the future from being Send
+        let paths: Vec<&str> = path.iter().map(|p| p.to_str().unwrap()).collect();
         let file_name = path.file_name().unwrap().to_str().unwrap();
-        let sub_dir_id = self.traverse_to_subdir(paths, true).await?.unwrap();
+        let sub_dir_id = self.traverse_to_subdir(paths.into_iter(), true).await?.unwrap();
+        self.mark_processed(sub_dir_id, file_name);
+        if self.non_canonical_paths.contains(path) {
+            self.non_canonical.insert((sub_dir_id, file_name.to_string()));
+        }
 
-        let latest_hsh = self.data_layer.get_latest_file(sub_dir_id, file_name).await?
-            .and_then(|f| f.hsh);
+        let latest_file = self.data_layer.get_latest_file(sub_dir_id, file_name).await?;
+        if let Some(latest) = latest_file.as_ref().filter(|latest| !latest.is_format_supported()) {
+            tracing::warn!(
+                "The latest backup of {} was stored in format version {}, but this version of drive_backup \
+                only understands up to {}. It may have been written by a newer version, and may not be handled correctly",
+                path.display(), latest.version, CURRENT_VERSION
+            );
+        }
+        let is_new = !matches!(latest_file, Some(FileModel { hsh: Some(_), .. }));
 
-        if let Some(latest_hsh) = latest_hsh {
+        // A latest version hashed with another algorithm, such as before `hash_algorithm` was changed,
+        // has its hash replaced in place if the file still hashes the same with that algorithm,
+        // rather than the file being backed up again
+        if let Some(FileModel { id, hsh: Some(latest_hsh), file_size: Some(latest_size), .. }) = &latest_file {
+            let legacy = algorithm_of(latest_hsh).filter(|legacy| Some(*legacy) != algorithm_of(hsh));
+            if let (Some(legacy), true) = (legacy, *latest_size == size as i64) {
+                if hash_file_with(path, legacy).await.as_ref() == Some(latest_hsh) {
+                    self.data_layer.update_file_hash(*id, hsh).await?;
+                    self.data_layer.update_latest_hsh_ts(sub_dir_id, file_name, self.time_provider.utc_start()).await?;
+                    return Ok(FileStatus::DoesNotNeedBackup);
+                }
+            }
+        }
+
+        if let Some(FileModel { hsh: Some(latest_hsh), file_size, .. }) = latest_file {
             if latest_hsh == hsh {
-                self.data_layer.update_latest_hsh_ts(
-                    sub_dir_id, file_name, self.time_provider.naive_utc_start()
-                ).await?;
-                return Ok(FileStatus::DoesNotNeedBackup);
+                match file_size {
+                    // A matching hash with a different size can only be a collision, 
+                    // so the file has changed and is backed up again
+                    Some(latest_size) if latest_size != size as i64 => tracing::warn!(
+                        "{} has the same hash as its latest backup, but its size changed from {} to {} bytes",
+                        path.display(), latest_size, size
+                    ),
+                    _ => {
+                        self.data_layer.update_latest_hsh_ts(
+                            sub_dir_id, file_name, self.time_provider.utc_start()
+                        ).await?;
+                        return Ok(FileStatus::DoesNotNeedBackup);
+                    }
+                }
             }
         }
 
-        let file_id = self.next_file_id; 
-        self.next_file_id += 1;
+        let file_id = self.data_layer.allocate_file_id().await.map_err(|e| Error::DataLayerError(e).context(
+            "Failed to allocate a file ID from the catalog. Is the schema migrated?"
+        ))?;
 
-        Ok(FileStatus::NeedsBackup { sub_dir_id, file_id, file_name })
+        Ok(FileStatus::NeedsBackup { sub_dir_id, file_id, file_name, is_new })
+    }
+    async fn mark_unchanged(&mut self, path: &Path) -> Result<()> {
+        let (Some(sub_dir_id), Some(file_name)) = (self.get_parent_dir_id(path).await?, path.file_name()) else { return Ok(()) };
+        let file_name = file_name.to_string_lossy();
+        self.mark_processed(sub_dir_id, &file_name);
+        self.data_layer.update_latest_hsh_ts(sub_dir_id, &file_name, self.time_provider.utc_start()).await?;
+        Ok(())
     }
-    async fn create_file_entry(&self, dir_id: i64, file_id: i64, file_name: &str, hsh: &str) -> Result<Option<i64>> {
-        self.data_layer.create_file_entry(dir_id, file_id, file_name, hsh, self.time_provider.naive_utc_start()).await?;
+    fn add_non_canonical_path(&mut self, path: PathBuf) {
+        self.non_canonical_paths.insert(path);
+    }
+    async fn create_file_entry(&self, dir_id: i64, file_id: i64, file_name: &str, hsh: &str, size: u64) -> Result<Option<i64>> {
+        let path_policy = match self.non_canonical.contains(&(dir_id, file_name.to_string())) {
+            true => CanonicalizePolicy::None,
+            false => self.path_policy
+        };
+        self.data_layer.create_file_entry(
+            dir_id, file_id, file_name, hsh, size as i64, self.time_provider.utc_start(), path_policy.as_str(), self.run_id
+        ).await?;
+        if let Some(label) = &self.run_label {
+            self.data_layer.set_version_label(file_id, Some(label.clone())).await?;
+        }
+        // Only the versions of the file's current life count towards its copies.
+        // Older generations are pruned once their deletion is old enough
         let files = self.data_layer.get_dir_files(dir_id, file_name).await?;
-        return if files.len() as i32 > self.max_copies {
-            let file_id = files.iter().min_by_key(|f| f.backup_ts).unwrap().id;
-            self.data_layer.delete_file_entry(file_id).await?;
-            Ok(Some(file_id))
-        } else {
-            Ok(None)
+        let generation = files.iter().map(|f| f.generation).max().unwrap_or(0);
+        let versions: Vec<_> = files.iter().filter(|f| f.generation == generation && f.hsh.is_some()).collect();
+        if self.max_copies == UNLIMITED_COPIES || versions.len() as i32 <= self.max_copies {
+            return Ok(None);
+        }
+        // The version just written is never pruned, even if it shares its backup time with an older one
+        let Some(pruned) = versions.iter().filter(|f| f.id != file_id).min_by_key(|f| f.backup_ts) else { return Ok(None) };
+        self.data_layer.delete_file_entry(pruned.id).await?;
+
+        Ok(Some(pruned.id))
+    }
+    async fn label_version(&self, path: &Path, version_id: Option<i64>, label: &str) -> Result<Option<i64>> {
+        let (Some(dir_id), Some(file_name)) = (self.get_parent_dir_id(path).await?, path.file_name()) else { return Ok(None) };
+        let versions = self.data_layer.get_dir_files(dir_id, &file_name.to_string_lossy()).await?;
+        let version = match version_id {
+            Some(version_id) => versions.into_iter().find(|version| version.id == version_id),
+            None => versions.into_iter().max_by_key(|version| version.backup_ts)
+        };
+        let Some(version) = version.filter(|version| version.hsh.is_some()) else { return Ok(None) };
+
+        self.data_layer.set_version_label(version.id, Some(label.to_string())).await?;
+        Ok(Some(version.id))
+    }
+    async fn get_labelled_version(&self, dir_id: i64, file_name: &str, label: &str) -> Result<Option<FileModel>> {
+        let mut labelled: Vec<_> = self.data_layer.get_dir_files(dir_id, file_name).await?.into_iter()
+            .filter(|version| version.label.as_deref() == Some(label))
+            .collect();
+        match labelled.len() {
+            0 | 1 => Ok(labelled.pop()),
+            _ => Err(Error::AmbiguousLabel(label.to_string(), labelled.iter().map(|version| version.id).collect()))
+        }
+    }
+    async fn find_version(&self, path: &Path, selector: &VersionSelector) -> Result<Option<FileModel>> {
+        let (Some(dir_id), Some(file_name)) = (self.get_parent_dir_id(path).await?, path.file_name()) else { return Ok(None) };
+        let file_name = file_name.to_string_lossy();
+        let version = match selector {
+            VersionSelector::Id(version_id) => self.data_layer.get_dir_files(dir_id, &file_name).await?.into_iter()
+                .find(|version| version.id == *version_id),
+            VersionSelector::Instant(instant) => self.data_layer.get_dir_files(dir_id, &file_name).await?.into_iter()
+                .filter(|version| version.backup_ts <= *instant)
+                .max_by_key(|version| version.backup_ts),
+            VersionSelector::Label(label) => self.get_labelled_version(dir_id, &file_name, label).await?
+        };
+
+        // Deletion markers have no contents to select
+        Ok(version.filter(|version| version.hsh.is_some()))
+    }
+    async fn prune_expired_generations(&self, retention: Duration) -> Result<Vec<i64>> {
+        let file_ids = self.data_layer.get_expired_generation_files(self.time_provider.utc_start() - retention).await?;
+        for file_id in file_ids.iter() {
+            self.data_layer.delete_file_entry(*file_id).await?;
+        }
+
+        Ok(file_ids)
+    }
+    async fn get_versions(&self, dir_id: i64, file_name: &str) -> Result<Vec<FileModel>> {
+        let mut files = self.data_layer.get_dir_files(dir_id, file_name).await?;
+        files.sort_by_key(|f| (f.generation, f.backup_ts));
+
+        Ok(files)
+    }
+    async fn mark_all_deleted_files(&self, skipped: &[PathBuf]) -> Result<Vec<PathBuf>> {
+        // Every dir is loaded at once, rather than walking the catalog for each skipped path and deleted file a query at a time
+        let tree = DirTree::new(self.data_layer.load_dir_tree().await?);
+        let mut exclusions = DeletionExclusions::default();
+        for path in skipped {
+            let (Some(parent_dir_id), Some(name)) = (path.parent().and_then(|parent| dir_id_at(&tree, parent)), path.file_name()) else { continue };
+            // A skipped path is either a file, or a directory whose files are all skipped, at any depth
+            if let Some(dir_id) = dir_id_at(&tree, path) {
+                exclusions.dir_ids.push(dir_id);
+                exclusions.dir_ids.extend(tree.descendants(dir_id)?);
+            }
+            exclusions.files.insert((parent_dir_id, name.to_string_lossy().to_string()));
+        }
+        // Files seen this run still exist, however long ago in the run they were seen
+        exclusions.files.extend(self.processed.iter().cloned());
+        let deleted = self.data_layer.mark_deleted_under(exclusions, self.time_provider.utc_start(), self.run_id).await?;
+
+        let mut dir_paths: HashMap<i64, PathBuf> = HashMap::new();
+        let mut paths = Vec::with_capacity(deleted.len());
+        for (dir_id, file_name) in deleted {
+            let dir_path = match dir_paths.entry(dir_id) {
+                Entry::Occupied(known) => known.into_mut(),
+                Entry::Vacant(unknown) => unknown.insert(tree.full_path(dir_id)?.into())
+            };
+            paths.push(dir_path.join(file_name));
+        }
+
+        Ok(paths)
+    }
+    async fn mark_excluded(&mut self, paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+        let mut excluded = Vec::new();
+        for path in paths {
+            let (Some(dir_id), Some(file_name)) = (self.get_parent_dir_id(path).await?, path.file_name().and_then(|name| name.to_str())) 
+                else { continue };
+            if self.processed.contains(&(dir_id, file_name.to_string())) {
+                continue;
+            }
+            // Files already marked as excluded or deleted are left as they are
+            if self.data_layer.get_latest_file(dir_id, file_name).await?.is_none_or(|latest| latest.hsh.is_none()) {
+                continue;
+            }
+
+            self.data_layer.create_excluded_marker(dir_id, file_name, self.time_provider.utc_start(), self.run_id).await?;
+            self.mark_processed(dir_id, file_name);
+            excluded.push(path.clone());
+        }
+
+        Ok(excluded)
+    }
+    async fn get_files_needing_reverification(&self, max_age: Duration, limit: u32) -> Result<Vec<FileModel>> {
+        let older_than = self.time_provider.utc_start() - max_age;
+        Ok(self.data_layer.get_files_needing_reverification(older_than, limit).await?)
+    }
+    async fn mark_file_verified(&self, file_id: i64) -> Result<()> {
+        self.data_layer.mark_file_verified(file_id, self.time_provider.utc_start()).await?;
+        Ok(())
+    }
+    async fn get_mismatched_path_policies(&self) -> Result<Vec<String>> {
+        Ok(self.data_layer.get_path_policies().await?.into_iter()
+            .filter(|p| p != self.path_policy.as_str()).collect())
+    }
+    async fn search(&self, query: &str) -> Result<Vec<FileWithPath>> {
+        Ok(self.data_layer.search_files(&glob_to_like_pattern(query)).await?)
+    }
+    async fn find_hash_collisions(&self) -> Result<Vec<HashCollisionEntry>> {
+        Ok(self.data_layer.find_hash_collisions().await?)
+    }
+    async fn find_case_collisions(&self) -> Result<Vec<Vec<PathBuf>>> {
+        let mut collisions: Vec<Vec<PathBuf>> = Vec::new();
+        let mut last_key = None;
+        let tree = DirTree::new(self.data_layer.load_dir_tree().await?);
+        // Colliding files are adjacent, so each set ends where the next one starts
+        for entry in self.data_layer.find_case_collisions().await? {
+            let key = (entry.dir_id, entry.file_name.to_ascii_lowercase());
+            let path = PathBuf::from(tree.full_path(entry.dir_id)?).join(&entry.file_name);
+            match last_key.as_ref() == Some(&key) {
+                true => collisions.last_mut().unwrap().push(path),
+                false => collisions.push(vec![path])
+            }
+            last_key = Some(key);
+        }
+
+        Ok(collisions)
+    }
+    async fn begin_run(&mut self, config_snapshot: &str, origin: &RunOrigin) -> Result<i64> {
+        let run_id = self.data_layer.begin_run(self.time_provider.utc_start(), config_snapshot, origin).await?;
+        self.run_id = Some(run_id);
+        self.run_label = origin.reason.clone();
+        self.processed.clear();
+        Ok(run_id)
+    }
+    async fn complete_run(&self, run_id: i64, stats: RunStats) -> Result<()> {
+        self.data_layer.complete_run(run_id, self.time_provider.utc_now(), &stats).await?;
+        Ok(())
+    }
+    fn run_deadline_passed(&self, max_run_duration: Duration) -> bool {
+        self.time_provider.utc_now() - self.time_provider.utc_start() >= max_run_duration
+    }
+    async fn get_recent_runs(&self, limit: u32) -> Result<Vec<RunModel>> {
+        Ok(self.data_layer.list_runs(limit).await?)
+    }
+    async fn get_run(&self, run_id: i64) -> Result<Option<RunModel>> {
+        Ok(self.data_layer.get_run(run_id).await?)
+    }
+    async fn find_run_by_reason(&self, reason: &str) -> Result<Option<RunModel>> {
+        Ok(self.data_layer.find_run_by_reason(reason).await?)
+    }
+    async fn get_last_run_ts(&self) -> Result<Option<DateTime<Utc>>> {
+        Ok(self.data_layer.get_last_run_ts().await?)
+    }
+    async fn record_file_chunks(&self, file_id: i64, chunks: &[ChunkModel]) -> Result<()> {
+        Ok(self.data_layer.create_file_chunks(file_id, chunks).await?)
+    }
+    async fn take_unreferenced_chunks(&self) -> Result<Vec<String>> {
+        Ok(self.data_layer.delete_unreferenced_chunks().await?)
+    }
+    async fn record_backup(
+        &self, file_id: i64, destination: &str, backend: &str, backend_key: &str, compressed_size: Option<u64>, delta_base_id: Option<i64>
+    ) -> Result<()> {
+        self.data_layer.create_backup_record(
+            file_id, destination, backend, backend_key, self.time_provider.utc_start(), compressed_size.map(|size| size as i64), delta_base_id
+        ).await?;
+        Ok(())
+    }
+    async fn get_file_backups(&self, file_id: i64) -> Result<Vec<BackupModel>> {
+        Ok(self.data_layer.get_file_backups(file_id).await?)
+    }
+    async fn get_backups_outside(&self, destinations: &[String]) -> Result<Vec<BackupModel>> {
+        Ok(self.data_layer.get_backups_outside(destinations).await?)
+    }
+    async fn get_delta_dependents(&self, file_id: i64) -> Result<Vec<i64>> {
+        Ok(self.data_layer.get_delta_dependents(file_id).await?)
+    }
+    async fn get_delta_backups(&self) -> Result<Vec<(i64, i64)>> {
+        Ok(self.data_layer.get_delta_backups().await?)
+    }
+    async fn get_delta_chain_length(&self, file_id: i64) -> Result<usize> {
+        let mut chain_length = 0;
+        let mut cur_file_id = file_id;
+        while let Some(base_id) = self.data_layer.get_file_backups(cur_file_id).await?
+            .into_iter().find_map(|backup| backup.delta_base_id) 
+        {
+            chain_length += 1;
+            cur_file_id = base_id;
+        }
+
+        Ok(chain_length)
+    }
+    async fn clear_delta_base(&self, file_id: i64) -> Result<()> {
+        Ok(self.data_layer.clear_delta_base(file_id).await?)
+    }
+    async fn get_latest_version(&self, dir_id: i64, file_name: &str) -> Result<Option<FileModel>> {
+        Ok(self.data_layer.get_latest_file(dir_id, file_name).await?.filter(|file| file.hsh.is_some()))
+    }
+    async fn get_latest_files(&self) -> Result<Vec<LatestFileEntry>> {
+        Ok(self.data_layer.get_latest_files_with_paths().await?)
+    }
+    async fn diff_snapshots(&self, before: DateTime<Utc>, after: DateTime<Utc>) -> Result<Vec<(PathBuf, ChangeType)>> {
+        let entries = self.data_layer.diff_snapshots(before, after).await?;
+        let tree = DirTree::new(self.data_layer.load_dir_tree().await?);
+
+        let mut dir_paths: HashMap<i64, PathBuf> = HashMap::new();
+        let mut diff = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let dir_path = match dir_paths.entry(entry.dir_id) {
+                Entry::Occupied(known) => known.into_mut(),
+                Entry::Vacant(unknown) => unknown.insert(tree.full_path(entry.dir_id)?.into())
+            };
+            diff.push((dir_path.join(entry.file_name), entry.change_type));
+        }
+        diff.sort_by(|a, b| a.0.cmp(&b.0));
+
+        Ok(diff)
+    }
+    async fn get_backup_run_diff(&self, run_id_a: i64, run_id_b: i64) -> Result<RunDiff<PathBuf>> {
+        let diff = self.data_layer.get_backup_run_diff(run_id_a, run_id_b).await?;
+        let tree = DirTree::new(self.data_layer.load_dir_tree().await?);
+
+        let mut dir_paths: HashMap<i64, PathBuf> = HashMap::new();
+        let dir_ids = diff.added.iter().chain(diff.modified.iter().map(|(location, _, _)| location)).chain(&diff.deleted)
+            .map(|location| location.dir_id);
+        for dir_id in dir_ids {
+            if let Entry::Vacant(entry) = dir_paths.entry(dir_id) {
+                entry.insert(tree.full_path(dir_id)?.into());
+            }
         }
+        let path_of = |location: FileLocation| dir_paths[&location.dir_id].join(location.file_name);
+
+        let mut added: Vec<_> = diff.added.into_iter().map(path_of).collect();
+        let mut modified: Vec<_> = diff.modified.into_iter().map(|(location, hsh_a, hsh_b)| (path_of(location), hsh_a, hsh_b)).collect();
+        let mut deleted: Vec<_> = diff.deleted.into_iter().map(path_of).collect();
+        added.sort();
+        modified.sort_by(|a, b| a.0.cmp(&b.0));
+        deleted.sort();
+
+        Ok(RunDiff { added, modified, deleted })
+    }
+    async fn get_latest_hashes(&self) -> Result<Vec<(PathBuf, String)>> {
+        let snapshot = self.data_layer.get_latest_files_snapshot().await?;
+        let tree = DirTree::new(self.data_layer.load_dir_tree().await?);
+
+        let mut dir_paths: HashMap<i64, PathBuf> = HashMap::new();
+        let mut hashes = Vec::with_capacity(snapshot.len());
+        for entry in snapshot {
+            if entry.is_deleted {
+                continue;
+            }
+            let dir_path = match dir_paths.entry(entry.dir_id) {
+                Entry::Occupied(known) => known.into_mut(),
+                Entry::Vacant(unknown) => unknown.insert(tree.full_path(entry.dir_id)?.into())
+            };
+            hashes.push((dir_path.join(entry.file_name), entry.latest_hsh));
+        }
+        hashes.sort();
+
+        Ok(hashes)
+    }
+    async fn total_backup_size_bytes(&self) -> Result<u64> {
+        Ok(self.data_layer.total_backup_size_bytes().await?)
     }
-    async fn mark_all_deleted_files(&self) -> Result<()> {
-        self.data_layer.mark_all_deleted_files(self.time_provider.naive_utc_start()).await?;
+    async fn delete_unused_dirs(&self) -> Result<u64> {
+        Ok(self.data_layer.delete_unused_dirs().await?)
+    }
+    async fn vacuum(&self) -> Result<()> {
+        Ok(self.data_layer.vacuum().await?)
+    }
+    async fn analyze(&self) -> Result<()> {
+        Ok(self.data_layer.analyze().await?)
+    }
+    async fn catalog_size_bytes(&self) -> Result<u64> {
+        Ok(self.data_layer.database_size_bytes().await?)
+    }
+    async fn get_backed_up_files(&self) -> Result<Vec<FileModel>> {
+        Ok(self.data_layer.get_backed_up_files().await?)
+    }
+    async fn get_verify_progress(&self) -> Result<HashMap<i64, i64>> {
+        Ok(self.data_layer.get_verify_progress().await?.into_iter().collect())
+    }
+    async fn record_verify_progress(&self, shard: i64, last_file_id: i64) -> Result<()> {
+        Ok(self.data_layer.set_verify_progress(shard, last_file_id).await?)
+    }
+    async fn record_verify_failure(&self, file_id: i64, reason: &str) -> Result<()> {
+        Ok(self.data_layer.create_verify_failure(file_id, reason, self.time_provider.utc_now()).await?)
+    }
+    async fn get_verify_failures(&self) -> Result<Vec<VerifyFailureModel>> {
+        Ok(self.data_layer.get_verify_failures().await?)
+    }
+    async fn reset_verify_progress(&self) -> Result<()> {
+        Ok(self.data_layer.clear_verify_progress().await?)
+    }
+    async fn check_consistency(&self, backup_svc: &dyn BackupService) -> Result<ConsistencyReport> {
+        let files = self.data_layer.get_backed_up_files().await?;
+        let stored: BTreeSet<i64> = backup_svc.list_backup_ids().await?.into_iter().collect();
+        let recorded: HashSet<i64> = files.iter().map(|file| file.id).collect();
+
+        let mut report = ConsistencyReport {
+            orphaned_backup_files: stored.iter().filter(|id| !recorded.contains(id)).copied().collect(),
+            ..Default::default()
+        };
+        for file in files {
+            if !stored.contains(&file.id) {
+                report.missing_backup_files.push(file.id);
+                continue;
+            }
+            let algorithm = file.hsh.as_deref().and_then(algorithm_of).unwrap_or_default();
+            let actual = match backup_svc.open_backup(file.id).await {
+                Ok(reader) => match tokio::task::spawn_blocking(move || hash_reader(reader, algorithm)).await {
+                    Ok(Ok((hsh, _))) => hsh,
+                    Ok(Err(e)) => format!("unreadable: {:?}", e),
+                    Err(e) => format!("unreadable: {}", e)
+                },
+                Err(e) => format!("unreadable: {}", e)
+            };
+            if file.hsh.as_ref() != Some(&actual) {
+                report.hash_mismatches.push((file.id, file.hsh.unwrap_or_default(), actual));
+            }
+        }
+
+        Ok(report)
+    }
+    async fn repair_consistency(&self, report: &ConsistencyReport, backup_svc: &mut dyn BackupService) -> Result<()> {
+        for file_id in &report.missing_backup_files {
+            self.data_layer.delete_file_entry(*file_id).await?;
+        }
+        for id in &report.orphaned_backup_files {
+            backup_svc.delete_backup(*id).await?;
+        }
+        Ok(())
+    }
+    async fn rehash(&self, backup_svc: &dyn BackupService, algorithm: HashAlgorithm) -> Result<RehashReport> {
+        let mut report = RehashReport::default();
+        for file in self.data_layer.get_backed_up_files().await? {
+            let Some(old_hsh) = file.hsh else { continue };
+            let old_algorithm = match algorithm_of(&old_hsh) {
+                // Already rehashed, such as by an earlier run which was interrupted
+                Some(old_algorithm) if old_algorithm == algorithm => {
+                    report.skipped += 1;
+                    continue;
+                },
+                Some(old_algorithm) => old_algorithm,
+                None => {
+                    report.failures.push((file.id, format!("the recorded hash {} wasn't made by a known algorithm", old_hsh)));
+                    continue;
+                }
+            };
+
+            // The old hash is checked as well, so a corrupt backup isn't given a hash vouching for it
+            let hashed = match backup_svc.open_backup(file.id).await {
+                Ok(reader) => tokio::task::spawn_blocking(move || hash_reader_with_each(reader, &[old_algorithm, algorithm])).await
+                    .map_err(|e| format!("unreadable: {}", e))
+                    .and_then(|hashed| hashed.map_err(|e| format!("unreadable: {:?}", e))),
+                Err(e) => Err(format!("unreadable: {}", e))
+            };
+            match hashed {
+                Ok((hashes, _)) if hashes[0] == old_hsh => {
+                    self.data_layer.update_file_hash(file.id, &hashes[1]).await?;
+                    report.rehashed += 1;
+                },
+                Ok((hashes, _)) => report.failures.push((file.id, format!("the backup has the hash {}, not {}", hashes[0], old_hsh))),
+                Err(reason) => report.failures.push((file.id, reason))
+            }
+        }
+
+        Ok(report)
+    }
+    async fn register_existing_backup(&mut self, path: &Path, hsh: &str, size: u64, file_id: i64, backup_ts: DateTime<Utc>) -> Result<()> {
+        let paths: Vec<&str> = path.iter().map(|p| p.to_str().unwrap()).collect();
+        let file_name = path.file_name().unwrap().to_str().unwrap();
+        let dir_id = self.traverse_to_subdir(paths.into_iter(), true).await?.unwrap();
+
+        self.data_layer.create_file_entry(
+            dir_id, file_id, file_name, hsh, size as i64, backup_ts, self.path_policy.as_str(), None
+        ).await?;
+
         Ok(())
     }
+    async fn record_empty_dirs(&self, dirs: &[EmptyDir]) -> Result<()> {
+        let mut models = Vec::with_capacity(dirs.len());
+        for dir in dirs {
+            // The final component is taken as a file name when traversing,
+            // so an empty one stands in for it to reach the directory itself
+            let paths: Vec<&str> = dir.path.iter().map(|p| p.to_str().unwrap()).chain(std::iter::once("")).collect();
+            let id = self.traverse_to_subdir(paths.into_iter(), true).await?.unwrap();
+            models.push(EmptyDirModel { id, permissions: dir.permissions.map(|mode| mode as i64) });
+        }
+
+        Ok(self.data_layer.set_empty_dirs(&models).await?)
+    }
+    async fn create_dir_tree(&self, paths: &[PathBuf]) -> Result<HashMap<PathBuf, i64>> {
+        // Every directory holding one of the files, as the names leading down to it, 
+        // shallowest first so each level's parents already have IDs
+        let mut dirs: Vec<Vec<&str>> = paths.iter()
+            .flat_map(|path| {
+                let names: Vec<&str> = path.iter().map(|p| p.to_str().unwrap()).collect();
+                (1..names.len()).map(move |depth| names[..depth].to_vec())
+            })
+            .collect::<BTreeSet<_>>().into_iter().collect();
+        dirs.sort_by_key(Vec::len);
+
+        let mut dir_ids: HashMap<Vec<&str>, i64> = HashMap::new();
+        let mut sub_dirs: HashMap<i64, Vec<DirModel>> = HashMap::new();
+        // Directories created here have no sub-directories in the catalog to look up
+        let mut created = HashSet::new();
+        for level in dirs.chunk_by(|a, b| a.len() == b.len()) {
+            let mut missing = Vec::new();
+            for dir in level {
+                let (dir_name, parents) = dir.split_last().unwrap();
+                let parent_dir_id = (!parents.is_empty()).then(|| dir_ids[parents]);
+                let existing = match parent_dir_id {
+                    None => self.data_layer.get_dir(dir_name).await?.map(|d| d.id),
+                    Some(parent_dir_id) if created.contains(&parent_dir_id) => None,
+                    Some(parent_dir_id) => {
+                        let siblings = match sub_dirs.entry(parent_dir_id) {
+                            Entry::Occupied(entry) => entry.into_mut(),
+                            Entry::Vacant(entry) => entry.insert(self.data_layer.get_sub_dirs(parent_dir_id).await?)
+                        };
+                        siblings.iter().find(|d| d.dir_name == *dir_name).map(|d| d.id)
+                    }
+                };
+                match existing {
+                    Some(id) => { dir_ids.insert(dir.clone(), id); },
+                    None => missing.push((dir, parent_dir_id))
+                }
+            }
+
+            if missing.is_empty() {
+                continue;
+            }
+            let new_dirs = missing.iter().map(|(dir, parent_dir_id)| (dir.last().unwrap().to_string(), *parent_dir_id)).collect();
+            let new_ids = self.data_layer.batch_create_dirs(new_dirs).await?;
+            for ((dir, _), (_, id)) in missing.into_iter().zip(new_ids) {
+                created.insert(id);
+                dir_ids.insert(dir.clone(), id);
+            }
+        }
+
+        Ok(dir_ids.into_iter().map(|(dir, id)| (dir.into_iter().collect(), id)).collect())
+    }
+    async fn get_empty_dirs(&self, query: &str) -> Result<Vec<EmptyDir>> {
+        let mut dirs = Vec::new();
+        let tree = DirTree::new(self.data_layer.load_dir_tree().await?);
+        for dir in self.data_layer.get_empty_dirs(&glob_to_like_pattern(query)).await? {
+            let path = tree.full_path(dir.id)?.into();
+            dirs.push(EmptyDir { path, permissions: dir.permissions.map(|mode| mode as u32) });
+        }
+
+        Ok(dirs)
+    }
+}
+
+///
+/// Hashes the file at `path` with `algorithm`, or `None` if it couldn't be read
+/// 
+async fn hash_file_with(path: &Path, algorithm: HashAlgorithm) -> Option<String> {
+    let path = long_path(path).into_owned();
+    tokio::task::spawn_blocking(move || std::fs::File::open(path).ok().and_then(|file| hash_reader(file, algorithm).ok()))
+        .await.ok().flatten()
+        .map(|(hsh, _)| hsh)
+}
+
+///
+/// Converts the wildcards of a glob-style `query` into a SQL `LIKE` pattern,
+/// escaping any characters `LIKE` would otherwise treat specially with `\`
+/// 
+fn glob_to_like_pattern(query: &str) -> String {
+    let mut pattern = String::with_capacity(query.len());
+    for c in query.chars() {
+        match c {
+            '*' => pattern.push('%'),
+            '?' => pattern.push('_'),
+            '%' | '_' | '\\' => { pattern.push('\\'); pattern.push(c); },
+            c => pattern.push(c)
+        }
+    }
+
+    pattern
 }
-impl<'a> FileHistoryService<'a> {
+impl FileHistoryService {
     pub async fn new(
-        data_layer: &'a dyn DataLayer, time_provider: &'a dyn TimeProvider, max_copies: i32
+        data_layer: Arc<dyn DataLayer>, time_provider: Arc<dyn TimeProvider>, max_copies: i32, path_policy: CanonicalizePolicy
     ) -> Result<Self> {
         Ok(Self { 
             data_layer, 
             time_provider,
-            next_file_id: data_layer.get_max_file_id().await? + 1,
-            max_copies
+            max_copies,
+            path_policy,
+            run_id: None,
+            run_label: None,
+            processed: HashSet::new(),
+            non_canonical_paths: HashSet::new(),
+            non_canonical: HashSet::new()
         })
     }
-    
-    #[async_recursion]
+
+    ///
+    /// Records the file named `file_name` in the dir with the given `dir_id` as seen 
+    /// during the current run, so it isn't marked as deleted once the run ends
+    /// 
+    fn mark_processed(&mut self, dir_id: i64, file_name: &str) {
+        self.processed.insert((dir_id, file_name.to_string()));
+    }
+
+    ///
+    /// Gets the ID of the directory holding the file or directory at `path`, if it's in the catalog
+    /// 
+    async fn get_parent_dir_id(&self, path: &Path) -> Result<Option<i64>> {
+        let Some(paths) = path.iter().map(|p| p.to_str()).collect::<Option<Vec<_>>>() else { return Ok(None) };
+        self.traverse_to_subdir(paths.into_iter(), false).await
+    }
+
+    ///
+    /// Gets the ID of the directory at `path`, walking down from its root one directory
+    /// at a time, and creating each directory which isn't in the catalog if `create_dirs` is set.
+    /// The root is recorded as `/` on Unix, and as its drive, ie. `C:\`, on Windows
+    /// 
     async fn traverse_to_subdir<'b>(
         &self, 
-        path: impl Iterator<Item = &'b str> + Send + 'async_recursion,
+        path: impl Iterator<Item = &'b str> + Send,
         create_dirs: bool
     ) -> Result<Option<i64>> {
         // Convert the path to a peekable iterator
         let mut path = path.peekable();
         // Attempt to retrieve the root path from the data layer.
         // If it does not exist, no rows exist in the database
-        let root_dir = path.next().unwrap();
-        let mut cur_dir_id = self.data_layer.get_dir(root_dir).await?.and_then(|d| Some(d.id));
+        let root_dir = root_dir_name(path.next().unwrap(), &mut path);
+        let mut cur_dir_id = self.data_layer.get_dir(&root_dir).await?.map(|d| d.id);
         if let (None, true) = (cur_dir_id, create_dirs) {
-            cur_dir_id = Some(self.data_layer.create_dir(root_dir, None).await?);
+            cur_dir_id = Some(self.data_layer.create_dir(&root_dir, None).await?);
         }
 
         while let (Some(sub_path), Some(dir_id)) = (path.next(), cur_dir_id) {
@@ -147,6 +1037,695 @@ impl<'a> FileHistoryService<'a> {
     }
 }
 
+///
+/// Gets the ID of the dir at `path` in the `tree`, if it's in the catalog
+/// 
+fn dir_id_at(tree: &DirTree, path: &Path) -> Option<i64> {
+    let mut names = path.iter().map(|p| p.to_str()).collect::<Option<Vec<_>>>()?.into_iter().peekable();
+    let root = root_dir_name(names.next()?, &mut names);
+    tree.find(&root, names)
+}
+
+///
+/// Gets the name the root `component` of a path is recorded by. A path split as a string, 
+/// rather than by its components, starts with an empty component in place of the Unix root.
+/// A Windows drive, ie. `C:`, is followed by its root directory, which is taken from the
+/// rest of the `path` so that both are recorded as one dir
+/// 
+fn root_dir_name<'b>(component: &'b str, path: &mut Peekable<impl Iterator<Item = &'b str>>) -> Cow<'b, str> {
+    if component.is_empty() {
+        return Cow::Borrowed("/");
+    }
+    let is_drive = component.len() == 2 && component.ends_with(':') && component.as_bytes()[0].is_ascii_alphabetic();
+    if is_drive && path.next_if(|next| *next == "\\" || *next == "/").is_some() {
+        return Cow::Owned(format!("{}\\", component));
+    }
+
+    Cow::Borrowed(component)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::{HashMap, HashSet}, path::{Path, PathBuf}, sync::Arc};
+
+    use chrono::{DateTime, Duration, TimeZone, Utc};
+    use mockall::predicate::eq;
+
+    use crate::{backup_service::{BackupService, FileBackupService}, config::{CanonicalizePolicy, HashAlgorithm, UNLIMITED_COPIES}, data_layer_error::DataLayerError, hash_svc::hash_reader, time_provider::MockTimeProvider};
+
+    use super::{data_layer::MockDataLayer, models::{ChangeType, DeletionExclusions, DirModel, FileDiffEntry, FileModel, RunOrigin, RunStats, VersionSelector, CURRENT_VERSION}, Error, FileHistoryService, FileStatus, HistoryService};
+
+    fn run_ts() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, 10, 0, 0, 0).unwrap()
+    }
+    fn build_mock_time_provider() -> MockTimeProvider {
+        MockTimeProvider::starting_at(run_ts())
+    }
+    ///
+    /// Builds a `MockDataLayer` holding the directory `/dir`, in which the latest 
+    /// version of `file.txt` has the given `hsh` and `file_size`
+    /// 
+    fn build_mock_data_layer(hsh: &'static str, file_size: i64) -> MockDataLayer {
+        let mut mock_dl = MockDataLayer::new();
+        mock_dl.expect_allocate_file_id().returning(|| Ok(11));
+        mock_dl.expect_get_dir()
+            .returning(|_| Ok(Some(DirModel { id: 1, parent_dir_id: None, dir_name: "/".to_string() })));
+        mock_dl.expect_get_sub_dirs()
+            .returning(|_| Ok(vec![DirModel { id: 2, parent_dir_id: Some(1), dir_name: "dir".to_string() }]));
+        mock_dl.expect_get_latest_file()
+            .returning(move |_, _| Ok(Some(FileModel { 
+                version: 1, id: 3, file_name: "file.txt".to_string(), backup_ts: run_ts(), 
+                hsh: Some(hsh.to_string()), verified_ts: None, file_size: Some(file_size), generation: 0, label: None, excluded: false
+            })));
+
+        mock_dl
+    }
+
+    #[tokio::test]
+    async fn test_get_file_status_matching_hash_and_size() {
+        let mut mock_dl = build_mock_data_layer("hash", 10);
+        mock_dl.expect_update_latest_hsh_ts().times(1).returning(|_, _, _| Ok(()));
+        let mock_tp = build_mock_time_provider();
+        let mut svc = FileHistoryService::new(Arc::new(mock_dl), Arc::new(mock_tp), 2, CanonicalizePolicy::Full).await.unwrap();
+
+        let status = svc.get_file_status(Path::new("/dir/file.txt"), "hash", 10).await.unwrap();
+        assert!(matches!(status, FileStatus::DoesNotNeedBackup));
+    }
+
+    #[tokio::test]
+    async fn test_get_file_status_with_future_format_version() {
+        let mut mock_dl = MockDataLayer::new();
+        mock_dl.expect_allocate_file_id().returning(|| Ok(11));
+        mock_dl.expect_get_dir()
+            .returning(|_| Ok(Some(DirModel { id: 1, parent_dir_id: None, dir_name: "/".to_string() })));
+        mock_dl.expect_get_sub_dirs()
+            .returning(|_| Ok(vec![DirModel { id: 2, parent_dir_id: Some(1), dir_name: "dir".to_string() }]));
+        let latest = FileModel { 
+            version: CURRENT_VERSION + 1, id: 3, file_name: "file.txt".to_string(), backup_ts: run_ts(), 
+            hsh: Some("hash".to_string()), verified_ts: None, file_size: Some(10), generation: 0, label: None, excluded: false
+        };
+        assert!(!latest.is_format_supported());
+        mock_dl.expect_get_latest_file().returning(move |_, _| Ok(Some(latest.clone())));
+        mock_dl.expect_update_latest_hsh_ts().times(1).returning(|_, _, _| Ok(()));
+        let mock_tp = build_mock_time_provider();
+        let mut svc = FileHistoryService::new(Arc::new(mock_dl), Arc::new(mock_tp), 2, CanonicalizePolicy::Full).await.unwrap();
+
+        // Only warned about, so the file is still compared as usual
+        let status = svc.get_file_status(Path::new("/dir/file.txt"), "hash", 10).await.unwrap();
+        assert!(matches!(status, FileStatus::DoesNotNeedBackup));
+    }
+
+    ///
+    /// Builds a data layer whose latest version of every file has the MD5 hash of `contents`,
+    /// and creates every directory it's asked for
+    /// 
+    fn build_legacy_data_layer(contents: &[u8]) -> MockDataLayer {
+        let (legacy_hsh, size) = hash_reader(contents, HashAlgorithm::Md5).unwrap();
+        let mut mock_dl = MockDataLayer::new();
+        mock_dl.expect_allocate_file_id().returning(|| Ok(11));
+        mock_dl.expect_get_dir().returning(|_| Ok(None));
+        mock_dl.expect_get_sub_dirs().returning(|_| Ok(Vec::new()));
+        mock_dl.expect_create_dir().returning(|_, _| Ok(2));
+        mock_dl.expect_get_latest_file()
+            .returning(move |_, _| Ok(Some(FileModel { 
+                version: 1, id: 3, file_name: "file.txt".to_string(), backup_ts: run_ts(), 
+                hsh: Some(legacy_hsh.clone()), verified_ts: None, file_size: Some(size as i64), generation: 0, label: None, excluded: false
+            })));
+
+        mock_dl
+    }
+
+    #[tokio::test]
+    async fn test_get_file_status_migrates_matching_legacy_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.txt");
+        std::fs::write(&path, "contents").unwrap();
+        let (hsh, size) = hash_reader(&b"contents"[..], HashAlgorithm::Sha256).unwrap();
+        let mut mock_dl = build_legacy_data_layer(b"contents");
+        let expected_hsh = hsh.clone();
+        mock_dl.expect_update_file_hash()
+            .withf(move |file_id, new_hsh| *file_id == 3 && new_hsh == expected_hsh)
+            .times(1).returning(|_, _| Ok(()));
+        mock_dl.expect_update_latest_hsh_ts().times(1).returning(|_, _, _| Ok(()));
+        mock_dl.expect_create_file_entry().never();
+        let mock_tp = build_mock_time_provider();
+        let mut svc = FileHistoryService::new(Arc::new(mock_dl), Arc::new(mock_tp), 2, CanonicalizePolicy::Full).await.unwrap();
+
+        let status = svc.get_file_status(&path, &hsh, size).await.unwrap();
+
+        // The stored hash is converted in place, so the file isn't backed up again
+        assert!(matches!(status, FileStatus::DoesNotNeedBackup));
+    }
+
+    #[tokio::test]
+    async fn test_get_file_status_backs_up_changed_file_with_legacy_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.txt");
+        std::fs::write(&path, "changed!").unwrap();
+        let (hsh, size) = hash_reader(&b"changed!"[..], HashAlgorithm::Sha256).unwrap();
+        let mut mock_dl = build_legacy_data_layer(b"contents");
+        mock_dl.expect_update_file_hash().never();
+        mock_dl.expect_update_latest_hsh_ts().never();
+        let mock_tp = build_mock_time_provider();
+        let mut svc = FileHistoryService::new(Arc::new(mock_dl), Arc::new(mock_tp), 2, CanonicalizePolicy::Full).await.unwrap();
+
+        let status = svc.get_file_status(&path, &hsh, size).await.unwrap();
+
+        assert!(matches!(status, FileStatus::NeedsBackup { file_id: 11, is_new: false, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_mark_unchanged_refreshes_latest_version() {
+        let mut mock_dl = build_mock_data_layer("hash", 10);
+        mock_dl.expect_update_latest_hsh_ts()
+            .with(eq(2), eq("file.txt"), eq(run_ts()))
+            .times(1).returning(|_, _, _| Ok(()));
+        let mock_tp = build_mock_time_provider();
+        let mut svc = FileHistoryService::new(Arc::new(mock_dl), Arc::new(mock_tp), 2, CanonicalizePolicy::Full).await.unwrap();
+
+        svc.mark_unchanged(Path::new("/dir/file.txt")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_register_existing_backup() {
+        let mut mock_dl = build_mock_data_layer("hash", 10);
+        mock_dl.expect_create_file_entry()
+            .withf(|dir_id, file_id, file_name, hsh, size, _, _, run_id| 
+                (*dir_id, *file_id, file_name, hsh, *size, *run_id) == (2, 50, "file.txt", "imported", 20, None))
+            .times(1)
+            .returning(|_, _, _, _, _, _, _, _| Ok(()));
+        let mock_tp = build_mock_time_provider();
+        let mut svc = FileHistoryService::new(Arc::new(mock_dl), Arc::new(mock_tp), 2, CanonicalizePolicy::Full).await.unwrap();
+
+        svc.register_existing_backup(Path::new("/dir/file.txt"), "imported", 20, 50, run_ts()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_file_status_explains_failed_id_allocation() {
+        let mut mock_dl = MockDataLayer::new();
+        mock_dl.expect_allocate_file_id().returning(|| Err(DataLayerError { err: "no such table: file_id_sequence".into() }));
+        mock_dl.expect_get_dir()
+            .returning(|_| Ok(Some(DirModel { id: 1, parent_dir_id: None, dir_name: "/".to_string() })));
+        mock_dl.expect_get_sub_dirs()
+            .returning(|_| Ok(vec![DirModel { id: 2, parent_dir_id: Some(1), dir_name: "dir".to_string() }]));
+        mock_dl.expect_get_latest_file().returning(|_, _| Ok(None));
+        let mock_tp = build_mock_time_provider();
+        let mut svc = FileHistoryService::new(Arc::new(mock_dl), Arc::new(mock_tp), 2, CanonicalizePolicy::Full).await.unwrap();
+
+        let result = svc.get_file_status(Path::new("/dir/file.txt"), "hash", 10).await;
+
+        assert!(matches!(
+            result,
+            Err(Error::Context(message, inner)) if message.contains("schema migrated") && matches!(*inner, Error::DataLayerError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_get_file_status_matching_hash_different_size() {
+        let mut mock_dl = build_mock_data_layer("hash", 10);
+        mock_dl.expect_update_latest_hsh_ts().never();
+        let mock_tp = build_mock_time_provider();
+        let mut svc = FileHistoryService::new(Arc::new(mock_dl), Arc::new(mock_tp), 2, CanonicalizePolicy::Full).await.unwrap();
+
+        let status = svc.get_file_status(Path::new("/dir/file.txt"), "hash", 20).await.unwrap();
+        assert!(matches!(status, FileStatus::NeedsBackup { sub_dir_id: 2, file_id: 11, file_name: "file.txt", is_new: false }));
+    }
+
+    fn version(id: i64, days_after_run: i64, hsh: Option<&str>, generation: i64) -> FileModel {
+        FileModel { 
+            version: 1, id, file_name: "file.txt".to_string(), backup_ts: run_ts() + Duration::days(days_after_run),
+            hsh: hsh.map(str::to_string), verified_ts: None, file_size: hsh.map(|_| 10), generation, label: None, excluded: false
+        }
+    }
+    ///
+    /// The versions of a file which was created, deleted, recreated and then modified
+    /// 
+    fn recreated_file_versions() -> Vec<FileModel> {
+        vec![
+            version(1, 0, Some("created"), 0),
+            version(2, 1, None, 0),
+            version(3, 2, Some("recreated"), 1),
+            version(4, 3, Some("modified"), 1),
+        ]
+    }
+    fn build_mock_data_layer_with_versions(versions: Vec<FileModel>) -> MockDataLayer {
+        let mut mock_dl = MockDataLayer::new();
+        mock_dl.expect_allocate_file_id().returning(|| Ok(11));
+        mock_dl.expect_create_file_entry().returning(|_, _, _, _, _, _, _, _| Ok(()));
+        mock_dl.expect_get_dir_files().returning(move |_, _| Ok(versions.clone()));
+        mock_dl
+    }
+
+    #[tokio::test]
+    async fn test_create_file_entry_ignores_older_generations() {
+        let mut mock_dl = build_mock_data_layer_with_versions(recreated_file_versions());
+        mock_dl.expect_delete_file_entry().never();
+        let mock_tp = build_mock_time_provider();
+        let svc = FileHistoryService::new(Arc::new(mock_dl), Arc::new(mock_tp), 2, CanonicalizePolicy::Full).await.unwrap();
+
+        let pruned = svc.create_file_entry(2, 4, "file.txt", "modified", 10).await.unwrap();
+        assert_eq!(pruned, None);
+    }
+
+    #[tokio::test]
+    async fn test_create_file_entry_prunes_within_current_generation() {
+        let mut versions = recreated_file_versions();
+        versions.push(version(5, 4, Some("modified again"), 1));
+        let mut mock_dl = build_mock_data_layer_with_versions(versions);
+        mock_dl.expect_delete_file_entry().with(eq(3)).times(1).returning(|_| Ok(()));
+        let mock_tp = build_mock_time_provider();
+        let svc = FileHistoryService::new(Arc::new(mock_dl), Arc::new(mock_tp), 2, CanonicalizePolicy::Full).await.unwrap();
+
+        let pruned = svc.create_file_entry(2, 5, "file.txt", "modified again", 10).await.unwrap();
+        assert_eq!(pruned, Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_create_file_entry_with_one_copy_keeps_the_new_version() {
+        // Both versions were backed up at the same time, with the new one listed first
+        let versions = vec![version(5, 2, Some("modified"), 1), version(3, 2, Some("recreated"), 1)];
+        let mut mock_dl = build_mock_data_layer_with_versions(versions);
+        mock_dl.expect_delete_file_entry().with(eq(3)).times(1).returning(|_| Ok(()));
+        let mock_tp = build_mock_time_provider();
+        let svc = FileHistoryService::new(Arc::new(mock_dl), Arc::new(mock_tp), 1, CanonicalizePolicy::Full).await.unwrap();
+
+        let pruned = svc.create_file_entry(2, 5, "file.txt", "modified", 10).await.unwrap();
+        assert_eq!(pruned, Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_create_file_entry_with_unlimited_copies_never_prunes() {
+        let versions = (3..9).map(|id| version(id, id, Some("modified"), 1)).collect();
+        let mut mock_dl = build_mock_data_layer_with_versions(versions);
+        mock_dl.expect_delete_file_entry().never();
+        let mock_tp = build_mock_time_provider();
+        let svc = FileHistoryService::new(Arc::new(mock_dl), Arc::new(mock_tp), UNLIMITED_COPIES, CanonicalizePolicy::Full).await.unwrap();
+
+        let pruned = svc.create_file_entry(2, 8, "file.txt", "modified", 10).await.unwrap();
+        assert_eq!(pruned, None);
+    }
+
+    #[tokio::test]
+    async fn test_prune_expired_generations() {
+        let mut mock_dl = MockDataLayer::new();
+        mock_dl.expect_allocate_file_id().returning(|| Ok(11));
+        mock_dl.expect_get_expired_generation_files()
+            .with(eq(run_ts() - Duration::days(30)))
+            .returning(|_| Ok(vec![1, 2]));
+        mock_dl.expect_delete_file_entry().times(2).returning(|_| Ok(()));
+        let mock_tp = build_mock_time_provider();
+        let svc = FileHistoryService::new(Arc::new(mock_dl), Arc::new(mock_tp), 2, CanonicalizePolicy::Full).await.unwrap();
+
+        assert_eq!(svc.prune_expired_generations(Duration::days(30)).await.unwrap(), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_run_stopped_at_deadline_is_recorded_partial() {
+        let mut mock_dl = MockDataLayer::new();
+        mock_dl.expect_allocate_file_id().returning(|| Ok(11));
+        mock_dl.expect_complete_run()
+            .withf(|run_id, _, stats| (*run_id, stats.files_scanned, stats.partial) == (7, 4, true))
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        // Each file examined is an hour further into the run
+        let mut mock_tp = MockTimeProvider::new();
+        mock_tp.expect_utc_start().returning(run_ts);
+        let mut hours = 0;
+        mock_tp.expect_utc_now().returning(move || {
+            hours += 1;
+            run_ts() + Duration::hours(hours)
+        });
+        let svc = FileHistoryService::new(Arc::new(mock_dl), Arc::new(mock_tp), 2, CanonicalizePolicy::Full).await.unwrap();
+
+        let examined = (0..4).take_while(|_| !svc.run_deadline_passed(Duration::hours(3))).count();
+        assert_eq!(examined, 2);
+
+        svc.complete_run(7, RunStats { files_scanned: 4, partial: examined < 4, ..Default::default() }).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_file_versions_are_recorded_with_their_run() {
+        let mut mock_dl = build_mock_data_layer("hash", 10);
+        mock_dl.expect_begin_run().returning(|_, _, _| Ok(3));
+        mock_dl.expect_create_file_entry()
+            .withf(|_, file_id, _, _, _, _, _, run_id| (*file_id, *run_id) == (11, Some(3)))
+            .times(1)
+            .returning(|_, _, _, _, _, _, _, _| Ok(()));
+        mock_dl.expect_get_dir_files().returning(|_, _| Ok(vec![version(11, 0, Some("changed"), 0)]));
+        mock_dl.expect_complete_run()
+            .withf(|run_id, _, stats| *run_id == 3 && stats.files_backed_up == 1 && stats.errors == ["unreadable.txt".to_string()])
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        let mock_tp = build_mock_time_provider();
+        let mut svc = FileHistoryService::new(Arc::new(mock_dl), Arc::new(mock_tp), 2, CanonicalizePolicy::Full).await.unwrap();
+
+        let run_id = svc.begin_run("{}", &RunOrigin::default()).await.unwrap();
+        let FileStatus::NeedsBackup { sub_dir_id, file_id, .. } = svc.get_file_status(Path::new("/dir/file.txt"), "changed", 10).await.unwrap() 
+            else { panic!("the changed file should need backing up") };
+        svc.create_file_entry(sub_dir_id, file_id, "file.txt", "changed", 10).await.unwrap();
+
+        let stats = RunStats { files_scanned: 1, files_backed_up: 1, errors: vec!["unreadable.txt".to_string()], ..Default::default() };
+        svc.complete_run(run_id, stats).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_reason_labels_its_versions() {
+        let mut mock_dl = build_mock_data_layer("hash", 10);
+        mock_dl.expect_begin_run().returning(|_, _, _| Ok(3));
+        mock_dl.expect_create_file_entry().returning(|_, _, _, _, _, _, _, _| Ok(()));
+        mock_dl.expect_get_dir_files().returning(|_, _| Ok(vec![version(11, 0, Some("changed"), 0)]));
+        mock_dl.expect_set_version_label()
+            .with(eq(11), eq(Some("before reformatting".to_string())))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mock_tp = build_mock_time_provider();
+        let mut svc = FileHistoryService::new(Arc::new(mock_dl), Arc::new(mock_tp), 2, CanonicalizePolicy::Full).await.unwrap();
+
+        let origin = RunOrigin { reason: Some("before reformatting".to_string()), ..Default::default() };
+        svc.begin_run("{}", &origin).await.unwrap();
+        svc.create_file_entry(2, 11, "file.txt", "changed", 10).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_label_version() {
+        let mut mock_dl = build_mock_data_layer("hash", 10);
+        mock_dl.expect_get_dir_files().returning(|_, _| Ok(recreated_file_versions()));
+        mock_dl.expect_set_version_label()
+            .withf(|file_id, label| [1, 4].contains(file_id) && label.as_deref() == Some("label"))
+            .times(2)
+            .returning(|_, _| Ok(()));
+        let mock_tp = build_mock_time_provider();
+        let svc = FileHistoryService::new(Arc::new(mock_dl), Arc::new(mock_tp), 2, CanonicalizePolicy::Full).await.unwrap();
+        let path = Path::new("/dir/file.txt");
+
+        // The latest version is labelled unless another is given
+        assert_eq!(svc.label_version(path, None, "label").await.unwrap(), Some(4));
+        assert_eq!(svc.label_version(path, Some(1), "label").await.unwrap(), Some(1));
+        // Deletion markers and other files' versions can't be labelled
+        assert_eq!(svc.label_version(path, Some(2), "label").await.unwrap(), None);
+        assert_eq!(svc.label_version(path, Some(99), "label").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_get_labelled_version() {
+        let mut mock_dl = build_mock_data_layer("hash", 10);
+        mock_dl.expect_get_dir_files().returning(|_, _| {
+            let mut versions = recreated_file_versions();
+            versions[0].label = Some("first".to_string());
+            versions[2].label = Some("twice".to_string());
+            versions[3].label = Some("twice".to_string());
+            Ok(versions)
+        });
+        let mock_tp = build_mock_time_provider();
+        let svc = FileHistoryService::new(Arc::new(mock_dl), Arc::new(mock_tp), 2, CanonicalizePolicy::Full).await.unwrap();
+
+        assert_eq!(svc.get_labelled_version(2, "file.txt", "first").await.unwrap().map(|version| version.id), Some(1));
+        assert!(svc.get_labelled_version(2, "file.txt", "missing").await.unwrap().is_none());
+        assert!(matches!(
+            svc.get_labelled_version(2, "file.txt", "twice").await,
+            Err(Error::AmbiguousLabel(label, ids)) if label == "twice" && ids == [3, 4]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_get_versions_orders_by_generation() {
+        let mut versions = recreated_file_versions();
+        versions.reverse();
+        let mock_dl = build_mock_data_layer_with_versions(versions);
+        let mock_tp = build_mock_time_provider();
+        let svc = FileHistoryService::new(Arc::new(mock_dl), Arc::new(mock_tp), 2, CanonicalizePolicy::Full).await.unwrap();
+
+        let ids: Vec<i64> = svc.get_versions(2, "file.txt").await.unwrap().iter().map(|f| f.id).collect();
+        assert_eq!(ids, vec![1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_check_and_repair_consistency() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut backup_svc = FileBackupService::new(dir.path().join("backups").to_string_lossy().to_string()).unwrap();
+        let (contents, changed) = (dir.path().join("contents.txt"), dir.path().join("changed.txt"));
+        std::fs::write(&contents, "file contents").unwrap();
+        std::fs::write(&changed, "changed contents").unwrap();
+        let hsh = hash_reader("file contents".as_bytes(), HashAlgorithm::Md5).unwrap().0;
+        // 1 is intact, 2 was never stored, 3 holds the wrong contents and 4 isn't in the catalog
+        for (id, path) in [(1, &contents), (3, &changed), (4, &contents)] {
+            backup_svc.backup_data(id, path).await.unwrap();
+        }
+        let files = vec![version(1, 0, Some(&hsh), 0), version(2, 1, Some(&hsh), 0), version(3, 2, Some(&hsh), 0)];
+
+        let mut mock_dl = MockDataLayer::new();
+        mock_dl.expect_allocate_file_id().returning(|| Ok(5));
+        mock_dl.expect_get_backed_up_files().returning(move || Ok(files.clone()));
+        mock_dl.expect_delete_file_entry().with(eq(2)).times(1).returning(|_| Ok(()));
+        let mock_tp = build_mock_time_provider();
+        let svc = FileHistoryService::new(Arc::new(mock_dl), Arc::new(mock_tp), 2, CanonicalizePolicy::Full).await.unwrap();
+
+        let report = svc.check_consistency(&backup_svc).await.unwrap();
+
+        assert_eq!(report.missing_backup_files, vec![2]);
+        assert_eq!(report.orphaned_backup_files, vec![4]);
+        assert_eq!(report.hash_mismatches, vec![(3, hsh.clone(), hash_reader("changed contents".as_bytes(), HashAlgorithm::Md5).unwrap().0)]);
+        assert!(!report.is_consistent());
+
+        // Checking changes nothing, repairing forgets the missing backup and deletes the orphan
+        assert_eq!(backup_svc.list_backup_ids().await.unwrap(), vec![1, 3, 4]);
+        svc.repair_consistency(&report, &mut backup_svc).await.unwrap();
+        assert_eq!(backup_svc.list_backup_ids().await.unwrap(), vec![1, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_rehash() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut backup_svc = FileBackupService::new(dir.path().join("backups").to_string_lossy().to_string()).unwrap();
+        let (contents, changed) = (dir.path().join("contents.txt"), dir.path().join("changed.txt"));
+        std::fs::write(&contents, "file contents").unwrap();
+        std::fs::write(&changed, "changed contents").unwrap();
+        let md5 = hash_reader("file contents".as_bytes(), HashAlgorithm::Md5).unwrap().0;
+        let sha256 = hash_reader("file contents".as_bytes(), HashAlgorithm::Sha256).unwrap().0;
+        // 1 is rehashed, 2 already was, 3 holds the wrong contents and 4 was never stored
+        for (id, path) in [(1, &contents), (2, &contents), (3, &changed)] {
+            backup_svc.backup_data(id, path).await.unwrap();
+        }
+        let files = vec![version(1, 0, Some(&md5), 0), version(2, 1, Some(&sha256), 0), version(3, 2, Some(&md5), 0), version(4, 3, Some(&md5), 0)];
+
+        let mut mock_dl = MockDataLayer::new();
+        mock_dl.expect_allocate_file_id().returning(|| Ok(5));
+        mock_dl.expect_get_backed_up_files().returning(move || Ok(files.clone()));
+        let expected = sha256.clone();
+        mock_dl.expect_update_file_hash()
+            .withf(move |file_id, new_hsh| *file_id == 1 && new_hsh == expected)
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mock_tp = build_mock_time_provider();
+        let svc = FileHistoryService::new(Arc::new(mock_dl), Arc::new(mock_tp), 2, CanonicalizePolicy::Full).await.unwrap();
+
+        let report = svc.rehash(&backup_svc, HashAlgorithm::Sha256).await.unwrap();
+
+        assert_eq!((report.rehashed, report.skipped), (1, 1));
+        assert_eq!(report.failures.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![3, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_diff_snapshots() {
+        assert_eq!(ChangeType::between(None, None), None);
+        assert_eq!(ChangeType::between(Some("a"), Some("b")), Some(ChangeType::Modified));
+
+        let mut mock_dl = MockDataLayer::new();
+        mock_dl.expect_allocate_file_id().returning(|| Ok(11));
+        mock_dl.expect_diff_snapshots().with(eq(run_ts()), eq(run_ts() + Duration::days(1)))
+            .returning(|_, _| Ok(vec![
+                FileDiffEntry { dir_id: 3, file_name: "b.txt".to_string(), change_type: ChangeType::Deleted },
+                FileDiffEntry { dir_id: 2, file_name: "z.txt".to_string(), change_type: ChangeType::Added },
+                FileDiffEntry { dir_id: 3, file_name: "a.txt".to_string(), change_type: ChangeType::Modified },
+            ]));
+        // The directories' paths are resolved from the dirs loaded once
+        mock_dl.expect_load_dir_tree()
+            .times(1)
+            .returning(|| Ok(vec![
+                DirModel { id: 1, parent_dir_id: None, dir_name: "/".to_string() },
+                DirModel { id: 2, parent_dir_id: Some(1), dir_name: "docs".to_string() },
+                DirModel { id: 3, parent_dir_id: Some(2), dir_name: "notes".to_string() }
+            ]));
+        let mock_tp = build_mock_time_provider();
+        let svc = FileHistoryService::new(Arc::new(mock_dl), Arc::new(mock_tp), 2, CanonicalizePolicy::Full).await.unwrap();
+
+        let diff = svc.diff_snapshots(run_ts(), run_ts() + Duration::days(1)).await.unwrap();
+
+        assert_eq!(diff, vec![
+            (PathBuf::from("/docs/notes/a.txt"), ChangeType::Modified),
+            (PathBuf::from("/docs/notes/b.txt"), ChangeType::Deleted),
+            (PathBuf::from("/docs/z.txt"), ChangeType::Added),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_mark_all_deleted_files_leaves_out_skipped_paths() {
+        let mut mock_dl = MockDataLayer::new();
+        mock_dl.expect_allocate_file_id().returning(|| Ok(11));
+        // The skipped paths and the deleted files' paths are all resolved from the dirs loaded once
+        mock_dl.expect_load_dir_tree()
+            .times(1)
+            .returning(|| Ok(vec![
+                DirModel { id: 1, parent_dir_id: None, dir_name: "/".to_string() },
+                DirModel { id: 2, parent_dir_id: Some(1), dir_name: "docs".to_string() },
+                DirModel { id: 3, parent_dir_id: Some(2), dir_name: "private".to_string() },
+                DirModel { id: 4, parent_dir_id: Some(3), dir_name: "letters".to_string() }
+            ]));
+        // The unreadable directory's whole subtree is left out, along with the unreadable file
+        mock_dl.expect_mark_deleted_under()
+            .withf(|exclusions, ts, _| *ts == run_ts() && *exclusions == DeletionExclusions {
+                dir_ids: vec![3, 4], 
+                files: HashSet::from([(2, "private".to_string()), (2, "locked.txt".to_string())])
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(vec![(2, "gone.txt".to_string())]));
+        let mock_tp = build_mock_time_provider();
+        let svc = FileHistoryService::new(Arc::new(mock_dl), Arc::new(mock_tp), 2, CanonicalizePolicy::Full).await.unwrap();
+
+        let skipped = vec![PathBuf::from("/docs/private"), PathBuf::from("/docs/locked.txt")];
+        let deleted = svc.mark_all_deleted_files(&skipped).await.unwrap();
+
+        assert_eq!(deleted, vec![PathBuf::from("/docs/gone.txt")]);
+    }
+
+    #[tokio::test]
+    async fn test_mark_all_deleted_files_leaves_out_processed_files() {
+        let mut mock_dl = build_mock_data_layer("hash", 10);
+        mock_dl.expect_update_latest_hsh_ts().returning(|_, _, _| Ok(()));
+        mock_dl.expect_begin_run().returning(|_, _, _| Ok(1));
+        mock_dl.expect_load_dir_tree().returning(|| Ok(Vec::new()));
+        mock_dl.expect_mark_deleted_under()
+            .withf(|exclusions, _, _| exclusions.files == HashSet::from([(2, "file.txt".to_string()), (2, "other.txt".to_string())]))
+            .times(1)
+            .returning(|_, _, _| Ok(Vec::new()));
+        let mock_tp = build_mock_time_provider();
+        let mut svc = FileHistoryService::new(Arc::new(mock_dl), Arc::new(mock_tp), 2, CanonicalizePolicy::Full).await.unwrap();
+        svc.mark_processed(2, "from an earlier run.txt");
+        svc.begin_run("{}", &RunOrigin::default()).await.unwrap();
+
+        svc.get_file_status(Path::new("/dir/file.txt"), "hash", 10).await.unwrap();
+        svc.mark_unchanged(Path::new("/dir/other.txt")).await.unwrap();
+
+        assert!(svc.mark_all_deleted_files(&[]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_create_dir_tree_creates_each_level_together() {
+        let mut mock_dl = MockDataLayer::new();
+        mock_dl.expect_allocate_file_id().returning(|| Ok(11));
+        mock_dl.expect_get_dir()
+            .returning(|_| Ok(Some(DirModel { id: 1, parent_dir_id: None, dir_name: "/".to_string() })));
+        // Only the directory already in the catalog has its sub-directories looked up
+        mock_dl.expect_get_sub_dirs()
+            .with(eq(1))
+            .times(1)
+            .returning(|_| Ok(vec![DirModel { id: 2, parent_dir_id: Some(1), dir_name: "dir".to_string() }]));
+        mock_dl.expect_batch_create_dirs()
+            .with(eq(vec![("photos".to_string(), Some(1))]))
+            .times(1)
+            .returning(|_| Ok(vec![("photos".to_string(), 3)]));
+        mock_dl.expect_batch_create_dirs()
+            .with(eq(vec![("2023".to_string(), Some(3)), ("2024".to_string(), Some(3))]))
+            .times(1)
+            .returning(|_| Ok(vec![("2023".to_string(), 4), ("2024".to_string(), 5)]));
+        let mock_tp = build_mock_time_provider();
+        let svc = FileHistoryService::new(Arc::new(mock_dl), Arc::new(mock_tp), 2, CanonicalizePolicy::Full).await.unwrap();
+
+        let dir_ids = svc.create_dir_tree(&[
+            PathBuf::from("/dir/file.txt"),
+            PathBuf::from("/photos/2024/b.jpg"),
+            PathBuf::from("/photos/2023/a.jpg"),
+            PathBuf::from("/photos/2024/c.jpg")
+        ]).await.unwrap();
+
+        assert_eq!(dir_ids, HashMap::from([
+            (PathBuf::from("/"), 1),
+            (PathBuf::from("/dir"), 2),
+            (PathBuf::from("/photos"), 3),
+            (PathBuf::from("/photos/2023"), 4),
+            (PathBuf::from("/photos/2024"), 5)
+        ]));
+    }
+
+    #[tokio::test]
+    async fn test_mark_excluded() {
+        let mut mock_dl = build_mock_data_layer("hash", 10);
+        mock_dl.expect_create_excluded_marker()
+            .withf(|dir_id, file_name, ts, run_id| (*dir_id, file_name, *ts, *run_id) == (2, "file.txt", run_ts(), Some(1)))
+            .times(1)
+            .returning(|_, _, _, _| Ok(()));
+        mock_dl.expect_begin_run().returning(|_, _, _| Ok(1));
+        mock_dl.expect_load_dir_tree().returning(|| Ok(Vec::new()));
+        mock_dl.expect_mark_deleted_under()
+            .withf(|exclusions, _, _| exclusions.files.contains(&(2, "file.txt".to_string())))
+            .times(1)
+            .returning(|_, _, _| Ok(Vec::new()));
+        let mock_tp = build_mock_time_provider();
+        let mut svc = FileHistoryService::new(Arc::new(mock_dl), Arc::new(mock_tp), 2, CanonicalizePolicy::Full).await.unwrap();
+        svc.begin_run("{}", &RunOrigin::default()).await.unwrap();
+        // Seen this run, so it's still backed up
+        svc.mark_processed(2, "seen.txt");
+
+        let excluded = svc.mark_excluded(&[PathBuf::from("/dir/file.txt"), PathBuf::from("/dir/seen.txt")]).await.unwrap();
+
+        assert_eq!(excluded, [PathBuf::from("/dir/file.txt")]);
+        // The excluded file isn't taken as deleted
+        assert!(svc.mark_all_deleted_files(&[]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_non_canonical_paths_are_recorded_with_the_none_policy() {
+        let mut mock_dl = build_mock_data_layer("hash", 10);
+        mock_dl.expect_create_file_entry()
+            .withf(|_, _, file_name, _, _, _, path_policy, _| file_name == "file.txt" && path_policy == "none")
+            .times(1)
+            .returning(|_, _, _, _, _, _, _, _| Ok(()));
+        mock_dl.expect_create_file_entry()
+            .withf(|_, _, file_name, _, _, _, path_policy, _| file_name == "other.txt" && path_policy == "full")
+            .times(1)
+            .returning(|_, _, _, _, _, _, _, _| Ok(()));
+        mock_dl.expect_get_dir_files().returning(|_, _| Ok(Vec::new()));
+        let mock_tp = build_mock_time_provider();
+        let mut svc = FileHistoryService::new(Arc::new(mock_dl), Arc::new(mock_tp), 2, CanonicalizePolicy::Full).await.unwrap();
+        svc.add_non_canonical_path(PathBuf::from("/dir/file.txt"));
+
+        for file_name in ["file.txt", "other.txt"] {
+            let path = Path::new("/dir").join(file_name);
+            let FileStatus::NeedsBackup { sub_dir_id, file_id, .. } = svc.get_file_status(&path, "changed", 10).await.unwrap() 
+                else { panic!("the changed file should need backing up") };
+            svc.create_file_entry(sub_dir_id, file_id, file_name, "changed", 10).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_find_version() {
+        let mut mock_dl = build_mock_data_layer("hash", 10);
+        mock_dl.expect_get_dir_files().returning(|_, _| {
+            let mut versions = recreated_file_versions();
+            versions[2].label = Some("recreated".to_string());
+            Ok(versions)
+        });
+        let mock_tp = build_mock_time_provider();
+        let svc = FileHistoryService::new(Arc::new(mock_dl), Arc::new(mock_tp), 2, CanonicalizePolicy::Full).await.unwrap();
+        let path = Path::new("/dir/file.txt");
+        let selectors = [
+            VersionSelector::Id(1),
+            VersionSelector::Instant(run_ts() + Duration::hours(60)),
+            VersionSelector::Label("recreated".to_string()),
+            // Nothing can be selected from a deletion, or before the file was first backed up
+            VersionSelector::Id(2),
+            VersionSelector::Instant(run_ts() + Duration::hours(36)),
+            VersionSelector::Instant(run_ts() - Duration::days(1)),
+        ];
+        let mut found = Vec::new();
+        for selector in selectors.iter() {
+            found.push(svc.find_version(path, selector).await.unwrap().map(|version| version.id));
+        }
+
+        assert_eq!(found, [Some(1), Some(3), Some(3), None, None, None]);
+    }
+}
+
 /*#[cfg(test)] 
 mod tests {
     use std::{path::PathBuf, str::FromStr};
@@ -154,7 +1733,7 @@ mod tests {
     use chrono::{TimeZone, Utc};
     use mockall::predicate::eq;
 
-    use crate::{history_service::{models::DirModel, HistoryService, FileHistoryService, MockDataLayer, BASE_PATH}, time_provider::MockTimeProvider};
+    use crate::{history_service::{models::DirModel, HistoryService, FileHistoryService, MockDataLayer, base_path}, time_provider::MockTimeProvider};
 
     use super::models::FileModel;
 
@@ -167,8 +1746,8 @@ mod tests {
     }
     fn build_mock_data_layer() -> MockDataLayer {
         let mut mock_dl = MockDataLayer::new();    
-        mock_dl.expect_get_dir().with(eq(BASE_PATH.to_string()))
-            .returning(|_| Ok(Some(DirModel { id: 1, dir_name: BASE_PATH.to_string(), parent_dir_id: None })));
+        mock_dl.expect_get_dir().with(eq(base_path().to_string()))
+            .returning(|_| Ok(Some(DirModel { id: 1, dir_name: base_path().to_string(), parent_dir_id: None })));
 
         mock_dl.expect_get_sub_dirs().with(eq(1))
             .returning(|_| Ok(vec![DirModel { id: 2, dir_name: "path".to_string(), parent_dir_id: Some(1) },]));
@@ -195,7 +1774,7 @@ mod tests {
         let mock_tp = build_mock_time_provider();
         let svc = FileHistoryService::new(&mock_dl, &mock_tp).await.unwrap();
 
-        let path = PathBuf::from_str(&format!("{}/path/entry1", BASE_PATH.to_string())).unwrap();
+        let path = PathBuf::from_str(&format!("{}/path/entry1", base_path().to_string())).unwrap();
         let hsh = svc.get_file_status(&path).await.unwrap();
 
         assert_eq!(hsh, Some(Some("hash".to_string())));
@@ -207,7 +1786,7 @@ mod tests {
         let mock_tp = build_mock_time_provider();
         let svc = FileHistoryService::new(&mock_dl, &mock_tp).await.unwrap();
 
-        let path = PathBuf::from_str(&format!("{}/path/path3/entry2", BASE_PATH.to_string())).unwrap();
+        let path = PathBuf::from_str(&format!("{}/path/path3/entry2", base_path().to_string())).unwrap();
         let hsh = svc.get_file_status(&path).await.unwrap();
         assert_eq!(hsh, Some(Some("hash3".to_string())));
     }
@@ -218,11 +1797,11 @@ mod tests {
         let mock_tp = build_mock_time_provider();
         let svc = FileHistoryService::new(&mock_dl, &mock_tp).await.unwrap();
 
-        let path = PathBuf::from_str(&format!("{}/path/path2/entry2", BASE_PATH.to_string())).unwrap();
+        let path = PathBuf::from_str(&format!("{}/path/path2/entry2", base_path().to_string())).unwrap();
         let hsh = svc.get_file_status(&path).await.unwrap();
         assert_eq!(hsh, Some(Some("hash3".to_string())));
 
-        let path = PathBuf::from_str(&format!("{}/path/path2/entry1", BASE_PATH.to_string())).unwrap();
+        let path = PathBuf::from_str(&format!("{}/path/path2/entry1", base_path().to_string())).unwrap();
         let hsh = svc.get_file_status(&path).await.unwrap();
         assert_eq!(hsh, Some(Some("hash1".to_string())));
     }
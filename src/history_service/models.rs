@@ -1,21 +1,474 @@
-use chrono::NaiveDateTime;
+use std::{collections::HashSet, fmt::Display, path::{Path, PathBuf}};
 
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct CacheEntryModel {
     pub hsh: String,
-    pub backup_ts: NaiveDateTime
+    pub backup_ts: DateTime<Utc>
 }
 
-#[derive(Clone, Debug)]
+///
+/// The version of the backup file format this application writes, recorded with every file version
+/// 
+pub const CURRENT_VERSION: i64 = 1;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct FileModel {
+    /// The version of the backup file format the file version was stored with, so a newer
+    /// application can tell how to read backups an older one wrote. Not the catalog's schema
+    /// version, which is recorded once in its `user_version`
     pub version: i64,
     pub id: i64,
     pub file_name: String,
-    pub backup_ts: NaiveDateTime,
-    pub hsh: Option<String>
+    pub backup_ts: DateTime<Utc>,
+    pub hsh: Option<String>,
+    pub verified_ts: Option<DateTime<Utc>>,
+    pub file_size: Option<i64>,
+    pub generation: i64,
+    /// A short label given to the version, ie. "before reformatting"
+    pub label: Option<String>,
+    /// Whether the version is a marker of the file being excluded by policy, rather than deleted
+    pub excluded: bool
+}
+
+impl FileModel {
+    ///
+    /// Whether the file version was stored in a format this application understands,
+    /// rather than by a newer one
+    /// 
+    pub fn is_format_supported(&self) -> bool {
+        self.version <= CURRENT_VERSION
+    }
+
+    ///
+    /// Whether the file version is a marker of the file being deleted, rather than a stored version.
+    /// Markers of a file being excluded by policy aren't tombstones, since the file still exists
+    /// 
+    pub fn is_tombstone(&self) -> bool {
+        self.hsh.is_none() && !self.excluded
+    }
 }
 
+///
+/// A dir which held no files to back up in the latest run
+/// 
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EmptyDirModel {
+    pub id: i64,
+    pub permissions: Option<i64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct DirModel {
     pub id: i64,
     pub parent_dir_id: Option<i64>,
     pub dir_name: String,
-}
\ No newline at end of file
+}
+
+///
+/// The full path of a dir in the catalog, joined from the names of the dirs leading to it
+/// 
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct FullPath(PathBuf);
+
+impl FullPath {
+    ///
+    /// Joins the names of the dirs in `chain`, which runs from a dir up through each of its 
+    /// parents to a root dir. Returns `None` if the chain is empty, doesn't end at a root dir,
+    /// or has a dir which isn't the parent of the one before it
+    /// 
+    pub fn from_dir_chain(chain: &[DirModel]) -> Option<Self> {
+        let linked = chain.windows(2).all(|pair| pair[0].parent_dir_id == Some(pair[1].id));
+        if !linked || chain.last()?.parent_dir_id.is_some() {
+            return None;
+        }
+
+        Some(Self(chain.iter().rev().map(|dir| dir.dir_name.as_str()).collect()))
+    }
+
+    ///
+    /// Gets the full path of the file named `file_name` in the dir
+    /// 
+    pub fn join(&self, file_name: &str) -> PathBuf {
+        self.0.join(file_name)
+    }
+
+    pub fn as_path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl From<FullPath> for PathBuf {
+    fn from(value: FullPath) -> Self {
+        value.0
+    }
+}
+
+impl Display for FullPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.display())
+    }
+}
+
+///
+/// A file's history, summarized and located by its full path
+/// 
+#[derive(Clone, Debug)]
+pub struct FileWithPath {
+    pub dir_id: i64,
+    pub file_name: String,
+    pub full_path: String,
+    pub latest_backup_ts: DateTime<Utc>,
+    pub version_count: i64
+}
+
+///
+/// The latest version of a file which hasn't been deleted, located by its full path
+/// 
+#[derive(Clone, Debug)]
+pub struct LatestFileEntry {
+    pub full_path: String,
+    pub file_size: Option<i64>,
+    pub backup_ts: DateTime<Utc>
+}
+
+///
+/// The latest version backed up of a file, located by its directory, and whether the
+/// file has been deleted since
+/// 
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileSnapshotEntry {
+    pub dir_id: i64,
+    pub file_name: String,
+    pub latest_id: i64,
+    pub latest_ts: DateTime<Utc>,
+    pub latest_hsh: String,
+    /// Whether the file's latest version is a deletion marker, following this version
+    pub is_deleted: bool
+}
+
+///
+/// What's left out when marking files as deleted, because it couldn't be read 
+/// and so may still exist
+/// 
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeletionExclusions {
+    /// Dirs whose files aren't marked, listing every dir beneath them as well
+    pub dir_ids: Vec<i64>,
+    /// Files which aren't marked, by their dir ID and name
+    pub files: HashSet<(i64, String)>
+}
+
+impl DeletionExclusions {
+    pub fn is_empty(&self) -> bool {
+        self.dir_ids.is_empty() && self.files.is_empty()
+    }
+}
+
+///
+/// How a file changed between two points in the backup history
+/// 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ChangeType {
+    Added,
+    Modified,
+    Deleted,
+    Unchanged
+}
+
+impl ChangeType {
+    ///
+    /// Classifies a change by the hash of the file's latest version `before` and `after`,
+    /// where `None` means it didn't exist, or was deleted
+    /// 
+    pub fn between(before: Option<&str>, after: Option<&str>) -> Option<Self> {
+        match (before, after) {
+            (None, None) => None,
+            (None, Some(_)) => Some(ChangeType::Added),
+            (Some(_), None) => Some(ChangeType::Deleted),
+            (Some(before), Some(after)) if before != after => Some(ChangeType::Modified),
+            (Some(_), Some(_)) => Some(ChangeType::Unchanged)
+        }
+    }
+}
+
+///
+/// A file which existed at one of two points in the backup history, and how it changed between them
+/// 
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileDiffEntry {
+    pub dir_id: i64,
+    pub file_name: String,
+    pub change_type: ChangeType
+}
+
+///
+/// Where a file is in the catalog, by its directory and name
+/// 
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FileLocation {
+    pub dir_id: i64,
+    pub file_name: String
+}
+
+///
+/// How the backed up files changed from the end of one run to the end of another,
+/// with each file located by `L`
+/// 
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunDiff<L = FileLocation> {
+    pub added: Vec<L>,
+    /// Each file with the hash it had after the first run, then after the second
+    pub modified: Vec<(L, String, String)>,
+    pub deleted: Vec<L>
+}
+
+///
+/// Which version of a file to use
+/// 
+#[derive(Clone, Debug, PartialEq)]
+pub enum VersionSelector {
+    /// The version with this ID, as listed by `list`
+    Id(i64),
+    /// The version which was the latest at this instant
+    Instant(DateTime<Utc>),
+    /// The version with this label
+    Label(String)
+}
+
+///
+/// A file version whose hash is shared with another file version of a different size
+/// 
+#[derive(Clone, Debug)]
+pub struct HashCollisionEntry {
+    pub id: i64,
+    pub dir_id: i64,
+    pub file_name: String,
+    pub hsh: String,
+    pub file_size: i64
+}
+
+///
+/// A file, not deleted in its latest version, sharing its directory with another
+/// whose name differs only in case
+/// 
+#[derive(Clone, Debug, PartialEq)]
+pub struct CaseCollisionEntry {
+    pub dir_id: i64,
+    pub file_name: String
+}
+
+///
+/// A copy of a file version stored in a backup destination
+/// 
+#[derive(Clone, Debug)]
+pub struct BackupModel {
+    pub id: i64,
+    pub file_id: i64,
+    pub destination: String,
+    pub backend: String,
+    pub backend_key: String,
+    pub backup_ts: DateTime<Utc>,
+    pub compressed_size: Option<i64>,
+    pub delta_base_id: Option<i64>
+}
+
+///
+/// A single content-addressed chunk of a chunked file version
+/// 
+#[derive(Clone, Debug)]
+pub struct ChunkModel {
+    pub hsh: String,
+    pub chunk_size: i64
+}
+
+///
+/// A single run of the backup process
+/// 
+#[derive(Clone, Debug)]
+pub struct RunModel {
+    pub id: i64,
+    pub started_at: DateTime<Utc>,
+    pub completed_at: Option<DateTime<Utc>>,
+    pub files_scanned: Option<i64>,
+    pub bytes_backed_up: Option<i64>,
+    pub config_snapshot: Option<String>,
+    pub status: RunStatus,
+    pub files_backed_up: Option<i64>,
+    /// The number of files examined which didn't need backing up
+    pub files_skipped: Option<i64>,
+    /// The message of every error met during the run
+    pub errors: Vec<String>,
+    /// What started the run, and why
+    pub origin: RunOrigin
+}
+
+///
+/// What started a backup run
+/// 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RunTrigger {
+    /// The run was started by hand
+    #[default]
+    Manual,
+    /// The run was started by a scheduler, such as cron or a systemd timer
+    Scheduled,
+    /// The run backed up files as change events for them arrived
+    Watch,
+    /// The run backed up a given list of paths instead of every file matching
+    /// the configured globs, so deleted files weren't marked
+    AdHoc
+}
+
+impl RunTrigger {
+    ///
+    /// The name of the trigger, as it's recorded in the catalog
+    /// 
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RunTrigger::Manual => "manual",
+            RunTrigger::Scheduled => "scheduled",
+            RunTrigger::Watch => "watch",
+            RunTrigger::AdHoc => "ad_hoc",
+        }
+    }
+    ///
+    /// Reads a trigger recorded in the catalog. Anything unrecognized is taken as `Manual`
+    /// 
+    pub fn parse(trigger: &str) -> Self {
+        match trigger {
+            "scheduled" => RunTrigger::Scheduled,
+            "watch" => RunTrigger::Watch,
+            "ad_hoc" => RunTrigger::AdHoc,
+            _ => RunTrigger::Manual
+        }
+    }
+}
+
+///
+/// What started a backup run, set by the entry point which started it, 
+/// with the reason given for it, if any
+/// 
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RunOrigin {
+    pub trigger: RunTrigger,
+    pub reason: Option<String>
+}
+
+///
+/// How far a backup run got
+/// 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunStatus {
+    /// The run is still running, or never finished
+    Running,
+    /// The run examined every file
+    Completed,
+    /// The run stopped at its deadline before examining every file, so deleted files weren't marked
+    Partial
+}
+
+impl RunStatus {
+    ///
+    /// The name of the status, as it's recorded in the catalog
+    /// 
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RunStatus::Running => "running",
+            RunStatus::Completed => "completed",
+            RunStatus::Partial => "partial",
+        }
+    }
+    ///
+    /// Reads a status recorded in the catalog. Anything unrecognized is taken as `Running`,
+    /// as the run can't be known to have finished.
+    /// 
+    pub fn parse(status: &str) -> Self {
+        match status {
+            "completed" => RunStatus::Completed,
+            "partial" => RunStatus::Partial,
+            _ => RunStatus::Running
+        }
+    }
+}
+
+///
+/// What a backup run did, recorded once it finishes
+/// 
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RunStats {
+    pub files_scanned: u64,
+    pub files_backed_up: u64,
+    /// The number of files examined which didn't need backing up
+    pub files_skipped: u64,
+    pub bytes_backed_up: u64,
+    /// The message of every error met during the run
+    pub errors: Vec<String>,
+    /// Whether the run stopped at its deadline before examining every file
+    pub partial: bool
+}
+
+///
+/// A backup found to be unreadable or corrupt by `verify`
+/// 
+#[derive(Clone, Debug, PartialEq)]
+pub struct VerifyFailureModel {
+    pub file_id: i64,
+    pub reason: String,
+    pub found_at: DateTime<Utc>
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use chrono::{TimeZone, Utc};
+
+    use super::{DirModel, FileModel, FullPath, CURRENT_VERSION};
+
+    fn file(hsh: Option<&str>, excluded: bool) -> FileModel {
+        FileModel {
+            version: CURRENT_VERSION, id: 7, file_name: "notes.txt".to_string(), 
+            backup_ts: Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap(), hsh: hsh.map(str::to_string), 
+            verified_ts: None, file_size: hsh.map(|_| 42), generation: 1, label: Some("before reformatting".to_string()), excluded
+        }
+    }
+
+    fn dir(id: i64, parent_dir_id: Option<i64>, dir_name: &str) -> DirModel {
+        DirModel { id, parent_dir_id, dir_name: dir_name.to_string() }
+    }
+
+    #[test]
+    fn test_models_round_trip_through_serde() {
+        let file = file(Some("hash"), false);
+        let json = serde_json::to_string(&file).unwrap();
+        assert_eq!(serde_json::from_str::<FileModel>(&json).unwrap(), file);
+
+        let dirs = vec![dir(3, Some(1), "home"), dir(1, None, "/")];
+        let json = serde_json::to_string(&dirs).unwrap();
+        assert_eq!(serde_json::from_str::<Vec<DirModel>>(&json).unwrap(), dirs);
+    }
+
+    #[test]
+    fn test_is_tombstone() {
+        assert!(!file(Some("hash"), false).is_tombstone());
+        assert!(file(None, false).is_tombstone());
+        // The file still exists, only left out by policy
+        assert!(!file(None, true).is_tombstone());
+    }
+
+    #[test]
+    fn test_full_path_from_dir_chain() {
+        let chain = [dir(5, Some(3), "docs"), dir(3, Some(1), "home"), dir(1, None, "/")];
+        let full_path = FullPath::from_dir_chain(&chain).unwrap();
+        assert_eq!(full_path.to_string(), PathBuf::from("/home/docs").display().to_string());
+        assert_eq!(full_path.join("notes.txt"), PathBuf::from("/home/docs/notes.txt"));
+
+        assert_eq!(FullPath::from_dir_chain(&[]), None);
+        // Broken at the middle, and not reaching a root
+        assert_eq!(FullPath::from_dir_chain(&[dir(5, Some(2), "docs"), dir(1, None, "/")]), None);
+        assert_eq!(FullPath::from_dir_chain(&chain[..2]), None);
+    }
+}
@@ -5,4 +5,17 @@ pub mod file_svc;
 pub mod hash_svc;
 pub mod time_provider;
 pub mod backup_service;
-pub mod config;
\ No newline at end of file
+pub mod config;
+pub mod cli;
+pub mod mount;
+pub mod estimate;
+pub mod failed_files;
+pub mod summary;
+pub mod path_map;
+pub mod status;
+pub mod verify;
+pub mod watch;
+pub mod bench;
+pub mod pipeline;
+pub mod version_diff;
+pub mod seed;
\ No newline at end of file
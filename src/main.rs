@@ -1,40 +1,1137 @@
-use std::env;
+use std::{env, fmt::Display, io::{IsTerminal, Read}, path::{Path, PathBuf}, str::FromStr, sync::{Arc, OnceLock}};
 
-use drive_backup::{backup_service::{BackupService, FileBackupService}, config::Config, file_svc::get_glob_files, hash_svc::gen_hashes, history_service::{self, FileHistoryService, FileStatus, HistoryService}, time_provider::CoreTimeProvider};
+use chrono::{DateTime, Duration, Utc};
+use clap::Parser;
+use drive_backup::{bench::{measure, render_table, BenchResult, SyntheticTree}, backup_service::{delta, error::Error as BackupErrorKind, factory::backup_service_builder, routed::{Route, RoutedBackupService}, BackupService, FileBackupService}, cli::{formatter::ColoredStatusFormatter, picker::pick_version, BackupArgs, BenchArgs, Cli, Command, MaintenanceState}, collections::GroupBy, config::{self, policy::{resolve_policy, PolicyMatch}, Config, Durability, HashAlgorithm}, estimate::{format_bytes, format_count, format_duration, format_timestamp, TimestampStyle}, file_svc::{is_network_filesystem, long_path::long_path, normalize_path, probe_case_sensitivity, read_path_list, set_permissions, FileScanner}, hash_svc::{checksums::{check_checksums, encode_hash, format_checksum_line, ChecksumMismatch}, algorithm_of, gen_hashes_buffered, hash_reader}, path_map::{disambiguate_case_collisions, PathMap, PathMapper}, pipeline::{delete_backup_keeping_dependents, describe_unscanned, error::Error as PipelineError, BackupPipelineBuilder, RunOptions, RunReport}, history_service::{compact::compact_catalog, error::Error as HistoryError, data_layer::DbDataLayer, catalog_cache::CatalogCheckout, lock::CatalogLock, models::{ChangeType, ChunkModel, RunModel, RunStatus, RunTrigger, VersionSelector}, FileHistoryService, FileStatus, HistoryService}, status::{classify, BackupStats, ScannedFile}, time_provider::CoreTimeProvider, verify::verify_backups, version_diff::VersionDiff};
 use futures_util::{pin_mut, StreamExt};
-use lazy_static::lazy_static;
-use sqlx::sqlite::SqlitePoolOptions;
+use sqlx::{sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous}, Executor, SqlitePool};
+use tokio_util::sync::CancellationToken;
 
-lazy_static! {
-    static ref CONFIG: Config = 
-        serde_json::from_str(&std::fs::read_to_string("config.json").unwrap())
-            .unwrap();
+///
+/// How long a catalog on a network filesystem waits for another process's lock before failing
+/// 
+const NETWORK_CATALOG_BUSY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(120);
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+static TIMESTAMP_STYLE: OnceLock<TimestampStyle> = OnceLock::new();
+
+///
+/// Gets the config, loading it from `config.json` the first time it's needed
+/// 
+fn config() -> &'static Config {
+    CONFIG.get_or_init(|| config::load_with_migration(Path::new("config.json")).unwrap())
+}
+
+///
+/// Formats an `instant` from the catalog as every command shows timestamps, per `--utc`
+/// 
+fn format_ts(instant: &DateTime<Utc>) -> String {
+    format_timestamp(instant, TIMESTAMP_STYLE.get().copied().unwrap_or_default())
 }
 
 #[tokio::main]
 async fn main() {
     dotenvy::dotenv().ok();
-    let paths = get_glob_files(CONFIG.backup_globs.clone().into_iter());
-    let hashes = gen_hashes(paths); 
+    tracing_subscriber::fmt::init();
+    let cli = Cli::parse();
+    let formatter = ColoredStatusFormatter::new(cli.no_color, cli.ascii);
+    TIMESTAMP_STYLE.set(if cli.utc { TimestampStyle::Utc } else { TimestampStyle::Local }).unwrap();
 
-    let db = SqlitePoolOptions::new().connect(&env::var("DATABASE_URL").unwrap()).await.unwrap();
+    let database_url = env::var("DATABASE_URL").unwrap();
+    let mut connect_options = SqliteConnectOptions::from_str(&database_url).unwrap();
+    let mut pool_options = SqlitePoolOptions::new();
+    let remote_catalog_path = connect_options.clone().get_filename().to_path_buf();
+    let checkout = match config().catalog_cache {
+        true => {
+            let cache_dir = config().catalog_cache_dir.clone().unwrap_or_else(|| env::temp_dir().join("drive_backup_catalog"));
+            let checkout = CatalogCheckout::checkout(&remote_catalog_path, &cache_dir).unwrap_or_else(|e| {
+                eprintln!("Could not copy the catalog: {}", e);
+                std::process::exit(2);
+            });
+            connect_options = connect_options.filename(checkout.local_path());
+            Some(checkout)
+        },
+        false => {
+            let catalog_dir = remote_catalog_path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            if is_network_filesystem(catalog_dir).unwrap_or(false) {
+                tracing::warn!(
+                    "The catalog {} is on a network filesystem, where sqlite's locking is unreliable, so it's used by one \
+                    connection at a time and waits longer for locks. Set \"catalog_cache\": true in the config to work \
+                    against a local copy instead.", remote_catalog_path.display()
+                );
+                // A write-ahead log needs memory shared between processes, which network filesystems can't provide
+                connect_options = connect_options.journal_mode(SqliteJournalMode::Delete).busy_timeout(NETWORK_CATALOG_BUSY_TIMEOUT);
+                pool_options = pool_options.max_connections(1);
+            }
+            None
+        }
+    };
+    let catalog_path = connect_options.clone().get_filename().to_path_buf();
+    if config().durability == Durability::Safe {
+        connect_options = connect_options.synchronous(SqliteSynchronous::Full);
+    }
+    let db = pool_options.connect_with(connect_options).await.unwrap();
     let time_provider = CoreTimeProvider::new();
 
-    let data_layer = history_service::data_layer::DbDataLayer::new(&db);
-    let mut cache_svc = FileHistoryService::new(&data_layer, &time_provider, CONFIG.max_copies).await.unwrap();
+    let data_layer = Arc::new(DbDataLayer::new(&db));
+    data_layer.migrate_schema().await.unwrap();
+    data_layer.migrate_utc_timestamps().await.unwrap();
+    data_layer.migrate_file_id_sequence().await.unwrap();
+    data_layer.migrate_is_latest().await.unwrap();
+    let mut cache_svc = FileHistoryService::new(
+        data_layer.clone(), Arc::new(time_provider), config().max_copies, config().canonicalize
+    ).await.unwrap();
 
-    let mut backup_service = FileBackupService::new(CONFIG.backup_path.to_string());
+    let mut backup_service = routed_backup_service(config());
+
+    match cli.command.unwrap_or(Command::Backup(BackupArgs::default())) {
+        Command::Backup(args) => {
+            let _lock = lock_catalog(&catalog_path);
+            run_backup(&cache_svc, data_layer, backup_service, args, None, &formatter).await
+        },
+        Command::Seed { from, args } => {
+            let _lock = lock_catalog(&catalog_path);
+            run_backup(&cache_svc, data_layer, backup_service, args, Some(&from), &formatter).await
+        },
+        Command::Status { disk_usage } => status(&cache_svc, &backup_service, disk_usage).await,
+        Command::ListRuns { limit } => list_runs(&cache_svc, limit).await,
+        Command::ShowRun { id, config } => show_run(&cache_svc, id, config).await,
+        Command::Import { register, id, hash } => import(&mut cache_svc, &backup_service, &register, id, &hash).await,
+        Command::VerifyStale { count, older_than_days } => 
+            verify_stale(&cache_svc, &backup_service, count, Duration::days(older_than_days)).await,
+        Command::Verify { resume, concurrency } => verify(&cache_svc, &backup_service, resume, concurrency).await,
+        Command::Check { fix } => check(&cache_svc, &mut backup_service, fix).await,
+        Command::Rehash { algorithm } => rehash(&cache_svc, &backup_service, algorithm).await,
+        Command::Diff { run_a: Some(run_a), run_b: Some(run_b), .. } => diff_runs(&cache_svc, run_a, run_b).await,
+        Command::Diff { path: Some(path), from, to, stat, .. } => 
+            diff_file(&cache_svc, &backup_service, &path, &from.unwrap(), &to.unwrap(), stat, formatter.uses_color()).await,
+        Command::Diff { from: Some(VersionSelector::Instant(from)), to: Some(VersionSelector::Instant(to)), .. } => 
+            diff(&cache_svc, from, to).await,
+        Command::Diff { .. } => {
+            eprintln!("Without a path, --from and --to must be dates or timestamps");
+            std::process::exit(2);
+        },
+        Command::PrintHashes { path } => print_hashes(&cache_svc, &path).await,
+        Command::ImportHashes { checksums } => import_hashes(&cache_svc, &checksums).await,
+        Command::Search { query } => search(&cache_svc, &query).await,
+        Command::List { query } => list(&cache_svc, &query).await,
+        Command::Label { path, label, version } => label_version(&cache_svc, &path, version, &label).await,
+        Command::Restore { query, root, maps, interactive: true, .. } => 
+            restore_interactively(&cache_svc, &backup_service, &query, root.as_deref(), maps).await,
+        Command::Restore { query, run, label, root, maps, .. } => {
+            // Required by the parser unless the restore is interactive
+            let root = root.unwrap();
+            let run = match run {
+                Some(reason) => Some(find_run_by_reason(&cache_svc, &reason).await),
+                None => None
+            };
+            restore(&cache_svc, &backup_service, &query, run.as_ref(), label.as_deref(), &root, maps).await
+        },
+        Command::CollisionAudit => collision_audit(&cache_svc).await,
+        Command::Rebase => rebase(&cache_svc, &mut backup_service).await,
+        Command::Maintenance { state: Some(MaintenanceState::On), note } => 
+            unwrap_backup(backup_service.enter_maintenance(&note).await),
+        Command::Maintenance { state: Some(MaintenanceState::Off), .. } => 
+            unwrap_backup(backup_service.leave_maintenance().await),
+        Command::Maintenance { state: None, .. } => maintain_catalog(&cache_svc).await,
+        Command::Doctor => doctor(&cache_svc, &backup_service).await,
+        Command::ExplainPolicy { path } => explain_policy(&path),
+        Command::Bench(args) => bench(args).await,
+        Command::Compact => compact(&cache_svc, &mut backup_service, &db, &catalog_path).await,
+        #[cfg(all(feature = "mount", unix))]
+        Command::Mount { mountpoint } => 
+            drive_backup::mount::mount(
+                db.clone(), unwrap_backup(backup_service_builder(&config().default_destination())
+                    .and_then(|builder| builder.backup_xattrs(config().xattr_backup.unwrap_or(false)).read_only().build())), &mountpoint
+            ).await.unwrap(),
+    }
+
+    if let Some(checkout) = checkout {
+        db.close().await;
+        match checkout.checkin() {
+            Ok(Some(generation)) => tracing::info!("Uploaded the catalog as generation {}", generation),
+            Ok(None) => { },
+            Err(e) => {
+                eprintln!("Could not upload the catalog: {}", e);
+                std::process::exit(2);
+            }
+        }
+    }
+}
 
+///
+/// Generates a synthetic tree in a temporary directory, then times hashing it, and backing it
+/// up to a fresh catalog and destination, under each configuration given in `args`.
+/// Every configuration processes the same files, which were just written, so all of
+/// them read the files from the OS's cache rather than the disk.
+/// 
+async fn bench(args: BenchArgs) {
+    let work_dir = env::temp_dir().join(format!("drive_backup_bench_{}", std::process::id()));
+    let tree = SyntheticTree {
+        file_count: args.files, min_file_size: args.min_size, max_file_size: args.max_size,
+        compressible_fraction: args.compressible, seed: args.seed, ..Default::default()
+    };
+    let files = tree.generate(&work_dir.join("tree")).unwrap();
+    let total_bytes = files.iter().map(|(_, size)| size).sum::<u64>();
+    println!("Generated {} files totalling {} with seed {}", format_count(files.len() as u64), format_bytes(total_bytes), args.seed);
+
+    let mut results = Vec::new();
+    for &concurrency in &args.concurrency {
+        let hashes = gen_hashes_buffered(files.iter().map(|(path, _)| (path.clone(), None)), concurrency, config().hash_algorithm);
+        let (hashed, wall, cpu) = measure(hashes.map(Result::unwrap).count()).await;
+        results.push(BenchResult { name: format!("hash x{}", concurrency), files: hashed as u64, bytes: total_bytes, wall, cpu });
+    }
+    for &concurrency in &args.concurrency {
+        for chunk_size in std::iter::once(None).chain(args.chunk_size.iter().copied().map(Some)) {
+            let case_dir = work_dir.join(format!("case_{}", results.len()));
+            let ((backed_up, bytes), wall, cpu) = measure(bench_pipeline(&files, &case_dir, concurrency, chunk_size)).await;
+            let name = match chunk_size {
+                Some(chunk_size) => format!("backup x{} chunks {}", concurrency, format_bytes(chunk_size as u64)),
+                None => format!("backup x{} whole", concurrency)
+            };
+            results.push(BenchResult { name, files: backed_up, bytes, wall, cpu });
+            std::fs::remove_dir_all(&case_dir).unwrap();
+        }
+    }
+
+    std::fs::remove_dir_all(&work_dir).unwrap();
+    print!("{}", render_table(&results));
+}
+
+///
+/// Hashes `files`, hashing `concurrency` at once, and backs each up to a new catalog and
+/// destination created under `dir`, in chunks of `chunk_size` if given or whole otherwise.
+/// Returns the number and total size of the files backed up.
+/// 
+async fn bench_pipeline(files: &[(PathBuf, u64)], dir: &Path, concurrency: usize, chunk_size: Option<usize>) -> (u64, u64) {
+    std::fs::create_dir_all(dir).unwrap();
+    let db = SqlitePoolOptions::new()
+        .connect_with(SqliteConnectOptions::new().filename(dir.join("catalog.db")).create_if_missing(true)).await.unwrap();
+    db.execute(include_str!("../sql/create.sql")).await.unwrap();
+    let data_layer = Arc::new(DbDataLayer::new(&db));
+    let time_provider = Arc::new(CoreTimeProvider::new());
+    let mut cache_svc = FileHistoryService::new(data_layer, time_provider, config().max_copies, config().canonicalize).await.unwrap();
+    let mut backup_service = FileBackupService::new(dir.join("destination").to_string_lossy().to_string()).unwrap();
+
+    let (mut backed_up, mut bytes) = (0, 0);
+    // Files are hashed ahead of the backups, up to `concurrency` at a time, as they are by a backup run
+    let hashes = gen_hashes_buffered(files.iter().map(|(path, _)| (path.clone(), None)), concurrency, config().hash_algorithm);
     pin_mut!(hashes);
-    while let Some(Ok((path, hsh))) = hashes.next().await {
-        let status = cache_svc.get_file_status(&path, &hsh).await.unwrap();
-        if let FileStatus::NeedsBackup { sub_dir_id, file_id, file_name } = status {
-            backup_service.backup_data(file_id, &path).await.unwrap();
-            if let Some(id) = cache_svc.create_file_entry(sub_dir_id, file_id, &file_name, &hsh).await.unwrap() {
-                backup_service.delete_backup(id).await.unwrap();
+    while let Some(hashed) = hashes.next().await {
+        let (path, hsh, size) = hashed.unwrap();
+        let FileStatus::NeedsBackup { sub_dir_id, file_id, file_name, .. } = cache_svc.get_file_status(&path, &hsh, size).await.unwrap() 
+            else { continue };
+        let file_name = file_name.to_string();
+        let chunks = match chunk_size {
+            Some(chunk_size) => Some(unwrap_backup(backup_service.backup_chunked(file_id, &path, chunk_size).await)),
+            None => {
+                unwrap_backup(backup_service.backup_data(file_id, &path).await);
+                None
+            }
+        };
+        cache_svc.create_file_entry(sub_dir_id, file_id, &file_name, &hsh, size).await.unwrap();
+        if let Some(chunks) = chunks {
+            let chunks: Vec<ChunkModel> = chunks.into_iter()
+                .map(|c| ChunkModel { hsh: c.hsh, chunk_size: c.size as i64 })
+                .collect();
+            cache_svc.record_file_chunks(file_id, &chunks).await.unwrap();
+        }
+        backed_up += 1;
+        bytes += size;
+    }
+
+    db.close().await;
+    (backed_up, bytes)
+}
+
+///
+/// Creates the service backing files up to every configured destination, following the configured routes
+/// 
+fn routed_backup_service(config: &Config) -> RoutedBackupService {
+    if config.backup_destination.is_none() {
+        tracing::warn!("backup_path is deprecated, set backup_destination to {{ \"type\": \"local\", \"path\": ... }} instead");
+    }
+    let xattr_backup = config.xattr_backup.unwrap_or(false);
+    let destinations = config.destinations().into_iter()
+        .map(|(name, destination)| {
+            let backup_service = backup_service_builder(&destination).and_then(|builder| builder
+                .backup_xattrs(xattr_backup).durability(config.durability).temp_dir(config.temp_dir.clone())
+                .temp_dir_max_bytes(config.temp_dir_max_bytes)
+                .skip_compression_extensions(&config.skip_compression_extensions).build());
+            (name, unwrap_backup(backup_service))
+        })
+        .collect();
+    let routes = config.routes.iter().map(|route| Route {
+        patterns: route.globs.iter().filter_map(|ptn| glob::Pattern::new(ptn)
+            .map_err(|e| tracing::warn!("Ignoring the route glob {}: {}", ptn, e)).ok()).collect(),
+        destinations: route.destinations.clone()
+    }).collect();
+
+    RoutedBackupService::new(destinations, routes)
+}
+
+///
+/// Backs up every file matching the configured globs which has changed since its last backup,
+/// then prints a summary of the run. Given a `mirror_root`, each file whose copy in the mirror 
+/// has the same size and modification time is read from the copy instead
+/// 
+async fn run_backup(
+    cache_svc: &dyn HistoryService, 
+    data_layer: Arc<DbDataLayer>,
+    backup_service: RoutedBackupService, 
+    args: BackupArgs, 
+    mirror_root: Option<&Path>, 
+    formatter: &ColoredStatusFormatter
+) {
+    let syncs_writes = backup_service.syncs_writes();
+    let mut pipeline = unwrap_pipeline(BackupPipelineBuilder::new()
+        .with_data_layer(data_layer)
+        .with_backup_service(Box::new(backup_service))
+        .with_config(config().clone())
+        .with_formatter(formatter.clone())
+        .build().await);
+    let listed_paths = args.paths_from.as_ref().map(|list| read_listed_paths(list, args.null));
+    let ad_hoc = listed_paths.is_some();
+    if args.dry_run {
+        let summary = unwrap_pipeline(pipeline.dry_run(listed_paths).await);
+        print!("{}", summary.render(args.depth, args.verbose));
+        println!("This was a dry run: files were judged by their size and modification time, and nothing was backed up");
+        return;
+    }
+
+    let options = RunOptions { origin: args.origin(), listed_paths, mirror_root: mirror_root.map(Path::to_path_buf), verbose: args.verbose };
+    let RunReport { stats, summary, time_boxed, lost_destination, exclusion_counts, .. } = unwrap_pipeline(pipeline.run_with(options).await);
+    warn_case_collisions(cache_svc).await;
+
+    print!("{}", summary.render(args.depth, args.verbose));
+    for (filter, count) in exclusion_counts.into_iter().filter(|(_, count)| *count > 0) {
+        println!("Excluded by {}: {}", filter, format_count(count));
+    }
+    if ad_hoc {
+        println!("Only the listed paths were backed up, so no files were marked as deleted");
+    }
+    if syncs_writes {
+        println!("Every backup was synced to disk before it was recorded in the catalog");
+    }
+    if let Some(max_run_duration) = time_boxed {
+        let examined = summary.totals().examined();
+        println!(
+            "The run was time-boxed: it stopped after {} with {} of {} files examined. \
+            The rest are examined first by the next run, and deleted files weren't marked.",
+            format_duration(max_run_duration), format_count(examined), format_count(stats.files_scanned)
+        );
+    }
+    if let Some(destination) = lost_destination {
+        eprintln!(
+            "The backup destination {} became unavailable, so the run was stopped after backing up {} files. \
+            Was its drive unplugged? The rest are backed up by the next run once it's available again, \
+            and deleted files weren't marked.",
+            destination.display(), format_count(stats.files_backed_up)
+        );
+        std::process::exit(1);
+    }
+}
+
+///
+/// Stores every delta backup more than the configured `max_chain_length` deltas
+/// behind a full copy in full, or every delta backup if deltas aren't configured
+/// 
+async fn rebase(cache_svc: &dyn HistoryService, backup_service: &mut impl BackupService) {
+    let max_chain_length = config().delta.as_ref().map_or(0, |delta| delta.max_chain_length);
+    let deltas = cache_svc.get_delta_backups().await.unwrap();
+    for file_id in delta::plan_rebase(&deltas, max_chain_length) {
+        unwrap_backup(backup_service.materialize(file_id).await);
+        cache_svc.clear_delta_base(file_id).await.unwrap();
+        println!("Stored the backup with id {} in full", file_id);
+    }
+}
+
+///
+/// Prints the files which appear to need backing up, without hashing them, and exits
+/// with 0 if there are none, 1 if there are any, or 2 if they couldn't be found
+/// 
+async fn status(cache_svc: &dyn HistoryService, backup_service: &RoutedBackupService, disk_usage: bool) {
+    let report = async {
+        let scanned: Vec<ScannedFile> = FileScanner::from_config(config()).scan_with_metadata().map_err(|e| format!("{:?}", e))?
+            .filter_map(|file| file.map_err(|e| tracing::warn!("{}", describe_unscanned(&e))).ok())
+            .filter_map(|(path, metadata)| ScannedFile::from_metadata(path, &metadata).ok())
+            .collect();
+        let catalog = cache_svc.get_latest_files().await.map_err(|e| format!("{:?}", e))?;
+        let last_run = cache_svc.get_recent_runs(1).await.map_err(|e| format!("{:?}", e))?;
+        let stats = BackupStats {
+            total_backup_size_bytes: cache_svc.total_backup_size_bytes().await.map_err(|e| format!("{:?}", e))?,
+            disk_usage_bytes: match disk_usage {
+                true => Some(backup_service.calculate_total_size().await.map_err(|e| e.to_string())?),
+                false => None
+            }
+        };
+        Ok::<_, String>((classify(scanned.into_iter(), catalog), last_run, stats))
+    }.await;
+
+    match report {
+        Ok((report, last_run, stats)) => {
+            println!("{}", report);
+            println!("{}", stats);
+            match last_run.first().and_then(|run| run.completed_at) {
+                Some(completed_at) => println!("Last successful run: {}", format_ts(&completed_at)),
+                None => println!("Last successful run: never"),
+            }
+            println!("These are guesses from file sizes and modification times; a backup may find some files unchanged.");
+            std::process::exit(if report.is_pending() { 1 } else { 0 });
+        },
+        Err(e) => {
+            eprintln!("Could not check the status of the backup: {}", e);
+            std::process::exit(2);
+        }
+    }
+}
+
+///
+/// Registers the backup with the given `file_id`, made outside of the tool, as a backup 
+/// of the file at `path`, after checking its contents have the given `hsh`
+/// 
+async fn import(cache_svc: &mut dyn HistoryService, backup_service: &RoutedBackupService, path: &Path, file_id: i64, hsh: &str) {
+    let reader = unwrap_backup(backup_service.open_backup(file_id).await);
+    let algorithm = algorithm_of(hsh).unwrap_or(config().hash_algorithm);
+    let (found_hsh, size) = tokio::task::spawn_blocking(move || hash_reader(reader, algorithm)).await.unwrap().unwrap();
+    if found_hsh != hsh {
+        eprintln!("The backup with id {} has the hash {}, not {}", file_id, found_hsh, hsh);
+        std::process::exit(1);
+    }
+
+    // The file may no longer exist to be normalized
+    let path = normalize_path(path, config().canonicalize).unwrap_or_else(|_| path.to_path_buf());
+    cache_svc.register_existing_backup(&path, hsh, size, file_id, Utc::now()).await.unwrap();
+    for (destination, stored) in unwrap_backup(backup_service.describe_copies(file_id).await) {
+        cache_svc.record_backup(file_id, &destination, stored.backend, &stored.key, stored.compressed_size, stored.delta_base_id).await.unwrap();
+    }
+
+    println!("Registered the backup with id {} as {}", file_id, path.display());
+}
+
+///
+/// Prints up to `limit` of the most recently completed runs, newest first, with what started each
+/// 
+async fn list_runs(cache_svc: &dyn HistoryService, limit: u32) {
+    for run in cache_svc.get_recent_runs(limit).await.unwrap() {
+        println!(
+            "{}\t{}\t{}\t{}\t{}", run.id, format_ts(&run.started_at), run.status.as_str(), 
+            run.origin.trigger.as_str(), run.origin.reason.as_deref().unwrap_or("")
+        );
+    }
+}
+
+///
+/// Prints the run with the given `run_id`, and if `show_config`, the config it was made
+/// with along with every field changed since
+/// 
+async fn show_run(cache_svc: &dyn HistoryService, run_id: i64, show_config: bool) {
+    let Some(run) = cache_svc.get_run(run_id).await.unwrap() else {
+        eprintln!("There is no run with id {}", run_id);
+        std::process::exit(1);
+    };
+
+    println!("Run {}", run.id);
+    println!("  Started:   {}", format_ts(&run.started_at));
+    match run.completed_at {
+        Some(completed_at) => println!("  Completed: {}", format_ts(&completed_at)),
+        None => println!("  Completed: never"),
+    }
+    println!("  Status: {}", run.status.as_str());
+    println!("  Trigger: {}", run.origin.trigger.as_str());
+    if let Some(reason) = &run.origin.reason {
+        println!("  Reason: {}", reason);
+    }
+    println!("  Files scanned: {}", run.files_scanned.unwrap_or(0));
+    println!("  Files backed up: {}", run.files_backed_up.unwrap_or(0));
+    println!("  Files unchanged: {}", run.files_skipped.unwrap_or(0));
+    println!("  Bytes backed up: {}", run.bytes_backed_up.unwrap_or(0));
+    if run.status == RunStatus::Partial {
+        println!("  Stopped at its deadline before examining every file");
+    }
+    if run.origin.trigger == RunTrigger::AdHoc {
+        println!("  Backed up a given list of paths, rather than every file matching the configured globs");
+    }
+    if !run.errors.is_empty() {
+        println!("  Errors:");
+        for error in &run.errors {
+            println!("    {}", error);
+        }
+    }
+    if !show_config {
+        return;
+    }
+
+    let Some(snapshot) = run.config_snapshot else {
+        println!("No config was recorded for this run");
+        return;
+    };
+    let snapshot: serde_json::Value = serde_json::from_str(&snapshot).unwrap();
+    println!("Config:\n{}", serde_json::to_string_pretty(&snapshot).unwrap());
+
+    let current: serde_json::Value = serde_json::from_str(&config().snapshot()).unwrap();
+    let changes = config::diff_snapshots(&snapshot, &current);
+    if changes.is_empty() {
+        println!("The config is unchanged since this run");
+    } else {
+        println!("Changed since this run:");
+    }
+    for change in changes {
+        let show = |value: Option<serde_json::Value>| value.map_or("(unset)".to_string(), |value| value.to_string());
+        println!("  {}: {} -> {}", change.field, show(change.old), show(change.new));
+    }
+}
+
+///
+/// Re-hashes the backups of up to `count` files not verified within `max_age`,
+/// comparing them against the hashes recorded when they were backed up
+/// 
+async fn verify_stale(cache_svc: &dyn HistoryService, backup_service: &RoutedBackupService, count: u32, max_age: Duration) {
+    let files = cache_svc.get_files_needing_reverification(max_age, count).await.unwrap();
+
+    for file in files {
+        let recorded = recorded_destinations(cache_svc, file.id).await;
+        let reader = match backup_service.open_recorded(file.id, &recorded).await {
+            Ok(reader) => reader,
+            Err(e) => {
+                println!("Could not open backup of {} (id={}): {:?}", file.file_name, file.id, e);
+                continue;
             }
+        };
+
+        let algorithm = file.hsh.as_deref().and_then(algorithm_of).unwrap_or_default();
+        match tokio::task::spawn_blocking(move || hash_reader(reader, algorithm)).await.unwrap() {
+            Ok((hsh, _)) if Some(&hsh) == file.hsh.as_ref() => cache_svc.mark_file_verified(file.id).await.unwrap(),
+            Ok((hsh, _)) => println!(
+                "Backup of {} (id={}) is corrupt: expected hash {}, found {}",
+                file.file_name, file.id, file.hsh.unwrap_or_default(), hsh
+            ),
+            Err(e) => println!("Could not read backup of {} (id={}): {:?}", file.file_name, file.id, e),
+        }
+    }
+}
+
+///
+/// Re-verifies the backup of every file version, stopping early on Ctrl-C so the run can be resumed,
+/// and prints every failure found. Exits with 1 if there are any.
+/// 
+async fn verify(cache_svc: &dyn HistoryService, backup_service: &RoutedBackupService, resume: bool, concurrency: usize) {
+    let cancel = CancellationToken::new();
+    let on_interrupt = cancel.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            on_interrupt.cancel();
+        }
+    });
+
+    let report = verify_backups(cache_svc, backup_service, resume, concurrency, &cancel).await.unwrap();
+
+    println!("Verified {} backups, skipped {} verified by an earlier run", format_count(report.verified), format_count(report.skipped));
+    for failure in &report.failures {
+        println!("Backup with id={} failed verification at {}: {}", failure.file_id, format_ts(&failure.found_at), failure.reason);
+    }
+    if report.cancelled {
+        println!("Verification was interrupted, run again with --resume to continue");
+    }
+    if !report.failures.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+///
+/// Prints every disagreement between the catalog and the destinations, and exits with 1 if 
+/// any are left. If `fix` is set, the missing and orphaned backups are resolved.
+/// 
+async fn check(cache_svc: &dyn HistoryService, backup_service: &mut RoutedBackupService, fix: bool) {
+    let report = cache_svc.check_consistency(backup_service).await.unwrap();
+    if report.is_consistent() {
+        println!("The catalog and the backups agree");
+        return;
+    }
+
+    for id in &report.missing_backup_files {
+        println!("Backup with id={} is in the catalog, but no destination holds it", id);
+    }
+    for id in &report.orphaned_backup_files {
+        println!("Backup with id={} is held by a destination, but isn't in the catalog", id);
+    }
+    for (id, expected, actual) in &report.hash_mismatches {
+        println!("Backup with id={} is corrupt: expected hash {}, found {}", id, expected, actual);
+    }
+    if fix {
+        if let Some(note) = unwrap_backup(backup_service.maintenance_note().await) {
+            exit_for_maintenance(&note);
+        }
+        cache_svc.repair_consistency(&report, backup_service).await.unwrap();
+        println!(
+            "Forgot {} missing and deleted {} orphaned backups, corrupt backups were left as they are",
+            report.missing_backup_files.len(), report.orphaned_backup_files.len()
+        );
+        if report.hash_mismatches.is_empty() {
+            return;
         }
     }
+    std::process::exit(1);
+}
+
+///
+/// Replaces the hash of every backed-up file version with one made with `algorithm`, printing 
+/// every version left as it was, and exits with 1 if there are any
+/// 
+async fn rehash(cache_svc: &dyn HistoryService, backup_service: &RoutedBackupService, algorithm: HashAlgorithm) {
+    let report = cache_svc.rehash(backup_service, algorithm).await.unwrap();
+
+    println!(
+        "Rehashed {} backups with {}, {} already were", 
+        format_count(report.rehashed), algorithm.as_str(), format_count(report.skipped)
+    );
+    for (id, reason) in &report.failures {
+        println!("Backup with id={} was left as it was: {}", id, reason);
+    }
+    if config().hash_algorithm != algorithm {
+        println!(
+            "Files are still hashed with {}. Set \"hash_algorithm\": \"{}\" in the config so they're hashed with {} too",
+            config().hash_algorithm.as_str(), algorithm.as_str(), algorithm.as_str()
+        );
+    }
+    if !report.failures.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+///
+/// Prints every file added, modified or deleted between `from` and `to`, one per line 
+/// prefixed by the kind of change, followed by the number of each
+/// 
+async fn diff(cache_svc: &dyn HistoryService, from: DateTime<Utc>, to: DateTime<Utc>) {
+    if from > to {
+        eprintln!("--from must be before --to");
+        std::process::exit(2);
+    }
+    let diff = cache_svc.diff_snapshots(from, to).await.unwrap();
+
+    for (path, change_type) in &diff {
+        let symbol = match change_type {
+            ChangeType::Added => "A",
+            ChangeType::Modified => "M",
+            ChangeType::Deleted => "D",
+            ChangeType::Unchanged => continue
+        };
+        println!("{}  {}", symbol, path.display());
+    }
+    let count = |change_type| format_count(diff.iter().filter(|(_, change)| *change == change_type).count() as u64);
+    println!(
+        "{} added, {} modified, {} deleted, {} unchanged",
+        count(ChangeType::Added), count(ChangeType::Modified), count(ChangeType::Deleted), count(ChangeType::Unchanged)
+    );
+}
+
+///
+/// Prints every file added, modified or deleted from the end of the run `run_a` to the end 
+/// of the run `run_b`, like `diff`, with the hashes each modified file changed between
+/// 
+async fn diff_runs(cache_svc: &dyn HistoryService, run_a: i64, run_b: i64) {
+    for run_id in [run_a, run_b] {
+        if cache_svc.get_run(run_id).await.unwrap().is_none() {
+            eprintln!("There is no run with id {}", run_id);
+            std::process::exit(1);
+        }
+    }
+    let diff = cache_svc.get_backup_run_diff(run_a, run_b).await.unwrap();
+
+    for path in &diff.added {
+        println!("A  {}", path.display());
+    }
+    for (path, hsh_a, hsh_b) in &diff.modified {
+        let (hsh_a, hsh_b) = (encode_hash(hsh_a, config().hash_encoding), encode_hash(hsh_b, config().hash_encoding));
+        println!("M  {}  {} -> {}", path.display(), hsh_a, hsh_b);
+    }
+    for path in &diff.deleted {
+        println!("D  {}", path.display());
+    }
+    println!(
+        "{} added, {} modified, {} deleted",
+        format_count(diff.added.len() as u64), format_count(diff.modified.len() as u64), format_count(diff.deleted.len() as u64)
+    );
+}
+
+///
+/// Prints how the contents of the file at `path` changed from its version picked by `from` to
+/// the one picked by `to`, as a unified diff, or only its line counts if `stat` is set
+/// 
+async fn diff_file(
+    cache_svc: &dyn HistoryService, backup_service: &RoutedBackupService, path: &Path, 
+    from: &VersionSelector, to: &VersionSelector, stat: bool, color: bool
+) {
+    // The file may no longer exist to be normalized
+    let path = normalize_path(path, config().canonicalize).unwrap_or_else(|_| path.to_path_buf());
+    let mut versions = Vec::new();
+    for selector in [from, to] {
+        let version = match cache_svc.find_version(&path, selector).await {
+            Ok(Some(version)) => version,
+            Ok(None) => {
+                eprintln!("{} has no version matching {:?}", path.display(), selector);
+                std::process::exit(1);
+            },
+            Err(HistoryError::AmbiguousLabel(label, ids)) => {
+                eprintln!(
+                    "{} has {} versions labelled \"{}\" (ids {}). Select one of them by its id",
+                    path.display(), ids.len(), label, ids.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+                );
+                std::process::exit(1);
+            },
+            Err(e) => panic!("{:?}", e)
+        };
+        let recorded = recorded_destinations(cache_svc, version.id).await;
+        let mut reader = unwrap_backup(backup_service.open_recorded(version.id, &recorded).await);
+        let contents = tokio::task::spawn_blocking(move || {
+            let mut contents = Vec::new();
+            reader.read_to_end(&mut contents).map(|_| contents)
+        }).await.unwrap().unwrap();
+        versions.push((version.id, contents));
+    }
+
+    let name = |version_id| format!("{} (version {})", path.display(), version_id);
+    let diff = VersionDiff::new(&versions[0].1, &versions[1].1, &name(versions[0].0), &name(versions[1].0));
+    print!("{}", diff.format(color, stat));
+}
+
+///
+/// Prints which of the configured policies applies to the file at `path`, every policy glob 
+/// matching it in order of precedence, and where each setting applied to it came from
+/// 
+fn explain_policy(path: &Path) {
+    let path = normalize_path(path, config().canonicalize).unwrap_or_else(|_| path.to_path_buf());
+    let resolved = resolve_policy(&config().policies, &path);
+    let describe = |policy: &PolicyMatch| format!("policy {} (glob {}, {} literal characters)", policy.policy_index + 1, policy.glob, policy.literal_prefix_len);
+
+    println!("Policy for {}", path.display());
+    if resolved.matches.is_empty() {
+        println!("  No policy matches it, so every setting is the default");
+    }
+    for (i, policy) in resolved.matches.iter().enumerate() {
+        println!("  {} {}", if i == 0 { "applied" } else { "ignored" }, describe(policy));
+    }
+    let source = resolved.change_detection.source.as_ref().map_or("the default".to_string(), describe);
+    println!("change_detection: {}, from {}", serde_json::to_string(&resolved.change_detection.value).unwrap(), source);
+}
+
+///
+/// Prints the hash of the latest version of every file under `root`, formatted like `md5sum`'s output
+/// 
+async fn print_hashes(cache_svc: &dyn HistoryService, root: &Path) {
+    let root = normalize_path(root, config().canonicalize).unwrap_or_else(|_| root.to_path_buf());
+    for (path, hsh) in cache_svc.get_latest_hashes().await.unwrap() {
+        if path.starts_with(&root) {
+            println!("{}", format_checksum_line(&hsh, &path));
+        }
+    }
+}
+
+///
+/// Compares the `md5sum` formatted checksum file at `path` against the catalog, printing every
+/// mismatch. Exits with 1 if there are any.
+/// 
+async fn import_hashes(cache_svc: &dyn HistoryService, path: &Path) {
+    let checksums = std::fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("Could not read the checksum file {}: {}", path.display(), e));
+    let catalog = cache_svc.get_latest_hashes().await.unwrap().into_iter().collect();
+
+    let mismatches = check_checksums(&checksums, &catalog);
+    if mismatches.is_empty() {
+        println!("Every checksum matches the catalog");
+        return;
+    }
+    for mismatch in &mismatches {
+        match mismatch {
+            ChecksumMismatch::Differs { path, listed, recorded } => 
+                println!("{}: listed with hash {}, but the catalog has {}", path.display(), listed, recorded),
+            ChecksumMismatch::NotInCatalog { path } => println!("{}: not in the catalog", path.display()),
+        }
+    }
+    std::process::exit(1);
+}
+
+///
+/// Gets the names of the destinations the catalog records as holding the version with the given `file_id`
+/// 
+async fn recorded_destinations(cache_svc: &dyn HistoryService, file_id: i64) -> Vec<String> {
+    cache_svc.get_file_backups(file_id).await.unwrap().into_iter().map(|backup| backup.destination).collect()
+}
+
+///
+/// Prints every stored copy the catalog records in a destination which is no longer configured,
+/// and exits with 1 if there are any
+/// 
+async fn doctor(cache_svc: &dyn HistoryService, backup_service: &RoutedBackupService) {
+    for (name, case_sensitive) in backup_service.probe_case_sensitivity() {
+        match case_sensitive {
+            Ok(true) => println!("The destination {} is case-sensitive", name),
+            Ok(false) => println!("The destination {} is case-insensitive", name),
+            Err(e) => println!("Couldn't tell whether the destination {} is case-sensitive: {}", name, e),
+        }
+    }
+    warn_case_collisions(cache_svc).await;
+
+    let configured: Vec<String> = backup_service.destination_names().into_iter().map(String::from).collect();
+    let orphaned = cache_svc.get_backups_outside(&configured).await.unwrap();
+    if orphaned.is_empty() {
+        println!("Every backup is held by a configured destination");
+        return;
+    }
+
+    for backup in orphaned {
+        println!(
+            "The backup with id {} is recorded in the destination {}, which is no longer configured", 
+            backup.file_id, backup.destination
+        );
+    }
+    std::process::exit(1);
+}
+
+///
+/// Warns about every set of backed up files whose names differ only in case
+/// 
+async fn warn_case_collisions(cache_svc: &dyn HistoryService) {
+    for colliding in cache_svc.find_case_collisions().await.unwrap() {
+        let paths: Vec<String> = colliding.iter().map(|path| path.display().to_string()).collect();
+        tracing::warn!(
+            "{} differ only in case. Restoring them onto a case-insensitive filesystem renames all but one",
+            paths.join(", ")
+        );
+    }
+}
+
+///
+/// Removes expired versions, unused chunks and directories no longer holding anything from the
+/// catalog, then rewrites it without its free space, printing how much space was reclaimed
+/// 
+async fn compact(cache_svc: &dyn HistoryService, backup_service: &mut RoutedBackupService, db: &SqlitePool, catalog_path: &Path) {
+    if let Some(note) = unwrap_backup(backup_service.maintenance_note().await) {
+        exit_for_maintenance(&note);
+    }
+    let _lock = lock_catalog(catalog_path);
+
+    let mut expired = 0;
+    if let Some(days) = config().tombstone_retention_days {
+        for file_id in cache_svc.prune_expired_generations(Duration::days(days)).await.unwrap() {
+            unwrap_pipeline(delete_backup_keeping_dependents(cache_svc, backup_service, file_id).await);
+            expired += 1;
+        }
+    }
+    let chunks = cache_svc.take_unreferenced_chunks().await.unwrap();
+    for hsh in &chunks {
+        unwrap_backup(backup_service.delete_chunk(hsh).await);
+    }
+    let dirs = cache_svc.delete_unused_dirs().await.unwrap();
+    println!(
+        "Removed {} expired versions, {} unused chunks and {} unused directories",
+        format_count(expired), format_count(chunks.len() as u64), format_count(dirs)
+    );
+
+    let (db, report) = compact_catalog(db, catalog_path).await.unwrap_or_else(|e| {
+        eprintln!("The catalog couldn't be compacted: {}", e.err);
+        std::process::exit(1);
+    });
+    db.close().await;
+    println!(
+        "Compacted the catalog from {} to {}, reclaiming {}",
+        format_bytes(report.size_before), format_bytes(report.size_after), format_bytes(report.bytes_reclaimed())
+    );
+}
+
+///
+/// Takes the lock of the catalog at `catalog_path`, exiting if another process holds it
+/// 
+fn lock_catalog(catalog_path: &Path) -> CatalogLock {
+    CatalogLock::acquire(catalog_path).unwrap_or_else(|e| {
+        eprintln!("Couldn't lock the catalog: {}", e);
+        std::process::exit(1);
+    })
+}
+
+///
+/// Vacuums and analyzes the catalog, printing its size before and after
+/// 
+async fn maintain_catalog(cache_svc: &dyn HistoryService) {
+    let before = cache_svc.catalog_size_bytes().await.unwrap();
+    cache_svc.vacuum().await.unwrap();
+    cache_svc.analyze().await.unwrap();
+    let after = cache_svc.catalog_size_bytes().await.unwrap();
+
+    println!("Catalog size before: {}", format_bytes(before));
+    println!("Catalog size after:  {}", format_bytes(after));
+}
+
+///
+/// Prints every file in the history matching `query`, with its latest backup time and version count
+/// 
+async fn search(cache_svc: &dyn HistoryService, query: &str) {
+    for file in cache_svc.search(query).await.unwrap() {
+        println!("{}\t{}\t{} version(s)", file.full_path, format_ts(&file.latest_backup_ts), file.version_count);
+    }
+}
+
+///
+/// Prints every version of every file in the history matching `query`, 
+/// marking where each of the file's generations begins
+/// 
+async fn list(cache_svc: &dyn HistoryService, query: &str) {
+    for file in cache_svc.search(query).await.unwrap() {
+        println!("{}", file.full_path);
+        let mut generation = None;
+        for version in cache_svc.get_versions(file.dir_id, &file.file_name).await.unwrap() {
+            if generation != Some(version.generation) {
+                generation = Some(version.generation);
+                println!("  -- generation {} --", version.generation);
+            }
+            match (version.hsh, version.label) {
+                (Some(hsh), Some(label)) => println!(
+                    "  {}\t{}\t{}\t\"{}\"", format_ts(&version.backup_ts), version.id, encode_hash(&hsh, config().hash_encoding), label
+                ),
+                (Some(hsh), None) => println!("  {}\t{}\t{}", format_ts(&version.backup_ts), version.id, encode_hash(&hsh, config().hash_encoding)),
+                (None, _) if version.excluded => println!("  {}\texcluded", format_ts(&version.backup_ts)),
+                (None, _) => println!("  {}\tdeleted", format_ts(&version.backup_ts)),
+            }
+        }
+    }
+}
+
+///
+/// Labels the version of the file at `path` with the given `version_id`, or its latest version
+/// 
+async fn label_version(cache_svc: &dyn HistoryService, path: &Path, version_id: Option<i64>, label: &str) {
+    // The file may no longer exist to be normalized
+    let path = normalize_path(path, config().canonicalize).unwrap_or_else(|_| path.to_path_buf());
+    match cache_svc.label_version(&path, version_id, label).await.unwrap() {
+        Some(version_id) => println!("Labelled version {} of {} \"{}\"", version_id, path.display(), label),
+        None => {
+            match version_id {
+                Some(version_id) => eprintln!("{} has no version with id {} to label", path.display(), version_id),
+                None => eprintln!("{} has no version to label", path.display())
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+///
+/// Gets the latest completed run whose reason contains `reason`, exiting if there's none
+/// 
+async fn find_run_by_reason(cache_svc: &dyn HistoryService, reason: &str) -> RunModel {
+    let Some(run) = cache_svc.find_run_by_reason(reason).await.unwrap() else {
+        eprintln!("No completed run has a reason containing \"{}\"", reason);
+        std::process::exit(1);
+    };
+    println!("Restoring files as they were after run {} ({})", run.id, run.origin.reason.as_deref().unwrap_or(""));
+
+    run
+}
+
+///
+/// Restores the latest version of every file in the history matching `query`, or the version
+/// it had when `run` completed, or its version with the `label`, if given, mapping each path
+/// with the configured maps and then the given `maps`. Files whose mapped path is outside of
+/// `root` are skipped. If any file has more than one version with the `label`, nothing is restored.
+/// 
+async fn restore(
+    cache_svc: &dyn HistoryService, backup_service: &RoutedBackupService, query: &str, 
+    run: Option<&RunModel>, label: Option<&str>, root: &Path, maps: Vec<PathMap>
+) {
+    let mapper = PathMapper::new(config().path_maps.iter().cloned().chain(maps));
+    let (mut restored, mut targets) = (Vec::new(), Vec::new());
+    for file in cache_svc.search(query).await.unwrap() {
+        let latest = match (label, run.and_then(|run| run.completed_at)) {
+            (Some(label), _) => match cache_svc.get_labelled_version(file.dir_id, &file.file_name, label).await {
+                Ok(version) => version,
+                Err(HistoryError::AmbiguousLabel(label, ids)) => {
+                    eprintln!(
+                        "{} has {} versions labelled \"{}\" (ids {}), so nothing was restored. Give all but one of them another label",
+                        file.full_path, ids.len(), label, ids.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+                    );
+                    std::process::exit(1);
+                },
+                Err(e) => panic!("{:?}", e)
+            },
+            (None, Some(completed_at)) => cache_svc.get_versions(file.dir_id, &file.file_name).await.unwrap().into_iter()
+                .filter(|version| version.backup_ts <= completed_at)
+                .max_by_key(|version| version.backup_ts)
+                // The file was deleted by then
+                .filter(|version| version.hsh.is_some()),
+            (None, None) => cache_svc.get_latest_version(file.dir_id, &file.file_name).await.unwrap()
+        };
+        let Some(latest) = latest else {
+            continue;
+        };
+        match mapper.map_within(&file.full_path, root) {
+            Ok(target) => {
+                restored.push((file.full_path, latest.id));
+                targets.push(target);
+            },
+            Err(e) => tracing::warn!("Not restoring {}: {}", file.full_path, e),
+        }
+    }
+    // Files whose names differ only in case would overwrite each other
+    tokio::fs::create_dir_all(root).await.unwrap();
+    match probe_case_sensitivity(root) {
+        Ok(true) => { },
+        Ok(false) => disambiguate_case_collisions(&mut targets),
+        Err(e) => tracing::warn!("Couldn't tell whether {} is case-sensitive, so files won't be renamed: {}", root.display(), e),
+    }
+
+    for ((full_path, file_id), target) in restored.into_iter().zip(targets) {
+        restore_version(cache_svc, backup_service, &full_path, file_id, &target).await;
+    }
+
+    let mut restored_dirs = Vec::new();
+    for dir in cache_svc.get_empty_dirs(query).await.unwrap() {
+        let full_path = dir.path.to_string_lossy().to_string();
+        let target = match mapper.map_within(&full_path, root) {
+            Ok(target) => target,
+            Err(e) => {
+                tracing::warn!("Not restoring {}: {}", full_path, e);
+                continue;
+            }
+        };
+
+        tokio::fs::create_dir_all(&target).await.unwrap();
+        println!("{}/ -> {}", full_path, target.display());
+        restored_dirs.push((target, dir.permissions));
+    }
+    // Permissions are set deepest first, so a read-only directory 
+    // can't keep the directories inside it from being created
+    restored_dirs.sort_by(|a, b| b.0.cmp(&a.0));
+    for (target, permissions) in restored_dirs {
+        if let Some(mode) = permissions {
+            set_permissions(&target, mode).unwrap();
+        }
+    }
+}
+
+///
+/// Restores the version with the given `file_id` of the file backed up from `full_path` to `target`, 
+/// creating the directories above it
+/// 
+async fn restore_version(cache_svc: &dyn HistoryService, backup_service: &RoutedBackupService, full_path: &str, file_id: i64, target: &Path) {
+    if let Some(parent) = target.parent() {
+        tokio::fs::create_dir_all(long_path(parent)).await.unwrap();
+    }
+    let recorded = recorded_destinations(cache_svc, file_id).await;
+    unwrap_backup(backup_service.restore_recorded(file_id, &recorded, target).await);
+    println!("{} -> {}", full_path, target.display());
+}
+
+///
+/// Lists the versions of the one file matching `query`, restoring the version picked at the prompt 
+/// once confirmed. It's restored within `root` if given, or otherwise where it was backed up from, 
+/// following the path maps either way
+/// 
+async fn restore_interactively(
+    cache_svc: &dyn HistoryService, backup_service: &RoutedBackupService, query: &str, root: Option<&Path>, maps: Vec<PathMap>
+) {
+    if !std::io::stdin().is_terminal() {
+        eprintln!("restore --interactive prompts for the version to restore, so needs a terminal. Pick the version with --label or --run instead");
+        std::process::exit(2);
+    }
+    let files = cache_svc.search(query).await.unwrap();
+    let file = match files.as_slice() {
+        [file] => file,
+        [] => {
+            eprintln!("No backed-up file matches {}", query);
+            std::process::exit(1);
+        },
+        files => {
+            eprintln!("{} files match {}, so give the path of only one of them:", files.len(), query);
+            for file in files {
+                eprintln!("  {}", file.full_path);
+            }
+            std::process::exit(1);
+        }
+    };
+
+    let mapper = PathMapper::new(config().path_maps.iter().cloned().chain(maps));
+    let target = match root {
+        Some(root) => mapper.map_within(&file.full_path, root).unwrap_or_else(|e| {
+            eprintln!("Not restoring {}: {}", file.full_path, e);
+            std::process::exit(1);
+        }),
+        None => mapper.map(&file.full_path).unwrap_or_else(|| PathBuf::from(&file.full_path))
+    };
+    let versions = cache_svc.get_versions(file.dir_id, &file.file_name).await.unwrap();
+    println!("{}", file.full_path);
+    let style = TIMESTAMP_STYLE.get().copied().unwrap_or_default();
+    let picked = pick_version(&versions, &target, style, config().hash_encoding, &mut std::io::stdin().lock(), &mut std::io::stdout()).unwrap();
+    match picked {
+        Some(version) => restore_version(cache_svc, backup_service, &file.full_path, version.id, &target).await,
+        None => println!("Nothing was restored")
+    }
+}
+
+///
+/// Prints every file version sharing a hash with a version of a different size, grouped by hash
+/// 
+async fn collision_audit(cache_svc: &dyn HistoryService) {
+    let collisions = cache_svc.find_hash_collisions().await.unwrap();
+    if collisions.is_empty() {
+        println!("No hash collisions found");
+    }
+    let mut collisions: Vec<_> = collisions.group_by(|c| c.hsh.clone()).into_iter().collect();
+    collisions.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for (hsh, entries) in collisions {
+        println!("{}", encode_hash(&hsh, config().hash_encoding));
+        for entry in entries {
+            println!("\t{} (id={}, dir_id={}): {} bytes", entry.file_name, entry.id, entry.dir_id, entry.file_size);
+        }
+    }
+}
+
+///
+/// Reads the paths to back up listed in the file at `list`, or from stdin if it's `-`, 
+/// exiting if they can't be read
+/// 
+fn read_listed_paths(list: &Path, null_separated: bool) -> Vec<PathBuf> {
+    let paths = match list == Path::new("-") {
+        true => read_path_list(std::io::stdin().lock(), null_separated),
+        false => std::fs::File::open(list).and_then(|file| read_path_list(file, null_separated))
+    };
+    paths.unwrap_or_else(|e| {
+        eprintln!("Couldn't read the paths to back up from {}: {}", list.display(), e);
+        std::process::exit(1);
+    })
+}
+
+///
+/// Unwraps the result of an operation on the backup destination, exiting with
+/// the maintenance note left there if the destination is in maintenance mode
+/// 
+fn unwrap_backup<T, E: Display + AsRef<BackupErrorKind>>(result: Result<T, E>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(e) => match e.as_ref() {
+            BackupErrorKind::MaintenanceMode(note) => exit_for_maintenance(note),
+            _ => panic!("{}", e)
+        }
+    }
+}
+
+///
+/// Unwraps the result of a backup run, exiting with the maintenance note left in the 
+/// destination if it's in maintenance mode, or if the files to back up couldn't be found
+/// 
+fn unwrap_pipeline<T>(result: Result<T, PipelineError>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(PipelineError::BackupServiceError(BackupErrorKind::MaintenanceMode(note))) => exit_for_maintenance(&note),
+        Err(PipelineError::ScanError(e)) => {
+            eprintln!("Couldn't find the files to back up: {:?}", e);
+            std::process::exit(1);
+        },
+        Err(e) => panic!("{}", e)
+    }
+}
 
-    cache_svc.mark_all_deleted_files().await.unwrap();
+fn exit_for_maintenance(note: &str) -> ! {
+    eprintln!("The backup destination is in maintenance mode, so it was left untouched: {}", note.trim());
+    std::process::exit(1);
 }
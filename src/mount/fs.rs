@@ -0,0 +1,238 @@
+use std::{collections::HashMap, ffi::OsStr, io::Read, path::Path, time::{Duration, UNIX_EPOCH}};
+
+use fuser::{
+    consts::FOPEN_DIRECT_IO, FileAttr, FileType, Filesystem, MountOption, 
+    ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, Request
+};
+use sqlx::SqlitePool;
+use tokio::runtime::Handle;
+
+use crate::{backup_service::{BackupService, FileBackupService}, collections::Cache, history_service::data_layer::DbDataLayer};
+
+use super::{inodes::{InodeTable, Node, ROOT_INO}, read_children};
+
+///
+/// How long the kernel may cache attributes and lookups. The catalog
+/// only changes when a backup runs, so this can be generous.
+/// 
+const TTL: Duration = Duration::from_secs(60);
+
+///
+/// A decompressing reader over a backup opened through the mount,
+/// along with how far into the file it has read
+/// 
+struct OpenFile {
+    backup_id: i64,
+    reader: Box<dyn Read + Send>,
+    pos: u64
+}
+
+///
+/// A read-only FUSE filesystem exposing the catalog's directory tree
+/// 
+struct CatalogFs {
+    pool: SqlitePool,
+    backup_service: FileBackupService,
+    runtime: Handle,
+    inodes: InodeTable,
+    listings: Cache<Vec<(String, u64, FileType)>>,
+    open_files: HashMap<u64, OpenFile>,
+    next_fh: u64
+}
+
+///
+/// Mounts a read-only view of the catalog at `mountpoint`, until Ctrl-C is pressed
+/// 
+pub async fn mount(pool: SqlitePool, backup_service: FileBackupService, mountpoint: &Path) -> std::io::Result<()> {
+    let fs = CatalogFs {
+        pool,
+        backup_service,
+        runtime: Handle::current(),
+        inodes: InodeTable::new(),
+        listings: Cache::new(),
+        open_files: HashMap::new(),
+        next_fh: 1
+    };
+    let options = [MountOption::RO, MountOption::FSName("drive_backup".to_string())];
+    let session = fuser::spawn_mount2(fs, mountpoint, &options)?;
+
+    tokio::signal::ctrl_c().await?;
+    // Dropping the session unmounts the filesystem
+    drop(session);
+
+    Ok(())
+}
+
+impl CatalogFs {
+    ///
+    /// Lists the entries of the directory inode `ino`, reading them from
+    /// the catalog the first time the directory is listed
+    /// 
+    fn list(&mut self, ino: u64) -> Result<Vec<(String, u64, FileType)>, i32> {
+        let path = self.inodes.path(ino).ok_or(libc::ENOENT)?;
+        if let Some(listing) = self.listings.get(&path) {
+            return Ok(listing.clone());
+        }
+
+        let node = self.inodes.get(ino).ok_or(libc::ENOENT)?.clone();
+        let data_layer = DbDataLayer::new(&self.pool);
+        let children = self.runtime.block_on(read_children(&data_layer, &node))
+            .map_err(|_| libc::EIO)?;
+
+        let listing: Vec<_> = children.into_iter().map(|(name, node)| {
+            let kind = file_type(&node);
+            (name.clone(), self.inodes.insert(ino, &name, node), kind)
+        }).collect();
+        self.listings.insert(&path, listing.clone());
+
+        Ok(listing)
+    }
+
+    fn attr(&self, ino: u64) -> Option<FileAttr> {
+        let node = self.inodes.get(ino)?;
+        let mtime = match node {
+            Node::File { backup_ts, .. } => UNIX_EPOCH + Duration::from_secs(backup_ts.timestamp().max(0) as u64),
+            _ => UNIX_EPOCH
+        };
+        let (kind, perm) = match file_type(node) {
+            FileType::RegularFile => (FileType::RegularFile, 0o444),
+            kind => (kind, 0o555)
+        };
+
+        Some(FileAttr {
+            ino,
+            // A backup's decompressed size isn't stored, so files are opened
+            // with direct IO, and are read until the end of the stream instead
+            size: 0,
+            blocks: 0,
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+            crtime: mtime,
+            kind,
+            perm,
+            nlink: 1,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            rdev: 0,
+            blksize: 4096,
+            flags: 0
+        })
+    }
+
+    fn open_reader(&self, backup_id: i64) -> std::io::Result<Box<dyn Read + Send>> {
+        self.runtime.block_on(self.backup_service.open_backup(backup_id))
+            .map_err(|e| std::io::Error::other(format!("{:?}", e)))
+    }
+}
+
+fn file_type(node: &Node) -> FileType {
+    match node {
+        Node::File { .. } => FileType::RegularFile,
+        _ => FileType::Directory
+    }
+}
+
+impl Filesystem for CatalogFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let Some(name) = name.to_str() else { return reply.error(libc::ENOENT) };
+        if let Err(e) = self.list(parent) {
+            return reply.error(e);
+        }
+
+        match self.inodes.lookup(parent, name).and_then(|ino| self.attr(ino)) {
+            Some(attr) => reply.entry(&TTL, &attr, 0),
+            None => reply.error(libc::ENOENT)
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.attr(ino) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(libc::ENOENT)
+        }
+    }
+
+    fn readdir(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        let listing = match self.list(ino) {
+            Ok(listing) => listing,
+            Err(e) => return reply.error(e)
+        };
+
+        let entries = [(".".to_string(), ino, FileType::Directory), ("..".to_string(), ROOT_INO, FileType::Directory)]
+            .into_iter().chain(listing);
+        for (i, (name, ino, kind)) in entries.enumerate().skip(offset as usize) {
+            // The offset given is that of the next entry to read
+            if reply.add(ino, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        let Some(Node::File { backup_id, .. }) = self.inodes.get(ino).cloned() else {
+            return reply.error(libc::EISDIR)
+        };
+
+        match self.open_reader(backup_id) {
+            Ok(reader) => {
+                let fh = self.next_fh;
+                self.next_fh += 1;
+                self.open_files.insert(fh, OpenFile { backup_id, reader, pos: 0 });
+                reply.opened(fh, FOPEN_DIRECT_IO);
+            },
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(libc::EIO))
+        }
+    }
+
+    fn read(
+        &mut self, _req: &Request<'_>, _ino: u64, fh: u64, offset: i64, size: u32, 
+        _flags: i32, _lock_owner: Option<u64>, reply: ReplyData
+    ) {
+        let Some(mut file) = self.open_files.remove(&fh) else { return reply.error(libc::EBADF) };
+        let offset = offset.max(0) as u64;
+
+        // Backups are compressed streams, so reading backwards means
+        // decompressing again from the start of the file
+        if offset < file.pos {
+            match self.open_reader(file.backup_id) {
+                Ok(reader) => { file.reader = reader; file.pos = 0; },
+                Err(e) => return reply.error(e.raw_os_error().unwrap_or(libc::EIO))
+            }
+        }
+
+        let result = (|| -> std::io::Result<Vec<u8>> {
+            file.pos += std::io::copy(&mut (&mut file.reader).take(offset - file.pos), &mut std::io::sink())?;
+
+            let mut buf = vec![0u8; size as usize];
+            let mut filled = 0;
+            while filled < buf.len() {
+                match file.reader.read(&mut buf[filled..]) {
+                    Ok(0) => break,
+                    Ok(n) => filled += n,
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e)
+                }
+            }
+            file.pos += filled as u64;
+            buf.truncate(filled);
+
+            Ok(buf)
+        })();
+
+        match result {
+            Ok(buf) => reply.data(&buf),
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(libc::EIO))
+        }
+        self.open_files.insert(fh, file);
+    }
+
+    fn release(
+        &mut self, _req: &Request<'_>, _ino: u64, fh: u64, _flags: i32, 
+        _lock_owner: Option<u64>, _flush: bool, reply: ReplyEmpty
+    ) {
+        self.open_files.remove(&fh);
+        reply.ok();
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+
+///
+/// The inode of the mount's root directory
+/// 
+pub const ROOT_INO: u64 = 1;
+
+///
+/// What an inode in the mounted view of the catalog refers to
+/// 
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    /// The root of the mount, holding the catalog's root directories
+    Root,
+    /// A directory in the catalog
+    Dir { dir_id: i64 },
+    /// A single backed-up version of a file
+    File { backup_id: i64, backup_ts: DateTime<Utc> },
+    /// The directory listing every stored version of a file
+    Versions { dir_id: i64, file_name: String },
+}
+
+struct InodeEntry {
+    parent: u64,
+    name: String,
+    node: Node
+}
+
+///
+/// Assigns stable inode numbers to the entries of the mounted view,
+/// resolving them by parent and name, and back into paths
+/// 
+pub struct InodeTable {
+    entries: HashMap<u64, InodeEntry>,
+    children: HashMap<(u64, String), u64>,
+    next_ino: u64
+}
+
+impl Default for InodeTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InodeTable {
+    ///
+    /// Creates a new `InodeTable`, holding only the root directory
+    /// 
+    pub fn new() -> Self {
+        let mut entries = HashMap::new();
+        entries.insert(ROOT_INO, InodeEntry { parent: ROOT_INO, name: String::new(), node: Node::Root });
+        Self { entries, children: HashMap::new(), next_ino: ROOT_INO + 1 }
+    }
+    ///
+    /// Gets the node the inode `ino` refers to
+    /// 
+    pub fn get(&self, ino: u64) -> Option<&Node> {
+        self.entries.get(&ino).map(|e| &e.node)
+    }
+    ///
+    /// Gets the inode of the entry named `name` under the directory inode `parent`
+    /// 
+    pub fn lookup(&self, parent: u64, name: &str) -> Option<u64> {
+        self.children.get(&(parent, name.to_string())).copied()
+    }
+    ///
+    /// Adds the entry `name` under the directory inode `parent`, returning its inode.
+    /// An entry which already exists keeps its inode, but refers to the new `node`.
+    /// 
+    pub fn insert(&mut self, parent: u64, name: &str, node: Node) -> u64 {
+        if let Some(ino) = self.lookup(parent, name) {
+            self.entries.get_mut(&ino).unwrap().node = node;
+            return ino;
+        }
+
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.entries.insert(ino, InodeEntry { parent, name: name.to_string(), node });
+        self.children.insert((parent, name.to_string()), ino);
+
+        ino
+    }
+    ///
+    /// Gets the path of the inode `ino` relative to the mount's root, 
+    /// with components separated by `/`. The root's path is empty.
+    /// 
+    pub fn path(&self, ino: u64) -> Option<String> {
+        let mut names = Vec::new();
+        let mut cur_ino = ino;
+        while cur_ino != ROOT_INO {
+            let entry = self.entries.get(&cur_ino)?;
+            names.push(entry.name.as_str());
+            cur_ino = entry.parent;
+        }
+        names.reverse();
+
+        Some(names.join("/"))
+    }
+}
+
+///
+/// Gets the name of the directory listing every version of the file `file_name`
+/// 
+pub fn versions_dir_name(file_name: &str) -> String {
+    format!("{}@versions", file_name)
+}
+
+///
+/// Gets the name a single version of a file is listed under in its versions directory
+/// 
+pub fn version_name(backup_ts: &DateTime<Utc>) -> String {
+    backup_ts.format("%Y-%m-%dT%H:%M:%S").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::{version_name, InodeTable, Node, ROOT_INO};
+
+    #[test]
+    fn test_inode_table_resolves_paths() {
+        let mut table = InodeTable::new();
+        let home = table.insert(ROOT_INO, "home", Node::Dir { dir_id: 1 });
+        let docs = table.insert(home, "docs", Node::Dir { dir_id: 2 });
+        let versions = table.insert(docs, "a.txt@versions", Node::Versions { dir_id: 2, file_name: "a.txt".to_string() });
+
+        assert_eq!(table.path(ROOT_INO), Some("".to_string()));
+        assert_eq!(table.path(docs), Some("home/docs".to_string()));
+        assert_eq!(table.path(versions), Some("home/docs/a.txt@versions".to_string()));
+        assert_eq!(table.lookup(home, "docs"), Some(docs));
+        assert_eq!(table.lookup(home, "missing"), None);
+        assert_eq!(table.path(999), None);
+    }
+
+    #[test]
+    fn test_inode_table_reinsert_keeps_inode() {
+        let ts = Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap();
+        let mut table = InodeTable::new();
+        let first = table.insert(ROOT_INO, "a.txt", Node::File { backup_id: 1, backup_ts: ts });
+        let second = table.insert(ROOT_INO, "a.txt", Node::File { backup_id: 2, backup_ts: ts });
+
+        assert_eq!(first, second);
+        assert_eq!(table.get(first), Some(&Node::File { backup_id: 2, backup_ts: ts }));
+        assert_eq!(version_name(&ts), "2024-01-02T03:04:05");
+    }
+}
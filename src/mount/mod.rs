@@ -0,0 +1,59 @@
+pub mod inodes;
+#[cfg(all(feature = "mount", unix))]
+mod fs;
+
+#[cfg(all(feature = "mount", unix))]
+pub use fs::mount;
+
+use crate::{data_layer_error::Result, history_service::data_layer::DataLayer};
+
+use inodes::{version_name, versions_dir_name, Node};
+
+///
+/// Reads the named entries under the given directory `node` from the catalog,
+/// sorted by name. Files show their latest backed-up version, and each has
+/// a sibling versions directory holding every version still stored.
+/// 
+pub async fn read_children(data_layer: &dyn DataLayer, node: &Node) -> Result<Vec<(String, Node)>> {
+    let mut children = match node {
+        Node::Root => {
+            let mut children = Vec::new();
+            for dir in data_layer.get_root_dirs().await? {
+                // The unix root directory has no name which can be shown
+                // in a directory listing, so its contents are shown instead
+                if dir.dir_name == "/" {
+                    children.extend(read_dir_children(data_layer, dir.id).await?);
+                } else {
+                    children.push((dir.dir_name, Node::Dir { dir_id: dir.id }));
+                }
+            }
+            children
+        },
+        Node::Dir { dir_id } => read_dir_children(data_layer, *dir_id).await?,
+        Node::Versions { dir_id, file_name } => data_layer.get_dir_files(*dir_id, file_name).await?
+            .into_iter()
+            .filter(|f| f.hsh.is_some())
+            .map(|f| (version_name(&f.backup_ts), Node::File { backup_id: f.id, backup_ts: f.backup_ts }))
+            .collect(),
+        Node::File { .. } => Vec::new()
+    };
+
+    children.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(children)
+}
+
+async fn read_dir_children(data_layer: &dyn DataLayer, dir_id: i64) -> Result<Vec<(String, Node)>> {
+    let mut children: Vec<(String, Node)> = data_layer.get_sub_dirs(dir_id).await?.into_iter()
+        .map(|d| (d.dir_name, Node::Dir { dir_id: d.id }))
+        .collect();
+
+    for file in data_layer.get_latest_dir_files(dir_id).await? {
+        children.push((
+            versions_dir_name(&file.file_name),
+            Node::Versions { dir_id, file_name: file.file_name.clone() }
+        ));
+        children.push((file.file_name, Node::File { backup_id: file.id, backup_ts: file.backup_ts }));
+    }
+
+    Ok(children)
+}
@@ -0,0 +1,247 @@
+use std::{collections::HashMap, fmt::Display, path::{Component, Path, PathBuf}, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::file_svc::long_path::clamp_file_name;
+
+///
+/// Maps paths under the `from` prefix to the same paths under the `to` prefix
+/// 
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PathMap {
+    pub from: String,
+    pub to: String
+}
+
+impl FromStr for PathMap {
+    type Err = String;
+
+    ///
+    /// Parses a map written as `from=to`
+    /// 
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((from, to)) if !from.is_empty() && !to.is_empty() => 
+                Ok(PathMap { from: from.to_string(), to: to.to_string() }),
+            _ => Err(format!("expected a map written as old-prefix=new-prefix, got \"{}\"", s))
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PathMapError {
+    /// The path would be written outside of the restore root
+    OutsideRoot(PathBuf),
+}
+
+impl Display for PathMapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PathMapError::OutsideRoot(path) => write!(f, "{} is outside of the restore root", path.display()),
+        }
+    }
+}
+
+///
+/// Maps paths reconstructed from the catalog onto the machine they're 
+/// being written to. Where more than one map applies to a path, the map 
+/// with the longest prefix wins, and then the map given last.
+/// 
+pub struct PathMapper {
+    maps: Vec<PathMap>
+}
+
+impl PathMapper {
+    pub fn new(maps: impl IntoIterator<Item = PathMap>) -> Self {
+        Self { maps: maps.into_iter().collect() }
+    }
+
+    ///
+    /// Maps the catalog `path` with the best matching map, or returns `None` if no map applies.
+    /// Paths are compared by component, splitting on both `/` and `\`, so that catalogs
+    /// recorded on any operating system can be mapped.
+    /// 
+    pub fn map(&self, path: &str) -> Option<PathBuf> {
+        let path_components = components(path);
+        let (map, prefix_len) = self.maps.iter()
+            .map(|map| (map, components(&map.from)))
+            .filter(|(_, prefix)| path_components.starts_with(prefix))
+            .map(|(map, prefix)| (map, prefix.len()))
+            // Of equally long prefixes, the last is kept
+            .max_by_key(|(_, prefix_len)| *prefix_len)?;
+
+        let mut mapped = PathBuf::from(&map.to);
+        mapped.extend(&path_components[prefix_len..]);
+        Some(mapped)
+    }
+
+    ///
+    /// Maps the catalog `path`, or takes it as it is if no map applies, and 
+    /// ensures the result is inside the `root` it's being restored to
+    /// 
+    pub fn map_within(&self, path: &str, root: &Path) -> Result<PathBuf, PathMapError> {
+        let mapped = self.map(path).unwrap_or_else(|| PathBuf::from(path));
+        let escapes = mapped.components().any(|component| component == Component::ParentDir);
+        if escapes || !mapped.starts_with(root) {
+            return Err(PathMapError::OutsideRoot(mapped));
+        }
+
+        Ok(mapped)
+    }
+}
+
+///
+/// Renames every path in `targets` differing from another only in ASCII case, so that restoring
+/// them onto a case-insensitive filesystem doesn't overwrite one with another. Of each set of
+/// colliding paths, the first in byte order is kept, and the rest are renamed by `disambiguated_path`.
+/// 
+pub fn disambiguate_case_collisions(targets: &mut [PathBuf]) {
+    let mut collisions: HashMap<String, Vec<usize>> = HashMap::new();
+    for (i, target) in targets.iter().enumerate() {
+        collisions.entry(target.to_string_lossy().to_ascii_lowercase()).or_default().push(i);
+    }
+
+    for mut colliding in collisions.into_values().filter(|colliding| colliding.len() > 1) {
+        colliding.sort_by(|a, b| targets[*a].cmp(&targets[*b]));
+        for i in colliding.into_iter().skip(1) {
+            targets[i] = disambiguated_path(&targets[i]);
+        }
+    }
+}
+
+///
+/// Appends a short hash of the whole of `path` to its file name, before any extension, ie.
+/// `/src/makefile` becomes `/src/makefile~6f1e0c`. The same path is always renamed the same way,
+/// and paths differing only in case are renamed differently.
+/// 
+pub fn disambiguated_path(path: &Path) -> PathBuf {
+    let hsh = format!("{:x}", md5::compute(path.to_string_lossy().as_bytes()));
+    let file_name = path.file_name().map_or(String::new(), |name| name.to_string_lossy().to_string());
+    let renamed = match file_name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{}~{}.{}", stem, &hsh[..6], ext),
+        _ => format!("{}~{}", file_name, &hsh[..6])
+    };
+
+    // The hash can push a name which was already as long as allowed past the limit
+    path.with_file_name(clamp_file_name(&renamed).as_ref())
+}
+
+///
+/// Splits `path` into its components on both `/` and `\`, 
+/// with a leading separator kept as its own component
+/// 
+fn components(path: &str) -> Vec<&str> {
+    let mut components = Vec::new();
+    if path.starts_with(['/', '\\']) {
+        components.push("/");
+    }
+    components.extend(path.split(['/', '\\']).filter(|component| !component.is_empty()));
+
+    components
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use crate::file_svc::long_path::MAX_NAME_BYTES;
+
+    use super::{disambiguate_case_collisions, disambiguated_path, PathMap, PathMapError, PathMapper};
+
+    fn mapper(maps: &[&str]) -> PathMapper {
+        PathMapper::new(maps.iter().map(|map| map.parse::<PathMap>().unwrap()))
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("/home/chris=/Users/chris".parse(), Ok(PathMap { from: "/home/chris".to_string(), to: "/Users/chris".to_string() }));
+        assert!("/home/chris".parse::<PathMap>().is_err());
+        assert!("=/Users/chris".parse::<PathMap>().is_err());
+    }
+
+    #[test]
+    fn test_map_longest_prefix_wins() {
+        let mapper = mapper(&["/home/chris/docs=/mnt/docs", "/home/chris=/Users/chris", "/home=/old"]);
+
+        assert_eq!(mapper.map("/home/chris/docs/a.txt"), Some(PathBuf::from("/mnt/docs/a.txt")));
+        assert_eq!(mapper.map("/home/chris/music/b.mp3"), Some(PathBuf::from("/Users/chris/music/b.mp3")));
+        assert_eq!(mapper.map("/home/alex/c.txt"), Some(PathBuf::from("/old/alex/c.txt")));
+        assert_eq!(mapper.map("/var/d.txt"), None);
+    }
+
+    #[test]
+    fn test_map_later_map_wins_ties() {
+        let mapper = mapper(&["/home/chris=/Users/chris", "/home/chris/=/mnt/chris"]);
+
+        assert_eq!(mapper.map("/home/chris/a.txt"), Some(PathBuf::from("/mnt/chris/a.txt")));
+    }
+
+    #[test]
+    fn test_map_respects_component_boundaries() {
+        let mapper = mapper(&["/home/chris=/Users/chris"]);
+
+        assert_eq!(mapper.map("/home/chrisx/a.txt"), None);
+        assert_eq!(mapper.map("/home/chris"), Some(PathBuf::from("/Users/chris")));
+    }
+
+    #[test]
+    fn test_map_windows_drive_prefix() {
+        let mapper = mapper(&["C:\\Users\\chris=/home/chris"]);
+
+        assert_eq!(mapper.map("C:/Users/chris/docs/a.txt"), Some(PathBuf::from("/home/chris/docs/a.txt")));
+        assert_eq!(mapper.map("C:\\Users\\chris\\docs\\a.txt"), Some(PathBuf::from("/home/chris/docs/a.txt")));
+        assert_eq!(mapper.map("D:/Users/chris/a.txt"), None);
+    }
+
+    #[test]
+    fn test_map_within_root() {
+        let mapper = mapper(&["/home/chris=/restore/chris", "/home/alex=/elsewhere"]);
+        let root = Path::new("/restore");
+
+        assert_eq!(mapper.map_within("/home/chris/a.txt", root), Ok(PathBuf::from("/restore/chris/a.txt")));
+        assert_eq!(
+            mapper.map_within("/home/alex/a.txt", root), 
+            Err(PathMapError::OutsideRoot(PathBuf::from("/elsewhere/a.txt")))
+        );
+        assert!(mapper.map_within("/home/chris/../../etc/passwd", root).is_err());
+        assert!(mapper.map_within("/var/a.txt", root).is_err());
+    }
+
+    #[test]
+    fn test_disambiguated_path() {
+        let renamed = disambiguated_path(Path::new("/src/makefile"));
+        let name = renamed.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("makefile~") && name.len() == "makefile~".len() + 6, "{}", name);
+        assert_eq!(renamed.parent(), Some(Path::new("/src")));
+
+        // The extension is kept, and the same path is always renamed the same way
+        let renamed = disambiguated_path(Path::new("/src/Notes.txt"));
+        assert!(renamed.to_str().unwrap().ends_with(".txt"));
+        assert_eq!(renamed, disambiguated_path(Path::new("/src/Notes.txt")));
+        assert_ne!(renamed, disambiguated_path(Path::new("/src/notes.txt")));
+        assert!(disambiguated_path(Path::new("/src/.bashrc")).to_str().unwrap().starts_with("/src/.bashrc~"));
+
+        // Names already as long as allowed stay within the limit
+        let long = format!("/src/{}.txt", "a".repeat(MAX_NAME_BYTES - 4));
+        let renamed = disambiguated_path(Path::new(&long));
+        assert_eq!(renamed.file_name().unwrap().len(), MAX_NAME_BYTES);
+        assert_ne!(renamed, disambiguated_path(Path::new(&long.to_uppercase())));
+    }
+
+    #[test]
+    fn test_disambiguate_case_collisions() {
+        let mut targets: Vec<PathBuf> = ["/r/src/makefile", "/r/src/Makefile", "/r/src/main.rs", "/r/SRC/MAKEFILE", "/r/docs/makefile"]
+            .iter().map(PathBuf::from).collect();
+
+        disambiguate_case_collisions(&mut targets);
+
+        // Of the colliding paths, the first in byte order keeps its name
+        assert_eq!(targets[0], disambiguated_path(Path::new("/r/src/makefile")));
+        assert_eq!(targets[1], disambiguated_path(Path::new("/r/src/Makefile")));
+        assert_eq!(targets[3], PathBuf::from("/r/SRC/MAKEFILE"));
+        assert_eq!(&targets[2..3], &[PathBuf::from("/r/src/main.rs")]);
+        assert_eq!(targets[4], PathBuf::from("/r/docs/makefile"));
+        let lowercase: std::collections::HashSet<String> = targets.iter().map(|t| t.to_string_lossy().to_ascii_lowercase()).collect();
+        assert_eq!(lowercase.len(), targets.len());
+    }
+}
@@ -0,0 +1,64 @@
+use std::fmt::Display;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// No data layer was given. There's no default, since it needs a catalog to work against
+    MissingDataLayer,
+    /// No config was given
+    MissingConfig,
+    /// The config has no `backup_globs` or `glob_list_file`, so a run would find nothing to back up
+    NoBackupGlobs,
+    /// The config's `max_copies` is negative. Configs read from JSON reject it, but one built in code may not
+    InvalidMaxCopies(i32),
+    /// The config's `max_run_duration` isn't written like "4h" or "1h30m"
+    InvalidMaxRunDuration(String),
+    /// No backup service was given, and the config has no destination to create one for
+    NoDestination,
+    HistoryError(crate::history_service::error::Error),
+    BackupServiceError(crate::backup_service::error::Error),
+    ScanError(crate::file_svc::error::Error)
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::MissingDataLayer => write!(f, "the pipeline needs a data layer to record backups in"),
+            Error::MissingConfig => write!(f, "the pipeline needs a config"),
+            Error::NoBackupGlobs => write!(f, "the config has no backup_globs or glob_list_file, so there's nothing to back up"),
+            Error::InvalidMaxCopies(max_copies) => write!(f, "max_copies can't be negative, but is {}", max_copies),
+            Error::InvalidMaxRunDuration(duration) => write!(f, "max_run_duration \"{}\" should be written like \"4h\" or \"1h30m\"", duration),
+            Error::NoDestination => write!(f, "the config has no backup destination, and no backup service was given"),
+            Error::HistoryError(e) => write!(f, "{:?}", e),
+            Error::BackupServiceError(e) => write!(f, "{}", e),
+            Error::ScanError(e) => write!(f, "{:?}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error { }
+
+impl From<crate::history_service::error::Error> for Error {
+    fn from(value: crate::history_service::error::Error) -> Self {
+        Error::HistoryError(value)
+    }
+}
+
+impl From<crate::backup_service::error::Error> for Error {
+    fn from(value: crate::backup_service::error::Error) -> Self {
+        Error::BackupServiceError(value)
+    }
+}
+
+impl From<crate::backup_service::error::BackupError> for Error {
+    fn from(value: crate::backup_service::error::BackupError) -> Self {
+        Error::BackupServiceError(value.kind)
+    }
+}
+
+impl From<crate::file_svc::error::Error> for Error {
+    fn from(value: crate::file_svc::error::Error) -> Self {
+        Error::ScanError(value)
+    }
+}
@@ -0,0 +1,673 @@
+pub mod error;
+mod stages;
+
+use std::{collections::{HashMap, HashSet}, fmt::Display, fs::Metadata, path::{Path, PathBuf}, sync::Arc};
+
+use chrono::Duration;
+use tokio::sync::{mpsc, Mutex};
+use tokio_util::sync::CancellationToken;
+
+use error::*;
+use stages::{backup_stage, hash_stage, print, scan_stage, status_check_stage, warn_unseen_root, BackupStageOutcome, ScanPlan, ScanStageOutcome};
+pub use stages::delete_backup_keeping_dependents;
+
+use crate::{
+    backup_service::{error::Error as BackupErrorKind, factory::create_backup_service, BackupService},
+    cli::formatter::{ColoredStatusFormatter, FileOutcome},
+    config::{BackupDestination, ChangeDetection, Config},
+    estimate::{estimate, format_count, parse_duration},
+    failed_files::{FailedFiles, FAILED_FILES_NAME},
+    file_svc::{error::Error as ScanError, filter::PathFilter, long_path::long_path, permissions_of, FileScanner, ScannedFiles},
+    history_service::{data_layer::DataLayer, models::{RunOrigin, RunStats, RunStatus, RunTrigger}, EmptyDir, FileHistoryService, HistoryService},
+    path_map::PathMapper,
+    seed::Mirror,
+    status::{order_least_recently_seen, summarize_dry_run, ScannedFile},
+    summary::{Change, RunSummary},
+    time_provider::{CoreTimeProvider, TimeProvider},
+    verify::{verify_backups, VerifyReport}
+};
+
+///
+/// The number of items which can be waiting between two stages of the backup pipeline
+/// 
+const STAGE_CHANNEL_SIZE: usize = 64;
+///
+/// The number of files whose directories a first run creates at once, before sending them on to be hashed
+/// 
+const DIR_TREE_BATCH_SIZE: usize = 1024;
+///
+/// The number of previous runs the start-of-run estimate is based on
+/// 
+const ESTIMATE_HISTORY_RUNS: u32 = 5;
+
+///
+/// Assembles a `BackupPipeline` from its components, for using drive_backup as a library.
+/// Only the data layer and config must be given. The time provider defaults to the system
+/// clock, and the backup service to one storing backups in the config's destination.
+///
+/// ```no_run
+/// # async fn example(db: sqlx::SqlitePool, config: drive_backup::config::Config) -> drive_backup::pipeline::error::Result<()> {
+/// use std::sync::Arc;
+///
+/// use drive_backup::{history_service::{data_layer::DbDataLayer, models::RunOrigin}, pipeline::BackupPipelineBuilder};
+///
+/// let mut pipeline = BackupPipelineBuilder::new()
+///     .with_data_layer(Arc::new(DbDataLayer::new(&db)))
+///     .with_config(config)
+///     .build().await?;
+/// let stats = pipeline.run(&RunOrigin::default()).await?;
+/// println!("Backed up {} of {} files", stats.files_backed_up, stats.files_scanned);
+/// # Ok(())
+/// # }
+/// ```
+/// 
+#[derive(Default)]
+pub struct BackupPipelineBuilder {
+    data_layer: Option<Arc<dyn DataLayer>>,
+    backup_service: Option<Box<dyn BackupService>>,
+    time_provider: Option<Arc<dyn TimeProvider>>,
+    config: Option<Config>,
+    filters: Vec<Arc<dyn PathFilter>>,
+    formatter: Option<ColoredStatusFormatter>
+}
+
+impl BackupPipelineBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_data_layer(mut self, data_layer: Arc<dyn DataLayer>) -> Self {
+        self.data_layer = Some(data_layer);
+        self
+    }
+
+    ///
+    /// Sets where backups are stored. Held in a `Box` rather than shared,
+    /// since backing up needs exclusive access to the service
+    /// 
+    pub fn with_backup_service(mut self, backup_service: Box<dyn BackupService>) -> Self {
+        self.backup_service = Some(backup_service);
+        self
+    }
+
+    pub fn with_time_provider(mut self, time_provider: Arc<dyn TimeProvider>) -> Self {
+        self.time_provider = Some(time_provider);
+        self
+    }
+
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    ///
+    /// Adds a filter deciding which of the files found by the config's globs are backed up.
+    /// Filters run in the order they're added, after the config's exclude globs and size limits
+    /// 
+    pub fn with_path_filter(mut self, filter: Arc<dyn PathFilter>) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    ///
+    /// Prints each file backed up, pruned or failed as it's handled, along with the run's estimate.
+    /// Without a formatter, runs print nothing
+    /// 
+    pub fn with_formatter(mut self, formatter: ColoredStatusFormatter) -> Self {
+        self.formatter = Some(formatter);
+        self
+    }
+
+    ///
+    /// Checks the components fit together, filling in the defaults of any not given,
+    /// and builds the pipeline
+    /// 
+    pub async fn build(self) -> Result<BackupPipeline> {
+        let config = self.config.ok_or(Error::MissingConfig)?;
+        let data_layer = self.data_layer.ok_or(Error::MissingDataLayer)?;
+        if config.backup_globs.is_empty() && config.glob_list_file.is_none() {
+            return Err(Error::NoBackupGlobs);
+        }
+        if config.max_copies < 0 {
+            return Err(Error::InvalidMaxCopies(config.max_copies));
+        }
+        let max_run_duration = match &config.max_run_duration {
+            Some(duration) => Some(parse_duration(duration).ok_or_else(|| Error::InvalidMaxRunDuration(duration.clone()))?),
+            None => None
+        };
+        let backup_service = match self.backup_service {
+            Some(backup_service) => backup_service,
+            None => match config.default_destination() {
+                BackupDestination::Local { path } if path.is_empty() => return Err(Error::NoDestination),
+                destination => create_backup_service(&destination)?
+            }
+        };
+        let time_provider = self.time_provider.unwrap_or_else(|| Arc::new(CoreTimeProvider::new()));
+        let history = FileHistoryService::new(data_layer, time_provider, config.max_copies, config.canonicalize).await?;
+
+        Ok(BackupPipeline { history, backup_service, config, max_run_duration, filters: self.filters, formatter: self.formatter })
+    }
+}
+
+///
+/// How a run differs from backing up every file matching the config's globs
+/// 
+#[derive(Default)]
+pub struct RunOptions {
+    /// What started the run
+    pub origin: RunOrigin,
+    /// The only paths backed up, instead of the files matching the config's globs.
+    /// A run given them doesn't see every file, so doesn't mark deleted files
+    pub listed_paths: Option<Vec<PathBuf>>,
+    /// A mirror of the globs' roots. Each file whose copy in the mirror has the same size and
+    /// modification time is read from the copy instead, and recorded as if it were read from the file itself
+    pub mirror_root: Option<PathBuf>,
+    /// Whether each file found unchanged is printed as it's checked
+    pub verbose: bool
+}
+
+///
+/// What a run did, beyond the stats recorded with it
+/// 
+pub struct RunReport {
+    pub run_id: i64,
+    pub stats: RunStats,
+    /// Every change the run made, by directory
+    pub summary: RunSummary,
+    /// The `max_run_duration` the run stopped at before examining every file, if it did
+    pub time_boxed: Option<Duration>,
+    /// The destination which became unavailable, stopping the run before every file was backed up
+    pub lost_destination: Option<PathBuf>,
+    /// How many files each of the config's filters excluded
+    pub exclusion_counts: Vec<(String, u64)>
+}
+
+///
+/// The files a dry run found
+/// 
+struct Scan {
+    files: Vec<(PathBuf, Metadata)>,
+    /// Paths which couldn't be read, whose files mustn't be taken as deleted
+    skipped: Vec<PathBuf>
+}
+
+///
+/// Backs up, restores and verifies files with components it owns, as assembled by a `BackupPipelineBuilder`
+/// 
+pub struct BackupPipeline {
+    history: FileHistoryService,
+    backup_service: Box<dyn BackupService>,
+    config: Config,
+    max_run_duration: Option<Duration>,
+    filters: Vec<Arc<dyn PathFilter>>,
+    formatter: Option<ColoredStatusFormatter>
+}
+
+impl BackupPipeline {
+    ///
+    /// Backs up every file matching the config's globs which changed since it was last backed up,
+    /// then marks the files which were excluded or deleted since, as `run_with` does.
+    /// Files which couldn't be read are counted in the returned stats' errors, and aren't marked as deleted
+    /// 
+    pub async fn run(&mut self, origin: &RunOrigin) -> Result<RunStats> {
+        Ok(self.run_with(RunOptions { origin: origin.clone(), ..Default::default() }).await?.stats)
+    }
+
+    ///
+    /// Backs up every file found by the config's globs, or given in the `options`, which changed since
+    /// it was last backed up. Files are hashed, checked against the catalog and backed up by stages
+    /// running concurrently. Unless the run was given its paths, or stopped before examining every file,
+    /// the files which were excluded or deleted since are then marked. A destination in maintenance
+    /// mode fails the run before anything is written to it
+    /// 
+    pub async fn run_with(&mut self, options: RunOptions) -> Result<RunReport> {
+        self.check_not_in_maintenance().await?;
+        for policy in self.history.get_mismatched_path_policies().await? {
+            tracing::warn!(
+                "The history contains paths canonicalized with the \"{}\" policy, but \"{}\" is configured. \
+                The same file may be recorded under more than one path.",
+                policy, self.config.canonicalize.as_str()
+            );
+        }
+
+        let scanner = self.scanner();
+        // A run backing up a given list of paths doesn't see every file, so can't tell which were deleted
+        let ad_hoc = options.listed_paths.is_some();
+        let listed_count = options.listed_paths.as_ref().map(|paths| paths.len() as u64);
+        let scanned = self.scanned_files(&scanner, options.listed_paths)?;
+        let failed_files_path = self.failed_files_path();
+        let mut failed_files = match &failed_files_path {
+            Some(path) => FailedFiles::load(path).unwrap_or_else(|e| {
+                tracing::warn!("Couldn't read the files which failed to back up from {}: {}", path.display(), e);
+                FailedFiles::default()
+            }),
+            None => FailedFiles::default()
+        };
+        // Files are examined as they're found, unless the run has a deadline. Then the files left over by a run
+        // stopped at its deadline are examined first, followed by those whose backups failed last time, so every
+        // file has to be found before any is examined. Without a deadline, every file is reached anyway
+        let (scanned, ordered_count): (ScannedFiles, Option<u64>) = match self.max_run_duration {
+            Some(_) => {
+                let (mut files, mut unscanned) = (Vec::new(), Vec::new());
+                for file in scanned {
+                    match file {
+                        Ok(file) => files.push(file),
+                        Err(e) => unscanned.push(Err(e))
+                    }
+                }
+                order_least_recently_seen(&mut files, |(path, _)| path.as_path(), self.history.get_latest_files().await?);
+                failed_files.move_to_front(&mut files, |(path, _)| path.as_path());
+                let count = files.len() as u64;
+                (Box::new(unscanned.into_iter().chain(files.into_iter().map(Ok))), Some(count))
+            },
+            None => (scanned, None)
+        };
+        if self.config.eager_hash_migration {
+            let report = self.history.rehash(&*self.backup_service, self.config.hash_algorithm).await?;
+            if report.rehashed > 0 {
+                self.print_plain(format!("Converted {} hashes to {}", format_count(report.rehashed), self.config.hash_algorithm.as_str()));
+            }
+            for (id, reason) in &report.failures {
+                tracing::warn!("The hash of the backup with id={} wasn't converted: {}", id, reason);
+            }
+        }
+        let recent_runs = self.history.get_recent_runs(ESTIMATE_HISTORY_RUNS).await?;
+        // Files found as they're examined aren't counted beforehand, so are taken to be as many as the last run examined
+        let expected_files = ordered_count.or(listed_count).or_else(|| recent_runs.iter()
+            .find(|run| run.status == RunStatus::Completed && run.origin.trigger != RunTrigger::AdHoc)
+            .and_then(|run| run.files_scanned)
+            .map(|count| count as u64));
+        if let Some(count) = expected_files {
+            self.print_plain(estimate(count, &recent_runs));
+        }
+
+        let run_id = self.history.begin_run(&self.config.snapshot(), &options.origin).await?;
+        // Files whose policy allows it are judged unchanged by their size and modification time, and
+        // files the catalog already has, which weren't modified since the last run to examine every file began,
+        // aren't hashed either
+        let by_metadata = self.config.policies.iter().any(|policy| policy.change_detection == Some(ChangeDetection::Metadata));
+        let cutoff = match self.config.skip_unmodified_since_last_run {
+            true => self.history.get_last_run_ts().await?,
+            false => None
+        };
+        let catalog = match by_metadata || cutoff.is_some() {
+            true => self.history.get_latest_files().await?.into_iter().map(|entry| (PathBuf::from(&entry.full_path), entry)).collect(),
+            false => HashMap::new()
+        };
+        let plan = ScanPlan {
+            config: &self.config,
+            catalog,
+            cutoff,
+            mirror: options.mirror_root.clone().map(|root| Mirror::new(root, scanner.glob_roots())),
+            roots: match ad_hoc {
+                true => Vec::new(),
+                false => scanner.glob_roots()
+            },
+            first_run: recent_runs.is_empty(),
+            verbose: options.verbose
+        };
+        let (scan_tx, scan_rx) = mpsc::channel(STAGE_CHANNEL_SIZE);
+        let (hash_tx, hash_rx) = mpsc::channel(STAGE_CHANNEL_SIZE);
+        let (backup_tx, backup_rx) = mpsc::channel(STAGE_CHANNEL_SIZE);
+        let history = Mutex::new(&mut self.history as &mut dyn HistoryService);
+        let summary = Mutex::new(RunSummary::new());
+        let formatter = self.formatter.as_ref();
+
+        // Each stage runs concurrently, so files are hashed, checked and backed up
+        // while the rest are still being found
+        let (scanned, hash_errors, time_boxed, backed_up) = tokio::join!(
+            scan_stage(scanned, &scanner, &plan, &history, &summary, formatter, scan_tx),
+            hash_stage(self.config.hash_algorithm, scan_rx, hash_tx),
+            status_check_stage(&history, &summary, formatter, options.verbose, self.max_run_duration, hash_rx, backup_tx),
+            backup_stage(&history, &mut *self.backup_service, &self.config, formatter, backup_rx)
+        );
+        let ScanStageOutcome { files_scanned, mut skipped, mut errors, file_dirs, read_from_mirror, completed } = scanned?;
+        let mut summary = summary.into_inner();
+        if let Some(root) = &options.mirror_root {
+            self.print_plain(format!(
+                "{} of {} files were read from the mirror {}", format_count(read_from_mirror), format_count(files_scanned), root.display()
+            ));
+        }
+        let (time_boxed, backed_up) = (time_boxed?, backed_up?);
+
+        let BackupStageOutcome { files_backed_up, bytes_backed_up, failures: backup_failures, too_large, lost_destination } = backed_up;
+        errors.extend(hash_errors.iter().map(ToString::to_string)
+            .chain(backup_failures.iter().map(|(path, e)| format!("Failed to backup file {}: {}", path.display(), e)))
+            .chain(too_large.iter().map(|(path, e)| format!("Skipped backing up {}: {}", path.display(), e)))
+            .chain(lost_destination.iter().map(|destination| format!("The backup destination {} became unavailable", destination.display()))));
+        // A run stopped early by its destination disappearing didn't reach every file, like a time-boxed run
+        let stopped_early = time_boxed || lost_destination.is_some();
+        let mut failures: Vec<(PathBuf, String)> = Vec::new();
+        for e in hash_errors {
+            skipped.extend(e.path().map(Path::to_path_buf));
+            summary.record_error(e.path(), &e);
+            failures.extend(e.path().map(|path| (path.to_path_buf(), e.to_string())));
+        }
+        for (path, e) in &backup_failures {
+            summary.record_error(Some(path), e);
+            failures.push((path.clone(), e.to_string()));
+        }
+        for (path, e) in &too_large {
+            summary.record_error(Some(path), e);
+        }
+        self.record_failed_files(&mut failed_files, failed_files_path.as_deref(), &failures, stopped_early);
+        // Files the run didn't reach weren't seen, so would be wrongly marked as deleted
+        if !stopped_early && !ad_hoc {
+            // Files excluded since they were backed up still exist, so they're marked apart from deleted files
+            let catalog = self.history.get_latest_files().await?.into_iter()
+                .filter(|entry| entry.file_size.is_some())
+                .map(|entry| PathBuf::from(entry.full_path));
+            let excluded = scanner.excluded_paths(catalog)?;
+            for path in self.history.mark_excluded(&excluded).await? {
+                summary.record(&path, Change::Excluded, 0);
+            }
+            for path in self.history.mark_all_deleted_files(&skipped).await? {
+                summary.record(&path, Change::Deleted, 0);
+            }
+        }
+        // The directories holding the files a stopped run didn't reach aren't known, so the empty directories found last are kept
+        if completed || !self.config.include_empty_dirs || ad_hoc {
+            let empty_dirs: Vec<EmptyDir> = match self.config.include_empty_dirs && !ad_hoc {
+                true => scanner.scan_empty_dirs(file_dirs.iter().map(PathBuf::as_path))?.into_iter()
+                    .map(|(path, metadata)| EmptyDir { permissions: permissions_of(&metadata), path })
+                    .collect(),
+                false => Vec::new()
+            };
+            self.history.record_empty_dirs(&empty_dirs).await?;
+        }
+        // Nothing can be removed from a destination which is gone
+        if lost_destination.is_none() {
+            if let Some(days) = self.config.tombstone_retention_days {
+                for file_id in self.history.prune_expired_generations(Duration::days(days)).await? {
+                    delete_backup_keeping_dependents(&self.history, &mut *self.backup_service, file_id).await?;
+                    print(self.formatter.as_ref(), FileOutcome::Pruned, format!("expired backup {}", file_id));
+                }
+            }
+            for hsh in self.history.take_unreferenced_chunks().await? {
+                self.backup_service.delete_chunk(&hsh).await?;
+            }
+        }
+        let stats = RunStats { 
+            files_scanned, files_backed_up, files_skipped: summary.totals().unchanged, bytes_backed_up, errors, partial: stopped_early
+        };
+        self.history.complete_run(run_id, stats.clone()).await?;
+        if let Some(runs) = self.config.maintenance.auto_vacuum_after_runs.filter(|runs| *runs > 0) {
+            if run_id % runs as i64 == 0 {
+                self.history.vacuum().await?;
+            }
+        }
+
+        Ok(RunReport {
+            run_id, stats, summary, 
+            time_boxed: self.max_run_duration.filter(|_| time_boxed), 
+            lost_destination, 
+            exclusion_counts: scanner.exclusion_counts()
+        })
+    }
+
+    ///
+    /// Summarizes what a run given the `listed_paths`, if any, would change, judging files by their size
+    /// and modification time without hashing them. Nothing is backed up, and the catalog isn't changed
+    /// 
+    pub async fn dry_run(&self, listed_paths: Option<Vec<PathBuf>>) -> Result<RunSummary> {
+        self.check_not_in_maintenance().await?;
+        let scanner = self.scanner();
+        // A run backing up a given list of paths doesn't mark deleted files, so neither does its dry run
+        let roots = match listed_paths.is_some() {
+            true => Vec::new(),
+            false => scanner.glob_roots()
+        };
+        let Scan { files, skipped } = self.scan(&scanner, listed_paths)?;
+        let catalog = self.history.get_latest_files().await?;
+        let excluded = scanner.excluded_paths(catalog.iter().map(|entry| PathBuf::from(&entry.full_path)))?;
+        let scanned = files.iter().filter_map(|(path, metadata)| ScannedFile::from_metadata(path.clone(), metadata).ok());
+
+        Ok(summarize_dry_run(scanned, catalog, &roots, &skipped, &excluded))
+    }
+
+    ///
+    /// Restores the latest version of every file matching `query` inside `root`,
+    /// applying the config's path maps. Returns where each file was restored to
+    /// 
+    pub async fn restore(&self, query: &str, root: &Path) -> Result<Vec<PathBuf>> {
+        let mapper = PathMapper::new(self.config.path_maps.iter().cloned());
+        let mut restored = Vec::new();
+        for file in self.history.search(query).await? {
+            let Some(latest) = self.history.get_latest_version(file.dir_id, &file.file_name).await? else {
+                continue;
+            };
+            let target = match mapper.map_within(&file.full_path, root) {
+                Ok(target) => target,
+                Err(e) => {
+                    tracing::warn!("Not restoring {}: {}", file.full_path, e);
+                    continue;
+                }
+            };
+            if let Some(parent) = target.parent() {
+                tokio::fs::create_dir_all(long_path(parent)).await.map_err(crate::backup_service::error::Error::from)?;
+            }
+            self.backup_service.restore_data(latest.id, &target).await?;
+            restored.push(target);
+        }
+
+        Ok(restored)
+    }
+
+    ///
+    /// Re-hashes every backup against the hash recorded for it, with up to `concurrency` read at once,
+    /// continuing from where the last verification stopped if `resume` is set
+    /// 
+    pub async fn verify(&self, resume: bool, concurrency: usize) -> Result<VerifyReport> {
+        Ok(verify_backups(&self.history, &*self.backup_service, resume, concurrency, &CancellationToken::new()).await?)
+    }
+
+    async fn check_not_in_maintenance(&self) -> Result<()> {
+        match self.backup_service.maintenance_note().await? {
+            Some(note) => Err(BackupErrorKind::MaintenanceMode(note).into()),
+            None => Ok(())
+        }
+    }
+
+    fn scanner(&self) -> FileScanner {
+        self.filters.iter().cloned().fold(FileScanner::from_config(&self.config), FileScanner::with_filter)
+    }
+
+    ///
+    /// Starts finding the files matching the config's globs with the `scanner`, or the `listed_paths` if given.
+    /// A pattern which can't be parsed fails straight away, rather than once the files are taken
+    /// 
+    fn scanned_files(&self, scanner: &FileScanner, listed_paths: Option<Vec<PathBuf>>) -> Result<ScannedFiles> {
+        Ok(match listed_paths {
+            Some(paths) => Box::new(scanner.scan_listed(paths)),
+            None => scanner.scan_with_metadata()?
+        })
+    }
+
+    ///
+    /// Finds every file matching the config's globs with the `scanner`, or the `listed_paths` if given.
+    /// A glob root under which no files were found is taken as unmounted or unreadable, so is skipped
+    /// 
+    fn scan(&self, scanner: &FileScanner, listed_paths: Option<Vec<PathBuf>>) -> Result<Scan> {
+        let ad_hoc = listed_paths.is_some();
+        let scanned = self.scanned_files(scanner, listed_paths)?;
+        let mut skipped = Vec::new();
+        let files: Vec<(PathBuf, Metadata)> = scanned
+            .filter_map(|file| file.map_err(|e| {
+                tracing::warn!("{}", describe_unscanned(&e));
+                skipped.extend(e.path().map(Path::to_path_buf));
+            }).ok())
+            .collect();
+        for root in scanner.glob_roots().into_iter().filter(|_| !ad_hoc) {
+            if !files.iter().any(|(path, _)| path.starts_with(&root)) {
+                warn_unseen_root(&root);
+                skipped.push(root);
+            }
+        }
+
+        Ok(Scan { files, skipped })
+    }
+
+    fn print_plain(&self, line: impl Display) {
+        if let Some(formatter) = &self.formatter {
+            formatter.print_plain(line);
+        }
+    }
+
+    ///
+    /// Gets where the files whose backups failed are listed between runs: beside the backups in
+    /// the default destination, if it's local. Otherwise they aren't kept, and aren't retried first
+    /// 
+    fn failed_files_path(&self) -> Option<PathBuf> {
+        match self.config.default_destination() {
+            BackupDestination::Local { path } if !path.is_empty() => Some(Path::new(&path).join(FAILED_FILES_NAME)),
+            _ => None
+        }
+    }
+
+    ///
+    /// Updates `failed_files` with the run's `failures`, taking off every other file which was
+    /// listed, then saves it to `path`. A `time_boxed` run may not have reached the files listed,
+    /// so only updates those which failed again
+    /// 
+    fn record_failed_files(&self, failed_files: &mut FailedFiles, path: Option<&Path>, failures: &[(PathBuf, String)], time_boxed: bool) {
+        let failing: HashSet<&Path> = failures.iter().map(|(path, _)| path.as_path()).collect();
+        let recovered: Vec<PathBuf> = failed_files.files().iter()
+            .map(|file| file.path.clone())
+            .filter(|path| !time_boxed && !failing.contains(path.as_path()))
+            .collect();
+        for recovered in recovered {
+            failed_files.record_success(&recovered);
+        }
+        for (failed, error) in failures {
+            if !failed_files.record_failure(failed, error, self.config.max_file_retry_attempts) {
+                tracing::warn!(
+                    "{} failed to back up in {} runs in a row, so it's no longer retried first",
+                    failed.display(), self.config.max_file_retry_attempts
+                );
+            }
+        }
+
+        let Some(path) = path else { return };
+        if let Err(e) = failed_files.save(path) {
+            tracing::warn!("Couldn't record the files which failed to back up in {}: {}", path.display(), e);
+        }
+    }
+}
+
+///
+/// Describes why a file matched by the config's globs couldn't be scanned
+/// 
+pub fn describe_unscanned(error: &ScanError) -> String {
+    match error {
+        ScanError::MetadataError(path, e) | ScanError::UnreadableDir(path, e) => format!("Could not read {}: {}", path.display(), e),
+        ScanError::NotAFile(path) => format!("Skipping {}, which isn't a regular file", path.display()),
+        e => format!("Could not scan a file: {:?}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    use crate::{
+        backup_service::{BackupService, FileBackupService},
+        config::Config,
+        history_service::data_layer::MockDataLayer,
+        time_provider::MockTimeProvider
+    };
+
+    use super::{error::Error, BackupPipelineBuilder};
+
+    fn config(globs: &[&str], backup_path: &str, max_copies: i32) -> Config {
+        serde_json::from_value(json!({
+            "schema_version": 2,
+            "backup_globs": globs,
+            "backup_path": backup_path,
+            "max_copies": max_copies
+        })).unwrap()
+    }
+
+    fn data_layer() -> Arc<MockDataLayer> {
+        Arc::new(MockDataLayer::new())
+    }
+
+    #[tokio::test]
+    async fn test_build_rejects_invalid_combinations() {
+        let dir = tempfile::tempdir().unwrap();
+        let backups = dir.path().join("backups").to_string_lossy().to_string();
+
+        let missing_config = BackupPipelineBuilder::new().with_data_layer(data_layer()).build().await;
+        assert!(matches!(missing_config, Err(Error::MissingConfig)));
+        let missing_data_layer = BackupPipelineBuilder::new().with_config(config(&["/data/**/*"], &backups, 2)).build().await;
+        assert!(matches!(missing_data_layer, Err(Error::MissingDataLayer)));
+        let no_globs = BackupPipelineBuilder::new().with_data_layer(data_layer()).with_config(config(&[], &backups, 2)).build().await;
+        assert!(matches!(no_globs, Err(Error::NoBackupGlobs)));
+        let mut negative_copies = config(&["/data/**/*"], &backups, 2);
+        negative_copies.max_copies = -1;
+        let negative_copies = BackupPipelineBuilder::new().with_data_layer(data_layer()).with_config(negative_copies).build().await;
+        assert!(matches!(negative_copies, Err(Error::InvalidMaxCopies(-1))));
+        let mut unparsable_duration = config(&["/data/**/*"], &backups, 2);
+        unparsable_duration.max_run_duration = Some("soon".to_string());
+        let unparsable_duration = BackupPipelineBuilder::new().with_data_layer(data_layer()).with_config(unparsable_duration).build().await;
+        assert!(matches!(unparsable_duration, Err(Error::InvalidMaxRunDuration(_))));
+        let no_destination = BackupPipelineBuilder::new().with_data_layer(data_layer()).with_config(config(&["/data/**/*"], "", 2)).build().await;
+        assert!(matches!(no_destination, Err(Error::NoDestination)));
+    }
+
+    #[tokio::test]
+    async fn test_build_with_given_components() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup_service: Box<dyn BackupService> = Box::new(FileBackupService::new(dir.path().to_string_lossy().to_string()).unwrap());
+
+        // A backup service given explicitly stands in for the config's missing destination
+        let pipeline = BackupPipelineBuilder::new()
+            .with_data_layer(data_layer())
+            .with_backup_service(backup_service)
+            .with_time_provider(Arc::new(MockTimeProvider::new()))
+            .with_config(config(&["/data/**/*"], "", 2))
+            .build().await;
+        assert!(pipeline.is_ok());
+
+        let backups = dir.path().join("backups").to_string_lossy().to_string();
+        let pipeline = BackupPipelineBuilder::new().with_data_layer(data_layer()).with_config(config(&["/data/**/*"], &backups, 2)).build().await;
+        assert!(pipeline.is_ok());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_run_backs_up_changed_files_and_marks_deleted_ones() {
+        use sqlx::{sqlite::{SqliteConnectOptions, SqlitePoolOptions}, Executor};
+
+        use crate::history_service::data_layer::DbDataLayer;
+
+        use super::RunOptions;
+
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        std::fs::create_dir(&src).unwrap();
+        std::fs::write(src.join("kept.txt"), "kept").unwrap();
+        std::fs::write(src.join("gone.txt"), "gone").unwrap();
+        let db = SqlitePoolOptions::new().max_connections(1)
+            .connect_with(SqliteConnectOptions::new().filename(dir.path().join("catalog.db")).create_if_missing(true)).await.unwrap();
+        db.execute(include_str!("../../sql/create.sql")).await.unwrap();
+        let glob = format!("{}/**/*", src.display());
+        let backups = dir.path().join("backups").to_string_lossy().to_string();
+        // Each pipeline's run is timed from when it was built
+        let pipeline = || BackupPipelineBuilder::new()
+            .with_data_layer(Arc::new(DbDataLayer::new(&db)))
+            .with_config(config(&[&glob], &backups, 2))
+            .build();
+
+        let first = pipeline().await.unwrap().run_with(RunOptions::default()).await.unwrap();
+        assert_eq!((first.stats.files_scanned, first.stats.files_backed_up), (2, 2));
+
+        std::fs::write(src.join("kept.txt"), "changed").unwrap();
+        std::fs::remove_file(src.join("gone.txt")).unwrap();
+        let second = pipeline().await.unwrap().run_with(RunOptions::default()).await.unwrap();
+        assert_eq!((second.stats.files_scanned, second.stats.files_backed_up), (1, 1));
+        let totals = second.summary.totals();
+        assert_eq!((totals.new, totals.modified, totals.deleted), (0, 1, 1));
+    }
+}
@@ -0,0 +1,439 @@
+use std::{collections::{HashMap, HashSet}, fmt::Display, fs::Metadata, path::{Path, PathBuf}};
+
+use chrono::{DateTime, Duration, Utc};
+use futures_util::{pin_mut, Stream, StreamExt};
+use tokio::sync::{mpsc::{Receiver, Sender}, Mutex};
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::{
+    backup_service::{error::Error as BackupErrorKind, BackupService},
+    cli::formatter::{ColoredStatusFormatter, FileOutcome},
+    config::{ChangeDetection, Config, HashAlgorithm},
+    file_svc::{error::Error as ScanError, is_modified_since, FileScanner},
+    hash_svc::{error::Error as HashError, gen_hashes_streamed},
+    history_service::{models::{ChunkModel, LatestFileEntry}, FileStatus, HistoryService},
+    seed::Mirror,
+    status::is_unchanged_by_metadata,
+    summary::{Change, RunSummary}
+};
+
+use super::{describe_unscanned, error::Result, DIR_TREE_BATCH_SIZE};
+
+///
+/// A changed file, waiting in the pipeline to be backed up
+/// 
+pub(crate) struct PendingBackup {
+    path: PathBuf,
+    /// Where the file's contents are read from, being the file itself, or its copy in a mirror
+    read_from: PathBuf,
+    hsh: String,
+    size: u64,
+    sub_dir_id: i64,
+    file_id: i64,
+    file_name: String
+}
+
+///
+/// A hashed file, with where its contents were read from
+/// 
+pub(crate) type Hashed = (PathBuf, PathBuf, String, u64);
+
+///
+/// Prints the line for the given `file` with the `formatter`, if the run prints its progress
+/// 
+pub(crate) fn print(formatter: Option<&ColoredStatusFormatter>, outcome: FileOutcome, file: impl Display) {
+    if let Some(formatter) = formatter {
+        formatter.print(outcome, file);
+    }
+}
+
+///
+/// The files the scan stage finds, how it judges which are unchanged without hashing them,
+/// and where it reads the rest from
+/// 
+pub(crate) struct ScanPlan<'a> {
+    pub config: &'a Config,
+    /// The catalog's latest version of each file, by its path, if any can be judged unchanged without hashing
+    pub catalog: HashMap<PathBuf, LatestFileEntry>,
+    /// When the last run to examine every file began, if the files the catalog has which weren't
+    /// modified since then aren't hashed
+    pub cutoff: Option<DateTime<Utc>>,
+    /// The mirror each file is read from instead, if its copy there matches
+    pub mirror: Option<Mirror>,
+    /// The glob roots under which files are expected, unless the run was given its paths
+    pub roots: Vec<PathBuf>,
+    /// Whether the catalog has no directories yet, so they're created a batch of files at a time
+    pub first_run: bool,
+    /// Whether each file judged unchanged is printed
+    pub verbose: bool
+}
+
+impl ScanPlan<'_> {
+    ///
+    /// Whether the file at `path` is judged unchanged without being hashed: by its size and modification 
+    /// time if its policy allows it, or by not having been modified since the `cutoff`
+    /// 
+    fn is_unchanged(&self, path: &Path, metadata: &Metadata) -> bool {
+        let Some(entry) = self.catalog.get(path) else { return false };
+        let by_metadata = self.config.change_detection(path) == ChangeDetection::Metadata
+            && is_unchanged_by_metadata(path, metadata, Some(entry));
+        let unmodified = entry.file_size.is_some()
+            && self.cutoff.is_some_and(|cutoff| !is_modified_since(metadata, cutoff.naive_utc()));
+        by_metadata || unmodified
+    }
+}
+
+///
+/// A scanned file to be hashed, with where its contents are read from: the file itself, or its copy in a mirror
+/// 
+pub(crate) struct Found {
+    path: PathBuf,
+    metadata: Metadata,
+    read_from: PathBuf
+}
+
+///
+/// What the scan stage found
+/// 
+#[derive(Default)]
+pub(crate) struct ScanStageOutcome {
+    pub files_scanned: u64,
+    /// Paths which couldn't be read, whose files mustn't be taken as deleted
+    pub skipped: Vec<PathBuf>,
+    pub errors: Vec<String>,
+    /// Every directory directly holding a scanned file, if the config includes empty directories
+    pub file_dirs: HashSet<PathBuf>,
+    /// The number of files read from the mirror
+    pub read_from_mirror: u64,
+    /// Whether every file was scanned, rather than the stage stopping once the stages after it did
+    pub completed: bool
+}
+
+///
+/// Takes each of the scanned `files` as it's found, sending those which can't be judged unchanged without
+/// hashing them on to the hash stage, as the `plan` lays out. Files judged unchanged are marked as seen
+/// in the history, and only printed if the plan is verbose. Once every file is scanned, a glob root
+/// under which none were found is taken as unmounted or unreadable, so is skipped.
+/// 
+pub(crate) async fn scan_stage(
+    files: impl Iterator<Item = std::result::Result<(PathBuf, Metadata), ScanError>>,
+    scanner: &FileScanner,
+    plan: &ScanPlan<'_>,
+    history: &Mutex<&mut dyn HistoryService>,
+    summary: &Mutex<RunSummary>,
+    formatter: Option<&ColoredStatusFormatter>,
+    tx: Sender<Found>
+) -> Result<ScanStageOutcome> {
+    let mut outcome = ScanStageOutcome::default();
+    let mut unseen_roots = plan.roots.clone();
+    let mut batch = Vec::new();
+    for file in files {
+        let (path, metadata) = match file {
+            Ok(file) => file,
+            Err(e) => {
+                let description = describe_unscanned(&e);
+                tracing::warn!("{}", description);
+                outcome.skipped.extend(e.path().map(Path::to_path_buf));
+                outcome.errors.push(description);
+                continue;
+            }
+        };
+        outcome.files_scanned += 1;
+        unseen_roots.retain(|root| !path.starts_with(root));
+        if plan.config.include_empty_dirs {
+            outcome.file_dirs.extend(path.parent().map(Path::to_path_buf));
+        }
+        if scanner.is_non_canonical(&path) {
+            history.lock().await.add_non_canonical_path(path.clone());
+        }
+        if plan.is_unchanged(&path, &metadata) {
+            history.lock().await.mark_unchanged(&path).await?;
+            summary.lock().await.record_unchanged_by_metadata(&path, metadata.len());
+            if plan.verbose {
+                print(formatter, FileOutcome::Skipped, path.display());
+            }
+            continue;
+        }
+
+        let read_from = match plan.mirror.as_ref().and_then(|mirror| mirror.matching_copy(&path, &metadata)) {
+            Some(copy) => {
+                outcome.read_from_mirror += 1;
+                copy
+            },
+            None => path.clone()
+        };
+        batch.push(Found { path, metadata, read_from });
+        // On a first run every directory is new, so they're created a batch of files at a time
+        // rather than one at a time as each file is checked
+        if (!plan.first_run || batch.len() >= DIR_TREE_BATCH_SIZE) && !send_found(&mut batch, plan.first_run, history, &tx).await? {
+            return Ok(outcome);
+        }
+    }
+    if !send_found(&mut batch, plan.first_run, history, &tx).await? {
+        return Ok(outcome);
+    }
+
+    for root in unseen_roots {
+        warn_unseen_root(&root);
+        outcome.skipped.push(root);
+    }
+    outcome.completed = true;
+    Ok(outcome)
+}
+
+///
+/// Sends every file in the `batch` to the hash stage, first creating the directories holding them if `create_dirs`.
+/// Returns `false` if the hash stage stopped.
+/// 
+async fn send_found(batch: &mut Vec<Found>, create_dirs: bool, history: &Mutex<&mut dyn HistoryService>, tx: &Sender<Found>) -> Result<bool> {
+    if create_dirs && !batch.is_empty() {
+        let paths: Vec<PathBuf> = batch.iter().map(|file| file.path.clone()).collect();
+        history.lock().await.create_dir_tree(&paths).await?;
+    }
+    for file in batch.drain(..) {
+        if tx.send(file).await.is_err() {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+///
+/// Logs that no files were found under the glob `root`, so its files aren't marked as deleted
+/// 
+pub(crate) fn warn_unseen_root(root: &Path) {
+    tracing::error!(
+        "No files were found under {}. If it's unmounted or unreadable, back up again once it's available. \
+        Its files won't be marked as deleted until then.", root.display()
+    );
+}
+
+///
+/// Hashes each file sent by the scan stage with `algorithm` as it arrives, sending its path, where it was 
+/// read from, hash and size to the status check stage. Files read from a copy in a mirror are read from 
+/// the file itself instead if the copy can't be read. Every file which couldn't be hashed is logged,
+/// and its error returned.
+/// 
+pub(crate) async fn hash_stage(algorithm: HashAlgorithm, rx: Receiver<Found>, tx: Sender<Hashed>) -> Vec<HashError> {
+    let mut errors = Vec::new();
+    let mut unread_copies = Vec::new();
+
+    let found = ReceiverStream::new(rx).map(|file| (file.path, file.read_from, Some(file.metadata)));
+    if !send_hashes(found, algorithm, &tx, &mut unread_copies, &mut errors).await {
+        return errors;
+    }
+    let unread = futures_util::stream::iter(unread_copies.into_iter().map(|path| (path.clone(), path, None)));
+    send_hashes(unread, algorithm, &tx, &mut Vec::new(), &mut errors).await;
+
+    errors
+}
+
+///
+/// Hashes each file as it's read from, sending it to the status check stage under its own path.
+/// Files read from a copy which couldn't be hashed are added to `unread_copies` by their own path,
+/// and every other file which couldn't be hashed to `errors`. Returns `false` if the status check stage stopped.
+/// 
+async fn send_hashes(
+    files: impl Stream<Item = (PathBuf, PathBuf, Option<Metadata>)>,
+    algorithm: HashAlgorithm,
+    tx: &Sender<Hashed>,
+    unread_copies: &mut Vec<PathBuf>,
+    errors: &mut Vec<HashError>
+) -> bool {
+    let hashes = gen_hashes_streamed(files, algorithm);
+
+    pin_mut!(hashes);
+    while let Some((path, hashed)) = hashes.next().await {
+        match hashed {
+            Ok((read_from, hsh, size)) => {
+                if tx.send((path, read_from, hsh, size)).await.is_err() {
+                    return false;
+                }
+            },
+            Err(e) if e.path().is_some_and(|read_from| read_from != path) => {
+                tracing::warn!("{}, so {} is read instead", e, path.display());
+                unread_copies.push(path);
+            },
+            Err(e) => {
+                tracing::error!("{}", e);
+                errors.push(e);
+            }
+        }
+    }
+
+    true
+}
+
+///
+/// Checks whether each hashed file has changed since its latest backup,
+/// sending those which have to the backup stage. Unchanged files are only printed if `verbose`.
+/// Once `max_run_duration` has passed, no more files are checked, and `true` is returned
+/// so the run is recorded as partial. Files already sent to the backup stage are still backed up.
+/// 
+pub(crate) async fn status_check_stage(
+    history: &Mutex<&mut dyn HistoryService>,
+    summary: &Mutex<RunSummary>,
+    formatter: Option<&ColoredStatusFormatter>,
+    verbose: bool,
+    max_run_duration: Option<Duration>,
+    mut rx: Receiver<Hashed>,
+    tx: Sender<PendingBackup>
+) -> Result<bool> {
+    while let Some((path, read_from, hsh, size)) = rx.recv().await {
+        let mut history = history.lock().await;
+        if max_run_duration.is_some_and(|duration| history.run_deadline_passed(duration)) {
+            return Ok(true);
+        }
+        let status = history.get_file_status(&path, &hsh, size).await?;
+        drop(history);
+        let change = match status {
+            FileStatus::NeedsBackup { is_new: true, .. } => Change::New,
+            FileStatus::NeedsBackup { is_new: false, .. } => Change::Modified,
+            FileStatus::DoesNotNeedBackup => Change::Unchanged,
+        };
+        summary.lock().await.record(&path, change, size);
+        if verbose && matches!(status, FileStatus::DoesNotNeedBackup) {
+            print(formatter, FileOutcome::from(&status), path.display());
+        }
+
+        if let FileStatus::NeedsBackup { sub_dir_id, file_id, file_name, .. } = status {
+            let file_name = file_name.to_string();
+            if tx.send(PendingBackup { path, read_from, hsh, size, sub_dir_id, file_id, file_name }).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+///
+/// What the backup stage did
+/// 
+#[derive(Default)]
+pub(crate) struct BackupStageOutcome {
+    pub files_backed_up: u64,
+    pub bytes_backed_up: u64,
+    /// Every file which failed to back up, with its error
+    pub failures: Vec<(PathBuf, BackupErrorKind)>,
+    /// Every file skipped as its backup would outgrow the temp_dir, with its error
+    pub too_large: Vec<(PathBuf, BackupErrorKind)>,
+    /// The destination which disappeared, stopping the stage before every file was backed up
+    pub lost_destination: Option<PathBuf>
+}
+
+///
+/// Backs up each file sent by the status check stage, as the `config` stores it, recording it in the
+/// history and removing any backup pruned as a result. A file which fails to back up is passed over,
+/// so the rest are still backed up, unless a destination disappeared, in which case no more files
+/// are backed up. A destination put in maintenance mode fails the stage.
+/// 
+pub(crate) async fn backup_stage(
+    history: &Mutex<&mut dyn HistoryService>,
+    backup_service: &mut dyn BackupService,
+    config: &Config,
+    formatter: Option<&ColoredStatusFormatter>,
+    mut rx: Receiver<PendingBackup>
+) -> Result<BackupStageOutcome> {
+    let mut outcome = BackupStageOutcome::default();
+    while let Some(pending) = rx.recv().await {
+        let chunking = config.chunking.as_ref()
+            .filter(|chunking| chunking.enabled && pending.size >= chunking.min_file_size);
+        let delta_base_id = match (&config.delta, chunking) {
+            (Some(delta), None) if delta.matches(&pending.path) =>
+                find_delta_base(&**history.lock().await, &pending, delta.max_chain_length).await?,
+            _ => None
+        };
+        let chunks = match (chunking, delta_base_id) {
+            (Some(chunking), _) => backup_service.backup_chunked(pending.file_id, &pending.read_from, chunking.avg_chunk_size).await
+                .map(Some),
+            (None, Some(base_id)) => match backup_service.backup_delta(pending.file_id, base_id, &pending.read_from).await {
+                Ok(()) => Ok(None),
+                // The previous version's backup may be missing from the destination
+                Err(e) => {
+                    tracing::warn!("{}, so it's stored in full", e);
+                    backup_service.backup_data(pending.file_id, &pending.read_from).await.map(|_| None)
+                }
+            },
+            (None, None) => backup_service.backup_data(pending.file_id, &pending.read_from).await
+                .map(|_| None)
+        };
+        let chunks = match chunks {
+            Ok(chunks) => chunks,
+            Err(e) => {
+                match e.kind {
+                    kind @ BackupErrorKind::MaintenanceMode(_) => return Err(kind.into()),
+                    // Every file after it would fail the same way, so the stage stops, which stops the stages
+                    // before it in turn. Its backup was never recorded, so there's nothing to undo
+                    BackupErrorKind::DestinationUnavailable(destination) => {
+                        tracing::error!("The backup destination {} became unavailable, so no more files are backed up", destination.display());
+                        outcome.lost_destination = Some(destination);
+                        break;
+                    },
+                    // Retrying can't make the file fit, so it isn't retried first
+                    kind @ BackupErrorKind::SpoolFull { .. } => {
+                        tracing::warn!("Skipped backing up {}: {}", pending.path.display(), kind);
+                        print(formatter, FileOutcome::Failed, pending.path.display());
+                        outcome.too_large.push((pending.path, kind));
+                        continue;
+                    },
+                    kind => {
+                        tracing::error!("Failed to backup file {} (id={}): {}. It's retried first by the next run", e.source_path.display(), e.file_id, kind);
+                        print(formatter, FileOutcome::Failed, pending.path.display());
+                        outcome.failures.push((pending.path, kind));
+                        continue;
+                    }
+                }
+            }
+        };
+        print(formatter, FileOutcome::BackedUp, pending.path.display());
+        outcome.files_backed_up += 1;
+        outcome.bytes_backed_up += pending.size;
+
+        let history = history.lock().await;
+        let pruned_id = history.create_file_entry(
+            pending.sub_dir_id, pending.file_id, &pending.file_name, &pending.hsh, pending.size
+        ).await?;
+        if let Some(chunks) = chunks {
+            let chunks: Vec<ChunkModel> = chunks.into_iter()
+                .map(|c| ChunkModel { hsh: c.hsh, chunk_size: c.size as i64 })
+                .collect();
+            history.record_file_chunks(pending.file_id, &chunks).await?;
+        }
+        for (destination, stored) in backup_service.describe_copies(pending.file_id).await? {
+            history.record_backup(
+                pending.file_id, &destination, stored.backend, &stored.key, stored.compressed_size, stored.delta_base_id
+            ).await?;
+        }
+        if let Some(id) = pruned_id {
+            delete_backup_keeping_dependents(&**history, backup_service, id).await?;
+            print(formatter, FileOutcome::Pruned, pending.path.display());
+        }
+    }
+
+    Ok(outcome)
+}
+
+///
+/// Gets the ID of the version the `pending` file should be stored as a delta against,
+/// being its latest version, unless that's already `max_chain_length` deltas behind a full copy
+/// 
+async fn find_delta_base(history: &dyn HistoryService, pending: &PendingBackup, max_chain_length: usize) -> Result<Option<i64>> {
+    let Some(base) = history.get_latest_version(pending.sub_dir_id, &pending.file_name).await? else { return Ok(None) };
+    Ok((history.get_delta_chain_length(base.id).await? < max_chain_length).then_some(base.id))
+}
+
+///
+/// Deletes the backup of the version with the given `file_id`, first storing every
+/// delta taken against it in full, since they can't be restored without it
+/// 
+pub async fn delete_backup_keeping_dependents(history: &dyn HistoryService, backup_service: &mut dyn BackupService, file_id: i64) -> Result<()> {
+    for dependent_id in history.get_delta_dependents(file_id).await? {
+        backup_service.materialize(dependent_id).await?;
+        history.clear_delta_base(dependent_id).await?;
+    }
+    backup_service.delete_backup(file_id).await?;
+
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use std::{fs::Metadata, path::{Path, PathBuf}, time::UNIX_EPOCH};
+
+use crate::file_svc::long_path::long_path;
+
+///
+/// A conventional copy of the files being backed up, such as an rsync mirror, whose
+/// files are laid out beneath its root as they are beneath the roots of the globs
+/// 
+pub struct Mirror {
+    root: PathBuf,
+    source_roots: Vec<PathBuf>
+}
+
+impl Mirror {
+    pub fn new(root: PathBuf, source_roots: Vec<PathBuf>) -> Self {
+        Self { root, source_roots }
+    }
+
+    ///
+    /// Gets where the file at `path` would be copied in the mirror, relative to the most
+    /// specific of the source roots it's beneath, or `None` if it's beneath none of them
+    /// 
+    pub fn copy_path(&self, path: &Path) -> Option<PathBuf> {
+        let relative = self.source_roots.iter()
+            .filter_map(|root| path.strip_prefix(root).ok())
+            .min_by_key(|relative| relative.components().count())?;
+        Some(self.root.join(relative))
+    }
+
+    ///
+    /// Gets the mirror's copy of the file at `path`, if it has the same size and modification
+    /// time, to the second, as the file's `metadata`. Otherwise the copy can't be trusted
+    /// to hold the same contents, and the file has to be read itself.
+    /// 
+    pub fn matching_copy(&self, path: &Path, metadata: &Metadata) -> Option<PathBuf> {
+        let copy = self.copy_path(path)?;
+        let copy_metadata = std::fs::metadata(long_path(&copy)).ok()?;
+        let matches = copy_metadata.is_file()
+            && copy_metadata.len() == metadata.len()
+            && modified_secs(&copy_metadata).is_some_and(|secs| Some(secs) == modified_secs(metadata));
+        matches.then_some(copy)
+    }
+}
+
+///
+/// Gets the modification time in `metadata` in whole seconds, as copies made to some
+/// filesystems don't keep it more precisely
+/// 
+fn modified_secs(metadata: &Metadata) -> Option<u64> {
+    Some(metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::{Path, PathBuf}, time::{Duration, UNIX_EPOCH}};
+
+    use super::Mirror;
+
+    fn write_file(path: &Path, contents: &[u8], modified_secs: u64) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+        let modified = UNIX_EPOCH + Duration::from_secs(modified_secs);
+        std::fs::File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+    }
+
+    #[test]
+    fn test_only_matching_copies_are_read_from_the_mirror() {
+        let (source, mirror_root) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        for (name, contents) in [("a.txt", "alpha"), ("docs/b.txt", "bravo"), ("docs/c.txt", "charlie")] {
+            write_file(&source.path().join(name), contents.as_bytes(), 1_700_000_000);
+            write_file(&mirror_root.path().join(name), contents.as_bytes(), 1_700_000_000);
+        }
+        // The source was changed since it was mirrored
+        write_file(&source.path().join("docs/c.txt"), b"charlie, edited", 1_700_000_500);
+        // Files missing from the mirror are read from the source too
+        write_file(&source.path().join("d.txt"), b"delta", 1_700_000_000);
+
+        let files: Vec<(PathBuf, std::fs::Metadata)> = ["a.txt", "docs/b.txt", "docs/c.txt", "d.txt"].iter()
+            .map(|name| source.path().join(name))
+            .map(|path| { let metadata = std::fs::metadata(&path).unwrap(); (path, metadata) })
+            .collect();
+        let mirror = Mirror::new(mirror_root.path().to_path_buf(), vec![source.path().to_path_buf()]);
+        let copies: HashMap<PathBuf, PathBuf> = files.iter()
+            .filter_map(|(path, metadata)| mirror.matching_copy(path, metadata).map(|copy| (path.clone(), copy)))
+            .collect();
+
+        let read_from: Vec<PathBuf> = files.iter().map(|(path, _)| copies.get(path).unwrap_or(path).clone()).collect();
+        assert_eq!(read_from.iter().filter(|path| path.starts_with(mirror_root.path())).count(), 2);
+        assert_eq!(read_from.iter().filter(|path| path.starts_with(source.path())).count(), 2);
+        assert_eq!(copies[&source.path().join("docs/b.txt")], mirror_root.path().join("docs/b.txt"));
+        assert!(!copies.contains_key(&source.path().join("docs/c.txt")));
+    }
+
+    #[test]
+    fn test_copy_path_is_relative_to_the_most_specific_root() {
+        let mirror = Mirror::new(PathBuf::from("/mnt/mirror"), vec![PathBuf::from("/home"), PathBuf::from("/home/me/photos")]);
+        assert_eq!(mirror.copy_path(Path::new("/home/me/photos/a.jpg")), Some(PathBuf::from("/mnt/mirror/a.jpg")));
+        assert_eq!(mirror.copy_path(Path::new("/home/me/b.txt")), Some(PathBuf::from("/mnt/mirror/me/b.txt")));
+        assert_eq!(mirror.copy_path(Path::new("/srv/c.txt")), None);
+    }
+}
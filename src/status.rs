@@ -0,0 +1,346 @@
+use std::{collections::HashMap, fmt::Display, fs::Metadata, path::{Path, PathBuf}};
+
+use chrono::{DateTime, Utc};
+
+use crate::{estimate::format_bytes, history_service::models::LatestFileEntry, summary::{Change, RunSummary}};
+
+///
+/// A file found on disk, with the metadata used to guess whether it has changed
+/// 
+#[derive(Clone, Debug)]
+pub struct ScannedFile {
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: DateTime<Utc>
+}
+
+impl ScannedFile {
+    ///
+    /// Reads the size and modification time of the file at `path`
+    /// 
+    pub fn read(path: PathBuf) -> std::io::Result<Self> {
+        let metadata = std::fs::metadata(&path)?;
+        Self::from_metadata(path, &metadata)
+    }
+
+    ///
+    /// Takes the size and modification time of the file at `path` from its already read `metadata`
+    /// 
+    pub fn from_metadata(path: PathBuf, metadata: &Metadata) -> std::io::Result<Self> {
+        let modified = DateTime::<Utc>::from(metadata.modified()?);
+        Ok(Self { path, size: metadata.len(), modified })
+    }
+}
+
+///
+/// Counts of the files which probably need backing up, judged by their size
+/// and modification time alone. A file which was touched but not changed is
+/// counted as modified, though a backup would find its hash still matches.
+/// 
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StatusReport {
+    pub probably_new: u64,
+    pub probably_modified: u64,
+    pub missing_from_disk: u64
+}
+
+impl StatusReport {
+    pub fn is_pending(&self) -> bool {
+        self.probably_new + self.probably_modified + self.missing_from_disk > 0
+    }
+}
+
+impl Display for StatusReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Probably new:       {}", self.probably_new)?;
+        writeln!(f, "Probably modified:  {}", self.probably_modified)?;
+        write!(f, "Missing from disk:  {}", self.missing_from_disk)
+    }
+}
+
+///
+/// How much the backup takes up, by the catalog and optionally on disk
+/// 
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BackupStats {
+    /// The original size of every file version in the catalog
+    pub total_backup_size_bytes: u64,
+    /// The size of every compressed file in the backup destination, if it was measured
+    pub disk_usage_bytes: Option<u64>
+}
+
+impl BackupStats {
+    ///
+    /// Whether the destination holds more than the catalog accounts for. Backups are 
+    /// compressed, so this usually means the destination holds backups the catalog 
+    /// no longer knows about
+    /// 
+    pub fn has_discrepancy(&self) -> bool {
+        self.disk_usage_bytes.is_some_and(|disk_usage| disk_usage > self.total_backup_size_bytes)
+    }
+}
+
+impl Display for BackupStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Catalog size:       {}", format_bytes(self.total_backup_size_bytes))?;
+        if let Some(disk_usage) = self.disk_usage_bytes {
+            write!(f, "\nDisk usage:         {}", format_bytes(disk_usage))?;
+        }
+        if self.has_discrepancy() {
+            write!(f, "\nThe destination holds more than the catalog accounts for, and may hold untracked backups")?;
+        }
+
+        Ok(())
+    }
+}
+
+///
+/// Compares the `scanned` files against the `catalog`'s latest version of each 
+/// file, without hashing. A file is probably modified if its size differs from 
+/// its latest version, or it was modified after that version was last seen.
+/// 
+pub fn classify(scanned: impl Iterator<Item = ScannedFile>, catalog: Vec<LatestFileEntry>) -> StatusReport {
+    let mut catalog: HashMap<PathBuf, LatestFileEntry> = catalog.into_iter()
+        .map(|entry| (PathBuf::from(&entry.full_path), entry))
+        .collect();
+
+    let mut report = StatusReport::default();
+    for file in scanned {
+        match catalog.remove(&file.path) {
+            None => report.probably_new += 1,
+            Some(entry) if !is_probably_unchanged(&file, &entry) => report.probably_modified += 1,
+            Some(_) => { }
+        }
+    }
+    // Files no longer matched by the globs may still be on disk
+    report.missing_from_disk = catalog.into_keys().filter(|path| !path.exists()).count() as u64;
+
+    report
+}
+
+///
+/// Summarizes what backing up the `scanned` files would change, without hashing them or
+/// touching the catalog. Each file is judged against the `catalog` as `classify` judges it,
+/// so files summarized as modified are only probably so. Of the files the catalog has which
+/// weren't scanned, those in `excluded` would be marked excluded, and those beneath one of the
+/// `roots`, but not beneath a `skipped` path, would be marked deleted.
+/// 
+pub fn summarize_dry_run(
+    scanned: impl Iterator<Item = ScannedFile>, catalog: Vec<LatestFileEntry>, roots: &[PathBuf], skipped: &[PathBuf], excluded: &[PathBuf]
+) -> RunSummary {
+    // Files whose latest version is a deletion or exclusion marker have no size
+    let mut catalog: HashMap<PathBuf, LatestFileEntry> = catalog.into_iter()
+        .filter(|entry| entry.file_size.is_some())
+        .map(|entry| (PathBuf::from(&entry.full_path), entry))
+        .collect();
+
+    let mut summary = RunSummary::new();
+    for file in scanned {
+        match catalog.remove(&file.path) {
+            None => summary.record(&file.path, Change::New, file.size),
+            Some(entry) if !is_probably_unchanged(&file, &entry) => summary.record(&file.path, Change::Modified, file.size),
+            Some(_) => summary.record_unchanged_by_metadata(&file.path, file.size)
+        }
+    }
+    for path in catalog.into_keys() {
+        if excluded.contains(&path) {
+            summary.record(&path, Change::Excluded, 0);
+        } else if roots.iter().any(|root| path.starts_with(root)) && !skipped.iter().any(|skipped| path.starts_with(skipped)) {
+            summary.record(&path, Change::Deleted, 0);
+        }
+    }
+
+    summary
+}
+
+///
+/// Whether the file at `path` is judged unchanged by its `metadata` alone: if the catalog's latest 
+/// version of it, `entry`, is the same size, and was last seen after the file was modified.
+/// Files whose modification time can't be read are never judged unchanged, so are always hashed
+/// 
+pub fn is_unchanged_by_metadata(path: &Path, metadata: &Metadata, entry: Option<&LatestFileEntry>) -> bool {
+    let (Some(entry), Ok(file)) = (entry, ScannedFile::from_metadata(path.to_path_buf(), metadata)) else { return false };
+    is_probably_unchanged(&file, entry)
+}
+
+///
+/// Whether the `file` is the same size as the catalog's latest version of it, and 
+/// wasn't modified after that version was last seen
+/// 
+fn is_probably_unchanged(file: &ScannedFile, entry: &LatestFileEntry) -> bool {
+    entry.file_size == Some(file.size as i64) && file.modified <= entry.backup_ts
+}
+
+///
+/// Orders `files` so that those the `catalog` has never seen come first, followed by the
+/// rest from the least recently seen. A run stopped at its deadline leaves its remaining
+/// files least recently seen, so the next run examines them first.
+/// 
+pub fn order_least_recently_seen<T>(files: &mut [T], path_of: impl Fn(&T) -> &Path, catalog: Vec<LatestFileEntry>) {
+    let last_seen: HashMap<PathBuf, DateTime<Utc>> = catalog.into_iter()
+        .map(|entry| (PathBuf::from(entry.full_path), entry.backup_ts))
+        .collect();
+    // `None` sorts before every `Some`, and the sort is stable, so ties keep their scanned order
+    files.sort_by_key(|file| last_seen.get(path_of(file)).copied());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use std::{collections::HashMap, path::Path};
+
+    use chrono::{Duration, Utc};
+
+    use crate::{config::{ChangeDetection, HashAlgorithm}, hash_svc::hash_reader, history_service::models::LatestFileEntry, summary::ChangeCounts};
+
+    use super::{classify, order_least_recently_seen, summarize_dry_run, is_unchanged_by_metadata, BackupStats, ScannedFile, StatusReport};
+
+    #[test]
+    fn test_classify() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["new.txt", "resized.txt", "touched.txt", "unchanged.txt"] {
+            std::fs::write(dir.path().join(name), "contents").unwrap();
+        }
+        let scanned: Vec<ScannedFile> = std::fs::read_dir(dir.path()).unwrap()
+            .map(|entry| ScannedFile::read(entry.unwrap().path()).unwrap())
+            .collect();
+
+        let now = Utc::now();
+        let entry = |name: &str, file_size: i64, backup_ts| LatestFileEntry { 
+            full_path: dir.path().join(name).to_string_lossy().to_string(), file_size: Some(file_size), backup_ts
+        };
+        let catalog = vec![
+            entry("resized.txt", 3, now + Duration::hours(1)),
+            entry("touched.txt", 8, now - Duration::hours(1)),
+            entry("unchanged.txt", 8, now + Duration::hours(1)),
+            entry("deleted.txt", 8, now + Duration::hours(1)),
+        ];
+
+        let report = classify(scanned.into_iter(), catalog);
+
+        assert_eq!(report, StatusReport { probably_new: 1, probably_modified: 2, missing_from_disk: 1 });
+        assert!(report.is_pending());
+    }
+
+    #[test]
+    fn test_summarize_dry_run() {
+        let now = Utc::now();
+        let scanned = |path: &str, size: u64| ScannedFile { path: PathBuf::from(path), size, modified: now - Duration::hours(2) };
+        let entry = |path: &str, file_size: Option<i64>| LatestFileEntry { 
+            full_path: path.to_string(), file_size, backup_ts: now - Duration::hours(1)
+        };
+        let catalog = vec![
+            entry("/home/me/resized.txt", Some(3)),
+            entry("/home/me/unchanged.txt", Some(8)),
+            entry("/home/me/gone.txt", Some(8)),
+            entry("/home/me/already_gone.txt", None),
+            entry("/home/me/secret.key", Some(8)),
+            entry("/home/me/locked/kept.txt", Some(8)),
+            entry("/srv/elsewhere.txt", Some(8)),
+        ];
+        let files = vec![scanned("/home/me/new.txt", 100), scanned("/home/me/resized.txt", 20), scanned("/home/me/unchanged.txt", 8)];
+
+        let summary = summarize_dry_run(
+            files.into_iter(), catalog, &[PathBuf::from("/home/me")], &[PathBuf::from("/home/me/locked")], &[PathBuf::from("/home/me/secret.key")]
+        );
+
+        // Files outside the roots, beneath a skipped path, or already deleted aren't marked deleted
+        assert_eq!(summary.totals(), ChangeCounts { new: 1, modified: 1, deleted: 1, excluded: 1, unchanged: 1, bytes_to_transfer: 120 });
+        assert_eq!(summary.unchanged_by(), (0, 1));
+    }
+
+    #[test]
+    fn test_classify_nothing_pending() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("unchanged.txt");
+        std::fs::write(&path, "contents").unwrap();
+        let catalog = vec![LatestFileEntry { 
+            full_path: path.to_string_lossy().to_string(), file_size: Some(8), 
+            backup_ts: Utc::now() + Duration::hours(1)
+        }];
+
+        let report = classify(std::iter::once(ScannedFile::read(path).unwrap()), catalog);
+
+        assert!(!report.is_pending());
+    }
+
+    #[test]
+    fn test_is_unchanged_by_metadata_with_mixed_policies() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = Utc::now();
+        let mut catalog = Vec::new();
+        for (subtree, name, last_seen) in [
+            ("photos", "a.jpg", now + Duration::hours(1)),
+            ("photos", "b.jpg", now + Duration::hours(1)),
+            ("photos", "touched.jpg", now - Duration::hours(1)),
+            ("src", "main.rs", now + Duration::hours(1)),
+            ("src", "lib.rs", now + Duration::hours(1))
+        ] {
+            let path = dir.path().join(subtree).join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, "contents").unwrap();
+            catalog.push(LatestFileEntry { full_path: path.to_string_lossy().to_string(), file_size: Some(8), backup_ts: last_seen });
+        }
+        std::fs::write(dir.path().join("photos").join("new.jpg"), "contents").unwrap();
+        let files: Vec<_> = ["photos", "src"].iter()
+            .flat_map(|subtree| std::fs::read_dir(dir.path().join(subtree)).unwrap())
+            .map(|entry| { let path = entry.unwrap().path(); let metadata = std::fs::metadata(&path).unwrap(); (path, metadata) })
+            .collect();
+        let photos = dir.path().join("photos");
+        let change_detection = |path: &Path| match path.starts_with(&photos) {
+            true => ChangeDetection::Metadata,
+            false => ChangeDetection::Hash
+        };
+        let catalog: HashMap<PathBuf, LatestFileEntry> = catalog.into_iter().map(|entry| (PathBuf::from(&entry.full_path), entry)).collect();
+
+        let (unchanged, files): (Vec<_>, Vec<_>) = files.into_iter().partition(|(path, metadata)| {
+            change_detection(path) == ChangeDetection::Metadata && is_unchanged_by_metadata(path, metadata, catalog.get(path))
+        });
+
+        // Hashing whatever's left counts which subtree each file hashed came from
+        let mut hashed: HashMap<String, usize> = HashMap::new();
+        let mut hasher = |path: &Path| {
+            let subtree = path.strip_prefix(dir.path()).unwrap().iter().next().unwrap().to_string_lossy().to_string();
+            *hashed.entry(subtree).or_default() += 1;
+            hash_reader(std::fs::File::open(path).unwrap(), HashAlgorithm::Md5).unwrap()
+        };
+        for (path, _) in &files {
+            hasher(path);
+        }
+
+        let mut unchanged: Vec<_> = unchanged.iter().map(|(path, _)| path.file_name().unwrap().to_string_lossy().to_string()).collect();
+        unchanged.sort();
+        assert_eq!(unchanged, ["a.jpg", "b.jpg"]);
+        // Only the photo touched since it was last seen, and the one never seen, are hashed,
+        // while every source file is hashed though none of them changed
+        assert_eq!(hashed.get("photos"), Some(&2));
+        assert_eq!(hashed.get("src"), Some(&2));
+    }
+
+    #[test]
+    fn test_backup_stats_discrepancy() {
+        let stats = BackupStats { total_backup_size_bytes: 4096, disk_usage_bytes: None };
+        assert!(!stats.has_discrepancy());
+        assert_eq!(stats.to_string(), "Catalog size:       4.0 KB");
+
+        let stats = BackupStats { total_backup_size_bytes: 4096, disk_usage_bytes: Some(5120) };
+        assert!(stats.has_discrepancy());
+        assert_eq!(stats.to_string(), "Catalog size:       4.0 KB\nDisk usage:         5.0 KB\n\
+            The destination holds more than the catalog accounts for, and may hold untracked backups");
+    }
+
+    #[test]
+    fn test_order_least_recently_seen() {
+        let now = Utc::now();
+        let entry = |path: &str, hours_ago| LatestFileEntry { 
+            full_path: path.to_string(), file_size: Some(1), backup_ts: now - Duration::hours(hours_ago)
+        };
+        let catalog = vec![entry("/a", 1), entry("/b", 30), entry("/c", 5)];
+        let mut files = vec![PathBuf::from("/a"), PathBuf::from("/new1"), PathBuf::from("/b"), PathBuf::from("/c"), PathBuf::from("/new2")];
+
+        order_least_recently_seen(&mut files, |path| path.as_path(), catalog);
+
+        assert_eq!(files, ["/new1", "/new2", "/b", "/c", "/a"].map(PathBuf::from));
+    }
+}
@@ -0,0 +1,350 @@
+use std::{fmt::{Display, Write}, path::{Component, Path}};
+
+use crate::{collections::{Cache, GroupBy}, estimate::{format_bytes, format_count}};
+
+///
+/// The most changed files a directory can directly hold for them
+/// to be listed individually, when not rendering verbosely
+/// 
+const FILE_DETAIL_THRESHOLD: usize = 5;
+
+///
+/// How a file changed during a run
+/// 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Change {
+    New,
+    Modified,
+    Deleted,
+    /// Backed up before, but now left out of the backup by policy while it still exists
+    Excluded,
+    Unchanged
+}
+
+#[derive(Clone, Debug)]
+pub struct FileChange {
+    pub change: Change,
+    pub size: u64
+}
+
+impl Display for FileChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.change {
+            Change::New => write!(f, "new, {}", format_bytes(self.size)),
+            Change::Modified => write!(f, "modified, {}", format_bytes(self.size)),
+            Change::Deleted => write!(f, "deleted"),
+            Change::Excluded => write!(f, "excluded"),
+            Change::Unchanged => write!(f, "unchanged"),
+        }
+    }
+}
+
+///
+/// The number of files beneath a directory with each kind of change, 
+/// and the total size of the files which need to be backed up
+/// 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChangeCounts {
+    pub new: u64,
+    pub modified: u64,
+    pub deleted: u64,
+    pub excluded: u64,
+    pub unchanged: u64,
+    pub bytes_to_transfer: u64
+}
+
+impl ChangeCounts {
+    pub fn changed(&self) -> u64 {
+        self.new + self.modified + self.deleted + self.excluded
+    }
+
+    ///
+    /// The number of files found on disk and checked, whether or not they changed
+    /// 
+    pub fn examined(&self) -> u64 {
+        self.new + self.modified + self.unchanged
+    }
+
+    fn add(&mut self, other: &ChangeCounts) {
+        self.new += other.new;
+        self.modified += other.modified;
+        self.deleted += other.deleted;
+        self.excluded += other.excluded;
+        self.unchanged += other.unchanged;
+        self.bytes_to_transfer += other.bytes_to_transfer;
+    }
+}
+
+impl Display for ChangeCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f, "{} new, {} modified, {} deleted, {} unchanged, {} to transfer",
+            format_count(self.new), format_count(self.modified), format_count(self.deleted),
+            format_count(self.unchanged), format_bytes(self.bytes_to_transfer)
+        )?;
+        // Only mentioned once a file's been excluded, which is rare
+        if self.excluded > 0 {
+            write!(f, ", {} excluded", format_count(self.excluded))?;
+        }
+        Ok(())
+    }
+}
+
+///
+/// The change to every file examined during a run, organized by directory
+/// 
+pub struct RunSummary {
+    files: Cache<FileChange>,
+    /// The number of unchanged files judged so by their metadata, without being hashed
+    unchanged_by_metadata: u64,
+    /// Every error which kept a file from being examined, with the file's path if it's known
+    errors: Vec<(Option<String>, String)>
+}
+
+impl Default for RunSummary {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RunSummary {
+    pub fn new() -> Self {
+        Self { files: Cache::new(), unchanged_by_metadata: 0, errors: Vec::new() }
+    }
+
+    ///
+    /// Records the `error` which kept the file at `path` from being examined
+    /// 
+    pub fn record_error(&mut self, path: Option<&Path>, error: impl Display) {
+        self.errors.push((path.map(|path| path.display().to_string()), error.to_string()));
+    }
+
+    ///
+    /// Gets the number of errors recorded
+    /// 
+    pub fn error_count(&self) -> usize {
+        self.errors.len()
+    }
+
+    ///
+    /// Records the `change` to the file at `path`, which is `size` bytes
+    /// 
+    pub fn record(&mut self, path: &Path, change: Change, size: u64) {
+        let key: Vec<_> = path.components().filter_map(|component| match component {
+            Component::Prefix(prefix) => Some(prefix.as_os_str().to_string_lossy()),
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None
+        }).collect();
+
+        self.files.insert(&key.join("/"), FileChange { change, size });
+    }
+
+    ///
+    /// Records the file at `path`, which is `size` bytes, as unchanged
+    /// judged by its metadata alone, without being hashed
+    /// 
+    pub fn record_unchanged_by_metadata(&mut self, path: &Path, size: u64) {
+        self.record(path, Change::Unchanged, size);
+        self.unchanged_by_metadata += 1;
+    }
+
+    ///
+    /// Gets the counts of every change recorded
+    /// 
+    pub fn totals(&self) -> ChangeCounts {
+        rollup(&self.files)
+    }
+
+    ///
+    /// Gets the number of unchanged files judged so by their hash, and by their metadata alone
+    /// 
+    pub fn unchanged_by(&self) -> (u64, u64) {
+        (self.totals().unchanged - self.unchanged_by_metadata, self.unchanged_by_metadata)
+    }
+
+    ///
+    /// Renders the summary as a tree of the directories holding changed files, each
+    /// with the counts of the changes beneath it. Directories are shown up to `depth` 
+    /// levels deep, and their changed files are listed when there are only a few of
+    /// them, or always if `verbose`. Entries are sorted by name. If any files were judged
+    /// unchanged by their metadata alone, how many were is given after the total. Every 
+    /// error recorded is listed after the tree.
+    /// 
+    pub fn render(&self, depth: Option<usize>, verbose: bool) -> String {
+        let mut rendered = format!("total  {}\n", self.totals());
+        if self.unchanged_by_metadata > 0 {
+            let (by_hash, by_metadata) = self.unchanged_by();
+            writeln!(rendered, "unchanged  {} by hash, {} by metadata", format_count(by_hash), format_count(by_metadata)).unwrap();
+        }
+        render_children(&mut rendered, &self.files, 0, depth, verbose);
+        if !self.errors.is_empty() {
+            writeln!(rendered, "errors  {}", format_count(self.errors.len() as u64)).unwrap();
+            for (path, error) in &self.errors {
+                match path {
+                    Some(path) => writeln!(rendered, "  {}  {}", path, error).unwrap(),
+                    None => writeln!(rendered, "  {}", error).unwrap()
+                }
+            }
+        }
+
+        rendered
+    }
+}
+
+///
+/// Counts every change recorded in `cache`, including in its sub-Caches
+/// 
+fn rollup(cache: &Cache<FileChange>) -> ChangeCounts {
+    let mut counts = ChangeCounts::default();
+    for (change, files) in cache.entries().map(|(_, file)| file).group_by(|file| file.change) {
+        let count = files.len() as u64;
+        match change {
+            Change::New => counts.new = count,
+            Change::Modified => counts.modified = count,
+            Change::Deleted => counts.deleted = count,
+            Change::Excluded => counts.excluded = count,
+            Change::Unchanged => counts.unchanged = count,
+        }
+        if matches!(change, Change::New | Change::Modified) {
+            counts.bytes_to_transfer += files.iter().map(|file| file.size).sum::<u64>();
+        }
+    }
+    for (_, sub_cache) in cache.sub_caches() {
+        counts.add(&rollup(sub_cache));
+    }
+
+    counts
+}
+
+fn render_children(rendered: &mut String, cache: &Cache<FileChange>, level: usize, depth: Option<usize>, verbose: bool) {
+    if depth.is_some_and(|depth| level >= depth) {
+        return;
+    }
+    let indent = "  ".repeat(level);
+
+    let mut changed_files: Vec<_> = cache.entries()
+        .filter(|(_, file)| file.change != Change::Unchanged)
+        .collect();
+    changed_files.sort_by_key(|(name, _)| *name);
+    if verbose || changed_files.len() <= FILE_DETAIL_THRESHOLD {
+        for (name, file) in changed_files {
+            writeln!(rendered, "{}{}  {}", indent, name, file).unwrap();
+        }
+    }
+
+    let mut sub_caches: Vec<_> = cache.sub_caches().collect();
+    sub_caches.sort_by_key(|(name, _)| *name);
+    for (name, sub_cache) in sub_caches {
+        // Directories holding nothing but a single sub-directory are
+        // shown on one line with it, ie. "home/user/docs/"
+        let (mut name, mut sub_cache) = (name.clone(), sub_cache);
+        while sub_cache.entries().next().is_none() && sub_cache.sub_caches().count() == 1 {
+            let (child_name, child) = sub_cache.sub_caches().next().unwrap();
+            name = format!("{}/{}", name, child_name);
+            sub_cache = child;
+        }
+
+        let counts = rollup(sub_cache);
+        if counts.changed() == 0 {
+            continue;
+        }
+        writeln!(rendered, "{}{}/  {}", indent, name, counts).unwrap();
+        render_children(rendered, sub_cache, level + 1, depth, verbose);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{Change, RunSummary};
+
+    fn build_summary() -> RunSummary {
+        let mut summary = RunSummary::new();
+        summary.record(Path::new("/home/user/docs/a.txt"), Change::New, 2048);
+        summary.record(Path::new("/home/user/docs/b.txt"), Change::Modified, 100);
+        summary.record(Path::new("/home/user/docs/c.txt"), Change::Unchanged, 100);
+        summary.record(Path::new("/home/user/docs/old/d.txt"), Change::Deleted, 0);
+        summary.record(Path::new("/home/user/unchanged/e.txt"), Change::Unchanged, 100);
+        for i in 0..6 {
+            summary.record(Path::new(&format!("/home/user/photos/{}.jpg", i)), Change::New, 1024);
+        }
+
+        summary
+    }
+
+    #[test]
+    fn test_totals() {
+        let totals = build_summary().totals();
+
+        assert_eq!((totals.new, totals.modified, totals.deleted, totals.unchanged), (7, 1, 1, 2));
+        assert_eq!(totals.bytes_to_transfer, 2048 + 100 + 6 * 1024);
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(build_summary().render(None, false), "\
+total  7 new, 1 modified, 1 deleted, 2 unchanged, 8.1 KB to transfer
+home/user/  7 new, 1 modified, 1 deleted, 2 unchanged, 8.1 KB to transfer
+  docs/  1 new, 1 modified, 1 deleted, 1 unchanged, 2.1 KB to transfer
+    a.txt  new, 2.0 KB
+    b.txt  modified, 100 B
+    old/  0 new, 0 modified, 1 deleted, 0 unchanged, 0 B to transfer
+      d.txt  deleted
+  photos/  6 new, 0 modified, 0 deleted, 0 unchanged, 6.0 KB to transfer
+");
+    }
+
+    #[test]
+    fn test_render_verbose() {
+        let rendered = build_summary().render(None, true);
+
+        assert!(rendered.ends_with("\
+  photos/  6 new, 0 modified, 0 deleted, 0 unchanged, 6.0 KB to transfer
+    0.jpg  new, 1.0 KB
+    1.jpg  new, 1.0 KB
+    2.jpg  new, 1.0 KB
+    3.jpg  new, 1.0 KB
+    4.jpg  new, 1.0 KB
+    5.jpg  new, 1.0 KB
+"));
+    }
+
+    #[test]
+    fn test_render_unchanged_by_metadata() {
+        let mut summary = build_summary();
+        summary.record_unchanged_by_metadata(Path::new("/home/user/photos/old.jpg"), 4096);
+        summary.record_unchanged_by_metadata(Path::new("/home/user/photos/older.jpg"), 4096);
+
+        assert_eq!(summary.unchanged_by(), (2, 2));
+        assert!(summary.render(Some(0), false).starts_with("\
+total  7 new, 1 modified, 1 deleted, 4 unchanged, 8.1 KB to transfer
+unchanged  2 by hash, 2 by metadata
+"));
+    }
+
+    #[test]
+    fn test_render_excluded() {
+        let mut summary = build_summary();
+        summary.record(Path::new("/home/user/docs/huge.iso"), Change::Excluded, 0);
+
+        let totals = summary.totals();
+        assert_eq!((totals.deleted, totals.excluded), (1, 1));
+        assert!(summary.render(None, false).starts_with("\
+total  7 new, 1 modified, 1 deleted, 2 unchanged, 8.1 KB to transfer, 1 excluded
+home/user/  7 new, 1 modified, 1 deleted, 2 unchanged, 8.1 KB to transfer, 1 excluded
+  docs/  1 new, 1 modified, 1 deleted, 1 unchanged, 2.1 KB to transfer, 1 excluded
+    a.txt  new, 2.0 KB
+    b.txt  modified, 100 B
+    huge.iso  excluded
+"));
+    }
+
+    #[test]
+    fn test_render_with_depth() {
+        assert_eq!(build_summary().render(Some(1), false), "\
+total  7 new, 1 modified, 1 deleted, 2 unchanged, 8.1 KB to transfer
+home/user/  7 new, 1 modified, 1 deleted, 2 unchanged, 8.1 KB to transfer
+");
+    }
+}
@@ -1,25 +1,118 @@
+use std::{future::Future, pin::Pin};
 
-use chrono::{NaiveDateTime, Utc};
+use chrono::{DateTime, Utc};
+use tokio::time::Instant;
 
 #[cfg(test)]
 use mockall::automock;
 
+///
+/// Provides the instants recorded in the catalog. Every instant is in UTC,
+/// and is only converted to local time when it's displayed.
+/// 
 #[cfg_attr(test, automock)]
 pub trait TimeProvider : Send + Sync {
-    fn naive_utc_start(&self) -> NaiveDateTime;
-    fn naive_utc_now(&self) -> NaiveDateTime {
-        Utc::now().naive_utc()
+    fn utc_start(&self) -> DateTime<Utc>;
+    fn utc_now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+    ///
+    /// Gets the current instant of the monotonic clock timers are measured against
+    /// 
+    fn now_instant(&self) -> Instant {
+        Instant::now()
+    }
+    ///
+    /// Waits until `target`, finishing at once if it's already passed.
+    /// Boxed, so the provider can still be used as a `dyn TimeProvider`
+    /// 
+    fn sleep_until(&self, target: DateTime<Utc>) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        let wait = (target - self.utc_now()).to_std().unwrap_or_default();
+        Box::pin(tokio::time::sleep_until(self.now_instant() + wait))
     }
 }
 
-pub struct CoreTimeProvider { start: NaiveDateTime }
+pub struct CoreTimeProvider { start: DateTime<Utc> }
 impl CoreTimeProvider {
     pub fn new() -> Self {
-        Self { start: Utc::now().naive_utc() }
+        Self { start: Utc::now() }
+    }
+}
+impl Default for CoreTimeProvider {
+    fn default() -> Self {
+        Self::new()
     }
 }
 impl TimeProvider for CoreTimeProvider {
-    fn naive_utc_start(&self) -> NaiveDateTime {
+    fn utc_start(&self) -> DateTime<Utc> {
         self.start
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+impl MockTimeProvider {
+    ///
+    /// Creates a `MockTimeProvider` whose run started at, and which is always now, `start`.
+    /// Time never passes, so sleeping until any time finishes at once
+    /// 
+    pub fn starting_at(start: DateTime<Utc>) -> Self {
+        let mut mock_tp = MockTimeProvider::new();
+        mock_tp.expect_utc_start().return_const(start);
+        mock_tp.expect_utc_now().return_const(start);
+        mock_tp.expect_now_instant().return_const(Instant::now());
+        mock_tp.expect_sleep_until().returning(|_| Box::pin(std::future::ready(())));
+        mock_tp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{DateTime, FixedOffset, NaiveDate, TimeZone, Utc};
+
+    use super::{CoreTimeProvider, MockTimeProvider, TimeProvider};
+
+    #[test]
+    fn test_instant_round_trips_across_time_zone_change() {
+        let start = Utc.with_ymd_and_hms(2024, 3, 10, 9, 30, 0).unwrap();
+        let time_provider = MockTimeProvider::starting_at(start);
+
+        // Stored as RFC3339, then read back on a machine whose zone has since moved
+        let stored = time_provider.utc_start().to_rfc3339();
+        let pacific = FixedOffset::west_opt(8 * 3600).unwrap();
+        let read_back = DateTime::parse_from_rfc3339(&stored).unwrap().with_timezone(&pacific);
+
+        assert_eq!(read_back, start);
+        assert_eq!(read_back.with_timezone(&Utc), time_provider.utc_now());
+        assert_eq!(read_back.format("%H:%M").to_string(), "01:30");
+    }
+
+    #[test]
+    fn test_legacy_naive_timestamp_is_read_as_utc() {
+        // What the catalog migration does to a timestamp stored before instants were typed
+        let legacy = "2024-03-10 09:30:00";
+        let migrated = format!("{}+00:00", legacy.replace(' ', "T"));
+
+        let naive = NaiveDate::from_ymd_opt(2024, 3, 10).unwrap().and_hms_opt(9, 30, 0).unwrap();
+        assert_eq!(DateTime::parse_from_rfc3339(&migrated).unwrap(), naive.and_utc());
+    }
+
+    #[tokio::test]
+    async fn test_sleep_until() {
+        // The mock never waits, however far off the target is
+        let start = Utc.with_ymd_and_hms(2024, 3, 10, 9, 30, 0).unwrap();
+        let mock_tp = MockTimeProvider::starting_at(start);
+        tokio::time::timeout(Duration::from_secs(1), mock_tp.sleep_until(start + chrono::Duration::days(1))).await.unwrap();
+        assert_eq!(mock_tp.now_instant(), mock_tp.now_instant());
+
+        // While a target already passed is reached at once
+        let time_provider = CoreTimeProvider::new();
+        let past = time_provider.utc_now() - chrono::Duration::hours(1);
+        tokio::time::timeout(Duration::from_secs(1), time_provider.sleep_until(past)).await.unwrap();
+        let soon = time_provider.utc_now() + chrono::Duration::milliseconds(20);
+        let before = time_provider.now_instant();
+        time_provider.sleep_until(soon).await;
+        assert!(time_provider.now_instant() - before >= Duration::from_millis(15));
+    }
+}
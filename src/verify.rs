@@ -0,0 +1,216 @@
+use std::collections::BTreeMap;
+
+use futures_util::{pin_mut, stream, StreamExt};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    backup_service::{shard_of, BackupService},
+    hash_svc::{algorithm_of, hash_reader},
+    history_service::{error::Result, models::{FileModel, VerifyFailureModel}, HistoryService}
+};
+
+///
+/// The outcome of verifying the backups, including every failure found by earlier
+/// runs which were resumed from
+/// 
+#[derive(Debug, Default, PartialEq)]
+pub struct VerifyReport {
+    /// The number of backups verified by this run
+    pub verified: u64,
+    /// The number of backups skipped, having been verified by an earlier run
+    pub skipped: u64,
+    /// Whether the run was cancelled before every backup was verified
+    pub cancelled: bool,
+    /// Every failure found since verification last started over
+    pub failures: Vec<VerifyFailureModel>
+}
+
+///
+/// Re-hashes the backup of every file version, comparing it against the hash recorded when it
+/// was backed up. Backups are verified a shard at a time for locality, with up to `concurrency`
+/// read at once. Progress and failures are recorded as each backup is verified, so a run which
+/// is cancelled or interrupted can be continued by another with `resume` set. Without `resume`,
+/// verification starts over.
+/// 
+pub async fn verify_backups(
+    cache_svc: &dyn HistoryService,
+    backup_service: &dyn BackupService,
+    resume: bool,
+    concurrency: usize,
+    cancel: &CancellationToken
+) -> Result<VerifyReport> {
+    if !resume {
+        cache_svc.reset_verify_progress().await?;
+    }
+    let progress = cache_svc.get_verify_progress().await?;
+
+    let mut report = VerifyReport::default();
+    let mut shards: BTreeMap<i64, Vec<FileModel>> = BTreeMap::new();
+    for file in cache_svc.get_backed_up_files().await? {
+        let shard = shard_of(file.id);
+        if progress.get(&shard).is_some_and(|&last_file_id| file.id <= last_file_id) {
+            report.skipped += 1;
+        } else {
+            shards.entry(shard).or_default().push(file);
+        }
+    }
+
+    for (shard, files) in shards {
+        // Results are taken in id order, so the progress recorded never skips
+        // past a backup which is still being verified
+        let results = stream::iter(files)
+            .take_while(|_| std::future::ready(!cancel.is_cancelled()))
+            .map(|file| async move {
+                let outcome = check_backup(backup_service, &file).await;
+                (file, outcome)
+            })
+            .buffered(concurrency.max(1));
+        pin_mut!(results);
+
+        while let Some((file, outcome)) = results.next().await {
+            match outcome {
+                Ok(()) => {
+                    cache_svc.mark_file_verified(file.id).await?;
+                    report.verified += 1;
+                },
+                Err(reason) => cache_svc.record_verify_failure(file.id, &reason).await?
+            }
+            cache_svc.record_verify_progress(shard, file.id).await?;
+        }
+        if cancel.is_cancelled() {
+            report.cancelled = true;
+            break;
+        }
+    }
+
+    report.failures = cache_svc.get_verify_failures().await?;
+    Ok(report)
+}
+
+///
+/// Checks the backup of the given `file` can be read and still has its recorded hash,
+/// returning why it failed otherwise
+/// 
+async fn check_backup(backup_service: &dyn BackupService, file: &FileModel) -> std::result::Result<(), String> {
+    let reader = backup_service.open_backup(file.id).await
+        .map_err(|e| format!("could not open the backup: {}", e))?;
+    let algorithm = file.hsh.as_deref().and_then(algorithm_of).unwrap_or_default();
+    let (hsh, _) = tokio::task::spawn_blocking(move || hash_reader(reader, algorithm)).await
+        .map_err(|e| format!("could not read the backup: {}", e))?
+        .map_err(|e| format!("could not read the backup: {:?}", e))?;
+
+    match file.hsh.as_ref() == Some(&hsh) {
+        true => Ok(()),
+        false => Err(format!("expected hash {}, found {}", file.hsh.clone().unwrap_or_default(), hsh))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::{Arc, Mutex}};
+
+    use chrono::{TimeZone, Utc};
+    use tokio_util::sync::CancellationToken;
+
+    use crate::{
+        backup_service::{BackupService, FileBackupService},
+        config::{CanonicalizePolicy, HashAlgorithm},
+        hash_svc::hash_reader,
+        history_service::{data_layer::MockDataLayer, models::{FileModel, VerifyFailureModel}, FileHistoryService},
+        time_provider::MockTimeProvider
+    };
+
+    use super::verify_backups;
+
+    ///
+    /// Keeps what a `MockDataLayer` records about verification between runs
+    /// 
+    #[derive(Clone, Default)]
+    struct VerifyState {
+        progress: Arc<Mutex<HashMap<i64, i64>>>,
+        failures: Arc<Mutex<Vec<VerifyFailureModel>>>,
+        verified: Arc<Mutex<Vec<i64>>>
+    }
+
+    fn build_mock_data_layer(files: Vec<FileModel>, state: &VerifyState, cancel_after: Option<(usize, CancellationToken)>) -> MockDataLayer {
+        let mut mock_dl = MockDataLayer::new();
+        mock_dl.expect_get_backed_up_files().returning(move || Ok(files.clone()));
+        let progress = state.progress.clone();
+        mock_dl.expect_get_verify_progress().returning(move || Ok(progress.lock().unwrap().clone().into_iter().collect()));
+        let progress = state.progress.clone();
+        mock_dl.expect_set_verify_progress().returning(move |shard, last_file_id| {
+            progress.lock().unwrap().insert(shard, last_file_id);
+            Ok(())
+        });
+        let failures = state.failures.clone();
+        mock_dl.expect_create_verify_failure().returning(move |file_id, reason, found_at| {
+            failures.lock().unwrap().push(VerifyFailureModel { file_id, reason: reason.to_string(), found_at });
+            Ok(())
+        });
+        let failures = state.failures.clone();
+        mock_dl.expect_get_verify_failures().returning(move || Ok(failures.lock().unwrap().clone()));
+        let (progress, failures) = (state.progress.clone(), state.failures.clone());
+        mock_dl.expect_clear_verify_progress().returning(move || {
+            progress.lock().unwrap().clear();
+            failures.lock().unwrap().clear();
+            Ok(())
+        });
+        let verified = state.verified.clone();
+        mock_dl.expect_mark_file_verified().returning(move |file_id, _| {
+            let mut verified = verified.lock().unwrap();
+            verified.push(file_id);
+            if let Some((count, cancel)) = &cancel_after {
+                if verified.len() == *count {
+                    cancel.cancel();
+                }
+            }
+            Ok(())
+        });
+        mock_dl
+    }
+
+    #[tokio::test]
+    async fn test_resumed_verify_skips_verified_backups() {
+        let dir = tempfile::tempdir().unwrap();
+        let backups = dir.path().join("backups");
+        let mut backup_service = FileBackupService::new(backups.to_string_lossy().to_string()).unwrap();
+        let mut files = Vec::new();
+        for id in 1..=6 {
+            let path = dir.path().join(format!("{}.txt", id));
+            let contents = format!("contents of file {}", id).repeat(id as usize);
+            std::fs::write(&path, &contents).unwrap();
+            backup_service.backup_data(id, &path).await.unwrap();
+            files.push(FileModel {
+                version: 1, id, file_name: format!("{}.txt", id), backup_ts: Utc::now(),
+                hsh: Some(hash_reader(contents.as_bytes(), HashAlgorithm::Md5).unwrap().0), verified_ts: None,
+                file_size: Some(contents.len() as i64), generation: 0, label: None, excluded: false
+            });
+        }
+        // Plant a corrupt backup later in the sequence, by swapping in another file's backup
+        std::fs::copy(backups.join("0").join("1.gz"), backups.join("0").join("5.gz")).unwrap();
+        let mock_tp = Arc::new(MockTimeProvider::starting_at(Utc.with_ymd_and_hms(2024, 6, 1, 2, 0, 0).unwrap()));
+        let state = VerifyState::default();
+
+        // The first run is interrupted after verifying two backups
+        let cancel = CancellationToken::new();
+        let mock_dl = build_mock_data_layer(files.clone(), &state, Some((2, cancel.clone())));
+        let svc = FileHistoryService::new(Arc::new(mock_dl), mock_tp.clone(), 2, CanonicalizePolicy::Full).await.unwrap();
+        let report = verify_backups(&svc, &backup_service, false, 1, &cancel).await.unwrap();
+
+        assert!(report.cancelled);
+        assert_eq!(report.verified, 2);
+        assert_eq!(*state.progress.lock().unwrap(), HashMap::from([(0, 2)]));
+
+        // The resumed run only verifies the backups the first didn't reach
+        let mock_dl = build_mock_data_layer(files, &state, None);
+        let svc = FileHistoryService::new(Arc::new(mock_dl), mock_tp.clone(), 2, CanonicalizePolicy::Full).await.unwrap();
+        let report = verify_backups(&svc, &backup_service, true, 4, &CancellationToken::new()).await.unwrap();
+
+        assert!(!report.cancelled);
+        assert_eq!((report.skipped, report.verified), (2, 3));
+        assert_eq!(*state.verified.lock().unwrap(), vec![1, 2, 3, 4, 6]);
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].file_id, 5);
+        assert!(report.failures[0].reason.starts_with("expected hash"));
+    }
+}
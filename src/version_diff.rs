@@ -0,0 +1,138 @@
+use console::{style, Color};
+use similar::{ChangeTag, TextDiff};
+
+///
+/// The size in bytes above which versions are compared as binary, even if they're text,
+/// since a line diff of them would be too slow, and too long to read
+/// 
+pub const MAX_TEXT_DIFF_SIZE: usize = 4 * 1024 * 1024;
+
+///
+/// How two versions of a file differ
+/// 
+#[derive(Clone, Debug, PartialEq)]
+pub enum VersionDiff {
+    /// Both versions are text, compared line by line
+    Text {
+        /// The changes as a unified diff, empty if there are none
+        unified: String,
+        added: usize,
+        removed: usize
+    },
+    /// At least one version isn't UTF-8 text, or is larger than `MAX_TEXT_DIFF_SIZE`
+    Binary {
+        old_size: u64,
+        new_size: u64,
+        identical: bool
+    }
+}
+
+impl VersionDiff {
+    ///
+    /// Compares the `old` and `new` contents of a file, naming them `old_name` and `new_name` in the diff's header
+    /// 
+    pub fn new(old: &[u8], new: &[u8], old_name: &str, new_name: &str) -> Self {
+        fn text(contents: &[u8]) -> Option<&str> {
+            if contents.len() > MAX_TEXT_DIFF_SIZE { None } else { std::str::from_utf8(contents).ok() }
+        }
+        let (Some(old_text), Some(new_text)) = (text(old), text(new)) else {
+            return VersionDiff::Binary { old_size: old.len() as u64, new_size: new.len() as u64, identical: old == new };
+        };
+
+        let diff = TextDiff::from_lines(old_text, new_text);
+        let count = |tag| diff.iter_all_changes().filter(|change| change.tag() == tag).count();
+        let (added, removed) = (count(ChangeTag::Insert), count(ChangeTag::Delete));
+        let unified = if added + removed == 0 {
+            String::new()
+        } else {
+            diff.unified_diff().header(old_name, new_name).to_string()
+        };
+
+        VersionDiff::Text { unified, added, removed }
+    }
+
+    ///
+    /// Formats the diff for printing, coloring added lines green, removed lines red and hunk headers cyan
+    /// if `color` is set, or, if `stat` is set, only how many lines were added and removed
+    /// 
+    pub fn format(&self, color: bool, stat: bool) -> String {
+        match self {
+            VersionDiff::Binary { identical: true, .. } => String::new(),
+            VersionDiff::Binary { old_size, new_size, .. } => format!("binary files differ (sizes {} vs {})\n", old_size, new_size),
+            VersionDiff::Text { added, removed, .. } if stat => format!("{} line(s) added, {} line(s) removed\n", added, removed),
+            VersionDiff::Text { unified, .. } => unified.split_inclusive('\n')
+                .map(|line| {
+                    let line_color = if line.starts_with("+++") || line.starts_with("---") {
+                        None
+                    } else if line.starts_with('+') {
+                        Some(Color::Green)
+                    } else if line.starts_with('-') {
+                        Some(Color::Red)
+                    } else if line.starts_with("@@") {
+                        Some(Color::Cyan)
+                    } else {
+                        None
+                    };
+                    match line_color {
+                        Some(line_color) if color => format!("{}\n", style(line.trim_end_matches('\n')).fg(line_color).force_styling(true)),
+                        _ => line.to_string()
+                    }
+                })
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use crate::backup_service::{BackupService, FileBackupService};
+
+    use super::{VersionDiff, MAX_TEXT_DIFF_SIZE};
+
+    #[tokio::test]
+    async fn test_diff_of_two_stored_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut backup_svc = FileBackupService::new(dir.path().join("backups").to_string_lossy().to_string()).unwrap();
+        let file = dir.path().join("notes.txt");
+        std::fs::write(&file, "groceries\neggs\nmilk\nbread\n").unwrap();
+        backup_svc.backup_data(3, &file).await.unwrap();
+        std::fs::write(&file, "groceries\neggs\noat milk\nbread\nbutter\n").unwrap();
+        backup_svc.backup_data(5, &file).await.unwrap();
+
+        let mut versions = Vec::new();
+        for id in [3, 5] {
+            let mut contents = Vec::new();
+            backup_svc.open_backup(id).await.unwrap().read_to_end(&mut contents).unwrap();
+            versions.push(contents);
+        }
+        let diff = VersionDiff::new(&versions[0], &versions[1], "notes.txt (3)", "notes.txt (5)");
+
+        assert_eq!(diff.format(false, false), concat!(
+            "--- notes.txt (3)\n",
+            "+++ notes.txt (5)\n",
+            "@@ -1,4 +1,5 @@\n",
+            " groceries\n",
+            " eggs\n",
+            "-milk\n",
+            "+oat milk\n",
+            " bread\n",
+            "+butter\n"
+        ));
+        assert_eq!(diff.format(false, true), "2 line(s) added, 1 line(s) removed\n");
+        assert!(diff.format(true, false).contains("\u{1b}[31m-milk\u{1b}[0m\n"));
+    }
+
+    #[test]
+    fn test_binary_fallback() {
+        let diff = VersionDiff::new(&[0xff, 0xfe, 0x00], b"text\n", "a", "b");
+        assert_eq!(diff.format(false, false), "binary files differ (sizes 3 vs 5)\n");
+
+        // Text too large to diff by line is compared the same way
+        let large = "line\n".repeat(MAX_TEXT_DIFF_SIZE / 5 + 1);
+        let diff = VersionDiff::new(large.as_bytes(), large.as_bytes(), "a", "b");
+        assert_eq!(diff, VersionDiff::Binary { old_size: large.len() as u64, new_size: large.len() as u64, identical: true });
+        assert_eq!(diff.format(false, false), "");
+    }
+}
@@ -0,0 +1,123 @@
+use std::{future::Future, path::Path, sync::{atomic::{AtomicBool, Ordering}, Arc}};
+
+use tokio::sync::Mutex;
+
+use crate::collections::Cache;
+
+///
+/// The backup of one path, shared by every change event for it
+/// 
+#[derive(Default)]
+struct PathSlot {
+    /// Held while the path is being backed up
+    lock: Mutex<()>,
+    /// Whether an event is already waiting for the lock, and will back up the path's final state
+    queued: AtomicBool
+}
+
+///
+/// Serializes the backups of each path, for backing files up as change events arrive
+/// rather than from a scan. Backups of different paths run concurrently, but a path
+/// is only backed up by one event at a time, so its versions are recorded in the order
+/// they finished. Events arriving while a path is being backed up are coalesced, so
+/// the path is only backed up once more, in its final state.
+/// 
+pub struct PathSerializer {
+    slots: std::sync::Mutex<Cache<Arc<PathSlot>>>
+}
+
+impl PathSerializer {
+    pub fn new() -> Self {
+        Self { slots: std::sync::Mutex::new(Cache::new()) }
+    }
+
+    ///
+    /// Runs the `backup` of the file at `path` for a change event, once no other backup
+    /// of it is running. Returns `None` without running it if another event is already
+    /// waiting to back the path up, as that backup will find the file's final state.
+    /// The `backup` should read the file when it runs, not when the event arrived.
+    /// 
+    pub async fn run<F, Fut>(&self, path: &Path, backup: F) -> Option<Fut::Output>
+        where F: FnOnce() -> Fut, Fut: Future {
+        let key = path.to_string_lossy();
+        let slot = {
+            let mut slots = self.slots.lock().unwrap();
+            match slots.get(&key) {
+                Some(slot) => slot.clone(),
+                None => {
+                    let slot = Arc::new(PathSlot::default());
+                    slots.insert(&key, slot.clone());
+                    slot
+                }
+            }
+        };
+        if slot.queued.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+
+        let _guard = slot.lock.lock().await;
+        // Events from here on may have changed the file after it's read, so must queue again
+        slot.queued.store(false, Ordering::Release);
+        let output = backup().await;
+
+        // Forget the path once nothing else is waiting on it
+        let mut slots = self.slots.lock().unwrap();
+        if Arc::strong_count(&slot) == 2 && !slot.queued.load(Ordering::Acquire) {
+            slots.remove(&key);
+        }
+
+        Some(output)
+    }
+}
+
+impl Default for PathSerializer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Mutex, time::Duration};
+
+    use futures_util::future::join_all;
+
+    use super::PathSerializer;
+
+    #[tokio::test]
+    async fn test_rapid_writes_back_up_final_content_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        let serializer = PathSerializer::new();
+        // Each version recorded, where a version is only recorded if its contents changed
+        let versions = Mutex::new(Vec::<String>::new());
+        let (path, versions) = (&path, &versions);
+        let backup = move || async move {
+            let contents = tokio::fs::read_to_string(&path).await.unwrap();
+            // Compressing takes a while, during which more events arrive
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let mut versions = versions.lock().unwrap();
+            if versions.last() != Some(&contents) {
+                versions.push(contents);
+            }
+        };
+
+        let mut events = Vec::new();
+        for i in 1..=5 {
+            std::fs::write(path, format!("version {}", i)).unwrap();
+            events.push(serializer.run(path, backup));
+        }
+        let ran = join_all(events).await.into_iter().filter(Option::is_some).count();
+
+        // The first event backs up the final content, one more is queued behind it,
+        // and the rest are coalesced into that one
+        assert_eq!(ran, 2);
+        assert_eq!(*versions.lock().unwrap(), vec!["version 5".to_string()]);
+
+        // A later write is backed up as a new version, once the earlier backups finished
+        std::fs::write(path, "version 6").unwrap();
+        assert!(serializer.run(path, backup).await.is_some());
+        assert_eq!(*versions.lock().unwrap(), vec!["version 5".to_string(), "version 6".to_string()]);
+        assert!(serializer.slots.lock().unwrap().get(&path.to_string_lossy()).is_none());
+    }
+}